@@ -1,3 +1,6 @@
+/* 版本 */
+pub const LUA_VERSION: &str = "Lua 5.4";
+
 /* 基本类型 */
 pub const LUA_TNONE: i8 = -1;
 pub const LUA_TNIL: i8 = 0;
@@ -40,8 +43,43 @@ pub const LUA_OPLE: u8 = 2; // <=
 pub const LUA_OPGT: u8 = 3; // >
 pub const LUA_OPGE: u8 = 4; // >=
 
+/* 线程状态 */
+pub const LUA_OK: u8 = 0;
+pub const LUA_YIELD: u8 = 1;
+pub const LUA_ERRRUN: u8 = 2;
+pub const LUA_ERRSYNTAX: u8 = 3;
+pub const LUA_ERRMEM: u8 = 4;
+pub const LUA_ERRERR: u8 = 5;
+
+/* 协程状态 */
+pub const COS_RUN: u8 = 0;
+pub const COS_DEAD: u8 = 1;
+pub const COS_YIELD: u8 = 2;
+pub const COS_NORM: u8 = 3;
+
+/* 垃圾回收选项 */
+pub const LUA_GCSTOP: u8 = 0;
+pub const LUA_GCRESTART: u8 = 1;
+pub const LUA_GCCOLLECT: u8 = 2;
+pub const LUA_GCCOUNT: u8 = 3;
+pub const LUA_GCCOUNTB: u8 = 4;
+pub const LUA_GCSTEP: u8 = 5;
+pub const LUA_GCSETPAUSE: u8 = 6;
+pub const LUA_GCSETSTEPMUL: u8 = 7;
+pub const LUA_GCISRUNNING: u8 = 9;
+pub const LUA_GCGEN: u8 = 10;
+pub const LUA_GCINC: u8 = 11;
+
 /* 其他常量 */
+pub const LUA_MULTRET: isize = -1;
 pub const LUA_MINSTACK: usize = 20;
+pub const LUAI_MAXCCALLS: usize = 200;
+pub const LUAI_MAXCALLS: usize = 200000;
 pub const LUAI_MAXSTACK: usize = 1000000;
 pub const LUA_REGISTRYINDEX: isize = -(LUAI_MAXSTACK as isize) - 1000;
 pub const LUA_RIDX_GLOBALS: isize = 2;
+
+/// 当前闭包第 `i` 个上值（从 1 开始）的伪索引
+pub const fn lua_upvalueindex(i: isize) -> isize {
+    LUA_REGISTRYINDEX - i
+}
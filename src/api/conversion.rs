@@ -0,0 +1,382 @@
+use std::{collections::HashMap, hash::Hash};
+
+use crate::state::math::number_to_integer;
+
+use super::{LuaAPI, LuaError};
+
+/// 可以转换为一个 Lua 值的 Rust 类型。
+pub trait IntoLua {
+    /// 把值转换为 Lua 值并推入栈顶。出错时不推入任何值。
+    fn into_lua(self, ls: &mut dyn LuaAPI) -> Result<(), LuaError>;
+}
+
+/// 可以由一个 Lua 值转换得到的 Rust 类型。
+pub trait FromLua: Sized {
+    /// 把指定索引处的值转换为 Rust 值，不改变栈。索引处没有值时按 nil 处理。
+    fn from_lua(ls: &mut dyn LuaAPI, idx: isize) -> Result<Self, LuaError>;
+}
+
+/// 可以转换为多个 Lua 值的 Rust 类型，用于函数的参数和返回值。
+/// 元组的每个元素是一个值，`()` 没有值，其他实现了 `IntoLua` 的类型是一个值。
+pub trait IntoLuaMulti {
+    /// 把值依次推入栈顶，返回推入的值的数量。
+    fn into_lua_multi(self, ls: &mut dyn LuaAPI) -> Result<usize, LuaError>;
+}
+
+/// 可以由多个 Lua 值转换得到的 Rust 类型。值不够时缺少的部分按 nil 处理，多余的值被忽略。
+pub trait FromLuaMulti: Sized {
+    /// 转换从绝对索引 `idx` 开始的 `n` 个值，不改变栈。
+    fn from_lua_multi(ls: &mut dyn LuaAPI, idx: isize, n: usize) -> Result<Self, LuaError>;
+}
+
+/// 把 Lua 字符串的字节转换为 Rust 字符串，不是合法的 UTF-8 时返回错误而不是替换其中的字节。
+pub(crate) fn utf8_string(bytes: Vec<u8>) -> Result<String, LuaError> {
+    String::from_utf8(bytes).map_err(|_| LuaError::runtime("invalid UTF-8 in string"))
+}
+
+/// 转换失败的错误，形如 `number expected, got table`。
+pub(crate) fn expected(ls: &dyn LuaAPI, idx: isize, tname: &str) -> LuaError {
+    let got = ls.type_name(ls.type_id(idx));
+    LuaError::runtime(format!("{} expected, got {}", tname, got))
+}
+
+impl IntoLua for bool {
+    fn into_lua(self, ls: &mut dyn LuaAPI) -> Result<(), LuaError> {
+        ls.push_boolean(self);
+        Ok(())
+    }
+}
+
+impl FromLua for bool {
+    /// 与 Lua 的条件判断相同，只有 nil 和 false 转换为 `false`。
+    fn from_lua(ls: &mut dyn LuaAPI, idx: isize) -> Result<Self, LuaError> {
+        Ok(ls.to_boolean(idx))
+    }
+}
+
+macro_rules! impl_integer {
+    ($($t:ty)*) => {$(
+        impl IntoLua for $t {
+            fn into_lua(self, ls: &mut dyn LuaAPI) -> Result<(), LuaError> {
+                let n = i64::try_from(self).map_err(|_| {
+                    LuaError::runtime(format!("integer {} out of range", self))
+                })?;
+                ls.push_integer(n);
+                Ok(())
+            }
+        }
+
+        impl FromLua for $t {
+            fn from_lua(ls: &mut dyn LuaAPI, idx: isize) -> Result<Self, LuaError> {
+                let n = match ls.to_integerx(idx) {
+                    Some(n) => n,
+                    None => match ls.to_numberx(idx) {
+                        Some(f) => number_to_integer(f).ok_or_else(|| {
+                            LuaError::runtime("number has no integer representation")
+                        })?,
+                        None => return Err(expected(ls, idx, "number")),
+                    },
+                };
+                <$t>::try_from(n).map_err(|_| {
+                    LuaError::runtime(format!(
+                        "integer {} out of range for {}",
+                        n,
+                        stringify!($t)
+                    ))
+                })
+            }
+        }
+    )*};
+}
+
+impl_integer!(i8 i16 i32 i64 isize u8 u16 u32 u64 usize);
+
+macro_rules! impl_float {
+    ($($t:ty)*) => {$(
+        impl IntoLua for $t {
+            fn into_lua(self, ls: &mut dyn LuaAPI) -> Result<(), LuaError> {
+                ls.push_number(self as f64);
+                Ok(())
+            }
+        }
+
+        impl FromLua for $t {
+            fn from_lua(ls: &mut dyn LuaAPI, idx: isize) -> Result<Self, LuaError> {
+                match ls.to_numberx(idx) {
+                    Some(n) => Ok(n as $t),
+                    None => Err(expected(ls, idx, "number")),
+                }
+            }
+        }
+    )*};
+}
+
+impl_float!(f32 f64);
+
+impl IntoLua for String {
+    fn into_lua(self, ls: &mut dyn LuaAPI) -> Result<(), LuaError> {
+        ls.push_string(self);
+        Ok(())
+    }
+}
+
+impl IntoLua for &str {
+    fn into_lua(self, ls: &mut dyn LuaAPI) -> Result<(), LuaError> {
+        ls.push_string(self.to_string());
+        Ok(())
+    }
+}
+
+impl FromLua for String {
+    /// 数字转换为它的字符串形式。字符串不是合法的 UTF-8 时返回错误。
+    fn from_lua(ls: &mut dyn LuaAPI, idx: isize) -> Result<Self, LuaError> {
+        let bytes = ls
+            .to_bytesx(idx)
+            .ok_or_else(|| expected(ls, idx, "string"))?;
+        utf8_string(bytes)
+    }
+}
+
+impl<T: IntoLua> IntoLua for Option<T> {
+    /// `None` 转换为 nil。
+    fn into_lua(self, ls: &mut dyn LuaAPI) -> Result<(), LuaError> {
+        match self {
+            Some(val) => val.into_lua(ls),
+            None => {
+                ls.push_nil();
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<T: FromLua> FromLua for Option<T> {
+    /// nil 和不存在的值转换为 `None`。
+    fn from_lua(ls: &mut dyn LuaAPI, idx: isize) -> Result<Self, LuaError> {
+        if ls.is_none_or_nil(idx) {
+            Ok(None)
+        } else {
+            T::from_lua(ls, idx).map(Some)
+        }
+    }
+}
+
+impl<T: IntoLua> IntoLua for Vec<T> {
+    /// 转换为以 1 开始的序列。
+    fn into_lua(self, ls: &mut dyn LuaAPI) -> Result<(), LuaError> {
+        ls.create_table(self.len(), 0);
+        for (i, val) in self.into_iter().enumerate() {
+            if let Err(e) = val.into_lua(ls) {
+                ls.pop(1);
+                return Err(e);
+            }
+            ls.raw_set_i(-2, i as i64 + 1);
+        }
+        Ok(())
+    }
+}
+
+impl<T: FromLua> FromLua for Vec<T> {
+    /// 转换表的序列部分，即索引 1 到表的原始长度的值，不调用元方法。
+    fn from_lua(ls: &mut dyn LuaAPI, idx: isize) -> Result<Self, LuaError> {
+        if !ls.is_table(idx) {
+            return Err(expected(ls, idx, "table"));
+        }
+        let idx = ls.abs_index(idx);
+        let len = ls.raw_len(idx);
+        let mut vec = Vec::with_capacity(len);
+        for i in 1..=len {
+            ls.raw_get_i(idx, i as i64);
+            let val = T::from_lua(ls, -1);
+            ls.pop(1);
+            vec.push(val?);
+        }
+        Ok(vec)
+    }
+}
+
+impl<K: IntoLua, V: IntoLua> IntoLua for HashMap<K, V> {
+    fn into_lua(self, ls: &mut dyn LuaAPI) -> Result<(), LuaError> {
+        let top = ls.get_top();
+        ls.create_table(0, self.len());
+        for (k, v) in self {
+            let res = k
+                .into_lua(ls)
+                .and_then(|()| v.into_lua(ls))
+                .and_then(|()| ls.raw_set(top + 1));
+            if let Err(e) = res {
+                ls.set_top(top);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<K: FromLua + Eq + Hash, V: FromLua> FromLua for HashMap<K, V> {
+    /// 转换表的所有键值对，不调用元方法。
+    fn from_lua(ls: &mut dyn LuaAPI, idx: isize) -> Result<Self, LuaError> {
+        if !ls.is_table(idx) {
+            return Err(expected(ls, idx, "table"));
+        }
+        let idx = ls.abs_index(idx);
+        let top = ls.get_top();
+        let mut map = HashMap::new();
+        ls.push_nil();
+        loop {
+            match ls.next(idx) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    ls.set_top(top);
+                    return Err(e);
+                }
+            }
+            let entry = K::from_lua(ls, -2).and_then(|k| Ok((k, V::from_lua(ls, -1)?)));
+            ls.pop(1);
+            match entry {
+                Ok((k, v)) => map.insert(k, v),
+                Err(e) => {
+                    ls.set_top(top);
+                    return Err(e);
+                }
+            };
+        }
+        Ok(map)
+    }
+}
+
+impl IntoLuaMulti for () {
+    fn into_lua_multi(self, _ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+        Ok(0)
+    }
+}
+
+impl FromLuaMulti for () {
+    fn from_lua_multi(_ls: &mut dyn LuaAPI, _idx: isize, _n: usize) -> Result<Self, LuaError> {
+        Ok(())
+    }
+}
+
+impl<T: IntoLua> IntoLuaMulti for T {
+    fn into_lua_multi(self, ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+        self.into_lua(ls).map(|()| 1)
+    }
+}
+
+impl<T: FromLua> FromLuaMulti for T {
+    fn from_lua_multi(ls: &mut dyn LuaAPI, idx: isize, n: usize) -> Result<Self, LuaError> {
+        if n == 0 {
+            ls.push_nil();
+            let val = T::from_lua(ls, -1);
+            ls.pop(1);
+            val
+        } else {
+            T::from_lua(ls, idx)
+        }
+    }
+}
+
+macro_rules! impl_tuple {
+    ($($name:ident $i:tt)+) => {
+        impl<$($name: IntoLua),+> IntoLuaMulti for ($($name,)+) {
+            fn into_lua_multi(self, ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+                let top = ls.get_top();
+                let res: Result<(), LuaError> = (|| {
+                    $(self.$i.into_lua(ls)?;)+
+                    Ok(())
+                })();
+                match res {
+                    Ok(()) => Ok((ls.get_top() - top) as usize),
+                    Err(e) => {
+                        ls.set_top(top);
+                        Err(e)
+                    }
+                }
+            }
+        }
+
+        impl<$($name: FromLua),+> FromLuaMulti for ($($name,)+) {
+            fn from_lua_multi(ls: &mut dyn LuaAPI, idx: isize, n: usize) -> Result<Self, LuaError> {
+                Ok(($($name::from_lua_multi(ls, idx + $i, n.saturating_sub($i))?,)+))
+            }
+        }
+    };
+}
+
+impl_tuple!(A 0);
+impl_tuple!(A 0 B 1);
+impl_tuple!(A 0 B 1 C 2);
+impl_tuple!(A 0 B 1 C 2 D 3);
+impl_tuple!(A 0 B 1 C 2 D 3 E 4);
+impl_tuple!(A 0 B 1 C 2 D 3 E 4 F 5);
+impl_tuple!(A 0 B 1 C 2 D 3 E 4 F 5 G 6);
+impl_tuple!(A 0 B 1 C 2 D 3 E 4 F 5 G 6 H 7);
+
+#[cfg(test)]
+mod tests {
+    use crate::state::new_lua_state;
+
+    use super::*;
+
+    #[test]
+    fn test_conversions() {
+        let mut ls = new_lua_state();
+        let ls: &mut dyn LuaAPI = &mut ls;
+
+        (1, 2.5, "three", Some(true), None::<i64>)
+            .into_lua_multi(ls)
+            .unwrap();
+        assert_eq!(ls.get_top(), 5);
+        let (a, b, c, d, e, f): (u8, f64, String, bool, Option<i64>, Option<String>) =
+            FromLuaMulti::from_lua_multi(ls, 1, 5).unwrap();
+        assert_eq!(
+            (a, b, c.as_str(), d, e, f),
+            (1, 2.5, "three", true, None, None)
+        );
+        assert_eq!(String::from_lua(ls, 1).unwrap(), "1");
+        ls.push_bytes(b"\xff".to_vec());
+        assert_eq!(
+            String::from_lua(ls, -1).unwrap_err().to_string(),
+            "invalid UTF-8 in string"
+        );
+        ls.pop(1);
+        assert_eq!(
+            i64::from_lua(ls, 2).unwrap_err().to_string(),
+            "number has no integer representation"
+        );
+        assert_eq!(
+            i32::from_lua(ls, 3).unwrap_err().to_string(),
+            "number expected, got string"
+        );
+        ls.set_top(0);
+
+        ls.push_number(300.0);
+        assert_eq!(i64::from_lua(ls, -1).unwrap(), 300);
+        assert_eq!(
+            u8::from_lua(ls, -1).unwrap_err().to_string(),
+            "integer 300 out of range for u8"
+        );
+        assert!(u64::MAX.into_lua(ls).is_err());
+        ls.set_top(0);
+
+        vec![vec![1, 2], vec![], vec![3]].into_lua(ls).unwrap();
+        let nested: Vec<Vec<i64>> = FromLua::from_lua(ls, -1).unwrap();
+        assert_eq!(nested, [vec![1, 2], vec![], vec![3]]);
+        assert!(Vec::<String>::from_lua(ls, -1).is_err());
+        assert_eq!(ls.get_top(), 1);
+
+        let map: HashMap<String, i64> = [("a".to_string(), 1), ("b".to_string(), 2)].into();
+        map.clone().into_lua(ls).unwrap();
+        assert_eq!(HashMap::<String, i64>::from_lua(ls, -1).unwrap(), map);
+        assert_eq!(
+            HashMap::<String, bool>::from_lua(ls, 1).unwrap(),
+            HashMap::from([
+                ("1".to_string(), true),
+                ("2".to_string(), true),
+                ("3".to_string(), true)
+            ])
+        );
+        assert_eq!(ls.get_top(), 2);
+    }
+}
@@ -0,0 +1,79 @@
+use std::fmt;
+
+use crate::state::{lua_value::LuaValue, math::number_to_str};
+
+use super::consts::{LUA_ERRERR, LUA_ERRRUN, LUA_YIELD};
+
+/// Lua 运行时错误。错误对象可以是任意 Lua 值，由 `error` 抛出的通常是带有位置信息的字符串。
+#[derive(Clone, Debug)]
+pub struct LuaError {
+    status: u8,
+    value: LuaValue,
+    /// 是否已经交给消息处理函数处理过
+    pub(crate) handled: bool,
+}
+
+impl LuaError {
+    /// 以任意 Lua 值为错误对象的运行时错误。
+    pub fn new(value: LuaValue) -> LuaError {
+        LuaError {
+            status: LUA_ERRRUN,
+            value,
+            handled: false,
+        }
+    }
+
+    /// 以字符串为错误对象的运行时错误，不附加位置信息。
+    pub fn runtime(msg: impl Into<String>) -> LuaError {
+        LuaError::new(LuaValue::Str(msg.into().into_bytes()))
+    }
+
+    /// 消息处理函数本身出错时产生的错误。
+    pub(crate) fn error_in_handler() -> LuaError {
+        LuaError {
+            status: LUA_ERRERR,
+            value: LuaValue::Str(b"error in error handling".to_vec()),
+            handled: true,
+        }
+    }
+
+    /// 协程让出时沿调用栈向上传递到 `resume` 的信号，不是真正的错误。
+    pub(crate) fn yield_signal() -> LuaError {
+        LuaError {
+            status: LUA_YIELD,
+            value: LuaValue::Nil,
+            handled: true,
+        }
+    }
+
+    /// 错误对应的状态码，`LUA_ERRRUN` 或 `LUA_ERRERR`，让出信号是 `LUA_YIELD`。
+    pub fn status(&self) -> u8 {
+        self.status
+    }
+
+    /// 错误对象。
+    pub fn value(&self) -> &LuaValue {
+        &self.value
+    }
+
+    pub(crate) fn set_value(&mut self, value: LuaValue) {
+        self.value = value;
+    }
+
+    pub fn into_value(self) -> LuaValue {
+        self.value
+    }
+}
+
+impl fmt::Display for LuaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.value {
+            LuaValue::Str(s) => write!(f, "{}", String::from_utf8_lossy(s)),
+            LuaValue::Integer(n) => write!(f, "{}", n),
+            LuaValue::Number(n) => write!(f, "{}", number_to_str(*n)),
+            v => write!(f, "(error object is a {} value)", v.type_name()),
+        }
+    }
+}
+
+impl std::error::Error for LuaError {}
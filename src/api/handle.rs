@@ -0,0 +1,627 @@
+use std::{
+    cell::{Ref, RefCell, RefMut},
+    marker::PhantomData,
+    rc::{Rc, Weak},
+};
+
+use crate::state::{lua_table::LuaTable, lua_userdata::LuaUserData, lua_value::LuaValue};
+
+use super::{
+    consts::{LUA_MULTRET, LUA_OK, LUA_YIELD},
+    conversion::{expected, FromLua, FromLuaMulti, IntoLua, IntoLuaMulti},
+    r#type::Type,
+    userdata::{push_userdata, UserData},
+    LuaAPI, LuaError,
+};
+
+/// 空闲引用链表的表头在注册表中的键，位于散列部分，不会与引用的位置冲突
+const FREELIST: LuaValue = LuaValue::Integer(0);
+/// nil 的引用，不占用注册表中的位置
+const REFNIL: i64 = -1;
+
+/// 注册表被借用时丢弃的引用的位置，下次访问注册表时再放回空闲链表。由状态持有，引用只保存它的弱引用。
+pub(crate) type PendingFree = Rc<RefCell<Vec<i64>>>;
+
+/// 保存在注册表中的值的引用，由 `LuaAPI::new_ref` 创建。
+///
+/// 引用存在期间值不会被回收，丢弃引用时释放它在注册表中的位置。引用只持有注册表的弱引用，
+/// 所以比状态活得更久也没有关系。
+#[derive(Debug)]
+pub struct LuaRef {
+    registry: Weak<RefCell<LuaTable>>,
+    pending: Weak<RefCell<Vec<i64>>>,
+    id: i64,
+}
+
+impl LuaRef {
+    /// 把 `val` 保存在注册表中的空闲位置，与 `luaL_ref` 相同：优先复用释放的位置，否则追加到数组部分末尾。
+    pub(crate) fn new(
+        registry: &Rc<RefCell<LuaTable>>,
+        pending: &PendingFree,
+        val: LuaValue,
+    ) -> LuaRef {
+        free_pending(registry, pending);
+        let id = if val.is_nil() {
+            REFNIL
+        } else {
+            let mut t = registry.borrow_mut();
+            let id = match t.get(&FREELIST) {
+                LuaValue::Integer(id) if id > 0 => {
+                    let next = t.get(&LuaValue::Integer(id));
+                    t.put(FREELIST, next);
+                    id
+                }
+                _ => t.len() as i64 + 1,
+            };
+            t.put(LuaValue::Integer(id), val);
+            id
+        };
+        LuaRef {
+            registry: Rc::downgrade(registry),
+            pending: Rc::downgrade(pending),
+            id,
+        }
+    }
+
+    /// 引用的值。引用属于其他状态时 panic。
+    pub(crate) fn get(&self, registry: &Rc<RefCell<LuaTable>>) -> LuaValue {
+        assert!(
+            Weak::ptr_eq(&self.registry, &Rc::downgrade(registry)),
+            "reference belongs to another state!"
+        );
+        if self.id == REFNIL {
+            LuaValue::Nil
+        } else {
+            registry.borrow().get(&LuaValue::Integer(self.id))
+        }
+    }
+}
+
+impl Drop for LuaRef {
+    fn drop(&mut self) {
+        if self.id == REFNIL {
+            return;
+        }
+        let (Some(registry), Some(pending)) = (self.registry.upgrade(), self.pending.upgrade())
+        else {
+            return;
+        };
+        pending.borrow_mut().push(self.id);
+        free_pending(&registry, &pending);
+    }
+}
+
+/// 把等待释放的位置放回空闲链表。注册表正被借用时（例如被回收的对象丢弃了它持有的引用）什么也不做，
+/// 留到下次访问注册表时再释放。
+fn free_pending(registry: &Rc<RefCell<LuaTable>>, pending: &PendingFree) {
+    loop {
+        let ids = std::mem::take(&mut *pending.borrow_mut());
+        if ids.is_empty() {
+            return;
+        }
+        let Ok(mut t) = registry.try_borrow_mut() else {
+            pending.borrow_mut().extend(ids);
+            return;
+        };
+        // drop the released values after the borrow ends, they may hold references too
+        let mut released = Vec::with_capacity(ids.len());
+        for id in ids {
+            let head = match t.get(&FREELIST) {
+                LuaValue::Integer(head) => head,
+                _ => 0,
+            };
+            released.push(t.get(&LuaValue::Integer(id)));
+            t.put(LuaValue::Integer(id), LuaValue::Integer(head));
+            t.put(FREELIST, LuaValue::Integer(id));
+        }
+        drop(t);
+        drop(released);
+    }
+}
+
+/// 在 `f` 执行完之后把栈恢复到原来的高度，无论 `f` 是否出错。
+fn balanced<R>(
+    ls: &mut dyn LuaAPI,
+    f: impl FnOnce(&mut dyn LuaAPI) -> Result<R, LuaError>,
+) -> Result<R, LuaError> {
+    let top = ls.get_top();
+    let res = f(ls);
+    ls.set_top(top);
+    res
+}
+
+/// 检查指定索引处的值的类型，把它保存为引用。
+fn to_ref(ls: &mut dyn LuaAPI, idx: isize, t: Type) -> Result<Rc<LuaRef>, LuaError> {
+    if ls.type_id(idx) != t as i8 {
+        return Err(expected(ls, idx, ls.type_name(t as i8)));
+    }
+    ls.push_value(idx);
+    Ok(Rc::new(ls.new_ref()))
+}
+
+/// Lua 表的句柄。
+///
+/// 句柄不持有状态，每个操作都需要传入状态。读写操作和 Lua 代码一样会调用 `__index`、`__newindex` 元方法，
+/// `raw_` 开头的操作不调用元方法。
+#[derive(Clone, Debug)]
+pub struct Table(Rc<LuaRef>);
+
+impl Table {
+    /// 创建一个新的空表。
+    pub fn new(ls: &mut dyn LuaAPI) -> Table {
+        ls.new_table();
+        Table(Rc::new(ls.new_ref()))
+    }
+
+    /// 读取 `t[key]`。
+    pub fn get<K: IntoLua, V: FromLua>(&self, ls: &mut dyn LuaAPI, key: K) -> Result<V, LuaError> {
+        balanced(ls, |ls| {
+            ls.push_ref(&self.0);
+            key.into_lua(ls)?;
+            ls.get_table(-2)?;
+            V::from_lua(ls, -1)
+        })
+    }
+
+    /// 执行 `t[key] = val`。
+    pub fn set<K: IntoLua, V: IntoLua>(
+        &self,
+        ls: &mut dyn LuaAPI,
+        key: K,
+        val: V,
+    ) -> Result<(), LuaError> {
+        balanced(ls, |ls| {
+            ls.push_ref(&self.0);
+            key.into_lua(ls)?;
+            val.into_lua(ls)?;
+            ls.set_table(-3)
+        })
+    }
+
+    /// 不调用元方法读取 `t[key]`。
+    pub fn raw_get<K: IntoLua, V: FromLua>(
+        &self,
+        ls: &mut dyn LuaAPI,
+        key: K,
+    ) -> Result<V, LuaError> {
+        balanced(ls, |ls| {
+            ls.push_ref(&self.0);
+            key.into_lua(ls)?;
+            ls.raw_get(-2);
+            V::from_lua(ls, -1)
+        })
+    }
+
+    /// 不调用元方法执行 `t[key] = val`。
+    pub fn raw_set<K: IntoLua, V: IntoLua>(
+        &self,
+        ls: &mut dyn LuaAPI,
+        key: K,
+        val: V,
+    ) -> Result<(), LuaError> {
+        balanced(ls, |ls| {
+            ls.push_ref(&self.0);
+            key.into_lua(ls)?;
+            val.into_lua(ls)?;
+            ls.raw_set(-3)
+        })
+    }
+
+    /// 表的长度，与 `#t` 相同，会调用 `__len` 元方法。
+    pub fn len(&self, ls: &mut dyn LuaAPI) -> Result<i64, LuaError> {
+        balanced(ls, |ls| {
+            ls.push_ref(&self.0);
+            ls.len(-1)?;
+            i64::from_lua(ls, -1)
+        })
+    }
+
+    /// 表的原始长度，不调用元方法。
+    pub fn raw_len(&self, ls: &mut dyn LuaAPI) -> usize {
+        ls.push_ref(&self.0);
+        let len = ls.raw_len(-1);
+        ls.pop(1);
+        len
+    }
+
+    /// 表的元表。
+    pub fn metatable(&self, ls: &mut dyn LuaAPI) -> Option<Table> {
+        ls.push_ref(&self.0);
+        let mt = ls.get_metatable(-1).then(|| Table(Rc::new(ls.new_ref())));
+        ls.pop(1);
+        mt
+    }
+
+    /// 设置表的元表，`None` 时移除元表。
+    pub fn set_metatable(&self, ls: &mut dyn LuaAPI, mt: Option<&Table>) {
+        ls.push_ref(&self.0);
+        match mt {
+            Some(mt) => ls.push_ref(&mt.0),
+            None => ls.push_nil(),
+        }
+        ls.set_metatable(-2);
+        ls.pop(1);
+    }
+
+    /// 遍历表的键值对，与 `next` 的顺序相同。遍历期间状态被迭代器借用。
+    pub fn pairs<'a, K: FromLua, V: FromLua>(
+        &self,
+        ls: &'a mut dyn LuaAPI,
+    ) -> TablePairs<'a, K, V> {
+        let top = ls.get_top();
+        ls.push_ref(&self.0);
+        ls.push_nil();
+        TablePairs {
+            ls,
+            top,
+            done: false,
+            marker: PhantomData,
+        }
+    }
+}
+
+/// `Table::pairs` 返回的迭代器。表和当前的键保存在栈顶，迭代器被丢弃时弹出。
+pub struct TablePairs<'a, K, V> {
+    ls: &'a mut dyn LuaAPI,
+    /// 开始遍历前的栈顶
+    top: isize,
+    done: bool,
+    marker: PhantomData<(K, V)>,
+}
+
+impl<K: FromLua, V: FromLua> Iterator for TablePairs<'_, K, V> {
+    type Item = Result<(K, V), LuaError>;
+
+    /// 转换失败的键值对产生一个错误，之后可以继续遍历；`next` 本身出错时遍历结束。
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.ls.next(self.top + 1) {
+            Ok(true) => {
+                let ls = &mut *self.ls;
+                let entry = K::from_lua(ls, -2).and_then(|k| Ok((k, V::from_lua(ls, -1)?)));
+                ls.pop(1);
+                Some(entry)
+            }
+            Ok(false) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<K, V> Drop for TablePairs<'_, K, V> {
+    fn drop(&mut self) {
+        self.ls.set_top(self.top);
+    }
+}
+
+/// Lua 函数或者 Rust 函数的句柄。
+#[derive(Clone, Debug)]
+pub struct Function(Rc<LuaRef>);
+
+impl Function {
+    /// 把参数类型和返回值类型确定的 Rust 闭包包装为函数。参数不够时缺少的部分按 nil 转换。
+    pub fn wrap<A, R, F>(ls: &mut dyn LuaAPI, f: F) -> Function
+    where
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+        F: Fn(&mut dyn LuaAPI, A) -> Result<R, LuaError> + 'static,
+    {
+        ls.push_closure(
+            Rc::new(move |ls: &mut dyn LuaAPI| {
+                let n = ls.get_top() as usize;
+                let args = A::from_lua_multi(ls, 1, n)?;
+                f(ls, args)?.into_lua_multi(ls)
+            }),
+            0,
+        );
+        Function(Rc::new(ls.new_ref()))
+    }
+
+    /// 调用函数，返回转换后的结果。
+    pub fn call<A: IntoLuaMulti, R: FromLuaMulti>(
+        &self,
+        ls: &mut dyn LuaAPI,
+        args: A,
+    ) -> Result<R, LuaError> {
+        balanced(ls, |ls| {
+            let top = ls.get_top();
+            ls.push_ref(&self.0);
+            let nargs = args.into_lua_multi(ls)?;
+            ls.call(nargs, LUA_MULTRET)?;
+            let nresults = (ls.get_top() - top) as usize;
+            R::from_lua_multi(ls, top + 1, nresults)
+        })
+    }
+}
+
+/// 线程（协程）的句柄。
+#[derive(Clone, Debug)]
+pub struct Thread(Rc<LuaRef>);
+
+impl Thread {
+    /// 创建以 `f` 为主函数的新线程。
+    pub fn new(ls: &mut dyn LuaAPI, f: &Function) -> Thread {
+        ls.push_ref(&f.0);
+        ls.new_thread();
+        Thread(Rc::new(ls.new_ref()))
+    }
+
+    /// 开始或者继续运行线程，返回让出的值或者主函数的返回值。线程出错时返回错误，线程随之终止。
+    pub fn resume<A: IntoLuaMulti, R: FromLuaMulti>(
+        &self,
+        ls: &mut dyn LuaAPI,
+        args: A,
+    ) -> Result<R, LuaError> {
+        balanced(ls, |ls| {
+            let top = ls.get_top();
+            ls.push_ref(&self.0);
+            let nargs = args.into_lua_multi(ls)?;
+            match ls.resume(top + 1, nargs) {
+                (LUA_OK | LUA_YIELD, n) => {
+                    let idx = ls.get_top() - n as isize + 1;
+                    R::from_lua_multi(ls, idx, n)
+                }
+                _ => Err(ls.error()),
+            }
+        })
+    }
+
+    /// 线程的状态，与 `LuaAPI::co_status` 相同。
+    pub fn status(&self, ls: &mut dyn LuaAPI) -> u8 {
+        ls.push_ref(&self.0);
+        let status = ls.co_status(-1);
+        ls.pop(1);
+        status
+    }
+}
+
+/// 任意完整用户数据的句柄，可以借用其中的 Rust 值。
+#[derive(Clone, Debug)]
+pub struct AnyUserData(Rc<LuaRef>, Rc<LuaUserData>);
+
+impl AnyUserData {
+    /// 把 `value` 作为用户数据推入 Lua 并返回它的句柄。
+    pub fn new<T: UserData>(ls: &mut dyn LuaAPI, value: T) -> Result<AnyUserData, LuaError> {
+        push_userdata(ls, value)?;
+        AnyUserData::from_lua(ls, -1).inspect(|_| ls.pop(1))
+    }
+
+    /// Rust 值的类型是否是 `T`。
+    pub fn is<T: 'static>(&self) -> bool {
+        self.1.is::<T>()
+    }
+
+    /// 借用类型为 `T` 的 Rust 值。
+    pub fn borrow<T: 'static>(&self) -> Result<Ref<'_, T>, LuaError> {
+        self.1.borrow::<T>().ok_or_else(|| borrow_error::<T>())
+    }
+
+    /// 可变借用类型为 `T` 的 Rust 值。
+    pub fn borrow_mut<T: 'static>(&self) -> Result<RefMut<'_, T>, LuaError> {
+        self.1.borrow_mut::<T>().ok_or_else(|| borrow_error::<T>())
+    }
+}
+
+fn borrow_error<T>() -> LuaError {
+    LuaError::runtime(format!(
+        "userdata is not a {} or is already borrowed",
+        std::any::type_name::<T>()
+    ))
+}
+
+macro_rules! impl_handle {
+    ($($name:ident $t:ident),*) => {$(
+        impl FromLua for $name {
+            fn from_lua(ls: &mut dyn LuaAPI, idx: isize) -> Result<Self, LuaError> {
+                to_ref(ls, idx, Type::$t).map($name)
+            }
+        }
+
+        impl IntoLua for $name {
+            fn into_lua(self, ls: &mut dyn LuaAPI) -> Result<(), LuaError> {
+                ls.push_ref(&self.0);
+                Ok(())
+            }
+        }
+
+        impl IntoLua for &$name {
+            fn into_lua(self, ls: &mut dyn LuaAPI) -> Result<(), LuaError> {
+                ls.push_ref(&self.0);
+                Ok(())
+            }
+        }
+    )*};
+}
+
+impl_handle!(Table Table, Function Function, Thread Thread);
+
+impl FromLua for AnyUserData {
+    fn from_lua(ls: &mut dyn LuaAPI, idx: isize) -> Result<Self, LuaError> {
+        let r = to_ref(ls, idx, Type::UserData)?;
+        let u = ls.to_userdata(idx).unwrap();
+        Ok(AnyUserData(r, u))
+    }
+}
+
+impl IntoLua for AnyUserData {
+    fn into_lua(self, ls: &mut dyn LuaAPI) -> Result<(), LuaError> {
+        ls.push_ref(&self.0);
+        Ok(())
+    }
+}
+
+impl IntoLua for &AnyUserData {
+    fn into_lua(self, ls: &mut dyn LuaAPI) -> Result<(), LuaError> {
+        ls.push_ref(&self.0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        api::consts::{COS_DEAD, COS_YIELD, LUA_GCCOLLECT, LUA_REGISTRYINDEX},
+        state::new_lua_state,
+        stdlib::{base::open_base, coroutine::open_coroutine},
+    };
+
+    use super::*;
+
+    fn eval<R: FromLuaMulti>(ls: &mut dyn LuaAPI, src: &str) -> R {
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        let f = Function::from_lua(ls, -1).unwrap();
+        ls.pop(1);
+        f.call(ls, ()).unwrap()
+    }
+
+    #[test]
+    fn test_handles() {
+        let mut ls = new_lua_state();
+        let ls: &mut dyn LuaAPI = &mut ls;
+        open_base(ls).unwrap();
+        open_coroutine(ls).unwrap();
+
+        let t: Table = eval(ls, "return { 10, 20, x = 'x' }");
+        let mt: Table = eval(
+            ls,
+            "return { __index = function(_, k) return k .. '!' end }",
+        );
+        t.set_metatable(ls, Some(&mt));
+        assert_eq!(t.get::<_, i64>(ls, 2).unwrap(), 20);
+        assert_eq!(t.get::<_, String>(ls, "y").unwrap(), "y!");
+        assert_eq!(t.raw_get::<_, Option<String>>(ls, "y").unwrap(), None);
+        t.set(ls, "list", vec![1, 2, 3]).unwrap();
+        assert_eq!(t.get::<_, Vec<i64>>(ls, "list").unwrap(), [1, 2, 3]);
+        assert_eq!(t.len(ls).unwrap(), 2);
+        assert!(t.metatable(ls).is_some());
+        let mut pairs: Vec<(String, String)> = t
+            .pairs::<String, String>(ls)
+            .filter_map(Result::ok)
+            .collect();
+        pairs.sort();
+        assert_eq!(
+            pairs,
+            [
+                ("1".into(), "10".into()),
+                ("2".into(), "20".into()),
+                ("x".into(), "x".into())
+            ]
+        );
+        assert_eq!(ls.get_top(), 0);
+
+        let add = Function::wrap(ls, |_, (a, b): (i64, Option<i64>)| Ok(a + b.unwrap_or(0)));
+        ls.push_global_table();
+        let globals = Table::from_lua(ls, -1).unwrap();
+        ls.pop(1);
+        globals.set(ls, "add", &add).unwrap();
+        assert_eq!(add.call::<_, i64>(ls, (1, 2)).unwrap(), 3);
+        assert_eq!(eval::<(i64, i64)>(ls, "return add(40, 2), add(1)"), (42, 1));
+        let e = add.call::<_, i64>(ls, "x").unwrap_err();
+        assert_eq!(e.to_string(), "number expected, got string");
+
+        let gen: Function = eval(
+            ls,
+            "return function(a) local b = coroutine.yield(a + 1) error(b) end",
+        );
+        let co = Thread::new(ls, &gen);
+        assert_eq!(co.resume::<_, i64>(ls, 1).unwrap(), 2);
+        assert_eq!(co.status(ls), COS_YIELD);
+        assert_eq!(
+            co.resume::<_, ()>(ls, "boom").unwrap_err().to_string(),
+            "test:1: boom"
+        );
+        assert_eq!(co.status(ls), COS_DEAD);
+        assert_eq!(ls.get_top(), 0);
+    }
+
+    #[test]
+    fn test_refs() {
+        let mut ls = new_lua_state();
+        let ls: &mut dyn LuaAPI = &mut ls;
+
+        // a referenced table survives collections while no Lua value refers to it
+        let t = Table::new(ls);
+        t.set(ls, "k", "v").unwrap();
+        ls.gc(LUA_GCCOLLECT, &[]);
+        assert_eq!(t.get::<_, String>(ls, "k").unwrap(), "v");
+
+        // dropped references are reused, clones share one slot
+        let t2 = t.clone();
+        let id = t.0.id;
+        drop(t);
+        assert_eq!(t2.0.id, id);
+        drop(t2);
+        let t3 = Table::new(ls);
+        assert_eq!(t3.0.id, id);
+        ls.raw_get_i(LUA_REGISTRYINDEX, id);
+        assert!(ls.is_table(-1));
+        ls.pop(1);
+
+        ls.push_nil();
+        let nil = ls.new_ref();
+        ls.push_ref(&nil);
+        assert!(ls.is_nil(-1));
+        ls.pop(1);
+        assert!(Table::from_lua(ls, 1).is_err());
+
+        let ud = AnyUserData::new(ls, Counter(1)).unwrap();
+        ud.borrow_mut::<Counter>().unwrap().0 += 1;
+        assert!(ud.is::<Counter>());
+        assert!(ud.borrow::<String>().is_err());
+        ud.into_lua(ls).unwrap();
+        let ud = AnyUserData::from_lua(ls, -1).unwrap();
+        assert_eq!(ud.borrow::<Counter>().unwrap().0, 2);
+        ls.pop(1);
+    }
+
+    #[test]
+    fn test_refs_dropped_while_registry_borrowed() {
+        let mut ls = new_lua_state();
+        let registry = match &ls.registry {
+            LuaValue::Table(t) => t.clone(),
+            _ => unreachable!(),
+        };
+        let ls: &mut dyn LuaAPI = &mut ls;
+
+        let t = Table::new(ls);
+        let id = t.0.id;
+        let guard = registry.borrow();
+        drop(t);
+        drop(guard);
+        // the slot is released when the registry is used again
+        let t2 = Table::new(ls);
+        assert_eq!(t2.0.id, id);
+        let t3 = Table::new(ls);
+        assert_ne!(t3.0.id, id);
+
+        // a referenced value that holds references releases them as well
+        struct Holder {
+            _inner: Table,
+        }
+        impl UserData for Holder {}
+        let inner = Table::new(ls);
+        let inner_id = inner.0.id;
+        let outer = AnyUserData::new(ls, Holder { _inner: inner }).unwrap();
+        let outer_id = outer.0.id;
+        drop(outer);
+        ls.gc(LUA_GCCOLLECT, &[]);
+        let tables = [Table::new(ls), Table::new(ls)];
+        let mut ids: Vec<i64> = tables.iter().map(|t| t.0.id).collect();
+        ids.sort();
+        let mut expected = vec![inner_id, outer_id];
+        expected.sort();
+        assert_eq!(ids, expected);
+    }
+
+    struct Counter(i64);
+
+    impl UserData for Counter {}
+}
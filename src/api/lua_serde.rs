@@ -0,0 +1,1130 @@
+use std::fmt::Display;
+
+use serde::{
+    de::{
+        self, DeserializeOwned, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess,
+        SeqAccess, VariantAccess, Visitor,
+    },
+    forward_to_deserialize_any,
+    ser::{self, Serialize},
+};
+
+use crate::state::math::number_to_integer;
+
+use super::{
+    consts::LUA_REGISTRYINDEX,
+    conversion::{utf8_string, FromLua, IntoLua},
+    r#type::Type,
+    LuaAPI, LuaError,
+};
+
+/// 数组元表在注册表中的键
+const ARRAY_METATABLE: &str = "_ARRAY";
+
+/// Rust 值与 Lua 值相互转换的选项。
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    /// 序列化时 `None` 和 `()` 转换为 null（空指针轻量用户数据）而不是 nil，这样它们在数组和表中仍然占有位置。
+    /// 反序列化时总是把 null 当作 nil。默认关闭。
+    pub unit_to_null: bool,
+    /// 序列化时给序列（`Vec`、元组等）转换得到的表设置数组元表，反序列化时据此把空表识别为空数组。默认打开。
+    pub array_metatable: bool,
+    /// 序列化时整数值的浮点数仍然转换为浮点数。关闭时转换为整数，例如 `2.0` 转换为 `2`。默认打开。
+    pub preserve_floats: bool,
+    /// 反序列化到类型不确定的目标（例如 `deserialize_any`）时，把键恰好为 1 到 n 的非空表当作数组，
+    /// 否则只有带有数组元表的表才是数组。默认打开。
+    pub sequences_as_arrays: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            unit_to_null: false,
+            array_metatable: true,
+            preserve_floats: true,
+            sequences_as_arrays: true,
+        }
+    }
+}
+
+impl ser::Error for LuaError {
+    fn custom<T: Display>(msg: T) -> Self {
+        LuaError::runtime(msg.to_string())
+    }
+}
+
+impl de::Error for LuaError {
+    fn custom<T: Display>(msg: T) -> Self {
+        LuaError::runtime(msg.to_string())
+    }
+}
+
+/// 把 null 推入栈顶。
+pub fn push_null(ls: &mut dyn LuaAPI) {
+    ls.push_light_userdata(std::ptr::null_mut());
+}
+
+/// 把数组元表推入栈顶，第一次使用时创建。
+pub fn push_array_metatable(ls: &mut dyn LuaAPI) -> Result<(), LuaError> {
+    if ls.get_field(LUA_REGISTRYINDEX, ARRAY_METATABLE)? == Type::Nil as i8 {
+        ls.pop(1);
+        ls.new_table();
+        ls.push_value(-1);
+        ls.set_field(LUA_REGISTRYINDEX, ARRAY_METATABLE)?;
+    }
+    Ok(())
+}
+
+/// 把 `value` 序列化为 Lua 值并推入栈顶。出错时栈保持不变。
+pub fn to_lua<T: Serialize + ?Sized>(ls: &mut dyn LuaAPI, value: &T) -> Result<(), LuaError> {
+    to_lua_with(ls, value, Options::default())
+}
+
+/// 与 `to_lua` 相同，但是使用指定的选项。
+pub fn to_lua_with<T: Serialize + ?Sized>(
+    ls: &mut dyn LuaAPI,
+    value: &T,
+    options: Options,
+) -> Result<(), LuaError> {
+    let top = ls.get_top();
+    value
+        .serialize(Serializer { ls, options })
+        .inspect_err(|_| ls.set_top(top))
+}
+
+/// 把指定索引处的 Lua 值反序列化为 Rust 值，不改变栈。
+pub fn from_lua<T: DeserializeOwned>(ls: &mut dyn LuaAPI, idx: isize) -> Result<T, LuaError> {
+    from_lua_with(ls, idx, Options::default())
+}
+
+/// 与 `from_lua` 相同，但是使用指定的选项。
+pub fn from_lua_with<T: DeserializeOwned>(
+    ls: &mut dyn LuaAPI,
+    idx: isize,
+    options: Options,
+) -> Result<T, LuaError> {
+    let top = ls.get_top();
+    let idx = ls.abs_index(idx);
+    let res = T::deserialize(Deserializer { ls, idx, options });
+    ls.set_top(top);
+    res
+}
+
+/// 通过 serde 转换的值，使任意可序列化的类型可以用于 `IntoLua`、`FromLua`，例如 `table.get::<_, Serde<Config>>(ls, "config")`。
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Serde<T>(pub T);
+
+impl<T: Serialize> IntoLua for Serde<T> {
+    fn into_lua(self, ls: &mut dyn LuaAPI) -> Result<(), LuaError> {
+        to_lua(ls, &self.0)
+    }
+}
+
+impl<T: DeserializeOwned> FromLua for Serde<T> {
+    fn from_lua(ls: &mut dyn LuaAPI, idx: isize) -> Result<Self, LuaError> {
+        from_lua(ls, idx).map(Serde)
+    }
+}
+
+/// 把 Rust 值转换为 Lua 值的序列化器，每个值转换后推入栈顶。
+pub struct Serializer<'a> {
+    ls: &'a mut dyn LuaAPI,
+    options: Options,
+}
+
+impl Serializer<'_> {
+    fn push_unit(self) -> Result<(), LuaError> {
+        if self.options.unit_to_null {
+            push_null(self.ls);
+        } else {
+            self.ls.push_nil();
+        }
+        Ok(())
+    }
+
+    /// 创建数组的表并推入栈顶，返回表的绝对索引。
+    fn push_array(&mut self, len: usize) -> Result<isize, LuaError> {
+        self.ls.create_table(len, 0);
+        if self.options.array_metatable {
+            push_array_metatable(self.ls)?;
+            self.ls.set_metatable(-2);
+        }
+        Ok(self.ls.get_top())
+    }
+
+    /// 创建只有一个键 `variant` 的表，推入表和键，值由调用者推入。
+    fn push_variant(&mut self, variant: &str) -> isize {
+        self.ls.create_table(0, 1);
+        self.ls.push_string(variant.to_string());
+        self.ls.get_top() - 1
+    }
+}
+
+impl<'a> ser::Serializer for Serializer<'a> {
+    type Ok = ();
+    type Error = LuaError;
+    type SerializeSeq = SerializeArray<'a>;
+    type SerializeTuple = SerializeArray<'a>;
+    type SerializeTupleStruct = SerializeArray<'a>;
+    type SerializeTupleVariant = SerializeArray<'a>;
+    type SerializeMap = SerializeTable<'a>;
+    type SerializeStruct = SerializeTable<'a>;
+    type SerializeStructVariant = SerializeTable<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), LuaError> {
+        self.ls.push_boolean(v);
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), LuaError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), LuaError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), LuaError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), LuaError> {
+        self.ls.push_integer(v);
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), LuaError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), LuaError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), LuaError> {
+        self.serialize_i64(v as i64)
+    }
+
+    /// 超出整数范围的值转换为浮点数。
+    fn serialize_u64(self, v: u64) -> Result<(), LuaError> {
+        match i64::try_from(v) {
+            Ok(n) => self.ls.push_integer(n),
+            Err(_) => self.ls.push_number(v as f64),
+        }
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), LuaError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), LuaError> {
+        match number_to_integer(v) {
+            Some(n) if !self.options.preserve_floats => self.ls.push_integer(n),
+            _ => self.ls.push_number(v),
+        }
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), LuaError> {
+        self.ls.push_string(v.to_string());
+        Ok(())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), LuaError> {
+        self.ls.push_string(v.to_string());
+        Ok(())
+    }
+
+    /// 字节序列转换为由整数组成的数组。
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), LuaError> {
+        use ser::SerializeSeq;
+        let mut seq = self.serialize_seq(Some(v.len()))?;
+        for b in v {
+            seq.serialize_element(b)?;
+        }
+        seq.end()
+    }
+
+    fn serialize_none(self) -> Result<(), LuaError> {
+        self.push_unit()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), LuaError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), LuaError> {
+        self.push_unit()
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), LuaError> {
+        self.push_unit()
+    }
+
+    /// 单元变体转换为变体名。
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<(), LuaError> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), LuaError> {
+        value.serialize(self)
+    }
+
+    /// 其他变体转换为 `{ Variant = value }`。
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        mut self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), LuaError> {
+        let idx = self.push_variant(variant);
+        value.serialize(Serializer {
+            ls: &mut *self.ls,
+            options: self.options,
+        })?;
+        self.ls.raw_set(idx)
+    }
+
+    fn serialize_seq(mut self, len: Option<usize>) -> Result<SerializeArray<'a>, LuaError> {
+        let idx = self.push_array(len.unwrap_or(0))?;
+        Ok(SerializeArray {
+            ls: self.ls,
+            options: self.options,
+            idx,
+            len: 0,
+            variant: None,
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeArray<'a>, LuaError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeArray<'a>, LuaError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        mut self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeArray<'a>, LuaError> {
+        let outer = self.push_variant(variant);
+        let idx = self.push_array(len)?;
+        Ok(SerializeArray {
+            ls: self.ls,
+            options: self.options,
+            idx,
+            len: 0,
+            variant: Some(outer),
+        })
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<SerializeTable<'a>, LuaError> {
+        self.ls.create_table(0, len.unwrap_or(0));
+        let idx = self.ls.get_top();
+        Ok(SerializeTable {
+            ls: self.ls,
+            options: self.options,
+            idx,
+            variant: None,
+        })
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeTable<'a>, LuaError> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        mut self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeTable<'a>, LuaError> {
+        let outer = self.push_variant(variant);
+        self.ls.create_table(0, len);
+        let idx = self.ls.get_top();
+        Ok(SerializeTable {
+            ls: self.ls,
+            options: self.options,
+            idx,
+            variant: Some(outer),
+        })
+    }
+}
+
+/// 正在序列化的数组，数组的表在栈上。
+pub struct SerializeArray<'a> {
+    ls: &'a mut dyn LuaAPI,
+    options: Options,
+    /// 数组的表的绝对索引
+    idx: isize,
+    /// 已经加入的元素数量
+    len: i64,
+    /// 元组变体外层的表的绝对索引，变体名在它上面
+    variant: Option<isize>,
+}
+
+impl SerializeArray<'_> {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), LuaError> {
+        value.serialize(Serializer {
+            ls: &mut *self.ls,
+            options: self.options,
+        })?;
+        self.len += 1;
+        self.ls.raw_set_i(self.idx, self.len);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), LuaError> {
+        match self.variant {
+            Some(outer) => self.ls.raw_set(outer),
+            None => Ok(()),
+        }
+    }
+}
+
+impl ser::SerializeSeq for SerializeArray<'_> {
+    type Ok = ();
+    type Error = LuaError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), LuaError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<(), LuaError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SerializeArray<'_> {
+    type Ok = ();
+    type Error = LuaError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), LuaError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<(), LuaError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeArray<'_> {
+    type Ok = ();
+    type Error = LuaError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), LuaError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<(), LuaError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeArray<'_> {
+    type Ok = ();
+    type Error = LuaError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), LuaError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<(), LuaError> {
+        self.finish()
+    }
+}
+
+/// 正在序列化的映射或者结构体，表在栈上。
+pub struct SerializeTable<'a> {
+    ls: &'a mut dyn LuaAPI,
+    options: Options,
+    /// 表的绝对索引
+    idx: isize,
+    /// 结构体变体外层的表的绝对索引，变体名在它上面
+    variant: Option<isize>,
+}
+
+impl SerializeTable<'_> {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), LuaError> {
+        value.serialize(Serializer {
+            ls: &mut *self.ls,
+            options: self.options,
+        })
+    }
+
+    fn finish(self) -> Result<(), LuaError> {
+        match self.variant {
+            Some(outer) => self.ls.raw_set(outer),
+            None => Ok(()),
+        }
+    }
+}
+
+impl ser::SerializeMap for SerializeTable<'_> {
+    type Ok = ();
+    type Error = LuaError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), LuaError> {
+        self.push(key)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), LuaError> {
+        self.push(value)?;
+        self.ls.raw_set(self.idx)
+    }
+
+    fn end(self) -> Result<(), LuaError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for SerializeTable<'_> {
+    type Ok = ();
+    type Error = LuaError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), LuaError> {
+        self.ls.push_string(key.to_string());
+        self.push(value)?;
+        self.ls.raw_set(self.idx)
+    }
+
+    fn end(self) -> Result<(), LuaError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeTable<'_> {
+    type Ok = ();
+    type Error = LuaError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), LuaError> {
+        ser::SerializeStruct::serialize_field(self, key, value)
+    }
+
+    fn end(self) -> Result<(), LuaError> {
+        self.finish()
+    }
+}
+
+/// 把栈上的 Lua 值转换为 Rust 值的反序列化器。
+pub struct Deserializer<'a> {
+    ls: &'a mut dyn LuaAPI,
+    /// 值的绝对索引
+    idx: isize,
+    options: Options,
+}
+
+impl<'a> Deserializer<'a> {
+    /// 反序列化栈顶的值的反序列化器。
+    fn top<'b>(ls: &'b mut dyn LuaAPI, options: Options) -> Deserializer<'b> {
+        let idx = ls.get_top();
+        Deserializer { ls, idx, options }
+    }
+
+    /// 值是 nil、null 或者不存在。
+    fn is_unit(&self) -> bool {
+        self.ls.is_none_or_nil(self.idx)
+            || self
+                .ls
+                .to_light_userdata(self.idx)
+                .is_some_and(|p| p.is_null())
+    }
+
+    /// 值是否带有数组元表。
+    fn has_array_metatable(&mut self) -> Result<bool, LuaError> {
+        if !self.ls.get_metatable(self.idx) {
+            return Ok(false);
+        }
+        push_array_metatable(self.ls)?;
+        let is_array = self.ls.raw_equal(-1, -2);
+        self.ls.pop(2);
+        Ok(is_array)
+    }
+
+    /// 表的键是否恰好为 1 到 n（n > 0），是时返回 n。
+    fn sequence_len(&mut self) -> Result<Option<usize>, LuaError> {
+        let mut n = 0;
+        let mut max = 0;
+        self.ls.push_nil();
+        while self.ls.next(self.idx)? {
+            n += 1;
+            match self.ls.to_integerx(-2) {
+                Some(i) if i >= 1 => max = max.max(i as usize),
+                _ => {
+                    self.ls.pop(2);
+                    return Ok(None);
+                }
+            }
+            self.ls.pop(1);
+        }
+        Ok((n > 0 && n == max).then_some(n))
+    }
+
+    fn visit_array<'de, V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, LuaError> {
+        visitor.visit_seq(ArrayAccess {
+            ls: self.ls,
+            idx: self.idx,
+            options: self.options,
+            next: 1,
+            len,
+        })
+    }
+
+    fn visit_table<'de, V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        let top = self.ls.get_top();
+        self.ls.push_nil();
+        let value = visitor.visit_map(TableAccess {
+            ls: &mut *self.ls,
+            idx: self.idx,
+            options: self.options,
+        });
+        // the visitor may stop before the last key
+        self.ls.set_top(top);
+        value
+    }
+
+    /// 索引处的字符串的原始字节。
+    fn string_bytes(&self) -> Vec<u8> {
+        self.ls.to_bytesx(self.idx).unwrap_or_default()
+    }
+
+    fn unsupported(&self) -> LuaError {
+        let tname = self.ls.type_name(self.ls.type_id(self.idx));
+        LuaError::runtime(format!("cannot deserialize a {} value", tname))
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer<'_> {
+    type Error = LuaError;
+
+    fn deserialize_any<V: Visitor<'de>>(mut self, visitor: V) -> Result<V::Value, LuaError> {
+        if self.is_unit() {
+            return visitor.visit_unit();
+        }
+        match Type::from_i8(self.ls.type_id(self.idx)) {
+            Some(Type::Boolean) => visitor.visit_bool(self.ls.to_boolean(self.idx)),
+            Some(Type::Number) => match self.ls.to_integerx(self.idx) {
+                Some(n) => visitor.visit_i64(n),
+                None => visitor.visit_f64(self.ls.to_number(self.idx)),
+            },
+            Some(Type::String) => visitor.visit_string(utf8_string(self.string_bytes())?),
+            Some(Type::Table) => {
+                if self.has_array_metatable()? {
+                    let len = self.ls.raw_len(self.idx);
+                    return self.visit_array(len, visitor);
+                }
+                if self.options.sequences_as_arrays {
+                    if let Some(len) = self.sequence_len()? {
+                        return self.visit_array(len, visitor);
+                    }
+                }
+                self.visit_table(visitor)
+            }
+            _ => Err(self.unsupported()),
+        }
+    }
+
+    /// 整数值的浮点数也可以转换为整数。
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        match self.ls.to_numberx(self.idx).and_then(number_to_integer) {
+            Some(n) if !self.ls.is_integer(self.idx) => visitor.visit_i64(n),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        self.deserialize_i64(visitor)
+    }
+
+    /// 整数也可以转换为浮点数。
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        match self.ls.to_numberx(self.idx) {
+            Some(n) => visitor.visit_f64(n),
+            None => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        self.deserialize_f64(visitor)
+    }
+
+    /// 字符串的字节，或者由整数组成的数组。
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        if self.ls.type_id(self.idx) == Type::String as i8 {
+            visitor.visit_byte_buf(self.string_bytes())
+        } else {
+            self.deserialize_seq(visitor)
+        }
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        if self.is_unit() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, LuaError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    /// 任何表都可以转换为序列，包括空表。
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        if self.ls.is_table(self.idx) {
+            let len = self.ls.raw_len(self.idx);
+            self.visit_array(len, visitor)
+        } else {
+            self.deserialize_any(visitor)
+        }
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, LuaError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, LuaError> {
+        self.deserialize_seq(visitor)
+    }
+
+    /// 任何表都可以转换为映射，包括序列。
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        if self.ls.is_table(self.idx) {
+            self.visit_table(visitor)
+        } else {
+            self.deserialize_any(visitor)
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, LuaError> {
+        self.deserialize_map(visitor)
+    }
+
+    /// 变体名是单元变体，`{ Variant = value }` 是其他变体。
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, LuaError> {
+        match Type::from_i8(self.ls.type_id(self.idx)) {
+            Some(Type::String) => {
+                let variant = utf8_string(self.string_bytes())?;
+                visitor.visit_enum(variant.into_deserializer())
+            }
+            Some(Type::Table) => {
+                self.ls.push_nil();
+                if !self.ls.next(self.idx)? {
+                    return Err(LuaError::runtime("expected a table with a single key"));
+                }
+                let access = VariantTable {
+                    ls: &mut *self.ls,
+                    options: self.options,
+                };
+                let value = visitor.visit_enum(access)?;
+                if self.ls.next(self.idx)? {
+                    return Err(LuaError::runtime("expected a table with a single key"));
+                }
+                Ok(value)
+            }
+            _ => Err(LuaError::runtime(format!(
+                "string or table expected, got {}",
+                self.ls.type_name(self.ls.type_id(self.idx))
+            ))),
+        }
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaError> {
+        visitor.visit_unit()
+    }
+
+    forward_to_deserialize_any! {
+        bool char str string unit unit_struct identifier
+    }
+}
+
+/// 按索引 1 到 `len` 访问数组的元素。
+struct ArrayAccess<'a> {
+    ls: &'a mut dyn LuaAPI,
+    idx: isize,
+    options: Options,
+    next: usize,
+    len: usize,
+}
+
+impl<'de> SeqAccess<'de> for ArrayAccess<'_> {
+    type Error = LuaError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, LuaError> {
+        if self.next > self.len {
+            return Ok(None);
+        }
+        self.ls.raw_get_i(self.idx, self.next as i64);
+        self.next += 1;
+        let value = seed.deserialize(Deserializer::top(&mut *self.ls, self.options))?;
+        self.ls.pop(1);
+        Ok(Some(value))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len + 1 - self.next)
+    }
+}
+
+/// 用 `next` 遍历表的键值对。当前的键和值在栈顶，取出值后弹出值，只留下键。
+struct TableAccess<'a> {
+    ls: &'a mut dyn LuaAPI,
+    idx: isize,
+    options: Options,
+}
+
+impl<'de> MapAccess<'de> for TableAccess<'_> {
+    type Error = LuaError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, LuaError> {
+        if !self.ls.next(self.idx)? {
+            return Ok(None);
+        }
+        self.ls.push_value(-2);
+        let key = seed.deserialize(Deserializer::top(&mut *self.ls, self.options))?;
+        self.ls.pop(1);
+        Ok(Some(key))
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, LuaError> {
+        let value = seed.deserialize(Deserializer::top(&mut *self.ls, self.options))?;
+        self.ls.pop(1);
+        Ok(value)
+    }
+}
+
+/// `{ Variant = value }` 形式的变体，变体名和值在栈顶。
+struct VariantTable<'a> {
+    ls: &'a mut dyn LuaAPI,
+    options: Options,
+}
+
+impl<'de, 'a> EnumAccess<'de> for VariantTable<'a> {
+    type Error = LuaError;
+    type Variant = VariantTable<'a>;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, VariantTable<'a>), LuaError> {
+        self.ls.push_value(-2);
+        let variant = seed.deserialize(Deserializer::top(&mut *self.ls, self.options))?;
+        self.ls.pop(1);
+        Ok((variant, self))
+    }
+}
+
+impl<'de> VariantAccess<'de> for VariantTable<'_> {
+    type Error = LuaError;
+
+    fn unit_variant(self) -> Result<(), LuaError> {
+        self.ls.pop(1);
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, LuaError> {
+        let value = seed.deserialize(Deserializer::top(&mut *self.ls, self.options))?;
+        self.ls.pop(1);
+        Ok(value)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, LuaError> {
+        let value = de::Deserializer::deserialize_seq(
+            Deserializer::top(&mut *self.ls, self.options),
+            visitor,
+        )?;
+        self.ls.pop(1);
+        Ok(value)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, LuaError> {
+        let value = de::Deserializer::deserialize_map(
+            Deserializer::top(&mut *self.ls, self.options),
+            visitor,
+        )?;
+        self.ls.pop(1);
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+
+    use crate::{
+        api::consts::{LUA_MULTRET, LUA_OK},
+        state::new_lua_state,
+    };
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Reward {
+        Nothing,
+        Gold(u32),
+        Item { name: String, count: u8 },
+        Pair(i64, i64),
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Level {
+        name: String,
+        speed: f64,
+        waves: Vec<Vec<u16>>,
+        boss: Option<String>,
+        rewards: Vec<Reward>,
+        tags: HashMap<String, bool>,
+        #[serde(default)]
+        hidden: bool,
+    }
+
+    fn eval(ls: &mut dyn LuaAPI, src: &str) {
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        ls.call(0, LUA_MULTRET).unwrap();
+    }
+
+    #[test]
+    fn test_from_lua() {
+        let mut ls = new_lua_state();
+        let ls: &mut dyn LuaAPI = &mut ls;
+        eval(
+            ls,
+            r#"return {
+                name = "forest",
+                speed = 2,
+                waves = { { 1, 2.0 }, {} },
+                rewards = { "Nothing", { Gold = 10 }, { Item = { name = "key", count = 1 } }, { Pair = { 1, 2 } } },
+                tags = { night = true },
+            }"#,
+        );
+        let level: Level = from_lua(ls, -1).unwrap();
+        assert_eq!(
+            level,
+            Level {
+                name: "forest".to_string(),
+                speed: 2.0,
+                waves: vec![vec![1, 2], vec![]],
+                boss: None,
+                rewards: vec![
+                    Reward::Nothing,
+                    Reward::Gold(10),
+                    Reward::Item {
+                        name: "key".to_string(),
+                        count: 1
+                    },
+                    Reward::Pair(1, 2),
+                ],
+                tags: HashMap::from([("night".to_string(), true)]),
+                hidden: false,
+            }
+        );
+        assert_eq!(ls.get_top(), 1);
+
+        eval(ls, "return { name = 'x', speed = 'fast' }");
+        let e = from_lua::<Level>(ls, -1).unwrap_err();
+        assert_eq!(e.to_string(), "invalid type: string \"fast\", expected f64");
+        eval(ls, "return 1.5");
+        assert!(from_lua::<i32>(ls, -1).is_err());
+        eval(ls, "return function() end");
+        assert_eq!(
+            from_lua::<i32>(ls, -1).unwrap_err().to_string(),
+            "cannot deserialize a function value"
+        );
+        assert_eq!(ls.get_top(), 4);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut ls = new_lua_state();
+        let ls: &mut dyn LuaAPI = &mut ls;
+        let level = Level {
+            name: "cave".to_string(),
+            speed: 1.0,
+            waves: vec![vec![3], vec![]],
+            boss: Some("bat".to_string()),
+            rewards: vec![Reward::Gold(5), Reward::Nothing, Reward::Pair(-1, 1)],
+            tags: HashMap::new(),
+            hidden: true,
+        };
+        to_lua(ls, &level).unwrap();
+        assert_eq!(ls.get_top(), 1);
+        assert_eq!(from_lua::<Level>(ls, 1).unwrap(), level);
+
+        // untyped targets keep integers, floats, arrays and tables apart
+        to_lua(
+            ls,
+            &(Vec::<i64>::new(), HashMap::<String, i64>::new(), 1, 1.0),
+        )
+        .unwrap();
+        let any: Value = from_lua(ls, -1).unwrap();
+        assert_eq!(any, json!([[], {}, 1, 1.0]));
+        eval(ls, "return { 'a', 'b' }, { [1] = 'a', [3] = 'c' }");
+        assert_eq!(from_lua::<Value>(ls, -2).unwrap(), json!(["a", "b"]));
+        let sparse: BTreeMap<i64, String> = from_lua(ls, -1).unwrap();
+        assert_eq!(
+            sparse,
+            BTreeMap::from([(1, "a".to_string()), (3, "c".to_string())])
+        );
+        ls.set_top(0);
+
+        let options = Options {
+            unit_to_null: true,
+            array_metatable: false,
+            preserve_floats: false,
+            sequences_as_arrays: false,
+        };
+        to_lua_with(ls, &(Some(2.0), None::<i64>, 3), options).unwrap();
+        assert_eq!(ls.raw_len(-1), 3);
+        ls.raw_get_i(-1, 1);
+        assert!(ls.is_integer(-1));
+        ls.pop(1);
+        let any: BTreeMap<i64, Value> = from_lua_with(ls, -1, options).unwrap();
+        assert_eq!(
+            any,
+            BTreeMap::from([(1, json!(2)), (2, json!(null)), (3, json!(3))])
+        );
+    }
+
+    /// 通过 `deserialize_byte_buf` 读取的字节串
+    #[derive(Debug, PartialEq)]
+    struct Bytes(Vec<u8>);
+
+    impl<'de> Deserialize<'de> for Bytes {
+        fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            struct BytesVisitor;
+
+            impl<'de> Visitor<'de> for BytesVisitor {
+                type Value = Bytes;
+
+                fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                    f.write_str("bytes")
+                }
+
+                fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Bytes, E> {
+                    Ok(Bytes(v))
+                }
+            }
+
+            deserializer.deserialize_byte_buf(BytesVisitor)
+        }
+    }
+
+    #[test]
+    fn test_binary_strings() {
+        let mut ls = new_lua_state();
+        let ls: &mut dyn LuaAPI = &mut ls;
+        eval(ls, r#"return "\xff\0a""#);
+        assert_eq!(
+            from_lua::<Bytes>(ls, -1).unwrap(),
+            Bytes(vec![0xff, 0, b'a'])
+        );
+        assert_eq!(
+            from_lua::<String>(ls, -1).unwrap_err().to_string(),
+            "invalid UTF-8 in string"
+        );
+        assert_eq!(
+            from_lua::<Value>(ls, -1).unwrap_err().to_string(),
+            "invalid UTF-8 in string"
+        );
+    }
+}
@@ -1,4 +1,18 @@
-pub type RustFn = fn(&dyn LuaState) -> usize;
+use std::{any::Any, ffi::c_void, rc::Rc};
+
+use crate::{compiler::LoadError, state::lua_userdata::LuaUserData};
+
+use super::{handle::LuaRef, LuaError};
+
+/// Rust 函数。参数在栈上，返回值是推送到栈顶的结果的数量；出错时返回 `LuaError`。
+pub type RustFn = fn(&mut dyn LuaState) -> Result<usize, LuaError>;
+
+/// 可以捕获状态的 Rust 函数，参数和返回值与 `RustFn` 相同。`RustFn` 也可以转换为 `RustClosure`。
+pub type RustClosure = Rc<dyn Fn(&mut dyn LuaState) -> Result<usize, LuaError>>;
+
+/// 延续函数，由 `pcallk` 注册。被调用的函数让出之后，Rust 函数的调用已经结束，协程继续运行时改为调用延续函数完成剩下的工作。
+/// 参数是状态、调用的状态码（正常返回时为 `LUA_YIELD`，出错时为错误状态码）和传给 `pcallk` 的上下文，返回值与 `RustFn` 相同。
+pub type KFunction = fn(&mut dyn LuaState, u8, isize) -> Result<usize, LuaError>;
 
 pub trait LuaState {
     /* 基本栈操作 */
@@ -178,6 +192,22 @@ pub trait LuaState {
     /// 返回值：如果指定索引处的值为 Rust 函数，则返回 `true`，否则返回 `false`。
     fn is_rust_function(&self, idx: isize) -> bool;
 
+    /// 检查指定索引处的值是否为用户数据（完整用户数据或轻量用户数据）。
+    ///
+    /// 参数：
+    /// * `idx` - 要检查的值的索引。
+    ///
+    /// 返回值：如果指定索引处的值为用户数据，则返回 `true`，否则返回 `false`。
+    fn is_userdata(&self, idx: isize) -> bool;
+
+    /// 检查指定索引处的值是否为轻量用户数据。
+    ///
+    /// 参数：
+    /// * `idx` - 要检查的值的索引。
+    ///
+    /// 返回值：如果指定索引处的值为轻量用户数据，则返回 `true`，否则返回 `false`。
+    fn is_light_userdata(&self, idx: isize) -> bool;
+
     /// 将指定索引处的值转换为布尔值。
     ///
     /// 参数：
@@ -227,6 +257,7 @@ pub trait LuaState {
     fn to_string(&self, idx: isize) -> String;
 
     /// 尝试将指定索引处的 Lua 值转换为字符串。如果值是字符串或数字，返回 `Some(String)`，否则返回 `None`。
+    /// 字符串中不是合法 UTF-8 的字节被替换为 U+FFFD，需要原始字节时使用 `to_bytesx`。
     ///
     /// 参数：
     /// * `idx` - 要转换的值的索引。
@@ -234,13 +265,64 @@ pub trait LuaState {
     /// 返回值：如果转换成功，返回 `Some(String)`，否则返回 `None`。
     fn to_stringx(&self, idx: isize) -> Option<String>;
 
-    /// 尝试将指定索引处的 Lua 值转换为 Rust 函数。如果值是 Rust 函数，返回 `Some(RustFn)`，否则返回 `None`。
+    /// 尝试将指定索引处的 Lua 值转换为字节串。如果值是字符串或数字，返回字符串的原始字节，否则返回 `None`。
+    ///
+    /// 参数：
+    /// * `idx` - 要转换的值的索引。
+    ///
+    /// 返回值：如果转换成功，返回 `Some(Vec<u8>)`，否则返回 `None`。
+    fn to_bytesx(&self, idx: isize) -> Option<Vec<u8>>;
+
+    /// 尝试将指定索引处的 Lua 值转换为 Rust 函数。如果值是 Rust 函数，返回 `Some(RustClosure)`，否则返回 `None`。
+    ///
+    /// 参数：
+    /// * `idx` - 要转换的值的索引。
+    ///
+    /// 返回值：如果转换成功，返回 `Some(RustClosure)`，否则返回 `None`。
+    fn to_rust_function(&self, idx: isize) -> Option<RustClosure>;
+
+    /// 尝试将指定索引处的值转换为完整用户数据。通过返回值的 `borrow`、`borrow_mut` 访问其中的 Rust 值。
     ///
     /// 参数：
     /// * `idx` - 要转换的值的索引。
     ///
-    /// 返回值：如果转换成功，返回 `Some(RustFn)`，否则返回 `None`。
-    fn to_rust_function(&self, idx: isize) -> Option<RustFn>;
+    /// 返回值：如果值是完整用户数据，返回 `Some`，否则返回 `None`。
+    fn to_userdata(&self, idx: isize) -> Option<Rc<LuaUserData>>;
+
+    /// 尝试将指定索引处的值转换为轻量用户数据的指针。
+    ///
+    /// 参数：
+    /// * `idx` - 要转换的值的索引。
+    ///
+    /// 返回值：如果值是轻量用户数据，返回它的指针，否则返回 `None`。
+    fn to_light_userdata(&self, idx: isize) -> Option<*mut c_void>;
+
+    /// 检查第 `arg` 个参数是元表为注册表中 `tname` 字段的完整用户数据（见 `auxlib::new_metatable`）。
+    ///
+    /// 参数：
+    /// * `arg` - 参数的索引。
+    /// * `tname` - 元表在注册表中的名字。
+    /// * `fname` - 函数名，用于错误信息。
+    ///
+    /// 返回值：参数是这样的用户数据时返回它，否则返回形如 `bad argument #1 to 'f' (tname expected, got nil)` 的错误。
+    fn check_userdata(
+        &self,
+        arg: isize,
+        tname: &str,
+        fname: &str,
+    ) -> Result<Rc<LuaUserData>, LuaError>;
+
+    /// 按 `tostring` 的规则把指定索引处的值转换为字符串。值的元表中有 `__tostring` 字段时调用它，
+    /// 否则表等值转换为带有类型名（或元表中的 `__name` 字段）和地址的字符串。
+    ///
+    /// 参数：
+    /// * `idx` - 要转换的值的索引。
+    ///
+    /// 返回值：转换后的字符串；`__tostring` 出错或没有返回字符串时返回错误。
+    fn to_string_meta(&mut self, idx: isize) -> Result<String, LuaError>;
+
+    /// 与 `to_string_meta` 相同，但是返回字符串的原始字节。
+    fn to_bytes_meta(&mut self, idx: isize) -> Result<Vec<u8>, LuaError>;
 
     /* 推送函数 (rust -> stack) */
     /// 将 nil 值推送到栈顶。
@@ -270,6 +352,12 @@ pub trait LuaState {
     /// * `s` - 要推送的字符串值。
     fn push_string(&mut self, s: String);
 
+    /// 将字节串作为 Lua 字符串推送到栈顶。Lua 字符串可以包含任意字节。
+    ///
+    /// 参数：
+    /// * `s` - 要推送的字节串。
+    fn push_bytes(&mut self, s: Vec<u8>);
+
     /// 将 Rust 函数推送到栈顶。
     ///
     /// 参数：
@@ -279,12 +367,47 @@ pub trait LuaState {
     /// 将全局表推送到栈顶。
     fn push_global_table(&mut self);
 
+    /// 把 Rust 函数和栈顶的 `n` 个值打包成闭包推入栈顶，这些值被弹出，成为闭包的上值，
+    /// 在函数中通过伪索引 `lua_upvalueindex(i)` 访问。
+    ///
+    /// 参数：
+    /// * `f` - 要推送的 Rust 函数。
+    /// * `n` - 上值的数量。
+    fn push_rust_closure(&mut self, f: RustFn, n: usize);
+
+    /// 与 `push_rust_closure` 相同，但是 `f` 可以捕获 Rust 中的状态。
+    ///
+    /// 参数：
+    /// * `f` - 要推送的 Rust 闭包。
+    /// * `n` - 上值的数量。
+    fn push_closure(&mut self, f: RustClosure, n: usize);
+
+    /// 把正在运行的线程推入栈顶。
+    ///
+    /// 返回值：该线程是否是主线程。
+    fn push_thread(&mut self) -> bool;
+
+    /// 创建一个持有 Rust 值 `data` 的完整用户数据并推入栈顶。它没有元表，有 `nuvalue` 个初始为 nil 的用户值。
+    ///
+    /// 参数：
+    /// * `data` - 用户数据持有的 Rust 值。
+    /// * `nuvalue` - 用户值的数量。
+    fn new_userdata(&mut self, data: Box<dyn Any>, nuvalue: usize);
+
+    /// 将轻量用户数据推送到栈顶。轻量用户数据只是一个指针，Lua 不管理它指向的内存。
+    ///
+    /// 参数：
+    /// * `p` - 要推送的指针。
+    fn push_light_userdata(&mut self, p: *mut c_void);
+
     /* 算数和比较运算函数 */
     /// 对栈顶的两个元素执行算术运算，并将结果推送到栈顶。运算类型由 `op` 参数指定。
     ///
     /// 参数：
     /// * `op` - 指定算术运算的类型。可能的值包括 `ADD`、`SUB`、`MUL`、`MOD`、`POW`、`DIV`、`IDIV`、`BAND`、`BOR`、`BXOR`、`SHL`、`SHR`、`UNM`、`BNOT`。
-    fn arith(&mut self, op: u8);
+    ///
+    /// 返回值：操作数不支持该运算且没有对应的元方法时返回错误。
+    fn arith(&mut self, op: u8) -> Result<(), LuaError>;
 
     /// 比较栈上的两个元素。比较类型由 `op` 参数指定。
     ///
@@ -293,21 +416,66 @@ pub trait LuaState {
     /// * `idx2` - 第二个要比较的元素的索引。
     /// * `op` - 指定比较的类型。可能的值包括 `EQ`、`LT`、`LE`。
     ///
-    /// 返回值：如果比较结果为真，返回 `true`，否则返回 `false`。
-    fn compare(&mut self, idx1: isize, idx2: isize, op: u8) -> bool;
+    /// 返回值：如果比较结果为真，返回 `true`，否则返回 `false`；无法比较时返回错误。
+    fn compare(&mut self, idx1: isize, idx2: isize, op: u8) -> Result<bool, LuaError>;
+
+    /// 不调用 `__eq` 元方法，比较栈上的两个元素是否相等。
+    ///
+    /// 参数：
+    /// * `idx1` - 第一个要比较的元素的索引。
+    /// * `idx2` - 第二个要比较的元素的索引。
+    ///
+    /// 返回值：如果两个元素原始相等，返回 `true`，否则返回 `false`。任何一个索引无效时返回 `false`。
+    fn raw_equal(&self, idx1: isize, idx2: isize) -> bool;
 
     /* 其他函数 */
     /// 计算指定索引处的 Lua 值的长度，并将结果推送到栈顶。对于字符串，这是其长度；对于表，这是适合数组部分的最大索引。
     ///
     /// 参数：
     /// * `idx` - 要计算长度的值的索引。
-    fn len(&mut self, idx: isize);
+    ///
+    /// 返回值：值没有长度且没有 `__len` 元方法时返回错误。
+    fn len(&mut self, idx: isize) -> Result<(), LuaError>;
+
+    /// 不调用 `__len` 元方法，返回指定索引处的值的原始长度。对于字符串和表，与 `len` 相同；其他值返回 0。
+    ///
+    /// 参数：
+    /// * `idx` - 要计算长度的值的索引。
+    ///
+    /// 返回值：值的原始长度。
+    fn raw_len(&self, idx: isize) -> usize;
 
     /// 连接栈顶的 `n` 个字符串值，并将结果推送到栈顶。如果 `n` 是 1，结果是一个字符串；如果 `n` 是 0，结果是一个空字符串。
     ///
     /// 参数：
     /// * `n` - 要连接的字符串值的数量。
-    fn concat(&mut self, n: isize);
+    ///
+    /// 返回值：有值无法连接且没有 `__concat` 元方法时返回错误。
+    fn concat(&mut self, n: isize) -> Result<(), LuaError>;
+
+    /// 按 Lua 的数字语法把字符串转换为整数或浮点数，并将结果推送到栈顶。
+    ///
+    /// 参数：
+    /// * `s` - 要转换的字符串。
+    ///
+    /// 返回值：转换成功时返回 `true`；失败时返回 `false`，不推送任何值。
+    fn string_to_number(&mut self, s: &str) -> bool;
+
+    /// 从栈顶弹出一个键，然后把指定索引处的表中该键之后的键值对推送到栈顶。
+    ///
+    /// 参数：
+    /// * `idx` - 表的索引。
+    ///
+    /// 返回值：如果还有键值对，返回 `true`；如果遍历结束，不推送任何值并返回 `false`。键无效时返回错误。
+    fn next(&mut self, idx: isize) -> Result<bool, LuaError>;
+
+    /// 把指定索引处的值标记为待关闭的变量。值为 nil 或 false 时什么也不做，否则值必须可以被关闭。
+    ///
+    /// 参数：
+    /// * `idx` - 要标记的值的索引。
+    ///
+    /// 返回值：值不可以被关闭时返回错误。
+    fn to_close(&mut self, idx: isize) -> Result<(), LuaError>;
 
     /* 获取函数 (Lua -> stack) */
     /// 创建一个新的空表并将其推送到栈顶。
@@ -325,8 +493,8 @@ pub trait LuaState {
     /// 参数：
     /// * `idx` - 表的索引。
     ///
-    /// 返回值：如果操作成功，返回 1，否则返回 0。
-    fn get_table(&mut self, idx: isize) -> i8;
+    /// 返回值：推送到栈顶的值的类型 ID；`__index` 元方法出错时返回错误。
+    fn get_table(&mut self, idx: isize) -> Result<i8, LuaError>;
 
     /// 获取指定索引处的表中的字段，并将其推送到栈顶。返回值表示操作的成功与否。
     ///
@@ -334,8 +502,8 @@ pub trait LuaState {
     /// * `idx` - 表的索引。
     /// * `k` - 字段的名称。
     ///
-    /// 返回值：如果操作成功，返回 1，否则返回 0。
-    fn get_field(&mut self, idx: isize, k: &str) -> i8;
+    /// 返回值：推送到栈顶的值的类型 ID；`__index` 元方法出错时返回错误。
+    fn get_field(&mut self, idx: isize, k: &str) -> Result<i8, LuaError>;
 
     /// 获取指定索引处的表中的元素，并将其推送到栈顶。返回值表示操作的成功与否。
     ///
@@ -343,50 +511,171 @@ pub trait LuaState {
     /// * `idx` - 表的索引。
     /// * `i` - 元素的索引。
     ///
-    /// 返回值：如果操作成功，返回 1，否则返回 0。
-    fn get_i(&mut self, idx: isize, i: i64) -> i8;
+    /// 返回值：推送到栈顶的值的类型 ID；`__index` 元方法出错时返回错误。
+    fn get_i(&mut self, idx: isize, i: i64) -> Result<i8, LuaError>;
+
+    /// 与 `get_table` 相同，但不调用 `__index` 元方法。
+    ///
+    /// 参数：
+    /// * `idx` - 表的索引。
+    ///
+    /// 返回值：推送到栈顶的值的类型 ID。
+    fn raw_get(&mut self, idx: isize) -> i8;
+
+    /// 与 `get_i` 相同，但不调用 `__index` 元方法。
+    ///
+    /// 参数：
+    /// * `idx` - 表的索引。
+    /// * `i` - 元素的索引。
+    ///
+    /// 返回值：推送到栈顶的值的类型 ID。
+    fn raw_get_i(&mut self, idx: isize, i: i64) -> i8;
+
+    /// 如果指定索引处的值有元表，把元表推送到栈顶并返回 `true`；否则不推送任何值并返回 `false`。
+    /// 表和完整用户数据有各自的元表，其他类型的值共用同一类型的元表。
+    ///
+    /// 参数：
+    /// * `idx` - 值的索引。
+    ///
+    /// 返回值：值是否有元表。
+    fn get_metatable(&mut self, idx: isize) -> bool;
+
+    /// 把指定索引处的完整用户数据的第 `n` 个用户值推送到栈顶。
+    ///
+    /// 参数：
+    /// * `idx` - 用户数据的索引。
+    /// * `n` - 用户值的序号，从 1 开始。
+    ///
+    /// 返回值：推送的值的类型 ID；用户数据没有这个用户值时推送 nil 并返回 `LUA_TNONE`。
+    fn get_iuservalue(&mut self, idx: isize, n: usize) -> i8;
 
     /// 获取全局变量的值，并将其推送到栈顶。返回值表示操作的成功与否。
     ///
     /// 参数：
     /// * `name` - 全局变量的名称。
     ///
-    /// 返回值：如果操作成功，返回 1，否则返回 0。
-    fn get_global(&mut self, name: &str) -> i8;
+    /// 返回值：推送到栈顶的值的类型 ID；`__index` 元方法出错时返回错误。
+    fn get_global(&mut self, name: &str) -> Result<i8, LuaError>;
 
     /* 设置函数 (stack -> Lua) */
     /// 将栈顶的值设置为指定索引处的表的值，并弹出栈顶的值。
     ///
     /// 参数：
     /// * `idx` - 表的索引。
-    fn set_table(&mut self, idx: isize);
+    ///
+    /// 返回值：键无效或 `__newindex` 元方法出错时返回错误。
+    fn set_table(&mut self, idx: isize) -> Result<(), LuaError>;
 
     /// 将栈顶的值设置为指定索引处的表的字段，并弹出栈顶的值。
     ///
     /// 参数：
     /// * `idx` - 表的索引。
     /// * `k` - 字段的名称。
-    fn set_field(&mut self, idx: isize, k: &str);
+    ///
+    /// 返回值：键无效或 `__newindex` 元方法出错时返回错误。
+    fn set_field(&mut self, idx: isize, k: &str) -> Result<(), LuaError>;
 
     /// 将栈顶的值设置为指定索引处的表的元素，并弹出栈顶的值。
     ///
     /// 参数：
     /// * `idx` - 表的索引。
     /// * `i` - 元素的索引。
-    fn set_i(&mut self, idx: isize, i: i64);
+    ///
+    /// 返回值：键无效或 `__newindex` 元方法出错时返回错误。
+    fn set_i(&mut self, idx: isize, i: i64) -> Result<(), LuaError>;
+
+    /// 与 `set_table` 相同，但不调用 `__newindex` 元方法。
+    ///
+    /// 参数：
+    /// * `idx` - 表的索引。
+    ///
+    /// 返回值：键为 nil 或 NaN 时返回错误。
+    fn raw_set(&mut self, idx: isize) -> Result<(), LuaError>;
+
+    /// 与 `set_i` 相同，但不调用 `__newindex` 元方法。
+    ///
+    /// 参数：
+    /// * `idx` - 表的索引。
+    /// * `i` - 元素的索引。
+    fn raw_set_i(&mut self, idx: isize, i: i64);
+
+    /// 如果指定索引处的表没有元表，且 `pos` 在 `1..=raw_len + 1` 内，弹出栈顶的值，直接插入到表的数组部分的 `pos` 处，
+    /// 原来的 `t[pos..]` 依次后移。
+    ///
+    /// 参数：
+    /// * `idx` - 表的索引。
+    /// * `pos` - 插入的位置。
+    ///
+    /// 返回值：是否已经插入；返回 `false` 时不做任何操作。
+    fn array_insert(&mut self, idx: isize, pos: i64) -> bool;
+
+    /// 如果指定索引处的表没有元表，且 `pos` 在 `1..=raw_len` 内，直接从表的数组部分删除 `pos` 处的值并推送到栈顶，
+    /// 原来的 `t[pos + 1..]` 依次前移。
+    ///
+    /// 参数：
+    /// * `idx` - 表的索引。
+    /// * `pos` - 删除的位置。
+    ///
+    /// 返回值：是否已经删除；返回 `false` 时不做任何操作。
+    fn array_remove(&mut self, idx: isize, pos: i64) -> bool;
+
+    /// 如果指定索引处的表没有元表，且数组部分全是数字或全是字符串，按 `<` 直接对数组部分原地排序。
+    ///
+    /// 参数：
+    /// * `idx` - 表的索引。
+    ///
+    /// 返回值：是否已经排序；返回 `false` 时不做任何操作。
+    fn array_sort(&mut self, idx: isize) -> bool;
+
+    /// 从栈顶弹出一个表（或 nil），把它设置为指定索引处的值的元表（或清除元表）。
+    /// 给表和完整用户数据以外的值设置元表时，会改变同一类型所有值共用的元表。
+    ///
+    /// 参数：
+    /// * `idx` - 值的索引。
+    fn set_metatable(&mut self, idx: isize);
+
+    /// 从栈顶弹出一个值，把它设置为指定索引处的完整用户数据的第 `n` 个用户值。
+    ///
+    /// 参数：
+    /// * `idx` - 用户数据的索引。
+    /// * `n` - 用户值的序号，从 1 开始。
+    ///
+    /// 返回值：用户数据没有这个用户值时返回 `false`。
+    fn set_iuservalue(&mut self, idx: isize, n: usize) -> bool;
 
     /// 将栈顶的值设置为全局变量的值，并弹出栈顶的值。
     ///
     /// 参数：
     /// * `name` - 全局变量的名称。
-    fn set_global(&mut self, name: &str);
+    ///
+    /// 返回值：键无效或 `__newindex` 元方法出错时返回错误。
+    fn set_global(&mut self, name: &str) -> Result<(), LuaError>;
 
     /// 注册一个 Rust 函数作为 Lua 函数。这个函数将被添加到全局环境中，可以在 Lua 代码中通过 `name` 来调用。
     ///
     /// 参数：
     /// * `name` - 函数在 Lua 中的名称。
     /// * `f` - 要注册的 Rust 函数。
-    fn register(&mut self, name: &str, f: RustFn);
+    fn register(&mut self, name: &str, f: RustFn) -> Result<(), LuaError>;
+
+    /// 与 `register` 相同，但是注册的是可以捕获状态的 Rust 闭包。
+    ///
+    /// 参数：
+    /// * `name` - 函数在 Lua 中的名称。
+    /// * `f` - 要注册的 Rust 闭包。
+    fn register_closure(&mut self, name: &str, f: RustClosure) -> Result<(), LuaError>;
+
+    /// 弹出栈顶的值，保存在注册表中，返回它的引用。引用存在期间值不会被回收，丢弃引用时从注册表中移除。
+    /// 值为 nil 时不占用注册表。
+    ///
+    /// 返回值：值的引用。
+    fn new_ref(&mut self) -> LuaRef;
+
+    /// 把引用的值推入栈顶。
+    ///
+    /// 参数：
+    /// * `r` - 由这个状态的 `new_ref` 创建的引用。
+    fn push_ref(&mut self, r: &LuaRef);
 
     /* 加载和调用函数 (加载和运行 Lua 代码) */
     /// 加载一个 Lua 代码块。这个函数将代码块编译为字节码，然后将生成的函数推送到栈顶。
@@ -396,13 +685,145 @@ pub trait LuaState {
     /// * `chunk_name` - 代码块的名称，用于错误消息和调试信息。
     /// * `mode` - 控制编译器的模式，可以是 "b"（只接受二进制代码块）、"t"（只接受文本代码块）或 "bt"（接受二进制或文本代码块）。
     ///
-    /// 返回值：如果加载成功，返回 0；如果发生错误，返回一个非零错误码。
+    /// 返回值：如果加载成功，返回 0；如果发生错误，返回一个非零错误码，并把错误信息推送到栈顶。
+    /// 与 C Lua 一样，模式不符、二进制格式错误和语法错误都返回 `LUA_ERRSYNTAX`，
+    /// 需要区分错误种类时使用 `load_chunk`。
     fn load(&mut self, chunk: Vec<u8>, chunk_name: &str, mode: &str) -> u8;
 
+    /// 与 `load` 相同，但出错时不推送错误信息，而是返回具体的 `LoadError`。
+    ///
+    /// 参数：
+    /// * `chunk` - 包含 Lua 代码块的字节向量。
+    /// * `chunk_name` - 代码块的名称，用于错误消息和调试信息。
+    /// * `mode` - 与 `load` 的 `mode` 参数相同。
+    ///
+    /// 返回值：如果加载成功，生成的函数在栈顶；否则返回加载错误。
+    fn load_chunk(&mut self, chunk: Vec<u8>, chunk_name: &str, mode: &str)
+        -> Result<(), LoadError>;
+
     /// 调用一个 Lua 函数。这个函数应该在栈顶，其参数应该在其下面，参数的数量由 `nargs` 指定。函数的返回值将被推送到栈顶。
     ///
     /// 参数：
     /// * `nargs` - 函数的参数数量。
     /// * `nresults` - 期望的返回值数量。如果是 -1，那么将返回所有的结果。
-    fn call(&mut self, nargs: usize, nresults: isize);
+    ///
+    /// 返回值：调用过程中发生的错误。出错时函数和参数已经从栈上移除。
+    fn call(&mut self, nargs: usize, nresults: isize) -> Result<(), LuaError>;
+
+    /// 在保护模式下调用一个函数。与 `call` 相同，但是出错时不传播错误，而是把错误对象（或消息处理函数的返回值）推送到栈顶，
+    /// 并返回错误状态码。
+    ///
+    /// 参数：
+    /// * `nargs` - 函数的参数数量。
+    /// * `nresults` - 期望的返回值数量。如果是 -1，那么将返回所有的结果。
+    /// * `msgh` - 消息处理函数的栈索引，0 表示没有消息处理函数。消息处理函数在出错的位置被调用，参数是错误对象，返回值作为新的错误对象。
+    ///
+    /// 返回值：`LUA_OK`，或者 `LUA_ERRRUN`、`LUA_ERRERR` 等错误状态码。
+    fn pcall(&mut self, nargs: usize, nresults: isize, msgh: isize) -> u8;
+
+    /// 与 `pcall` 相同，但是被调用的函数可以让出。让出时返回 `Err`，调用者应当直接把它返回；协程继续运行后，
+    /// 被调用的函数返回或者出错时调用延续函数 `k`，栈上的内容与 `pcall` 返回时相同，`k` 的返回值作为 Rust 函数的返回值。
+    ///
+    /// 参数：
+    /// * `nargs`、`nresults`、`msgh` - 与 `pcall` 相同。
+    /// * `ctx` - 传给 `k` 的上下文。
+    /// * `k` - 延续函数。
+    ///
+    /// 返回值：没有让出时与 `pcall` 相同；让出时返回让出的信号。
+    fn pcallk(
+        &mut self,
+        nargs: usize,
+        nresults: isize,
+        msgh: isize,
+        ctx: isize,
+        k: KFunction,
+    ) -> Result<u8, LuaError>;
+
+    /* 错误处理函数 */
+    /// 从栈顶弹出一个值，构造以它为错误对象的错误。用法是 `return Err(ls.error())`。
+    ///
+    /// 返回值：以栈顶的值为错误对象的错误。
+    fn error(&mut self) -> LuaError;
+
+    /// 返回调用栈第 `level` 层函数的当前位置，格式为 `chunkname:currentline: `，用于错误信息。
+    /// 第 0 层是正在运行的函数，第 1 层是调用它的函数，依此类推。该层不是 Lua 函数时返回空字符串。
+    ///
+    /// 参数：
+    /// * `level` - 调用栈的层数。
+    ///
+    /// 返回值：位置信息。
+    fn where_(&self, level: usize) -> String;
+
+    /* 协程函数 */
+    /// 弹出栈顶的函数，创建以它为主函数的新线程，并把线程推入栈顶。
+    fn new_thread(&mut self);
+
+    /// 开始或者继续运行指定索引处的线程。从栈顶弹出 `nargs` 个值，第一次运行时作为主函数的参数，
+    /// 之后作为线程挂起时调用的 `yield_` 的返回值。线程让出或者运行结束时，把让出的值或者主函数的返回值推入栈顶；
+    /// 出错时把错误对象推入栈顶，线程随之终止。
+    ///
+    /// 参数：
+    /// * `idx` - 线程的索引。
+    /// * `nargs` - 传给线程的值的数量。
+    ///
+    /// 返回值：状态码和推入栈顶的值的数量。线程让出时状态码为 `LUA_YIELD`，运行结束时为 `LUA_OK`，出错时为错误状态码。
+    fn resume(&mut self, idx: isize, nargs: usize) -> (u8, usize);
+
+    /// 让出正在运行的协程，栈顶的 `nresults` 个值被弹出，成为 `resume` 的结果。用法是 `return Err(ls.yield_(n))`，
+    /// 协程再次运行时，调用这个 Rust 函数的 Lua 函数得到 `resume` 传入的值作为调用的返回值。
+    ///
+    /// 参数：
+    /// * `nresults` - 让出的值的数量。
+    ///
+    /// 返回值：沿调用栈传递到 `resume` 的让出信号；当前不能让出时是相应的运行时错误。
+    fn yield_(&mut self, nresults: usize) -> LuaError;
+
+    /// 返回指定索引处的线程的状态。
+    ///
+    /// 参数：
+    /// * `idx` - 线程的索引。
+    ///
+    /// 返回值：`COS_RUN`（正在运行）、`COS_YIELD`（挂起或者还没有开始）、`COS_NORM`（运行了其他协程，等待它返回）
+    /// 或 `COS_DEAD`（运行结束或者因错误终止）。
+    fn co_status(&self, idx: isize) -> u8;
+
+    /// 判断指定索引处的线程能否让出。主线程和正在通过 `call`、`pcall` 或元方法调用函数的线程不能让出。
+    ///
+    /// 参数：
+    /// * `idx` - 线程的索引。
+    ///
+    /// 返回值：线程能否让出。
+    fn is_yieldable(&self, idx: isize) -> bool;
+
+    /// 关闭指定索引处挂起或者已经终止的线程：关闭它所有打开的上值，丢弃它的调用栈，此后线程处于死亡状态。
+    /// 线程曾因错误终止时，把错误对象推入栈顶。
+    ///
+    /// 参数：
+    /// * `idx` - 线程的索引。
+    ///
+    /// 返回值：线程因错误终止时返回错误状态码，否则返回 `LUA_OK`。
+    fn close_thread(&mut self, idx: isize) -> u8;
+
+    /* 垃圾回收函数 */
+    /// 控制垃圾回收器。
+    ///
+    /// 参数：
+    /// * `what` - 要执行的操作：
+    ///   * `LUA_GCSTOP` - 停止自动回收；
+    ///   * `LUA_GCRESTART` - 重新开始自动回收；
+    ///   * `LUA_GCCOLLECT` - 执行一次完整的回收；
+    ///   * `LUA_GCCOUNT` - 返回使用中的内存的 KB 数；
+    ///   * `LUA_GCCOUNTB` - 返回使用中的内存的字节数除以 1024 的余数；
+    ///   * `LUA_GCSTEP` - 执行一步回收，`args[0]` 是额外的工作量，相当于分配了这么多 KB；
+    ///   * `LUA_GCSETPAUSE` - 把回收间隔设为 `args[0]`，返回原来的值；
+    ///   * `LUA_GCSETSTEPMUL` - 把步进倍率设为 `args[0]`，返回原来的值；
+    ///   * `LUA_GCISRUNNING` - 返回自动回收是否在运行；
+    ///   * `LUA_GCGEN` - 切换到分代模式，`args` 依次是次要回收和主要回收的间隔，为 0 的保持不变；
+    ///   * `LUA_GCINC` - 切换到增量模式，`args` 依次是回收间隔、步进倍率和步长，为 0 的保持不变。
+    /// * `args` - 操作的参数。
+    ///
+    /// 返回值：与操作相关的值；`LUA_GCSTEP` 完成一个回收周期时返回 1；`LUA_GCGEN` 和 `LUA_GCINC`
+    /// 返回原来的模式；
+    /// `what` 无效时返回 -1。
+    fn gc(&mut self, what: u8, args: &[isize]) -> isize;
 }
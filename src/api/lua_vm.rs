@@ -1,3 +1,5 @@
+use super::LuaError;
+
 pub trait LuaVM: super::lua_state::LuaState {
     /// 获取当前的程序计数器（pc）的值。程序计数器用于跟踪虚拟机当前正在执行的指令。
     ///
@@ -15,6 +17,14 @@ pub trait LuaVM: super::lua_state::LuaState {
     /// 返回值：当前程序计数器指向的指令。
     fn fetch(&mut self) -> u32;
 
+    /// 获取相对程序计数器（pc）偏移 `n` 处的指令，不改变程序计数器。
+    ///
+    /// 参数：
+    /// * `n` - 相对程序计数器的偏移。
+    ///
+    /// 返回值：偏移处的指令。
+    fn peek(&self, n: isize) -> u32;
+
     /// 获取指定索引的常量，并将其推送到栈顶。这个函数用于读取常量表中的值。
     ///
     /// 参数：
@@ -43,4 +53,49 @@ pub trait LuaVM: super::lua_state::LuaState {
     ///
     /// 返回值：当前闭包需要的寄存器数量。
     fn register_count(&self) -> usize;
+
+    /// 不调用元方法，对栈顶的两个元素执行算术运算。成功时弹出两个元素并推送结果，失败时不改变栈，
+    /// 留给随后的 `MMBIN` 类指令调用元方法。
+    ///
+    /// 参数：
+    /// * `op` - 指定算术运算的类型。
+    ///
+    /// 返回值：运算是否成功。
+    fn raw_arith(&mut self, op: u8) -> bool;
+
+    /// 关闭当前栈帧中引用索引 `a` 及其之上的寄存器的上值，把寄存器的值复制到上值中；
+    /// 再按照与标记相反的顺序，以 nil 为错误对象调用这些寄存器中待关闭的变量的 `__close` 元方法。
+    ///
+    /// 参数：
+    /// * `a` - 第一个要关闭的寄存器的栈索引。
+    ///
+    /// 返回值：`__close` 元方法出错时返回错误。
+    fn close(&mut self, a: isize) -> Result<(), LuaError>;
+
+    /// 构造运行时错误，当前正在运行 Lua 函数时在消息前附加 `chunkname:currentline: ` 形式的位置信息。
+    ///
+    /// 参数：
+    /// * `msg` - 错误消息。
+    ///
+    /// 返回值：构造的错误，由调用者返回。
+    fn runtime_error(&self, msg: &str) -> LuaError;
+
+    /// 调用栈顶的函数，函数和参数在栈上的布局与 `call` 相同。Rust 函数直接运行，返回值推入栈顶；
+    /// Lua 函数只压入新的栈帧，由解释器接着执行，它返回后解释器再完成发起调用的指令。
+    ///
+    /// 参数：
+    /// * `nargs` - 函数的参数数量。
+    /// * `nresults` - 期望的返回值数量。如果是 -1，那么将返回所有的结果。
+    ///
+    /// 返回值：被调用的是否是已经运行结束的 Rust 函数。
+    fn precall(&mut self, nargs: usize, nresults: isize) -> Result<bool, LuaError>;
+
+    /// 尾调用栈顶的函数。与 `precall` 相同，但是被调用的 Lua 函数的栈帧替换当前栈帧，
+    /// 它的返回值直接交给当前函数的调用者。
+    ///
+    /// 参数：
+    /// * `nargs` - 函数的参数数量。
+    ///
+    /// 返回值：被调用的是否是已经运行结束的 Rust 函数，这时所有的返回值都在栈顶。
+    fn pretailcall(&mut self, nargs: usize) -> Result<bool, LuaError>;
 }
@@ -1,7 +1,14 @@
 pub mod consts;
+pub mod conversion;
+mod error;
+pub mod handle;
+#[cfg(feature = "serde")]
+pub mod lua_serde;
 mod lua_state;
 mod lua_vm;
 pub mod op;
 pub mod r#type;
-pub use self::lua_state::{LuaState as LuaAPI, RustFn};
+pub mod userdata;
+pub use self::error::LuaError;
+pub use self::lua_state::{KFunction, LuaState as LuaAPI, RustClosure, RustFn};
 pub use self::lua_vm::LuaVM;
@@ -0,0 +1,399 @@
+use std::{marker::PhantomData, rc::Rc};
+
+use crate::stdlib::auxlib;
+
+use super::{consts::lua_upvalueindex, r#type::Type, LuaAPI, LuaError, RustClosure};
+
+/// 可以作为不透明对象推入 Lua 的 Rust 类型。
+///
+/// 类型在 `register` 中注册方法、字段和元方法。第一次推入这个类型的值时据此生成元表，
+/// 以 `type_name` 为键保存在注册表中，之后推入的值共用这个元表。
+pub trait UserData: Sized + 'static {
+    /// 类型名：元表的 `__name` 字段，也是元表在注册表中的键。默认为 Rust 类型的完整路径。
+    fn type_name() -> &'static str {
+        std::any::type_name::<Self>()
+    }
+
+    /// 注册类型的方法、字段和元方法。
+    fn register(_registry: &mut UserDataRegistry<Self>) {}
+}
+
+/// 收集 `UserData` 类型注册的方法、字段和元方法。
+///
+/// 方法和字段通过 `__index`、`__newindex` 元方法访问；字段优先于方法，两者都没有时交给注册的
+/// `__index`、`__newindex` 元方法处理。方法的第一个参数（栈索引 1）是对象自身，其余参数从栈索引 2 开始。
+pub struct UserDataRegistry<T> {
+    methods: Vec<(String, RustClosure)>,
+    getters: Vec<(String, RustClosure)>,
+    setters: Vec<(String, RustClosure)>,
+    meta_methods: Vec<(String, RustClosure)>,
+    marker: PhantomData<T>,
+}
+
+impl<T: UserData> UserDataRegistry<T> {
+    fn new() -> Self {
+        UserDataRegistry {
+            methods: Vec::new(),
+            getters: Vec::new(),
+            setters: Vec::new(),
+            meta_methods: Vec::new(),
+            marker: PhantomData,
+        }
+    }
+
+    /// 注册方法 `obj:name(...)`。`f` 的返回值是推入栈顶的结果的数量。
+    pub fn add_method<F>(&mut self, name: &str, f: F)
+    where
+        F: Fn(&T, &mut dyn LuaAPI) -> Result<usize, LuaError> + 'static,
+    {
+        self.methods.push((name.to_string(), method(name, f)));
+    }
+
+    /// 与 `add_method` 相同，但是可以修改对象。
+    pub fn add_method_mut<F>(&mut self, name: &str, f: F)
+    where
+        F: Fn(&mut T, &mut dyn LuaAPI) -> Result<usize, LuaError> + 'static,
+    {
+        self.methods.push((name.to_string(), method_mut(name, f)));
+    }
+
+    /// 注册不借用对象的函数 `obj.name(...)`。
+    pub fn add_function<F>(&mut self, name: &str, f: F)
+    where
+        F: Fn(&mut dyn LuaAPI) -> Result<usize, LuaError> + 'static,
+    {
+        self.methods.push((name.to_string(), Rc::new(f)));
+    }
+
+    /// 注册字段 `obj.name` 的读取函数。`f` 把字段的值推入栈顶。
+    pub fn add_getter<F>(&mut self, name: &str, f: F)
+    where
+        F: Fn(&T, &mut dyn LuaAPI) -> Result<(), LuaError> + 'static,
+    {
+        let getter = method(name, move |this, ls| f(this, ls).map(|()| 1));
+        self.getters.push((name.to_string(), getter));
+    }
+
+    /// 注册字段 `obj.name = v` 的写入函数。新的值在栈索引 3 处。
+    pub fn add_setter<F>(&mut self, name: &str, f: F)
+    where
+        F: Fn(&mut T, &mut dyn LuaAPI) -> Result<(), LuaError> + 'static,
+    {
+        let setter = method_mut(name, move |this, ls| f(this, ls).map(|()| 0));
+        self.setters.push((name.to_string(), setter));
+    }
+
+    /// 注册元方法，比如 `__tostring`、`__close`。对象必须是第一个操作数。
+    pub fn add_meta_method<F>(&mut self, name: &str, f: F)
+    where
+        F: Fn(&T, &mut dyn LuaAPI) -> Result<usize, LuaError> + 'static,
+    {
+        self.meta_methods.push((name.to_string(), method(name, f)));
+    }
+
+    /// 与 `add_meta_method` 相同，但是可以修改对象。
+    pub fn add_meta_method_mut<F>(&mut self, name: &str, f: F)
+    where
+        F: Fn(&mut T, &mut dyn LuaAPI) -> Result<usize, LuaError> + 'static,
+    {
+        self.meta_methods
+            .push((name.to_string(), method_mut(name, f)));
+    }
+
+    /// 注册不借用对象的元方法。`__add`、`__eq` 等二元运算的对象可能是任意一个操作数，需要用这种形式。
+    pub fn add_meta_function<F>(&mut self, name: &str, f: F)
+    where
+        F: Fn(&mut dyn LuaAPI) -> Result<usize, LuaError> + 'static,
+    {
+        self.meta_methods.push((name.to_string(), Rc::new(f)));
+    }
+}
+
+/// 以栈索引 1 处的对象为 `&T` 调用 `f`。
+fn method<T, F>(name: &str, f: F) -> RustClosure
+where
+    T: UserData,
+    F: Fn(&T, &mut dyn LuaAPI) -> Result<usize, LuaError> + 'static,
+{
+    let name = name.to_string();
+    Rc::new(move |ls| {
+        let ud = ls.check_userdata(1, T::type_name(), &name)?;
+        let this = ud.borrow::<T>().ok_or_else(borrow_error::<T>)?;
+        f(&this, ls)
+    })
+}
+
+/// 以栈索引 1 处的对象为 `&mut T` 调用 `f`。
+fn method_mut<T, F>(name: &str, f: F) -> RustClosure
+where
+    T: UserData,
+    F: Fn(&mut T, &mut dyn LuaAPI) -> Result<usize, LuaError> + 'static,
+{
+    let name = name.to_string();
+    Rc::new(move |ls| {
+        let ud = ls.check_userdata(1, T::type_name(), &name)?;
+        let mut this = ud.borrow_mut::<T>().ok_or_else(borrow_error::<T>)?;
+        f(&mut this, ls)
+    })
+}
+
+fn borrow_error<T: UserData>() -> LuaError {
+    LuaError::runtime(format!("{} is already borrowed", T::type_name()))
+}
+
+/// 把 `value` 作为完整用户数据推入栈顶，元表为类型 `T` 的元表。
+pub fn push_userdata<T: UserData>(ls: &mut dyn LuaAPI, value: T) -> Result<(), LuaError> {
+    ls.new_userdata(Box::new(value), 0);
+    push_metatable::<T>(ls)?;
+    ls.set_metatable(-2);
+    Ok(())
+}
+
+/// 把类型 `T` 的元表推入栈顶，注册表中还没有时生成它。
+fn push_metatable<T: UserData>(ls: &mut dyn LuaAPI) -> Result<(), LuaError> {
+    if !auxlib::new_metatable(ls, T::type_name())? {
+        return Ok(());
+    }
+    let mut registry = UserDataRegistry::<T>::new();
+    T::register(&mut registry);
+
+    let (mut index, mut newindex) = (None, None);
+    for (name, f) in registry.meta_methods {
+        match name.as_str() {
+            "__index" => index = Some(f),
+            "__newindex" => newindex = Some(f),
+            _ => {
+                ls.push_closure(f, 0);
+                ls.set_field(-2, &name)?;
+            }
+        }
+    }
+
+    if registry.methods.is_empty() && registry.getters.is_empty() {
+        push_optional_closure(ls, index);
+    } else {
+        push_closure_table(ls, registry.methods)?;
+        push_closure_table(ls, registry.getters)?;
+        push_optional_closure(ls, index);
+        ls.push_rust_closure(index_event, 3);
+    }
+    ls.set_field(-2, "__index")?;
+
+    if registry.setters.is_empty() {
+        push_optional_closure(ls, newindex);
+    } else {
+        push_closure_table(ls, registry.setters)?;
+        push_optional_closure(ls, newindex);
+        ls.push_string(T::type_name().to_string());
+        ls.push_rust_closure(newindex_event, 3);
+    }
+    ls.set_field(-2, "__newindex")
+}
+
+fn push_optional_closure(ls: &mut dyn LuaAPI, f: Option<RustClosure>) {
+    match f {
+        Some(f) => ls.push_closure(f, 0),
+        None => ls.push_nil(),
+    }
+}
+
+/// 把名字到函数的表推入栈顶。
+fn push_closure_table(ls: &mut dyn LuaAPI, fs: Vec<(String, RustClosure)>) -> Result<(), LuaError> {
+    ls.create_table(0, fs.len());
+    for (name, f) in fs {
+        ls.push_closure(f, 0);
+        ls.set_field(-2, &name)?;
+    }
+    Ok(())
+}
+
+/// 用户数据的 `__index` 元方法。上值依次是方法表、字段读取函数表和注册的 `__index` 元方法。
+fn index_event(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    ls.push_value(2);
+    if ls.raw_get(lua_upvalueindex(2)) != Type::Nil as i8 {
+        ls.push_value(1);
+        ls.call(1, 1)?;
+        return Ok(1);
+    }
+    ls.pop(1);
+    ls.push_value(2);
+    if ls.raw_get(lua_upvalueindex(1)) != Type::Nil as i8 {
+        return Ok(1);
+    }
+    ls.pop(1);
+    ls.push_value(lua_upvalueindex(3));
+    if ls.is_function(-1) {
+        ls.push_value(1);
+        ls.push_value(2);
+        ls.call(2, 1)?;
+    }
+    Ok(1)
+}
+
+/// 用户数据的 `__newindex` 元方法。上值依次是字段写入函数表、注册的 `__newindex` 元方法和类型名。
+fn newindex_event(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    ls.push_value(2);
+    let setter = ls.raw_get(lua_upvalueindex(1)) != Type::Nil as i8;
+    if !setter {
+        ls.pop(1);
+        ls.push_value(lua_upvalueindex(2));
+        if !ls.is_function(-1) {
+            let key = ls.to_string_meta(2)?;
+            let tname = ls.to_string(lua_upvalueindex(3));
+            let msg = format!("attempt to set unknown field '{}' of {}", key, tname);
+            return Err(LuaError::runtime(msg));
+        }
+    }
+    ls.push_value(1);
+    ls.push_value(2);
+    ls.push_value(3);
+    ls.call(3, 0)?;
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use crate::{
+        api::consts::{LUA_MULTRET, LUA_OK},
+        state::new_lua_state,
+        stdlib::base::open_base,
+    };
+
+    use super::*;
+
+    #[derive(Clone, Copy)]
+    struct Vec2 {
+        x: f64,
+        y: f64,
+    }
+
+    thread_local! {
+        static CLOSED: Cell<usize> = const { Cell::new(0) };
+    }
+
+    fn check_vec2(ls: &dyn LuaAPI, arg: isize, fname: &str) -> Result<Vec2, LuaError> {
+        let ud = ls.check_userdata(arg, Vec2::type_name(), fname)?;
+        let v = *ud.borrow::<Vec2>().unwrap();
+        Ok(v)
+    }
+
+    impl UserData for Vec2 {
+        fn type_name() -> &'static str {
+            "Vec2"
+        }
+
+        fn register(registry: &mut UserDataRegistry<Self>) {
+            registry.add_getter("x", |v, ls| {
+                ls.push_number(v.x);
+                Ok(())
+            });
+            registry.add_getter("y", |v, ls| {
+                ls.push_number(v.y);
+                Ok(())
+            });
+            registry.add_setter("x", |v, ls| {
+                v.x = ls.to_number(3);
+                Ok(())
+            });
+            registry.add_method("length", |v, ls| {
+                ls.push_number((v.x * v.x + v.y * v.y).sqrt());
+                Ok(1)
+            });
+            registry.add_method_mut("scale", |v, ls| {
+                let k = ls.to_number(2);
+                v.x *= k;
+                v.y *= k;
+                Ok(0)
+            });
+            registry.add_function("new", |ls| {
+                let v = Vec2 {
+                    x: ls.to_number(1),
+                    y: ls.to_number(2),
+                };
+                push_userdata(ls, v)?;
+                Ok(1)
+            });
+            registry.add_meta_function("__add", |ls| {
+                let a = check_vec2(ls, 1, "__add")?;
+                let b = check_vec2(ls, 2, "__add")?;
+                push_userdata(
+                    ls,
+                    Vec2 {
+                        x: a.x + b.x,
+                        y: a.y + b.y,
+                    },
+                )?;
+                Ok(1)
+            });
+            registry.add_meta_function("__eq", |ls| {
+                let a = check_vec2(ls, 1, "__eq")?;
+                let b = check_vec2(ls, 2, "__eq")?;
+                ls.push_boolean(a.x == b.x && a.y == b.y);
+                Ok(1)
+            });
+            registry.add_meta_method("__tostring", |v, ls| {
+                ls.push_string(format!("({}, {})", v.x, v.y));
+                Ok(1)
+            });
+            registry.add_meta_method("__index", |_, ls| {
+                ls.push_string(format!("no {}", ls.to_string(2)));
+                Ok(1)
+            });
+            registry.add_meta_method("__close", |_, _| {
+                CLOSED.with(|closed| closed.set(closed.get() + 1));
+                Ok(0)
+            });
+        }
+    }
+
+    #[test]
+    fn test_userdata() {
+        let mut ls = new_lua_state();
+        open_base(&mut ls).unwrap();
+        push_userdata(&mut ls, Vec2 { x: 3.0, y: 4.0 }).unwrap();
+        ls.set_global("v").unwrap();
+        let src = r#"
+            local w = v.new(1, 2)
+            local len = v:length()
+            v:scale(2)
+            w.x = 10
+            local sum = v + w
+            local ok, e = pcall(function() w.z = 1 end)
+            local ok2, e2 = pcall(v.length, {})
+            do local c <close> = v.new(0, 0) end
+            return len, v.x, v.y, w.x, sum, sum == v.new(16, 10), v.missing, e, e2
+        "#;
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        ls.call(0, LUA_MULTRET).unwrap();
+        let results: Vec<String> = (1..=ls.get_top())
+            .map(|i| ls.to_string_meta(i).unwrap())
+            .collect();
+        assert_eq!(
+            results,
+            [
+                "5.0",
+                "6.0",
+                "8.0",
+                "10.0",
+                "(16, 10)",
+                "true",
+                "no missing",
+                "attempt to set unknown field 'z' of Vec2",
+                "bad argument #1 to 'length' (Vec2 expected, got table)",
+            ]
+        );
+        assert_eq!(CLOSED.with(|closed| closed.get()), 1);
+
+        // every value of the type shares the cached metatable
+        ls.set_top(0);
+        ls.get_global("v").unwrap();
+        push_userdata(&mut ls, Vec2 { x: 0.0, y: 0.0 }).unwrap();
+        assert!(ls.get_metatable(1));
+        assert!(ls.get_metatable(2));
+        assert!(ls.raw_equal(-1, -2));
+        assert_eq!(ls.get_field(-1, "__name").unwrap(), Type::String as i8);
+        assert_eq!(ls.to_string(-1), "Vec2");
+    }
+}
@@ -11,7 +11,7 @@ pub const LUAC_INT: i64 = 0x5678;
 pub const LUAC_NUM: f64 = 370.5;
 
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct BinaryChunk {
     pub header: Header,
     pub size_of_upvalues: u8,
@@ -19,7 +19,7 @@ pub struct BinaryChunk {
 }
 
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Header {
     pub lua_signature: [u8; 4],
     pub luac_version: u8,
@@ -33,7 +33,7 @@ pub struct Header {
 }
 
 #[allow(dead_code)]
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default)]
 pub struct Prototype {
     pub source: Option<String>, // debug
     pub line_defined: usize,
@@ -51,8 +51,27 @@ pub struct Prototype {
     pub upvalue_names: Vec<String>,      // debug
 }
 
+impl Prototype {
+    /// 第 `pc` 条指令对应的源代码行号，没有调试信息时返回 `None`。
+    pub fn line_of(&self, pc: usize) -> Option<usize> {
+        if pc >= self.line_info.len() {
+            return None;
+        }
+        // start from the last absolute line info at or before 'pc'
+        let (mut base_pc, mut line) = match self.abs_line_info.iter().rev().find(|a| a.pc <= pc) {
+            Some(abs) => (abs.pc as isize, abs.line as isize),
+            None => (-1, self.line_defined as isize),
+        };
+        while base_pc < pc as isize {
+            base_pc += 1;
+            line += self.line_info[base_pc as usize] as isize;
+        }
+        Some(line as usize)
+    }
+}
+
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Upvalue {
     pub instack: u8,
     pub idx: u8,
@@ -60,14 +79,14 @@ pub struct Upvalue {
 }
 
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct AbsLineInfo {
     pub pc: usize,
     pub line: usize,
 }
 
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct LocVar {
     pub var_name: String,
     pub start_pc: usize,
@@ -75,11 +94,11 @@ pub struct LocVar {
 }
 
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Constant {
     Nil,
     Boolean(bool),
     Number(f64),
     Integer(i64),
-    Str(String),
+    Str(Vec<u8>),
 }
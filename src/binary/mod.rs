@@ -1,13 +1,44 @@
-use std::rc::Rc;
+use std::{fmt, rc::Rc};
 
 pub mod chunk;
 mod reader;
 
-pub fn undump(data: Vec<u8>) -> Rc<chunk::Prototype> {
+/// 二进制代码块格式错误。
+#[derive(Debug, Clone, PartialEq)]
+pub struct UndumpError {
+    pub chunk_name: String,
+    pub message: String,
+}
+
+impl fmt::Display for UndumpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // same naming rule as `luaU_undump`
+        let name = if let Some(name) = self
+            .chunk_name
+            .strip_prefix('@')
+            .or_else(|| self.chunk_name.strip_prefix('='))
+        {
+            name
+        } else if self.chunk_name.as_bytes().first() == Some(&chunk::LUA_SIGNATURE[0]) {
+            "binary string"
+        } else {
+            &self.chunk_name
+        };
+        write!(f, "{}: bad binary format ({})", name, self.message)
+    }
+}
+
+/// 解析预编译的二进制代码块，格式不正确时返回错误而不是 panic。
+pub fn undump(data: Vec<u8>, chunk_name: &str) -> Result<Rc<chunk::Prototype>, UndumpError> {
     let mut r = reader::Reader::new(data);
-    r.check_header();
-    r.read_byte(); // size_upvalues
-    r.read_proto()
+    let result = r.check_header().and_then(|_| {
+        r.read_byte()?; // size_upvalues
+        r.read_proto()
+    });
+    result.map_err(|message| UndumpError {
+        chunk_name: chunk_name.to_string(),
+        message: message.to_string(),
+    })
 }
 
 #[cfg(test)]
@@ -21,7 +52,49 @@ mod tests {
         let mut data = Vec::new();
         file.read_to_end(&mut data).expect("Failed to read file");
 
-        let result = undump(data);
-        dbg!("{:?}", result);
+        let proto = undump(data, "@lua/all.luac").unwrap();
+        assert_eq!(proto.source.as_deref(), Some("@lua/all.lua"));
+        assert_eq!(proto.line_defined, 0);
+        assert_eq!(proto.last_line_defined, 0);
+        assert_eq!(proto.num_params, 0);
+        assert_eq!(proto.is_vararg, 1);
+        assert_eq!(proto.upvalue_names, ["_ENV"]);
+        assert_eq!(proto.protos.len(), 9);
+        assert!(!proto.code.is_empty());
+        assert_eq!(proto.line_info.len(), proto.code.len());
+    }
+
+    #[test]
+    fn test_undump_malformed() {
+        let mut file = File::open("lua/all.luac").expect("Failed to open file");
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).expect("Failed to read file");
+        let err = |data: Vec<u8>| undump(data, "=stdin").unwrap_err().to_string();
+
+        for n in [1, 10, 40, data.len() / 2, data.len() - 1] {
+            assert_eq!(
+                err(data[..n].to_vec()),
+                "stdin: bad binary format (truncated chunk)"
+            );
+        }
+        let mut bad = data.clone();
+        bad[2] = b'x';
+        assert_eq!(err(bad), "stdin: bad binary format (not a binary chunk)");
+        let mut bad = data.clone();
+        bad[4] = 0x53;
+        assert_eq!(err(bad), "stdin: bad binary format (version mismatch)");
+        let mut bad = data.clone();
+        bad[13] = 4;
+        assert_eq!(
+            err(bad),
+            "stdin: bad binary format (lua_Integer size mismatch)"
+        );
+
+        let e = undump(data[..8].to_vec(), "\x1bLua").unwrap_err();
+        assert_eq!(e.message, "truncated chunk");
+        assert_eq!(
+            e.to_string(),
+            "binary string: bad binary format (truncated chunk)"
+        );
     }
 }
@@ -6,6 +6,9 @@ use crate::api::r#type::VType;
 
 use super::chunk::Prototype;
 
+/// 读取失败时返回的原因，由 `undump` 加上代码块名称。
+pub type ReadResult<T> = Result<T, &'static str>;
+
 pub struct Reader {
     data: Vec<u8>,
     pos: usize,
@@ -16,181 +19,182 @@ impl Reader {
         Reader { data, pos: 0 }
     }
 
-    pub fn read_byte(&mut self) -> u8 {
-        let b = self.data[self.pos];
+    pub fn read_byte(&mut self) -> ReadResult<u8> {
+        let b = *self.data.get(self.pos).ok_or("truncated chunk")?;
         self.pos += 1;
-        b
+        Ok(b)
     }
 
-    fn read_u32(&mut self) -> u32 {
-        let a0 = self.read_byte() as u32;
-        let a1 = self.read_byte() as u32;
-        let a2 = self.read_byte() as u32;
-        let a3 = self.read_byte() as u32;
-        (a3 << 24) | (a2 << 16) | (a1 << 8) | a0
+    fn read_u32(&mut self) -> ReadResult<u32> {
+        let a0 = self.read_byte()? as u32;
+        let a1 = self.read_byte()? as u32;
+        let a2 = self.read_byte()? as u32;
+        let a3 = self.read_byte()? as u32;
+        Ok((a3 << 24) | (a2 << 16) | (a1 << 8) | a0)
     }
 
-    fn read_u64(&mut self) -> u64 {
-        let a0 = self.read_u32() as u64;
-        let a1 = self.read_u32() as u64;
-        (a1 << 32) | a0
+    fn read_u64(&mut self) -> ReadResult<u64> {
+        let a0 = self.read_u32()? as u64;
+        let a1 = self.read_u32()? as u64;
+        Ok((a1 << 32) | a0)
     }
 
-    fn read_lua_integer(&mut self) -> i64 {
-        self.read_u64() as i64
+    fn read_lua_integer(&mut self) -> ReadResult<i64> {
+        Ok(self.read_u64()? as i64)
     }
 
-    fn read_lua_number(&mut self) -> f64 {
-        use std::f64; // TODO
-        f64::from_bits(self.read_u64())
+    fn read_lua_number(&mut self) -> ReadResult<f64> {
+        Ok(f64::from_bits(self.read_u64()?))
     }
 
-    fn read_bytes(&mut self, n: usize) -> Vec<u8> {
-        let mut vec = Vec::new();
-        for _i in 0..n {
-            vec.push(self.read_byte());
+    fn read_bytes(&mut self, n: usize) -> ReadResult<Vec<u8>> {
+        if n > self.data.len() - self.pos {
+            return Err("truncated chunk");
         }
-        vec
+        let vec = self.data[self.pos..self.pos + n].to_vec();
+        self.pos += n;
+        Ok(vec)
     }
 
-    fn read_size(&mut self) -> usize {
+    fn read_size(&mut self) -> ReadResult<usize> {
         let mut x = 0usize;
-        let mut b;
-        let mut limit = std::usize::MAX;
-        limit >>= 7;
+        let limit = usize::MAX >> 7;
         loop {
-            b = self.read_byte();
+            let b = self.read_byte()?;
             if x >= limit {
-                panic!("integer overflow");
+                return Err("integer overflow");
             }
             x = (x << 7) | ((b & 0x7f) as usize);
             if b & 0x80 != 0 {
-                break;
+                return Ok(x);
             }
         }
-        x
     }
 
-    fn read_string(&mut self) -> String {
-        self.read_string0().unwrap_or_else(|| String::new())
+    fn read_string(&mut self) -> ReadResult<String> {
+        Ok(self.read_string0()?.unwrap_or_default())
     }
 
-    fn read_string0(&mut self) -> Option<String> {
-        let size = self.read_size();
+    /// 读取字符串常量，内容可以是任意字节。
+    fn read_lstring(&mut self) -> ReadResult<Vec<u8>> {
+        let size = self.read_size()?;
         if size == 0 {
-            return None;
+            return Ok(Vec::new());
         }
-        let bytes = self.read_bytes(size - 1);
-        let string = String::from_utf8(bytes);
-        string.ok() // Some(string.unwrap())
+        self.read_bytes(size - 1)
     }
 
-    fn read_vec<T, F>(&mut self, f: F) -> Vec<T>
+    fn read_string0(&mut self) -> ReadResult<Option<String>> {
+        let size = self.read_size()?;
+        if size == 0 {
+            return Ok(None);
+        }
+        let bytes = self.read_bytes(size - 1)?;
+        Ok(String::from_utf8(bytes).ok())
+    }
+
+    fn read_vec<T, F>(&mut self, f: F) -> ReadResult<Vec<T>>
     where
-        F: Fn(&mut Reader) -> T,
+        F: Fn(&mut Reader) -> ReadResult<T>,
     {
-        let n = self.read_size();
-        let mut vec = Vec::with_capacity(n);
+        let n = self.read_size()?;
+        // every element takes at least one byte, so a bogus size cannot make us over-allocate
+        let mut vec = Vec::with_capacity(n.min(self.data.len() - self.pos));
         for _i in 0..n {
-            vec.push(f(self));
+            vec.push(f(self)?);
         }
-        vec
-    }
-
-    pub fn check_header(&mut self) {
-        assert_eq!(
-            self.read_bytes(4),
-            chunk::LUA_SIGNATURE,
-            "not a precompiled chunk!"
-        );
-        assert_eq!(self.read_byte(), chunk::LUAC_VERSION, "version mismatch!");
-        assert_eq!(self.read_byte(), chunk::LUAC_FORMAT, "format mismatch!");
-        assert_eq!(self.read_bytes(6), chunk::LUAC_DATA, "corrupted!");
-        assert_eq!(
-            self.read_byte(),
-            chunk::INSTRUCTION_SIZE,
-            "instruction size mismatch!"
-        );
-        assert_eq!(
-            self.read_byte(),
-            chunk::LUA_INTEGER_SIZE,
-            "lua_Integer size mismatch!"
-        );
-        assert_eq!(
-            self.read_byte(),
-            chunk::LUA_NUMBER_SIZE,
-            "lua_Number size mismatch!"
-        );
-        assert_eq!(
-            self.read_lua_integer(),
-            chunk::LUAC_INT,
-            "endianness mismatch!"
-        );
-        assert_eq!(
-            self.read_lua_number(),
-            chunk::LUAC_NUM,
-            "float format mismatch!"
-        );
-    }
-
-    pub fn read_proto(&mut self) -> Rc<Prototype> {
-        self.read_proto0(None)
+        Ok(vec)
     }
 
-    fn read_proto0(&mut self, parent_source: Option<String>) -> Rc<Prototype> {
-        let source = self.read_string0().or(parent_source);
-        Rc::new(Prototype {
-            source: source.clone(), // debug
-            line_defined: self.read_size(),
-            last_line_defined: self.read_size(),
-            num_params: self.read_byte(),
-            is_vararg: self.read_byte(),
-            max_stack_size: self.read_byte(),
-            code: self.read_vec(|r| r.read_u32()),
-            constants: self.read_vec(|r| r.read_constant()),
-            upvalues: self.read_vec(|r| r.read_upvalue()),
-            protos: self.read_vec(|r| r.read_proto0(source.clone())),
-            line_info: self.read_vec(|r| r.read_byte() as i8), // debug
-            abs_line_info: self.read_vec(|r| r.read_abs_line_info()), // debug
-            loc_vars: self.read_vec(|r| r.read_loc_var()),     // debug
-            upvalue_names: self.read_vec(|r| r.read_string()), // debug
-        })
+    fn check_literal(&mut self, s: &[u8], msg: &'static str) -> ReadResult<()> {
+        if self.read_bytes(s.len())? != s {
+            return Err(msg);
+        }
+        Ok(())
     }
 
-    fn read_constant(&mut self) -> chunk::Constant {
-        let tag = self.read_byte();
-        match VType::from_u8(tag) {
+    fn check_byte(&mut self, b: u8, msg: &'static str) -> ReadResult<()> {
+        if self.read_byte()? != b {
+            return Err(msg);
+        }
+        Ok(())
+    }
+
+    pub fn check_header(&mut self) -> ReadResult<()> {
+        self.check_literal(&chunk::LUA_SIGNATURE, "not a binary chunk")?;
+        self.check_byte(chunk::LUAC_VERSION, "version mismatch")?;
+        self.check_byte(chunk::LUAC_FORMAT, "format mismatch")?;
+        self.check_literal(&chunk::LUAC_DATA, "corrupted chunk")?;
+        self.check_byte(chunk::INSTRUCTION_SIZE, "Instruction size mismatch")?;
+        self.check_byte(chunk::LUA_INTEGER_SIZE, "lua_Integer size mismatch")?;
+        self.check_byte(chunk::LUA_NUMBER_SIZE, "lua_Number size mismatch")?;
+        if self.read_lua_integer()? != chunk::LUAC_INT {
+            return Err("integer format mismatch");
+        }
+        if self.read_lua_number()? != chunk::LUAC_NUM {
+            return Err("float format mismatch");
+        }
+        Ok(())
+    }
+
+    pub fn read_proto(&mut self) -> ReadResult<Rc<Prototype>> {
+        self.read_proto0(None)
+    }
+
+    fn read_proto0(&mut self, parent_source: Option<String>) -> ReadResult<Rc<Prototype>> {
+        let source = self.read_string0()?.or(parent_source);
+        Ok(Rc::new(Prototype {
+            source: source.clone(), // debug
+            line_defined: self.read_size()?,
+            last_line_defined: self.read_size()?,
+            num_params: self.read_byte()?,
+            is_vararg: self.read_byte()?,
+            max_stack_size: self.read_byte()?,
+            code: self.read_vec(|r| r.read_u32())?,
+            constants: self.read_vec(|r| r.read_constant())?,
+            upvalues: self.read_vec(|r| r.read_upvalue())?,
+            protos: self.read_vec(|r| r.read_proto0(source.clone()))?,
+            line_info: self.read_vec(|r| Ok(r.read_byte()? as i8))?, // debug
+            abs_line_info: self.read_vec(|r| r.read_abs_line_info())?, // debug
+            loc_vars: self.read_vec(|r| r.read_loc_var())?,          // debug
+            upvalue_names: self.read_vec(|r| r.read_string())?,      // debug
+        }))
+    }
+
+    fn read_constant(&mut self) -> ReadResult<chunk::Constant> {
+        let tag = self.read_byte()?;
+        Ok(match VType::from_u8(tag) {
             Some(VType::VNil) => chunk::Constant::Nil,
             Some(VType::VFalse) => chunk::Constant::Boolean(false),
             Some(VType::VTrue) => chunk::Constant::Boolean(true),
-            Some(VType::VNumInt) => chunk::Constant::Integer(self.read_lua_integer()),
-            Some(VType::VNumFlt) => chunk::Constant::Number(self.read_lua_number()),
-            Some(VType::VShrStr) => chunk::Constant::Str(self.read_string()),
-            Some(VType::VLngStr) => chunk::Constant::Str(self.read_string()),
-            _ => panic!("unexpected tag: {tag:?}"),
-        }
+            Some(VType::VNumInt) => chunk::Constant::Integer(self.read_lua_integer()?),
+            Some(VType::VNumFlt) => chunk::Constant::Number(self.read_lua_number()?),
+            Some(VType::VShrStr) => chunk::Constant::Str(self.read_lstring()?),
+            Some(VType::VLngStr) => chunk::Constant::Str(self.read_lstring()?),
+            _ => return Err("bad constant tag"),
+        })
     }
 
-    fn read_upvalue(&mut self) -> chunk::Upvalue {
-        chunk::Upvalue {
-            instack: self.read_byte(),
-            idx: self.read_byte(),
-            kind: self.read_byte(),
-        }
+    fn read_upvalue(&mut self) -> ReadResult<chunk::Upvalue> {
+        Ok(chunk::Upvalue {
+            instack: self.read_byte()?,
+            idx: self.read_byte()?,
+            kind: self.read_byte()?,
+        })
     }
 
-    fn read_abs_line_info(&mut self) -> chunk::AbsLineInfo {
-        chunk::AbsLineInfo {
-            pc: self.read_size(),
-            line: self.read_size(),
-        }
+    fn read_abs_line_info(&mut self) -> ReadResult<chunk::AbsLineInfo> {
+        Ok(chunk::AbsLineInfo {
+            pc: self.read_size()?,
+            line: self.read_size()?,
+        })
     }
 
-    fn read_loc_var(&mut self) -> chunk::LocVar {
-        chunk::LocVar {
-            var_name: self.read_string(),
-            start_pc: self.read_size(),
-            end_pc: self.read_size(),
-        }
+    fn read_loc_var(&mut self) -> ReadResult<chunk::LocVar> {
+        Ok(chunk::LocVar {
+            var_name: self.read_string()?,
+            start_pc: self.read_size()?,
+            end_pc: self.read_size()?,
+        })
     }
 }
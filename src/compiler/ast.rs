@@ -0,0 +1,227 @@
+use super::lexer::Span;
+
+/// 语句块，`ret` 为块末尾可选的 `return` 语句。
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub stats: Vec<Stat>,
+    pub ret: Option<Return>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Return {
+    pub exprs: Vec<Expr>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Name {
+    pub name: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Attrib {
+    Const,
+    Close,
+}
+
+/// 局部变量声明中的变量名及其属性（`<const>` 或 `<close>`）。
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalName {
+    pub name: Name,
+    pub attrib: Option<Attrib>,
+}
+
+/// `function a.b.c:m` 中的函数名。
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuncName {
+    pub path: Vec<Name>,
+    pub method: Option<Name>,
+}
+
+/// 函数体，区间从参数列表的 `(` 开始到 `end` 结束。
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuncBody {
+    pub params: Vec<Name>,
+    pub is_vararg: bool,
+    pub block: Block,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stat {
+    pub kind: StatKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatKind {
+    Assign {
+        targets: Vec<Expr>,
+        exprs: Vec<Expr>,
+    },
+    Call(Expr),
+    Label(Name),
+    Break,
+    Goto(Name),
+    Do(Block),
+    While {
+        cond: Expr,
+        block: Block,
+    },
+    Repeat {
+        block: Block,
+        cond: Expr,
+    },
+    If {
+        conds: Vec<(Expr, Block)>,
+        else_block: Option<Block>,
+    },
+    NumericFor {
+        var: Name,
+        start: Box<Expr>,
+        limit: Box<Expr>,
+        step: Option<Box<Expr>>,
+        block: Block,
+    },
+    GenericFor {
+        names: Vec<Name>,
+        exprs: Vec<Expr>,
+        block: Block,
+    },
+    Function {
+        name: FuncName,
+        body: Box<FuncBody>,
+    },
+    LocalFunction {
+        name: Name,
+        body: Box<FuncBody>,
+    },
+    Local {
+        names: Vec<LocalName>,
+        exprs: Vec<Expr>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Nil,
+    True,
+    False,
+    Vararg,
+    Integer(i64),
+    Float(f64),
+    String(Vec<u8>),
+    Function(Box<FuncBody>),
+    Table(Vec<Field>),
+    Binary {
+        op: BinOp,
+        op_span: Span,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    Unary {
+        op: UnOp,
+        expr: Box<Expr>,
+    },
+    Name(String),
+    Index {
+        obj: Box<Expr>,
+        key: Box<Expr>,
+    },
+    Call {
+        func: Box<Expr>,
+        args: Vec<Expr>,
+    },
+    MethodCall {
+        obj: Box<Expr>,
+        method: Name,
+        args: Vec<Expr>,
+    },
+    /// 括号表达式，会将多返回值截断为一个。
+    Paren(Box<Expr>),
+}
+
+impl Expr {
+    /// 是否可能产生多个值（函数调用或 `...`）。
+    pub fn is_multi(&self) -> bool {
+        matches!(
+            self.kind,
+            ExprKind::Call { .. } | ExprKind::MethodCall { .. } | ExprKind::Vararg
+        )
+    }
+}
+
+/// 表构造器中的字段。
+#[derive(Debug, Clone, PartialEq)]
+pub enum Field {
+    /// `exp`
+    Positional(Expr),
+    /// `name = exp`
+    Named(Name, Expr),
+    /// `[exp] = exp`
+    Keyed(Expr, Expr),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Mod,
+    Pow,
+    Div,
+    IDiv,
+    BAnd,
+    BOr,
+    BXor,
+    Shl,
+    Shr,
+    Concat,
+    Eq,
+    Lt,
+    Le,
+    Ne,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+impl BinOp {
+    /// 返回左右优先级，与 `lparser.c` 中的 `priority` 表一致。
+    pub fn priority(self) -> (u8, u8) {
+        match self {
+            BinOp::Add | BinOp::Sub => (10, 10),
+            BinOp::Mul | BinOp::Mod => (11, 11),
+            BinOp::Pow => (14, 13), // right associative
+            BinOp::Div | BinOp::IDiv => (11, 11),
+            BinOp::BAnd => (6, 6),
+            BinOp::BOr => (4, 4),
+            BinOp::BXor => (5, 5),
+            BinOp::Shl | BinOp::Shr => (7, 7),
+            BinOp::Concat => (9, 8), // right associative
+            BinOp::Eq | BinOp::Lt | BinOp::Le | BinOp::Ne | BinOp::Gt | BinOp::Ge => (3, 3),
+            BinOp::And => (2, 2),
+            BinOp::Or => (1, 1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnOp {
+    Minus,
+    BNot,
+    Not,
+    Len,
+}
+
+/// 一元运算符的优先级。
+pub const UNARY_PRIORITY: u8 = 12;
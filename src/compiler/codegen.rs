@@ -0,0 +1,1411 @@
+use std::rc::Rc;
+
+use crate::{
+    binary::chunk::{LocVar, Prototype, Upvalue},
+    vm::{instruction::Instruction, opcodes::*},
+};
+
+use super::{
+    ast::*,
+    func_state::{ExpDesc, ExpKind, FuncState, LFIELDS_PER_FLUSH, LUA_MULTRET, NO_JUMP},
+    lexer::{Position, Span, SyntaxError},
+};
+
+/// 普通局部变量
+const VDKREG: u8 = 0;
+/// `<const>` 局部变量
+const RDKCONST: u8 = 1;
+/// `<close>` 局部变量
+const RDKTOCLOSE: u8 = 2;
+
+/// 每个函数最多的局部变量数量
+const MAX_VARS: usize = 200;
+/// 每个函数最多的上值数量
+const MAX_UPVAL: usize = 255;
+
+/// 活动局部变量的描述
+struct VarDesc {
+    name: String,
+    kind: u8,
+    /// 变量所在的寄存器
+    ridx: usize,
+    /// 变量在 `loc_vars` 中的索引
+    pidx: usize,
+}
+
+/// 待解析的 `goto` 或已定义的标签
+struct LabelDesc {
+    name: String,
+    /// 标签的位置，或 `goto` 对应的跳转链表
+    pc: isize,
+    line: usize,
+    /// 该位置的活动局部变量数量
+    nactvar: usize,
+    /// `goto` 跳出时是否需要关闭上值
+    close: bool,
+}
+
+/// 语句块的作用域信息
+#[derive(Clone)]
+struct BlockCnt {
+    first_label: usize,
+    first_goto: usize,
+    nactvar: usize,
+    /// 块内是否有局部变量被用作上值
+    upval: bool,
+    is_loop: bool,
+    /// 是否位于待关闭变量的作用域内
+    inside_tbc: bool,
+}
+
+/// 正在生成代码的函数
+struct Function {
+    fs: FuncState,
+    blocks: Vec<BlockCnt>,
+    /// 本函数第一个局部变量在 `actvar` 中的索引
+    first_local: usize,
+    /// 本函数第一个标签在 `labels` 中的索引
+    first_label: usize,
+}
+
+/// 把语法树编译为函数原型。
+pub fn gen_proto(block: &Block, chunk_name: &str) -> Result<Rc<Prototype>, SyntaxError> {
+    let mut g = CodeGen {
+        chunk_name: chunk_name.to_string(),
+        funcs: Vec::new(),
+        actvar: Vec::new(),
+        gotos: Vec::new(),
+        labels: Vec::new(),
+    };
+    g.main_func(block).map(Rc::new)
+}
+
+/// 语法树上的代码生成器，对应 `lparser.c` 中生成代码的部分。
+struct CodeGen {
+    chunk_name: String,
+    /// 嵌套的函数，最后一个为当前函数
+    funcs: Vec<Function>,
+    actvar: Vec<VarDesc>,
+    gotos: Vec<LabelDesc>,
+    labels: Vec<LabelDesc>,
+}
+
+impl CodeGen {
+    fn func(&mut self) -> &mut Function {
+        self.funcs.last_mut().unwrap()
+    }
+
+    fn fs(&mut self) -> &mut FuncState {
+        &mut self.func().fs
+    }
+
+    fn block_cnt(&mut self) -> &mut BlockCnt {
+        self.func().blocks.last_mut().unwrap()
+    }
+
+    /// 把当前行号更新到 `line`，空语法节点不会影响行号
+    fn set_line(&mut self, span: Span) {
+        if span.start.offset != span.end.offset {
+            self.fs().line = span.end.line;
+        }
+    }
+
+    fn set_pos(&mut self, pos: Position) {
+        self.fs().pos = pos;
+    }
+
+    fn sem_error<T>(&mut self, msg: &str) -> Result<T, SyntaxError> {
+        Err(self.fs().error(msg))
+    }
+
+    /* 函数 */
+
+    fn open_func(&mut self, line_defined: usize) {
+        let fs = FuncState::new(&self.chunk_name, line_defined);
+        self.funcs.push(Function {
+            fs,
+            blocks: Vec::new(),
+            first_local: self.actvar.len(),
+            first_label: self.labels.len(),
+        });
+        self.enter_block(false);
+    }
+
+    fn close_func(&mut self) -> Result<Prototype, SyntaxError> {
+        let nactvar = self.fs().nactvar;
+        self.fs().ret(nactvar, 0);
+        self.leave_block()?;
+        self.fs().finish();
+        Ok(self.funcs.pop().unwrap().fs.f)
+    }
+
+    fn set_vararg(&mut self, nparams: usize) {
+        let fs = self.fs();
+        fs.f.is_vararg = 1;
+        fs.code_abc(OP_VARARGPREP, nparams, 0, 0);
+    }
+
+    /// 主函数总是可变参数函数，且唯一的上值是 `_ENV`
+    fn main_func(&mut self, block: &Block) -> Result<Prototype, SyntaxError> {
+        self.open_func(0);
+        self.fs().line = 1;
+        self.set_vararg(0);
+        let fs = self.fs();
+        fs.f.upvalues.push(Upvalue {
+            instack: 1,
+            idx: 0,
+            kind: VDKREG,
+        });
+        fs.f.upvalue_names.push("_ENV".to_string());
+        self.stat_list(&block.stats, &block.ret, false)?;
+        self.set_line(block.span);
+        self.set_pos(block.span.end);
+        self.close_func()
+    }
+
+    /// 编译函数体，生成 `CLOSURE` 指令
+    fn body(
+        &mut self,
+        body: &FuncBody,
+        is_method: bool,
+        line: usize,
+    ) -> Result<ExpDesc, SyntaxError> {
+        self.open_func(line);
+        self.set_pos(body.span.start);
+        if is_method {
+            // create 'self' parameter
+            self.new_local_var("self")?;
+            self.adjust_local_vars(1);
+        }
+        for param in &body.params {
+            self.new_local_var(&param.name)?;
+        }
+        self.adjust_local_vars(body.params.len());
+        let nparams = self.fs().nactvar;
+        self.fs().f.num_params = nparams as u8;
+        if body.is_vararg {
+            self.set_vararg(nparams);
+        }
+        self.fs().reserve_regs(nparams)?;
+        self.stat_list(&body.block.stats, &body.block.ret, false)?;
+        let end_line = body.span.end.line;
+        self.fs().f.last_line_defined = end_line;
+        self.fs().line = end_line;
+        self.set_pos(body.span.end);
+        let proto = self.close_func()?;
+
+        let fs = self.fs();
+        fs.line = end_line;
+        fs.f.protos.push(Rc::new(proto));
+        let np = fs.f.protos.len() - 1;
+        let mut e = ExpDesc::new(ExpKind::Reloc(fs.code_abx(OP_CLOSURE, 0, np)));
+        fs.exp2nextreg(&mut e)?;
+        Ok(e)
+    }
+
+    /* 局部变量 */
+
+    fn new_local_var(&mut self, name: &str) -> Result<usize, SyntaxError> {
+        let first_local = self.func().first_local;
+        let n = self.actvar.len() + 1 - first_local;
+        self.fs().check_limit(n, MAX_VARS, "local variables")?;
+        self.actvar.push(VarDesc {
+            name: name.to_string(),
+            kind: VDKREG,
+            ridx: 0,
+            pidx: 0,
+        });
+        Ok(self.actvar.len() - 1 - first_local)
+    }
+
+    fn var_desc(&mut self, vidx: usize) -> &mut VarDesc {
+        let first_local = self.func().first_local;
+        &mut self.actvar[first_local + vidx]
+    }
+
+    /// 激活最近声明的 `nvars` 个局部变量
+    fn adjust_local_vars(&mut self, nvars: usize) {
+        let first = self.fs().nactvar;
+        // every active variable lives in a register, so its register is its index
+        for vidx in first..first + nvars {
+            self.fs().nactvar += 1;
+            let name = self.var_desc(vidx).name.clone();
+            let fs = self.fs();
+            fs.f.loc_vars.push(LocVar {
+                var_name: name,
+                start_pc: fs.pc(),
+                end_pc: 0,
+            });
+            let pidx = fs.f.loc_vars.len() - 1;
+            let var = self.var_desc(vidx);
+            var.ridx = vidx;
+            var.pidx = pidx;
+        }
+    }
+
+    /// 结束作用域内的局部变量
+    fn remove_vars(&mut self, to_level: usize) {
+        let first_local = self.func().first_local;
+        while self.fs().nactvar > to_level {
+            self.fs().nactvar -= 1;
+            let vidx = self.fs().nactvar;
+            let pidx = self.actvar[first_local + vidx].pidx;
+            let fs = self.fs();
+            fs.f.loc_vars[pidx].end_pc = fs.pc();
+        }
+        self.actvar.truncate(first_local + to_level);
+    }
+
+    /// 在第 `level` 层函数中查找变量，找不到时返回 `Void`
+    fn single_var_aux(
+        &mut self,
+        level: usize,
+        name: &str,
+        base: bool,
+    ) -> Result<ExpDesc, SyntaxError> {
+        let func = &self.funcs[level];
+        let found = (0..func.fs.nactvar)
+            .rev()
+            .find(|&i| self.actvar[func.first_local + i].name == name);
+        if let Some(vidx) = found {
+            let ridx = self.actvar[func.first_local + vidx].ridx;
+            if !base {
+                // local will be used as an upvalue
+                self.mark_upval(level, vidx);
+            }
+            return Ok(ExpDesc::new(ExpKind::Local { ridx, vidx }));
+        }
+        if let Some(idx) = func.fs.f.upvalue_names.iter().position(|n| n == name) {
+            return Ok(ExpDesc::new(ExpKind::Upval(idx)));
+        }
+        if level == 0 {
+            // global name
+            return Ok(ExpDesc::new(ExpKind::Void));
+        }
+        let var = self.single_var_aux(level - 1, name, false)?;
+        match var.kind {
+            ExpKind::Local { .. } | ExpKind::Upval(_) => {
+                let idx = self.new_upvalue(level, name, &var)?;
+                Ok(ExpDesc::new(ExpKind::Upval(idx)))
+            }
+            _ => Ok(var),
+        }
+    }
+
+    fn mark_upval(&mut self, level: usize, vidx: usize) {
+        let func = &mut self.funcs[level];
+        if let Some(bl) = func.blocks.iter_mut().rev().find(|bl| bl.nactvar <= vidx) {
+            bl.upval = true;
+        }
+        func.fs.need_close = true;
+    }
+
+    fn mark_to_be_closed(&mut self) {
+        let bl = self.block_cnt();
+        bl.upval = true;
+        bl.inside_tbc = true;
+        self.fs().need_close = true;
+    }
+
+    fn new_upvalue(
+        &mut self,
+        level: usize,
+        name: &str,
+        var: &ExpDesc,
+    ) -> Result<usize, SyntaxError> {
+        let prev = &self.funcs[level - 1];
+        let upvalue = match var.kind {
+            ExpKind::Local { ridx, vidx } => Upvalue {
+                instack: 1,
+                idx: ridx as u8,
+                kind: self.actvar[prev.first_local + vidx].kind,
+            },
+            ExpKind::Upval(idx) => Upvalue {
+                instack: 0,
+                idx: idx as u8,
+                kind: prev.fs.f.upvalues[idx].kind,
+            },
+            _ => unreachable!(),
+        };
+        let fs = &mut self.funcs[level].fs;
+        fs.check_limit(fs.f.upvalues.len() + 1, MAX_UPVAL, "upvalues")?;
+        fs.f.upvalues.push(upvalue);
+        fs.f.upvalue_names.push(name.to_string());
+        Ok(fs.f.upvalues.len() - 1)
+    }
+
+    /// 查找变量，全局变量转换为 `_ENV.name`
+    fn single_var(&mut self, name: &str) -> Result<ExpDesc, SyntaxError> {
+        let level = self.funcs.len() - 1;
+        let mut var = self.single_var_aux(level, name, true)?;
+        if var.kind == ExpKind::Void {
+            var = self.single_var_aux(level, "_ENV", true)?;
+            let fs = self.fs();
+            fs.exp2anyregup(&mut var)?;
+            let mut key = ExpDesc::new(ExpKind::KStr(name.as_bytes().to_vec()));
+            fs.indexed(&mut var, &mut key)?;
+        }
+        Ok(var)
+    }
+
+    fn check_readonly(&mut self, e: &ExpDesc) -> Result<(), SyntaxError> {
+        let name = match e.kind {
+            ExpKind::Local { vidx, .. } => {
+                let var = self.var_desc(vidx);
+                (var.kind != VDKREG).then(|| var.name.clone())
+            }
+            ExpKind::Upval(idx) => {
+                let f = &self.fs().f;
+                (f.upvalues[idx].kind != VDKREG).then(|| f.upvalue_names[idx].clone())
+            }
+            _ => None,
+        };
+        match name {
+            Some(name) => self.sem_error(&format!("attempt to assign to const variable '{name}'")),
+            None => Ok(()),
+        }
+    }
+
+    /* 语句块与跳转 */
+
+    fn enter_block(&mut self, is_loop: bool) {
+        let inside_tbc = self.func().blocks.last().is_some_and(|bl| bl.inside_tbc);
+        let bl = BlockCnt {
+            first_label: self.labels.len(),
+            first_goto: self.gotos.len(),
+            nactvar: self.fs().nactvar,
+            upval: false,
+            is_loop,
+            inside_tbc,
+        };
+        self.func().blocks.push(bl);
+    }
+
+    fn leave_block(&mut self) -> Result<BlockCnt, SyntaxError> {
+        let bl = self.block_cnt().clone();
+        let has_previous = self.func().blocks.len() > 1;
+        let stk_level = bl.nactvar;
+        self.remove_vars(bl.nactvar);
+        let mut has_close = false;
+        if bl.is_loop {
+            // fix pending breaks
+            has_close = self.create_label("break", 0, false)?;
+        }
+        if !has_close && has_previous && bl.upval {
+            self.fs().code_abc(OP_CLOSE, stk_level, 0, 0);
+        }
+        self.fs().free_reg = stk_level;
+        self.labels.truncate(bl.first_label);
+        self.func().blocks.pop();
+        if has_previous {
+            self.move_gotos_out(&bl);
+        } else if bl.first_goto < self.gotos.len() {
+            return self.undef_goto(bl.first_goto);
+        }
+        Ok(bl)
+    }
+
+    fn block(&mut self, block: &Block) -> Result<(), SyntaxError> {
+        self.enter_block(false);
+        self.stat_list(&block.stats, &block.ret, false)?;
+        self.set_line(block.span);
+        self.leave_block()?;
+        Ok(())
+    }
+
+    fn undef_goto<T>(&mut self, g: usize) -> Result<T, SyntaxError> {
+        let gt = &self.gotos[g];
+        let msg = if gt.name == "break" {
+            format!("break outside a loop at line {}", gt.line)
+        } else {
+            format!(
+                "no visible label '{}' for <goto> at line {}",
+                gt.name, gt.line
+            )
+        };
+        self.sem_error(&msg)
+    }
+
+    /// 块结束时把未解析的 `goto` 移到外层块
+    fn move_gotos_out(&mut self, bl: &BlockCnt) {
+        for gt in &mut self.gotos[bl.first_goto..] {
+            if gt.nactvar > bl.nactvar {
+                gt.close |= bl.upval;
+            }
+            gt.nactvar = bl.nactvar;
+        }
+    }
+
+    fn new_goto_entry(&mut self, name: &str, line: usize, pc: isize) {
+        let nactvar = self.fs().nactvar;
+        self.gotos.push(LabelDesc {
+            name: name.to_string(),
+            pc,
+            line,
+            nactvar,
+            close: false,
+        });
+    }
+
+    fn find_label(&self, name: &str) -> Option<&LabelDesc> {
+        let first = self.funcs.last().unwrap().first_label;
+        self.labels[first..].iter().find(|lb| lb.name == name)
+    }
+
+    /// 创建标签并解析跳到它的 `goto`，返回是否生成了 `CLOSE`
+    fn create_label(&mut self, name: &str, line: usize, last: bool) -> Result<bool, SyntaxError> {
+        let pc = self.fs().get_label();
+        let nactvar = if last {
+            // label is last no-op statement in the block: assume that locals are already out of scope
+            self.block_cnt().nactvar
+        } else {
+            self.fs().nactvar
+        };
+        self.labels.push(LabelDesc {
+            name: name.to_string(),
+            pc: pc as isize,
+            line,
+            nactvar,
+            close: false,
+        });
+        if self.solve_gotos(self.labels.len() - 1)? {
+            // need close
+            let nactvar = self.fs().nactvar;
+            self.fs().code_abc(OP_CLOSE, nactvar, 0, 0);
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// 解析当前块中所有跳到标签 `l` 的 `goto`
+    fn solve_gotos(&mut self, l: usize) -> Result<bool, SyntaxError> {
+        let mut i = self.block_cnt().first_goto;
+        let mut need_close = false;
+        while i < self.gotos.len() {
+            if self.gotos[i].name == self.labels[l].name {
+                need_close |= self.gotos[i].close;
+                self.solve_goto(i, l)?;
+            } else {
+                i += 1;
+            }
+        }
+        Ok(need_close)
+    }
+
+    fn solve_goto(&mut self, g: usize, l: usize) -> Result<(), SyntaxError> {
+        let (gt, lb) = (&self.gotos[g], &self.labels[l]);
+        if gt.nactvar < lb.nactvar {
+            let var = &self.actvar[self.funcs.last().unwrap().first_local + gt.nactvar];
+            let msg = format!(
+                "<goto {}> at line {} jumps into the scope of local '{}'",
+                gt.name, gt.line, var.name
+            );
+            return self.sem_error(&msg);
+        }
+        let (list, target) = (gt.pc, lb.pc as usize);
+        self.fs().patch_list(list, target)?;
+        self.gotos.remove(g);
+        Ok(())
+    }
+
+    /* 语句 */
+
+    fn stat_list(
+        &mut self,
+        stats: &[Stat],
+        ret: &Option<Return>,
+        until: bool,
+    ) -> Result<(), SyntaxError> {
+        for (i, stat) in stats.iter().enumerate() {
+            if let StatKind::Label(name) = &stat.kind {
+                // a label is the last statement if only labels follow it up to the end of the block
+                let last = !until
+                    && ret.is_none()
+                    && stats[i + 1..]
+                        .iter()
+                        .all(|s| matches!(s.kind, StatKind::Label(_)));
+                self.set_pos(stat.span.start);
+                self.label_stat(name, last)?;
+            } else {
+                self.statement(stat)?;
+            }
+        }
+        if let Some(ret) = ret {
+            self.set_pos(ret.span.start);
+            self.ret_stat(ret)?;
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, stat: &Stat) -> Result<(), SyntaxError> {
+        let line = stat.span.start.line;
+        self.set_pos(stat.span.start);
+        match &stat.kind {
+            StatKind::Assign { targets, exprs } => self.assign_stat(targets, exprs, stat.span)?,
+            StatKind::Call(e) => {
+                let v = self.expr(e)?;
+                if let ExpKind::Call(pc) = v.kind {
+                    // call statement uses no results
+                    self.fs().f.code[pc].set_arg_c(1);
+                }
+            }
+            StatKind::Label(name) => self.label_stat(name, false)?,
+            StatKind::Break => {
+                let pc = self.fs().jump();
+                self.new_goto_entry("break", line, pc as isize);
+            }
+            StatKind::Goto(name) => self.goto_stat(&name.name, line)?,
+            StatKind::Do(block) => self.block(block)?,
+            StatKind::While { cond, block } => self.while_stat(cond, block, stat.span)?,
+            StatKind::Repeat { block, cond } => self.repeat_stat(block, cond)?,
+            StatKind::If { conds, else_block } => self.if_stat(conds, else_block, stat.span)?,
+            StatKind::NumericFor {
+                var,
+                start,
+                limit,
+                step,
+                block,
+            } => {
+                let step = step.as_deref();
+                self.for_num(var, start, limit, step, block, stat.span)?
+            }
+            StatKind::GenericFor {
+                names,
+                exprs,
+                block,
+            } => self.for_list(names, exprs, block, stat.span)?,
+            StatKind::Function { name, body } => self.func_stat(name, body, line)?,
+            StatKind::LocalFunction { name, body } => self.local_func(name, body)?,
+            StatKind::Local { names, exprs } => self.local_stat(names, exprs, stat.span)?,
+        }
+        // free registers
+        let fs = self.fs();
+        fs.free_reg = fs.nactvar;
+        Ok(())
+    }
+
+    fn label_stat(&mut self, name: &Name, last: bool) -> Result<(), SyntaxError> {
+        if let Some(lb) = self.find_label(&name.name) {
+            let msg = format!("label '{}' already defined on line {}", name.name, lb.line);
+            return self.sem_error(&msg);
+        }
+        self.create_label(&name.name, name.span.start.line, last)?;
+        Ok(())
+    }
+
+    fn goto_stat(&mut self, name: &str, line: usize) -> Result<(), SyntaxError> {
+        match self.find_label(name).map(|lb| (lb.pc as usize, lb.nactvar)) {
+            Some((pc, lb_level)) => {
+                // backward jump; will be resolved here
+                let fs = self.fs();
+                if fs.nactvar > lb_level {
+                    // leaving the scope of a variable
+                    fs.code_abc(OP_CLOSE, lb_level, 0, 0);
+                }
+                let j = fs.jump();
+                fs.patch_list(j as isize, pc)?;
+            }
+            None => {
+                // forward jump; will be resolved when the label is declared
+                let j = self.fs().jump();
+                self.new_goto_entry(name, line, j as isize);
+            }
+        }
+        Ok(())
+    }
+
+    /// 多重赋值：先求出所有右值，再从最后一个变量开始依次赋值
+    fn assign_stat(
+        &mut self,
+        targets: &[Expr],
+        exprs: &[Expr],
+        span: Span,
+    ) -> Result<(), SyntaxError> {
+        let mut lhs: Vec<ExpDesc> = Vec::with_capacity(targets.len());
+        for target in targets {
+            let v = self.expr(target)?;
+            if !v.is_indexed() {
+                self.check_conflict(&mut lhs, &v);
+            }
+            self.check_readonly(&v)?;
+            lhs.push(v);
+        }
+        let (mut e, nexps) = self.expr_list(exprs)?;
+        self.set_line(span);
+        let last = lhs.pop().unwrap();
+        if nexps != targets.len() {
+            self.adjust_assign(targets.len(), nexps, &mut e)?;
+            let fs = self.fs();
+            let mut e = ExpDesc::new(ExpKind::NonReloc(fs.free_reg - 1));
+            fs.store_var(&last, &mut e)?;
+        } else {
+            let fs = self.fs();
+            fs.set_one_ret(&mut e);
+            fs.store_var(&last, &mut e)?;
+        }
+        for var in lhs.iter().rev() {
+            let fs = self.fs();
+            let mut e = ExpDesc::new(ExpKind::NonReloc(fs.free_reg - 1));
+            fs.store_var(var, &mut e)?;
+        }
+        Ok(())
+    }
+
+    /// 多重赋值中，若之前的索引目标用到了将被赋值的局部变量，
+    /// 先把该变量复制到临时寄存器，保证索引使用赋值前的值
+    fn check_conflict(&mut self, lhs: &mut [ExpDesc], v: &ExpDesc) {
+        let fs = self.fs();
+        let extra = fs.free_reg;
+        let mut conflict = false;
+        for e in lhs.iter_mut() {
+            match (&mut e.kind, &v.kind) {
+                (ExpKind::IndexUp { t, idx }, ExpKind::Upval(up)) if *t == *up => {
+                    // table is the upvalue being assigned now
+                    conflict = true;
+                    e.kind = ExpKind::IndexStr {
+                        t: extra,
+                        idx: *idx,
+                    };
+                }
+                (ExpKind::Indexed { t, idx }, ExpKind::Local { ridx, .. }) => {
+                    if *t == *ridx {
+                        conflict = true;
+                        *t = extra;
+                    }
+                    if *idx == *ridx {
+                        // index is the local being assigned
+                        conflict = true;
+                        *idx = extra;
+                    }
+                }
+                (ExpKind::IndexStr { t, .. }, ExpKind::Local { ridx, .. }) if *t == *ridx => {
+                    conflict = true;
+                    *t = extra;
+                }
+                _ => {}
+            }
+        }
+        if conflict {
+            // copy upvalue/local value to a temporary (in position 'extra')
+            match v.kind {
+                ExpKind::Local { ridx, .. } => fs.code_abc(OP_MOVE, extra, ridx, 0),
+                ExpKind::Upval(idx) => fs.code_abc(OP_GETUPVAL, extra, idx, 0),
+                _ => unreachable!(),
+            };
+            // cannot fail: the register was already reserved by the indexed expressions
+            let _ = fs.reserve_regs(1);
+        }
+    }
+
+    /// 调整表达式列表的值数量与变量数量一致
+    fn adjust_assign(
+        &mut self,
+        nvars: usize,
+        nexps: usize,
+        e: &mut ExpDesc,
+    ) -> Result<(), SyntaxError> {
+        let fs = self.fs();
+        let needed = nvars as isize - nexps as isize;
+        if e.has_mult_ret() {
+            let extra = (needed + 1).max(0);
+            fs.set_returns(e, extra)?;
+        } else {
+            if e.kind != ExpKind::Void {
+                fs.exp2nextreg(e)?;
+            }
+            if needed > 0 {
+                fs.nil(fs.free_reg, needed as usize);
+            }
+        }
+        if needed > 0 {
+            fs.reserve_regs(needed as usize)?;
+        } else {
+            fs.free_reg = (fs.free_reg as isize + needed) as usize;
+        }
+        Ok(())
+    }
+
+    fn cond(&mut self, cond: &Expr) -> Result<isize, SyntaxError> {
+        let mut v = self.expr(cond)?;
+        if v.kind == ExpKind::Nil {
+            // 'falses' are all equal here
+            v.kind = ExpKind::False;
+        }
+        self.fs().go_if_true(&mut v)?;
+        Ok(v.f)
+    }
+
+    fn while_stat(&mut self, cond: &Expr, block: &Block, span: Span) -> Result<(), SyntaxError> {
+        let while_init = self.fs().get_label();
+        let cond_exit = self.cond(cond)?;
+        self.enter_block(true);
+        self.block(block)?;
+        let fs = self.fs();
+        let j = fs.jump();
+        fs.patch_list(j as isize, while_init)?;
+        self.set_line(span);
+        self.leave_block()?;
+        self.fs().patch_to_here(cond_exit)
+    }
+
+    fn repeat_stat(&mut self, block: &Block, cond: &Expr) -> Result<(), SyntaxError> {
+        let repeat_init = self.fs().get_label();
+        // loop block and scope block
+        self.enter_block(true);
+        self.enter_block(false);
+        self.stat_list(&block.stats, &block.ret, true)?;
+        let mut cond_exit = self.cond(cond)?;
+        let bl2 = self.leave_block()?;
+        let fs = self.fs();
+        if bl2.upval {
+            // upvalues must be closed before looping back
+            let exit = fs.jump();
+            fs.patch_to_here(cond_exit)?;
+            fs.code_abc(OP_CLOSE, bl2.nactvar, 0, 0);
+            cond_exit = fs.jump() as isize;
+            fs.patch_to_here(exit as isize)?;
+        }
+        fs.patch_list(cond_exit, repeat_init)?;
+        self.leave_block()?;
+        Ok(())
+    }
+
+    fn test_then_block(
+        &mut self,
+        cond: &Expr,
+        block: &Block,
+        escape_list: &mut isize,
+        has_else: bool,
+    ) -> Result<(), SyntaxError> {
+        let mut v = self.expr(cond)?;
+        let jf;
+        if let Some(Stat {
+            kind: StatKind::Break,
+            span,
+        }) = block.stats.first()
+        {
+            // 'if x then break': jump directly to the break label
+            self.fs().go_if_false(&mut v)?;
+            self.enter_block(false);
+            self.new_goto_entry("break", span.start.line, v.t);
+            if block.stats.len() == 1 && block.ret.is_none() {
+                // 'break' is the entire block
+                self.leave_block()?;
+                return Ok(());
+            }
+            jf = self.fs().jump() as isize;
+            self.stat_list(&block.stats[1..], &block.ret, false)?;
+        } else {
+            self.fs().go_if_true(&mut v)?;
+            self.enter_block(false);
+            jf = v.f;
+            self.stat_list(&block.stats, &block.ret, false)?;
+        }
+        self.set_line(block.span);
+        self.leave_block()?;
+        let fs = self.fs();
+        if has_else {
+            let j = fs.jump();
+            fs.concat(escape_list, j as isize)?;
+        }
+        fs.patch_to_here(jf)
+    }
+
+    fn if_stat(
+        &mut self,
+        conds: &[(Expr, Block)],
+        else_block: &Option<Block>,
+        span: Span,
+    ) -> Result<(), SyntaxError> {
+        let mut escape_list = NO_JUMP;
+        for (i, (cond, block)) in conds.iter().enumerate() {
+            let has_else = i + 1 < conds.len() || else_block.is_some();
+            self.test_then_block(cond, block, &mut escape_list, has_else)?;
+        }
+        if let Some(block) = else_block {
+            self.block(block)?;
+        }
+        self.set_line(span);
+        self.fs().patch_to_here(escape_list)
+    }
+
+    fn exp1(&mut self, e: &Expr) -> Result<(), SyntaxError> {
+        let mut v = self.expr(e)?;
+        self.fs().exp2nextreg(&mut v)
+    }
+
+    fn for_body(
+        &mut self,
+        base: usize,
+        line: usize,
+        nvars: usize,
+        is_gen: bool,
+        block: &Block,
+    ) -> Result<(), SyntaxError> {
+        let op = if is_gen { OP_TFORPREP } else { OP_FORPREP };
+        let prep = self.fs().code_abx(op, base, 0);
+        // scope for declared variables
+        self.enter_block(false);
+        self.adjust_local_vars(nvars);
+        self.fs().reserve_regs(nvars)?;
+        self.block(block)?;
+        self.leave_block()?;
+        let fs = self.fs();
+        let here = fs.get_label();
+        fs.fix_for_jump(prep, here, false)?;
+        if is_gen {
+            fs.code_abc(OP_TFORCALL, base, 0, nvars);
+            fs.fix_line(line);
+        }
+        let op = if is_gen { OP_TFORLOOP } else { OP_FORLOOP };
+        let end_for = fs.code_abx(op, base, 0);
+        fs.fix_for_jump(end_for, prep + 1, true)?;
+        fs.fix_line(line);
+        Ok(())
+    }
+
+    fn for_num(
+        &mut self,
+        var: &Name,
+        start: &Expr,
+        limit: &Expr,
+        step: Option<&Expr>,
+        block: &Block,
+        span: Span,
+    ) -> Result<(), SyntaxError> {
+        // scope for loop and control variables
+        self.enter_block(true);
+        let base = self.fs().free_reg;
+        self.new_local_var("(for state)")?;
+        self.new_local_var("(for state)")?;
+        self.new_local_var("(for state)")?;
+        self.new_local_var(&var.name)?;
+        self.exp1(start)?;
+        self.exp1(limit)?;
+        match step {
+            Some(step) => self.exp1(step)?,
+            None => {
+                // default step = 1
+                let fs = self.fs();
+                fs.code_int(fs.free_reg, 1);
+                fs.reserve_regs(1)?;
+            }
+        }
+        self.adjust_local_vars(3);
+        self.for_body(base, span.start.line, 1, false, block)?;
+        self.set_line(span);
+        self.leave_block()?;
+        Ok(())
+    }
+
+    fn for_list(
+        &mut self,
+        names: &[Name],
+        exprs: &[Expr],
+        block: &Block,
+        span: Span,
+    ) -> Result<(), SyntaxError> {
+        self.enter_block(true);
+        let base = self.fs().free_reg;
+        // create control variables
+        for _ in 0..4 {
+            self.new_local_var("(for state)")?;
+        }
+        for name in names {
+            self.new_local_var(&name.name)?;
+        }
+        let line = exprs[0].span.start.line;
+        let (mut e, nexps) = self.expr_list(exprs)?;
+        self.adjust_assign(4, nexps, &mut e)?;
+        self.adjust_local_vars(4);
+        // last control var. must be closed
+        self.mark_to_be_closed();
+        // extra space to call generator
+        self.fs().check_stack(3)?;
+        self.for_body(base, line, names.len(), true, block)?;
+        self.set_line(span);
+        self.leave_block()?;
+        Ok(())
+    }
+
+    fn func_stat(
+        &mut self,
+        name: &FuncName,
+        body: &FuncBody,
+        line: usize,
+    ) -> Result<(), SyntaxError> {
+        let mut v = self.single_var(&name.path[0].name)?;
+        let mut prev = &name.path[0];
+        for key in name.path[1..].iter().chain(name.method.iter()) {
+            self.fs().line = prev.span.end.line;
+            self.field_sel(&mut v, key)?;
+            prev = key;
+        }
+        let mut b = self.body(body, name.method.is_some(), line)?;
+        self.check_readonly(&v)?;
+        let fs = self.fs();
+        fs.store_var(&v, &mut b)?;
+        // definition "happens" in the first line
+        fs.fix_line(line);
+        Ok(())
+    }
+
+    fn field_sel(&mut self, v: &mut ExpDesc, key: &Name) -> Result<(), SyntaxError> {
+        let fs = self.fs();
+        fs.exp2anyregup(v)?;
+        let mut k = ExpDesc::new(ExpKind::KStr(key.name.as_bytes().to_vec()));
+        fs.indexed(v, &mut k)
+    }
+
+    fn local_func(&mut self, name: &Name, body: &FuncBody) -> Result<(), SyntaxError> {
+        let fvar = self.fs().nactvar;
+        self.new_local_var(&name.name)?;
+        self.adjust_local_vars(1);
+        self.body(body, false, body.span.start.line)?;
+        // debug information will only see the variable after this point
+        let pidx = self.var_desc(fvar).pidx;
+        let fs = self.fs();
+        fs.f.loc_vars[pidx].start_pc = fs.pc();
+        Ok(())
+    }
+
+    fn local_stat(
+        &mut self,
+        names: &[LocalName],
+        exprs: &[Expr],
+        span: Span,
+    ) -> Result<(), SyntaxError> {
+        let mut to_close = None;
+        for (i, local) in names.iter().enumerate() {
+            let vidx = self.new_local_var(&local.name.name)?;
+            let kind = match local.attrib {
+                Some(Attrib::Const) => RDKCONST,
+                Some(Attrib::Close) => {
+                    to_close = Some(self.fs().nactvar + i);
+                    RDKTOCLOSE
+                }
+                None => VDKREG,
+            };
+            self.var_desc(vidx).kind = kind;
+        }
+        let (mut e, nexps) = if exprs.is_empty() {
+            (ExpDesc::new(ExpKind::Void), 0)
+        } else {
+            self.expr_list(exprs)?
+        };
+        self.set_line(span);
+        self.adjust_assign(names.len(), nexps, &mut e)?;
+        self.adjust_local_vars(names.len());
+        if let Some(level) = to_close {
+            self.mark_to_be_closed();
+            self.fs().code_abc(OP_TBC, level, 0, 0);
+        }
+        Ok(())
+    }
+
+    fn ret_stat(&mut self, ret: &Return) -> Result<(), SyntaxError> {
+        let mut first = self.fs().nactvar;
+        let nret;
+        if ret.exprs.is_empty() {
+            nret = 0;
+        } else {
+            let (mut e, nexps) = self.expr_list(&ret.exprs)?;
+            if e.has_mult_ret() {
+                let inside_tbc = self.block_cnt().inside_tbc;
+                let fs = self.fs();
+                fs.set_mult_ret(&mut e)?;
+                if let ExpKind::Call(pc) = e.kind {
+                    if nexps == 1 && !inside_tbc {
+                        // tail call
+                        fs.f.code[pc].set_opcode(OP_TAILCALL);
+                    }
+                }
+                nret = LUA_MULTRET;
+            } else if nexps == 1 {
+                first = self.fs().exp2anyreg(&mut e)?;
+                nret = 1;
+            } else {
+                self.fs().exp2nextreg(&mut e)?;
+                nret = nexps as isize;
+            }
+        }
+        self.set_line(ret.span);
+        self.fs().ret(first, nret);
+        Ok(())
+    }
+
+    /* 表达式 */
+
+    fn expr_list(&mut self, exprs: &[Expr]) -> Result<(ExpDesc, usize), SyntaxError> {
+        let mut v = self.expr(&exprs[0])?;
+        for e in &exprs[1..] {
+            self.fs().exp2nextreg(&mut v)?;
+            v = self.expr(e)?;
+        }
+        Ok((v, exprs.len()))
+    }
+
+    fn expr(&mut self, e: &Expr) -> Result<ExpDesc, SyntaxError> {
+        let v = match &e.kind {
+            ExprKind::Nil => ExpDesc::new(ExpKind::Nil),
+            ExprKind::True => ExpDesc::new(ExpKind::True),
+            ExprKind::False => ExpDesc::new(ExpKind::False),
+            ExprKind::Integer(i) => ExpDesc::new(ExpKind::KInt(*i)),
+            ExprKind::Float(n) => ExpDesc::new(ExpKind::KFlt(*n)),
+            ExprKind::String(s) => ExpDesc::new(ExpKind::KStr(s.clone())),
+            ExprKind::Vararg => {
+                let pc = self.fs().code_abc(OP_VARARG, 0, 0, 1);
+                ExpDesc::new(ExpKind::Vararg(pc))
+            }
+            ExprKind::Table(fields) => self.constructor(fields, e.span)?,
+            ExprKind::Function(body) => self.body(body, false, body.span.start.line)?,
+            ExprKind::Binary {
+                op,
+                op_span,
+                lhs,
+                rhs,
+            } => {
+                let mut v = self.expr(lhs)?;
+                self.fs().line = op_span.end.line;
+                self.fs().infix(*op, &mut v)?;
+                let v2 = self.expr(rhs)?;
+                self.fs().posfix(*op, &mut v, v2, op_span.start.line)?;
+                v
+            }
+            ExprKind::Unary { op, expr } => {
+                let mut v = self.expr(expr)?;
+                self.fs().prefix(*op, &mut v, e.span.start.line)?;
+                v
+            }
+            ExprKind::Name(name) => self.single_var(name)?,
+            ExprKind::Index { obj, key } => {
+                let mut v = self.expr(obj)?;
+                self.fs().exp2anyregup(&mut v)?;
+                let mut k = self.expr(key)?;
+                let fs = self.fs();
+                fs.exp2val(&mut k)?;
+                fs.indexed(&mut v, &mut k)?;
+                v
+            }
+            ExprKind::Call { func, args } => {
+                let mut v = self.expr(func)?;
+                self.fs().exp2nextreg(&mut v)?;
+                self.func_args(&mut v, args, func.span.end.line, e.span)?;
+                v
+            }
+            ExprKind::MethodCall { obj, method, args } => {
+                let mut v = self.expr(obj)?;
+                let mut key = ExpDesc::new(ExpKind::KStr(method.name.as_bytes().to_vec()));
+                self.fs().line = method.span.end.line;
+                self.fs().code_self(&mut v, &mut key)?;
+                self.func_args(&mut v, args, method.span.end.line, e.span)?;
+                v
+            }
+            ExprKind::Paren(inner) => {
+                let mut v = self.expr(inner)?;
+                self.fs().discharge_vars(&mut v);
+                v
+            }
+        };
+        self.set_line(e.span);
+        Ok(v)
+    }
+
+    fn func_args(
+        &mut self,
+        f: &mut ExpDesc,
+        args: &[Expr],
+        line: usize,
+        span: Span,
+    ) -> Result<(), SyntaxError> {
+        let mut a = ExpDesc::new(ExpKind::Void);
+        if !args.is_empty() {
+            a = self.expr_list(args)?.0;
+            if a.has_mult_ret() {
+                self.fs().set_mult_ret(&mut a)?;
+            }
+        }
+        self.set_line(span);
+        let fs = self.fs();
+        let base = f.info();
+        let nparams = if a.has_mult_ret() {
+            // open call
+            LUA_MULTRET
+        } else {
+            if a.kind != ExpKind::Void {
+                // close last argument
+                fs.exp2nextreg(&mut a)?;
+            }
+            (fs.free_reg - (base + 1)) as isize
+        };
+        let pc = fs.code_abc(OP_CALL, base, (nparams + 1) as usize, 2);
+        fs.fix_line(line);
+        f.kind = ExpKind::Call(pc);
+        // call removes function and arguments and leaves one result (unless changed later)
+        fs.free_reg = base + 1;
+        Ok(())
+    }
+
+    /// 表构造器：数组部分每 `LFIELDS_PER_FLUSH` 个元素生成一条 `SETLIST`
+    fn constructor(&mut self, fields: &[Field], span: Span) -> Result<ExpDesc, SyntaxError> {
+        let fs = self.fs();
+        fs.line = span.start.line;
+        let pc = fs.code_abc(OP_NEWTABLE, 0, 0, 0);
+        // space for extra arg.
+        fs.code(0);
+        let t = fs.free_reg;
+        fs.reserve_regs(1)?;
+        let (mut na, mut nh, mut to_store) = (0, 0, 0);
+        let mut v = ExpDesc::new(ExpKind::Void);
+        for field in fields {
+            // close list field
+            if v.kind != ExpKind::Void {
+                let fs = self.fs();
+                fs.exp2nextreg(&mut v)?;
+                v = ExpDesc::new(ExpKind::Void);
+                if to_store == LFIELDS_PER_FLUSH {
+                    fs.set_list(t, na, to_store as isize);
+                    na += to_store;
+                    to_store = 0;
+                }
+            }
+            match field {
+                Field::Positional(e) => {
+                    v = self.expr(e)?;
+                    to_store += 1;
+                }
+                Field::Named(..) | Field::Keyed(..) => {
+                    self.rec_field(t, field)?;
+                    nh += 1;
+                }
+            }
+        }
+        self.set_line(span);
+        // last list field
+        let fs = self.fs();
+        if to_store > 0 {
+            if v.has_mult_ret() {
+                fs.set_mult_ret(&mut v)?;
+                fs.set_list(t, na, LUA_MULTRET);
+                // do not count last expression (unknown number of elements)
+                to_store -= 1;
+            } else {
+                if v.kind != ExpKind::Void {
+                    fs.exp2nextreg(&mut v)?;
+                }
+                fs.set_list(t, na, to_store as isize);
+            }
+            na += to_store;
+        }
+        fs.set_table_size(pc, t, na, nh);
+        Ok(ExpDesc::new(ExpKind::NonReloc(t)))
+    }
+
+    fn rec_field(&mut self, t: usize, field: &Field) -> Result<(), SyntaxError> {
+        let reg = self.fs().free_reg;
+        let (mut key, val) = match field {
+            Field::Named(name, val) => {
+                let key = ExpDesc::new(ExpKind::KStr(name.name.as_bytes().to_vec()));
+                (key, val)
+            }
+            Field::Keyed(key, val) => {
+                let mut key = self.expr(key)?;
+                self.fs().exp2val(&mut key)?;
+                (key, val)
+            }
+            Field::Positional(_) => unreachable!(),
+        };
+        let mut tab = ExpDesc::new(ExpKind::NonReloc(t));
+        self.fs().indexed(&mut tab, &mut key)?;
+        let mut v = self.expr(val)?;
+        let fs = self.fs();
+        fs.store_var(&tab, &mut v)?;
+        // free registers
+        fs.free_reg = reg;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::opcodes::OpMode;
+
+    use super::super::compile;
+    use super::*;
+
+    /// 以 `luac -l` 的风格列出指令，便于与参考实现的输出对照
+    fn listing(f: &Prototype) -> Vec<String> {
+        f.code
+            .iter()
+            .map(|&i| {
+                let name = &i.opname()["OP_".len()..];
+                match OPCODES[i.opcode() as usize].opmode {
+                    OpMode::IABC => {
+                        let k = if i.get_arg_k() != 0 { "k" } else { "" };
+                        format!(
+                            "{name} {} {} {}{k}",
+                            i.get_arg_a(),
+                            i.get_arg_b(),
+                            i.get_arg_c()
+                        )
+                    }
+                    OpMode::IABx => format!("{name} {} {}", i.get_arg_a(), i.get_arg_bx()),
+                    OpMode::IAsBx => format!("{name} {} {}", i.get_arg_a(), i.get_arg_sbx()),
+                    OpMode::IAx => format!("{name} {}", i.get_arg_ax()),
+                    OpMode::IsJ => format!("{name} {}", i.get_arg_sj()),
+                }
+            })
+            .collect()
+    }
+
+    fn loc_vars(f: &Prototype) -> Vec<(&str, usize, usize)> {
+        f.loc_vars
+            .iter()
+            .map(|v| (v.var_name.as_str(), v.start_pc, v.end_pc))
+            .collect()
+    }
+
+    fn compile_err(src: &str) -> String {
+        compile(src.as_bytes(), "=t").unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_locals_and_tables() {
+        let src = "local a, b = 1, \"x\"\nlocal c = a .. b .. a\nlocal t = {1, 2, x = a, [b] = c}\nprint(t.x, #t)";
+        let f = compile(src.as_bytes(), "=t").unwrap();
+        assert_eq!(
+            listing(&f),
+            [
+                "VARARGPREP 0 0 0",
+                "LOADI 0 1",
+                "LOADK 1 0",
+                "MOVE 2 0 0",
+                "MOVE 3 1 0",
+                "MOVE 4 0 0",
+                "CONCAT 2 3 0",
+                "NEWTABLE 3 2 2",
+                "EXTRAARG 0",
+                "LOADI 4 1",
+                "LOADI 5 2",
+                "SETFIELD 3 0 0",
+                "SETTABLE 3 1 2",
+                "SETLIST 3 2 0",
+                "GETTABUP 4 0 1",
+                "GETFIELD 5 3 0",
+                "LEN 6 3 0",
+                "CALL 4 3 1",
+                "RETURN 4 1 1",
+            ]
+        );
+        assert_eq!(f.max_stack_size, 7);
+        assert_eq!(
+            f.line_info,
+            [1, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            loc_vars(&f),
+            [("a", 3, 19), ("b", 3, 19), ("c", 7, 19), ("t", 14, 19)]
+        );
+    }
+
+    #[test]
+    fn test_loops() {
+        let src = "local i = 0\nwhile i do\n  if i then break end\n  i = i + i\nend\nrepeat local j = i until j\nreturn i";
+        let f = compile(src.as_bytes(), "=t").unwrap();
+        assert_eq!(
+            listing(&f),
+            [
+                "VARARGPREP 0 0 0",
+                "LOADI 0 0",
+                "TEST 0 0 0",
+                "JMP 5",
+                "TEST 0 0 0k",
+                "JMP 3",
+                "ADD 0 0 0",
+                "MMBIN 0 0 6",
+                "JMP -7",
+                "MOVE 1 0 0",
+                "TEST 1 0 0",
+                "JMP -3",
+                "RETURN 0 2 1",
+                "RETURN 1 1 1",
+            ]
+        );
+        assert_eq!(f.line_info, [1, 0, 1, 0, 1, 0, 1, 0, 0, 2, 0, 0, 1, 0]);
+        assert_eq!(loc_vars(&f), [("i", 2, 14), ("j", 10, 12)]);
+    }
+
+    #[test]
+    fn test_closures() {
+        let src = "local x = 1\nlocal function f(a, ...)\n  return function() return x, a end\nend\nreturn f";
+        let main = compile(src.as_bytes(), "=t").unwrap();
+        assert_eq!(
+            listing(&main),
+            [
+                "VARARGPREP 0 0 0",
+                "LOADI 0 1",
+                "CLOSURE 1 0",
+                "RETURN 1 2 1k",
+                "RETURN 2 1 1k",
+            ]
+        );
+        assert_eq!(loc_vars(&main), [("x", 2, 5), ("f", 3, 5)]);
+
+        let f = &main.protos[0];
+        assert_eq!((f.line_defined, f.last_line_defined), (2, 4));
+        assert_eq!((f.num_params, f.is_vararg), (1, 1));
+        assert_eq!(
+            listing(f),
+            [
+                "VARARGPREP 1 0 0",
+                "CLOSURE 1 0",
+                "RETURN 1 2 2k",
+                "RETURN 1 1 2k"
+            ]
+        );
+        assert_eq!(f.upvalue_names, ["x"]);
+        assert_eq!((f.upvalues[0].instack, f.upvalues[0].idx), (1, 0));
+
+        let g = &f.protos[0];
+        assert_eq!(
+            listing(g),
+            [
+                "GETUPVAL 0 0 0",
+                "GETUPVAL 1 1 0",
+                "RETURN 0 3 0",
+                "RETURN0 0 1 0"
+            ]
+        );
+        assert_eq!(g.upvalue_names, ["x", "a"]);
+        let upvalues: Vec<_> = g.upvalues.iter().map(|u| (u.instack, u.idx)).collect();
+        assert_eq!(upvalues, [(0, 0), (1, 0)]);
+    }
+
+    #[test]
+    fn test_semantic_errors() {
+        assert_eq!(compile_err("break"), "t:1: break outside a loop at line 1");
+        assert_eq!(
+            compile_err("goto x"),
+            "t:1: no visible label 'x' for <goto> at line 1"
+        );
+        assert_eq!(
+            compile_err("::a:: ::a::"),
+            "t:1: label 'a' already defined on line 1"
+        );
+        assert_eq!(
+            compile_err("do goto l; local x = 1; ::l:: print(x) end"),
+            "t:1: <goto l> at line 1 jumps into the scope of local 'x'"
+        );
+        assert_eq!(
+            compile_err("local x <const> = 1\nfunction f() x = 2 end"),
+            "t:2: attempt to assign to const variable 'x'"
+        );
+        let many = format!("local {}", vec!["a"; 201].join(", "));
+        assert_eq!(
+            compile_err(&many),
+            "t:1: too many local variables (limit is 200) in main function"
+        );
+        // a label at the end of a block is outside the scope of its locals
+        assert!(compile(b"do goto l; local x ::l:: end", "=t").is_ok());
+    }
+}
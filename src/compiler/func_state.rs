@@ -0,0 +1,1157 @@
+use std::collections::HashMap;
+
+use crate::{
+    binary::chunk::{AbsLineInfo, Constant, Prototype},
+    vm::{instruction::*, opcodes::*},
+};
+
+use super::{
+    ast::{BinOp, UnOp},
+    lexer::{Position, SyntaxError},
+};
+
+/// 空跳转链表的标记
+pub const NO_JUMP: isize = -1;
+/// `TESTSET` 中表示“不需要寄存器”的值
+const NO_REG: usize = MAXARG_A as usize;
+/// 函数可使用的最大寄存器数量
+const MAX_REGS: usize = 255;
+/// RK 操作数能引用的最大常量索引
+pub const MAX_INDEX_RK: usize = MAXARG_B as usize;
+/// 短字符串的最大长度，只有短字符串常量能用作 `GETFIELD` 等指令的键
+pub const MAX_SHORT_LEN: usize = 40;
+/// 相对行号能表示的最大差值
+pub const LIM_LINE_DIFF: isize = 0x80;
+/// 两条绝对行号之间最多能有多少条相对行号
+pub const MAX_IWTHABS: u8 = 128;
+/// `line_info` 中表示“见 `abs_line_info`”的标记
+pub const ABS_LINE_INFO: i8 = -0x80;
+/// 每条 `SETLIST` 指令最多写入的元素数量
+pub const LFIELDS_PER_FLUSH: usize = 50;
+/// 表示“所有返回值”的结果数量
+pub const LUA_MULTRET: isize = -1;
+/// 与 `ltm.h` 中元方法的顺序一致，算术运算从 `__add` 开始依次排列
+pub const TM_ADD: u8 = 6;
+
+/// 表达式的描述，对应 `lparser.h` 中的 `expkind`。
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExpKind {
+    /// 空表达式（表达式列表为空时使用）
+    Void,
+    Nil,
+    True,
+    False,
+    /// 常量表中的常量
+    K(usize),
+    KFlt(f64),
+    KInt(i64),
+    KStr(Vec<u8>),
+    /// 值已位于固定寄存器中
+    NonReloc(usize),
+    /// 局部变量：寄存器位置与在活动变量中的索引
+    Local {
+        ridx: usize,
+        vidx: usize,
+    },
+    Upval(usize),
+    /// `t[k]`，表与键都在寄存器中
+    Indexed {
+        t: usize,
+        idx: usize,
+    },
+    /// 上值表以短字符串常量为键的索引
+    IndexUp {
+        t: usize,
+        idx: usize,
+    },
+    /// 寄存器中的表以短字符串常量为键的索引
+    IndexStr {
+        t: usize,
+        idx: usize,
+    },
+    /// 比较或测试，值为对应跳转指令的位置
+    Jmp(usize),
+    /// 结果可放入任意寄存器的指令位置
+    Reloc(usize),
+    Call(usize),
+    Vararg(usize),
+}
+
+/// 表达式及其为真、为假时的跳转链表。
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpDesc {
+    pub kind: ExpKind,
+    pub t: isize,
+    pub f: isize,
+}
+
+impl ExpDesc {
+    pub fn new(kind: ExpKind) -> Self {
+        ExpDesc {
+            kind,
+            t: NO_JUMP,
+            f: NO_JUMP,
+        }
+    }
+
+    pub fn has_jumps(&self) -> bool {
+        self.t != self.f
+    }
+
+    pub fn has_mult_ret(&self) -> bool {
+        matches!(self.kind, ExpKind::Call(_) | ExpKind::Vararg(_))
+    }
+
+    pub fn is_indexed(&self) -> bool {
+        matches!(
+            self.kind,
+            ExpKind::Indexed { .. } | ExpKind::IndexUp { .. } | ExpKind::IndexStr { .. }
+        )
+    }
+
+    /// 寄存器、指令位置或常量索引，对应 C 实现中的 `u.info`
+    pub fn info(&self) -> usize {
+        match self.kind {
+            ExpKind::K(i)
+            | ExpKind::NonReloc(i)
+            | ExpKind::Upval(i)
+            | ExpKind::Jmp(i)
+            | ExpKind::Reloc(i)
+            | ExpKind::Call(i)
+            | ExpKind::Vararg(i) => i,
+            ExpKind::Local { ridx, .. } => ridx,
+            _ => unreachable!("expression has no info: {:?}", self.kind),
+        }
+    }
+}
+
+/// 常量表去重时使用的键：浮点数按位比较，避免 `NaN` 与 `-0.0` 的问题
+#[derive(PartialEq, Eq, Hash)]
+enum ConstKey {
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    Number(u64),
+    Str(Vec<u8>),
+}
+
+/// 单个函数的代码生成状态，对应 `lcode.c` 中对 `FuncState` 的操作。
+pub struct FuncState {
+    pub f: Prototype,
+    chunk_name: String,
+    /// 最近读入的记号所在的行，新指令使用这个行号
+    pub line: usize,
+    /// 报告语义错误时使用的位置
+    pub pos: Position,
+    /// 第一个空闲寄存器
+    pub free_reg: usize,
+    /// 活动局部变量的数量
+    pub nactvar: usize,
+    /// 函数返回前是否需要关闭上值
+    pub need_close: bool,
+    /// 最后一个跳转目标的位置
+    last_target: usize,
+    previous_line: usize,
+    /// 自上一条绝对行号后已写入的相对行号数量
+    iwthabs: u8,
+    cache: HashMap<ConstKey, usize>,
+}
+
+impl FuncState {
+    pub fn new(chunk_name: &str, line_defined: usize) -> Self {
+        FuncState {
+            f: Prototype {
+                source: Some(chunk_name.to_string()),
+                line_defined,
+                max_stack_size: 2,
+                ..Default::default()
+            },
+            chunk_name: chunk_name.to_string(),
+            line: line_defined,
+            pos: Position::default(),
+            free_reg: 0,
+            nactvar: 0,
+            need_close: false,
+            last_target: 0,
+            previous_line: line_defined,
+            iwthabs: 0,
+            cache: HashMap::new(),
+        }
+    }
+
+    pub fn pc(&self) -> usize {
+        self.f.code.len()
+    }
+
+    /// 生成语义错误，不附带 `near` 部分
+    pub fn error(&self, msg: &str) -> SyntaxError {
+        SyntaxError {
+            chunk_name: self.chunk_name.clone(),
+            position: self.pos,
+            message: msg.to_string(),
+            near: None,
+        }
+    }
+
+    /// 超出实现限制时的错误，格式与 `errorlimit` 相同
+    pub fn check_limit(&self, v: usize, limit: usize, what: &str) -> Result<(), SyntaxError> {
+        if v <= limit {
+            return Ok(());
+        }
+        let place = match self.f.line_defined {
+            0 => "main function".to_string(),
+            line => format!("function at line {line}"),
+        };
+        Err(self.error(&format!("too many {what} (limit is {limit}) in {place}")))
+    }
+
+    /* 指令生成 */
+
+    pub fn code(&mut self, i: u32) -> usize {
+        self.f.code.push(i);
+        self.save_line_info(self.line);
+        self.pc() - 1
+    }
+
+    pub fn code_abck(&mut self, op: u8, a: usize, b: usize, c: usize, k: bool) -> usize {
+        self.code(create_abck(
+            op, a as isize, b as isize, c as isize, k as isize,
+        ))
+    }
+
+    pub fn code_abc(&mut self, op: u8, a: usize, b: usize, c: usize) -> usize {
+        self.code_abck(op, a, b, c, false)
+    }
+
+    pub fn code_abx(&mut self, op: u8, a: usize, bx: usize) -> usize {
+        self.code(create_abx(op, a as isize, bx as isize))
+    }
+
+    fn code_asbx(&mut self, op: u8, a: usize, sbx: isize) -> usize {
+        self.code(create_abx(op, a as isize, sbx + OFFSET_SBX))
+    }
+
+    fn code_sj(&mut self, op: u8, sj: isize) -> usize {
+        self.code(create_sj(op, sj))
+    }
+
+    fn code_extra_arg(&mut self, a: usize) -> usize {
+        self.code(create_ax(OP_EXTRAARG, a as isize))
+    }
+
+    /// 加载常量，索引超出 Bx 范围时改用 `LOADKX`
+    fn code_k(&mut self, reg: usize, k: usize) -> usize {
+        if k <= MAXARG_BX as usize {
+            self.code_abx(OP_LOADK, reg, k)
+        } else {
+            let p = self.code_abx(OP_LOADKX, reg, 0);
+            self.code_extra_arg(k);
+            p
+        }
+    }
+
+    /* 行号信息 */
+
+    fn save_line_info(&mut self, line: usize) {
+        let mut line_diff = line as isize - self.previous_line as isize;
+        let pc = self.pc() - 1;
+        if line_diff.abs() >= LIM_LINE_DIFF || self.iwthabs >= MAX_IWTHABS {
+            self.f.abs_line_info.push(AbsLineInfo { pc, line });
+            line_diff = ABS_LINE_INFO as isize;
+            self.iwthabs = 1;
+        } else {
+            self.iwthabs += 1;
+        }
+        self.f.line_info.push(line_diff as i8);
+        self.previous_line = line;
+    }
+
+    fn remove_last_line_info(&mut self) {
+        let diff = self.f.line_info.pop().unwrap();
+        if diff != ABS_LINE_INFO {
+            self.previous_line = (self.previous_line as isize - diff as isize) as usize;
+            self.iwthabs -= 1;
+        } else {
+            self.f.abs_line_info.pop();
+            // force next line info to be absolute
+            self.iwthabs = MAX_IWTHABS + 1;
+        }
+    }
+
+    fn remove_last_instruction(&mut self) {
+        self.remove_last_line_info();
+        self.f.code.pop();
+    }
+
+    /// 把最后一条指令的行号改为 `line`
+    pub fn fix_line(&mut self, line: usize) {
+        self.remove_last_line_info();
+        self.save_line_info(line);
+    }
+
+    /* 寄存器分配 */
+
+    pub fn check_stack(&mut self, n: usize) -> Result<(), SyntaxError> {
+        let new_stack = self.free_reg + n;
+        if new_stack > self.f.max_stack_size as usize {
+            if new_stack >= MAX_REGS {
+                return Err(self.error("function or expression needs too many registers"));
+            }
+            self.f.max_stack_size = new_stack as u8;
+        }
+        Ok(())
+    }
+
+    pub fn reserve_regs(&mut self, n: usize) -> Result<(), SyntaxError> {
+        self.check_stack(n)?;
+        self.free_reg += n;
+        Ok(())
+    }
+
+    /// 释放临时寄存器，局部变量占用的寄存器不会被释放
+    fn free_register(&mut self, reg: usize) {
+        if reg >= self.nactvar {
+            self.free_reg -= 1;
+            debug_assert_eq!(reg, self.free_reg);
+        }
+    }
+
+    /// 按与分配相反的顺序释放两个寄存器
+    fn free_registers(&mut self, r1: usize, r2: usize) {
+        if r1 > r2 {
+            self.free_register(r1);
+            self.free_register(r2);
+        } else {
+            self.free_register(r2);
+            self.free_register(r1);
+        }
+    }
+
+    fn free_exp(&mut self, e: &ExpDesc) {
+        if let ExpKind::NonReloc(reg) = e.kind {
+            self.free_register(reg);
+        }
+    }
+
+    fn free_exps(&mut self, e1: &ExpDesc, e2: &ExpDesc) {
+        match (&e1.kind, &e2.kind) {
+            (ExpKind::NonReloc(r1), ExpKind::NonReloc(r2)) => self.free_registers(*r1, *r2),
+            _ => {
+                self.free_exp(e1);
+                self.free_exp(e2);
+            }
+        }
+    }
+
+    /* 常量表 */
+
+    fn add_k(&mut self, key: ConstKey, v: Constant) -> usize {
+        if let Some(&idx) = self.cache.get(&key) {
+            return idx;
+        }
+        let idx = self.f.constants.len();
+        self.f.constants.push(v);
+        self.cache.insert(key, idx);
+        idx
+    }
+
+    pub fn string_k(&mut self, s: &[u8]) -> usize {
+        let v = Constant::Str(s.to_vec());
+        self.add_k(ConstKey::Str(s.to_vec()), v)
+    }
+
+    fn int_k(&mut self, i: i64) -> usize {
+        self.add_k(ConstKey::Integer(i), Constant::Integer(i))
+    }
+
+    fn number_k(&mut self, n: f64) -> usize {
+        self.add_k(ConstKey::Number(n.to_bits()), Constant::Number(n))
+    }
+
+    fn bool_k(&mut self, b: bool) -> usize {
+        self.add_k(ConstKey::Boolean(b), Constant::Boolean(b))
+    }
+
+    fn nil_k(&mut self) -> usize {
+        self.add_k(ConstKey::Nil, Constant::Nil)
+    }
+
+    pub fn code_int(&mut self, reg: usize, i: i64) {
+        if fits_bx(i) {
+            self.code_asbx(OP_LOADI, reg, i as isize);
+        } else {
+            let k = self.int_k(i);
+            self.code_k(reg, k);
+        }
+    }
+
+    fn code_float(&mut self, reg: usize, n: f64) {
+        let i = n as i64;
+        if i as f64 == n && fits_bx(i) {
+            self.code_asbx(OP_LOADF, reg, i as isize);
+        } else {
+            let k = self.number_k(n);
+            self.code_k(reg, k);
+        }
+    }
+
+    /* 跳转链表 */
+
+    fn get_jump(&self, pc: usize) -> isize {
+        let offset = self.f.code[pc].get_arg_sj();
+        if offset == NO_JUMP {
+            NO_JUMP
+        } else {
+            pc as isize + 1 + offset
+        }
+    }
+
+    fn fix_jump(&mut self, pc: usize, dest: usize) -> Result<(), SyntaxError> {
+        let offset = dest as isize - (pc as isize + 1);
+        if !(-OFFSET_SJ..=MAXARG_SJ - OFFSET_SJ).contains(&offset) {
+            return Err(self.error("control structure too long"));
+        }
+        self.f.code[pc].set_arg_sj(offset);
+        Ok(())
+    }
+
+    /// 把跳转链表 `l2` 接到 `l1` 的末尾
+    pub fn concat(&mut self, l1: &mut isize, l2: isize) -> Result<(), SyntaxError> {
+        if l2 == NO_JUMP {
+            return Ok(());
+        }
+        if *l1 == NO_JUMP {
+            *l1 = l2;
+            return Ok(());
+        }
+        let mut list = *l1 as usize;
+        loop {
+            let next = self.get_jump(list);
+            if next == NO_JUMP {
+                break;
+            }
+            list = next as usize;
+        }
+        self.fix_jump(list, l2 as usize)
+    }
+
+    pub fn jump(&mut self) -> usize {
+        self.code_sj(OP_JMP, NO_JUMP)
+    }
+
+    pub fn ret(&mut self, first: usize, nret: isize) {
+        let op = match nret {
+            0 => OP_RETURN0,
+            1 => OP_RETURN1,
+            _ => OP_RETURN,
+        };
+        self.code_abc(op, first, (nret + 1) as usize, 0);
+    }
+
+    fn cond_jump(&mut self, op: u8, a: usize, b: usize, c: usize, k: bool) -> usize {
+        self.code_abck(op, a, b, c, k);
+        self.jump()
+    }
+
+    /// 标记当前位置为跳转目标并返回它
+    pub fn get_label(&mut self) -> usize {
+        self.last_target = self.pc();
+        self.pc()
+    }
+
+    /// 跳转指令的控制指令：条件跳转前的测试指令，否则是跳转本身
+    fn get_jump_control(&self, pc: usize) -> usize {
+        if pc >= 1 && OPCODES[self.f.code[pc - 1].opcode() as usize].t == 1 {
+            pc - 1
+        } else {
+            pc
+        }
+    }
+
+    /// 为 `TESTSET` 指定目标寄存器，不需要值时改为 `TEST`
+    fn patch_test_reg(&mut self, node: usize, reg: usize) -> bool {
+        let pc = self.get_jump_control(node);
+        let i = self.f.code[pc];
+        if i.opcode() != OP_TESTSET {
+            return false;
+        }
+        if reg != NO_REG && reg != i.get_arg_b() as usize {
+            self.f.code[pc].set_arg_a(reg as isize);
+        } else {
+            self.f.code[pc] = create_abck(OP_TEST, i.get_arg_b(), 0, 0, i.get_arg_k());
+        }
+        true
+    }
+
+    fn remove_values(&mut self, mut list: isize) {
+        while list != NO_JUMP {
+            self.patch_test_reg(list as usize, NO_REG);
+            list = self.get_jump(list as usize);
+        }
+    }
+
+    fn patch_list_aux(
+        &mut self,
+        mut list: isize,
+        vtarget: usize,
+        reg: usize,
+        dtarget: isize,
+    ) -> Result<(), SyntaxError> {
+        while list != NO_JUMP {
+            let next = self.get_jump(list as usize);
+            if self.patch_test_reg(list as usize, reg) {
+                self.fix_jump(list as usize, vtarget)?;
+            } else {
+                self.fix_jump(list as usize, dtarget as usize)?;
+            }
+            list = next;
+        }
+        Ok(())
+    }
+
+    pub fn patch_list(&mut self, list: isize, target: usize) -> Result<(), SyntaxError> {
+        self.patch_list_aux(list, target, NO_REG, target as isize)
+    }
+
+    pub fn patch_to_here(&mut self, list: isize) -> Result<(), SyntaxError> {
+        let here = self.get_label();
+        self.patch_list(list, here)
+    }
+
+    /// 循环指令的跳转使用无符号的 Bx，`back` 表示向后跳
+    pub fn fix_for_jump(&mut self, pc: usize, dest: usize, back: bool) -> Result<(), SyntaxError> {
+        let mut offset = dest as isize - (pc as isize + 1);
+        if back {
+            offset = -offset;
+        }
+        if offset > MAXARG_BX {
+            return Err(self.error("control structure too long"));
+        }
+        self.f.code[pc].set_arg_bx(offset);
+        Ok(())
+    }
+
+    /* 表达式求值 */
+
+    /// 上一条指令，若当前位置是跳转目标则返回 `None`
+    fn previous_instruction(&self) -> Option<usize> {
+        if self.pc() > self.last_target {
+            Some(self.pc() - 1)
+        } else {
+            None
+        }
+    }
+
+    /// 把寄存器 `from` 起的 `n` 个寄存器置为 nil，尽量与上一条 `LOADNIL` 合并
+    pub fn nil(&mut self, mut from: usize, n: usize) {
+        let mut l = from + n - 1;
+        if let Some(pc) = self.previous_instruction() {
+            let prev = self.f.code[pc];
+            if prev.opcode() == OP_LOADNIL {
+                let pfrom = prev.get_arg_a() as usize;
+                let pl = pfrom + prev.get_arg_b() as usize;
+                if (pfrom <= from && from <= pl + 1) || (from <= pfrom && pfrom <= l + 1) {
+                    from = from.min(pfrom);
+                    l = l.max(pl);
+                    self.f.code[pc].set_arg_a(from as isize);
+                    self.f.code[pc].set_arg_b((l - from) as isize);
+                    return;
+                }
+            }
+        }
+        self.code_abc(OP_LOADNIL, from, n - 1, 0);
+    }
+
+    /// 调整多返回值表达式的结果数量
+    pub fn set_returns(&mut self, e: &mut ExpDesc, nresults: isize) -> Result<(), SyntaxError> {
+        match e.kind {
+            ExpKind::Call(pc) => self.f.code[pc].set_arg_c(nresults + 1),
+            ExpKind::Vararg(pc) => {
+                self.f.code[pc].set_arg_c(nresults + 1);
+                self.f.code[pc].set_arg_a(self.free_reg as isize);
+                self.reserve_regs(1)?;
+            }
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    pub fn set_mult_ret(&mut self, e: &mut ExpDesc) -> Result<(), SyntaxError> {
+        self.set_returns(e, LUA_MULTRET)
+    }
+
+    /// 把多返回值表达式调整为只有一个结果
+    pub fn set_one_ret(&mut self, e: &mut ExpDesc) {
+        match e.kind {
+            ExpKind::Call(pc) => {
+                e.kind = ExpKind::NonReloc(self.f.code[pc].get_arg_a() as usize);
+            }
+            ExpKind::Vararg(pc) => {
+                self.f.code[pc].set_arg_c(2);
+                e.kind = ExpKind::Reloc(pc);
+            }
+            _ => {}
+        }
+    }
+
+    /// 变量与索引表达式转换为值
+    pub fn discharge_vars(&mut self, e: &mut ExpDesc) {
+        match e.kind {
+            ExpKind::Local { ridx, .. } => e.kind = ExpKind::NonReloc(ridx),
+            ExpKind::Upval(idx) => {
+                e.kind = ExpKind::Reloc(self.code_abc(OP_GETUPVAL, 0, idx, 0));
+            }
+            ExpKind::IndexUp { t, idx } => {
+                e.kind = ExpKind::Reloc(self.code_abc(OP_GETTABUP, 0, t, idx));
+            }
+            ExpKind::IndexStr { t, idx } => {
+                self.free_register(t);
+                e.kind = ExpKind::Reloc(self.code_abc(OP_GETFIELD, 0, t, idx));
+            }
+            ExpKind::Indexed { t, idx } => {
+                self.free_registers(t, idx);
+                e.kind = ExpKind::Reloc(self.code_abc(OP_GETTABLE, 0, t, idx));
+            }
+            ExpKind::Call(_) | ExpKind::Vararg(_) => self.set_one_ret(e),
+            _ => {}
+        }
+    }
+
+    fn discharge2reg(&mut self, e: &mut ExpDesc, reg: usize) {
+        self.discharge_vars(e);
+        match &e.kind {
+            ExpKind::Nil => self.nil(reg, 1),
+            ExpKind::False => {
+                self.code_abc(OP_LOADFALSE, reg, 0, 0);
+            }
+            ExpKind::True => {
+                self.code_abc(OP_LOADTRUE, reg, 0, 0);
+            }
+            ExpKind::KStr(s) => {
+                let k = self.string_k(s);
+                self.code_k(reg, k);
+            }
+            ExpKind::K(k) => {
+                self.code_k(reg, *k);
+            }
+            ExpKind::KFlt(n) => self.code_float(reg, *n),
+            ExpKind::KInt(i) => self.code_int(reg, *i),
+            ExpKind::Reloc(pc) => self.f.code[*pc].set_arg_a(reg as isize),
+            ExpKind::NonReloc(r) => {
+                if *r != reg {
+                    self.code_abc(OP_MOVE, reg, *r, 0);
+                }
+            }
+            _ => {
+                debug_assert!(matches!(e.kind, ExpKind::Jmp(_)));
+                return;
+            }
+        }
+        e.kind = ExpKind::NonReloc(reg);
+    }
+
+    fn discharge2anyreg(&mut self, e: &mut ExpDesc) -> Result<(), SyntaxError> {
+        if !matches!(e.kind, ExpKind::NonReloc(_)) {
+            self.reserve_regs(1)?;
+            self.discharge2reg(e, self.free_reg - 1);
+        }
+        Ok(())
+    }
+
+    fn code_load_bool(&mut self, a: usize, op: u8) -> usize {
+        self.get_label();
+        self.code_abc(op, a, 0, 0)
+    }
+
+    /// 跳转链表中是否有不产生值的跳转（即控制指令不是 `TESTSET`）
+    fn need_value(&self, mut list: isize) -> bool {
+        while list != NO_JUMP {
+            let i = self.f.code[self.get_jump_control(list as usize)];
+            if i.opcode() != OP_TESTSET {
+                return true;
+            }
+            list = self.get_jump(list as usize);
+        }
+        false
+    }
+
+    /// 把表达式（包括跳转链表）的值放入寄存器 `reg`
+    fn exp2reg(&mut self, e: &mut ExpDesc, reg: usize) -> Result<(), SyntaxError> {
+        self.discharge2reg(e, reg);
+        if let ExpKind::Jmp(pc) = e.kind {
+            self.concat(&mut e.t, pc as isize)?;
+        }
+        if e.has_jumps() {
+            let mut p_f = NO_JUMP;
+            let mut p_t = NO_JUMP;
+            if self.need_value(e.t) || self.need_value(e.f) {
+                let fj = match e.kind {
+                    ExpKind::Jmp(_) => NO_JUMP,
+                    _ => self.jump() as isize,
+                };
+                p_f = self.code_load_bool(reg, OP_LFALSESKIP) as isize;
+                p_t = self.code_load_bool(reg, OP_LOADTRUE) as isize;
+                self.patch_to_here(fj)?;
+            }
+            let end = self.get_label();
+            self.patch_list_aux(e.f, end, reg, p_f)?;
+            self.patch_list_aux(e.t, end, reg, p_t)?;
+        }
+        e.t = NO_JUMP;
+        e.f = NO_JUMP;
+        e.kind = ExpKind::NonReloc(reg);
+        Ok(())
+    }
+
+    pub fn exp2nextreg(&mut self, e: &mut ExpDesc) -> Result<(), SyntaxError> {
+        self.discharge_vars(e);
+        self.free_exp(e);
+        self.reserve_regs(1)?;
+        self.exp2reg(e, self.free_reg - 1)
+    }
+
+    pub fn exp2anyreg(&mut self, e: &mut ExpDesc) -> Result<usize, SyntaxError> {
+        self.discharge_vars(e);
+        if let ExpKind::NonReloc(reg) = e.kind {
+            if !e.has_jumps() {
+                return Ok(reg);
+            }
+            if reg >= self.nactvar {
+                self.exp2reg(e, reg)?;
+                return Ok(reg);
+            }
+        }
+        self.exp2nextreg(e)?;
+        Ok(e.info())
+    }
+
+    /// 值放入寄存器或保持为上值
+    pub fn exp2anyregup(&mut self, e: &mut ExpDesc) -> Result<(), SyntaxError> {
+        if !matches!(e.kind, ExpKind::Upval(_)) || e.has_jumps() {
+            self.exp2anyreg(e)?;
+        }
+        Ok(())
+    }
+
+    /// 值放入寄存器或保持为常量
+    pub fn exp2val(&mut self, e: &mut ExpDesc) -> Result<(), SyntaxError> {
+        if e.has_jumps() {
+            self.exp2anyreg(e)?;
+        } else {
+            self.discharge_vars(e);
+        }
+        Ok(())
+    }
+
+    /// 尝试把常量表达式转为常量表中的 K 操作数
+    fn exp2k(&mut self, e: &mut ExpDesc) -> bool {
+        if e.has_jumps() {
+            return false;
+        }
+        let info = match &e.kind {
+            ExpKind::True => self.bool_k(true),
+            ExpKind::False => self.bool_k(false),
+            ExpKind::Nil => self.nil_k(),
+            ExpKind::KInt(i) => self.int_k(*i),
+            ExpKind::KFlt(n) => self.number_k(*n),
+            ExpKind::KStr(s) => self.string_k(s),
+            ExpKind::K(k) => *k,
+            _ => return false,
+        };
+        if info <= MAX_INDEX_RK {
+            e.kind = ExpKind::K(info);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// 转为 RK 操作数，返回值表示结果是否为常量
+    fn exp2rk(&mut self, e: &mut ExpDesc) -> Result<bool, SyntaxError> {
+        if self.exp2k(e) {
+            Ok(true)
+        } else {
+            self.exp2anyreg(e)?;
+            Ok(false)
+        }
+    }
+
+    fn code_abrk(
+        &mut self,
+        op: u8,
+        a: usize,
+        b: usize,
+        ec: &mut ExpDesc,
+    ) -> Result<(), SyntaxError> {
+        let k = self.exp2rk(ec)?;
+        self.code_abck(op, a, b, ec.info(), k);
+        Ok(())
+    }
+
+    /// 把 `ex` 的值赋给变量 `var`
+    pub fn store_var(&mut self, var: &ExpDesc, ex: &mut ExpDesc) -> Result<(), SyntaxError> {
+        match var.kind {
+            ExpKind::Local { ridx, .. } => {
+                self.free_exp(ex);
+                return self.exp2reg(ex, ridx);
+            }
+            ExpKind::Upval(idx) => {
+                let e = self.exp2anyreg(ex)?;
+                self.code_abc(OP_SETUPVAL, e, idx, 0);
+            }
+            ExpKind::IndexUp { t, idx } => self.code_abrk(OP_SETTABUP, t, idx, ex)?,
+            ExpKind::IndexStr { t, idx } => self.code_abrk(OP_SETFIELD, t, idx, ex)?,
+            ExpKind::Indexed { t, idx } => self.code_abrk(OP_SETTABLE, t, idx, ex)?,
+            _ => unreachable!("invalid assignment target"),
+        }
+        self.free_exp(ex);
+        Ok(())
+    }
+
+    /// 方法调用 `e:key(...)` 的 `SELF` 指令
+    pub fn code_self(&mut self, e: &mut ExpDesc, key: &mut ExpDesc) -> Result<(), SyntaxError> {
+        let ereg = self.exp2anyreg(e)?;
+        self.free_exp(e);
+        let base = self.free_reg;
+        e.kind = ExpKind::NonReloc(base);
+        self.reserve_regs(2)?;
+        self.code_abrk(OP_SELF, base, ereg, key)?;
+        self.free_exp(key);
+        Ok(())
+    }
+
+    /* 条件跳转 */
+
+    fn negate_condition(&mut self, e: &ExpDesc) {
+        let pc = self.get_jump_control(e.info());
+        let k = self.f.code[pc].get_arg_k();
+        self.f.code[pc].set_arg_k(k ^ 1);
+    }
+
+    fn jump_on_cond(&mut self, e: &mut ExpDesc, cond: bool) -> Result<usize, SyntaxError> {
+        if let ExpKind::Reloc(pc) = e.kind {
+            let ie = self.f.code[pc];
+            if ie.opcode() == OP_NOT {
+                // 'not' is removed and the condition is inverted
+                self.remove_last_instruction();
+                return Ok(self.cond_jump(OP_TEST, ie.get_arg_b() as usize, 0, 0, !cond));
+            }
+        }
+        self.discharge2anyreg(e)?;
+        self.free_exp(e);
+        Ok(self.cond_jump(OP_TESTSET, NO_REG, e.info(), 0, cond))
+    }
+
+    /// 表达式为真时继续执行，为假时跳转
+    pub fn go_if_true(&mut self, e: &mut ExpDesc) -> Result<(), SyntaxError> {
+        self.discharge_vars(e);
+        let pc = match e.kind {
+            ExpKind::Jmp(pc) => {
+                self.negate_condition(e);
+                pc as isize
+            }
+            ExpKind::K(_)
+            | ExpKind::KFlt(_)
+            | ExpKind::KInt(_)
+            | ExpKind::KStr(_)
+            | ExpKind::True => NO_JUMP,
+            _ => self.jump_on_cond(e, false)? as isize,
+        };
+        self.concat(&mut e.f, pc)?;
+        self.patch_to_here(e.t)?;
+        e.t = NO_JUMP;
+        Ok(())
+    }
+
+    /// 表达式为假时继续执行，为真时跳转
+    pub fn go_if_false(&mut self, e: &mut ExpDesc) -> Result<(), SyntaxError> {
+        self.discharge_vars(e);
+        let pc = match e.kind {
+            ExpKind::Jmp(pc) => pc as isize,
+            ExpKind::Nil | ExpKind::False => NO_JUMP,
+            _ => self.jump_on_cond(e, true)? as isize,
+        };
+        self.concat(&mut e.t, pc)?;
+        self.patch_to_here(e.f)?;
+        e.f = NO_JUMP;
+        Ok(())
+    }
+
+    fn code_not(&mut self, e: &mut ExpDesc) -> Result<(), SyntaxError> {
+        match e.kind {
+            ExpKind::Nil | ExpKind::False => e.kind = ExpKind::True,
+            ExpKind::K(_)
+            | ExpKind::KFlt(_)
+            | ExpKind::KInt(_)
+            | ExpKind::KStr(_)
+            | ExpKind::True => e.kind = ExpKind::False,
+            ExpKind::Jmp(_) => self.negate_condition(e),
+            ExpKind::Reloc(_) | ExpKind::NonReloc(_) => {
+                self.discharge2anyreg(e)?;
+                self.free_exp(e);
+                e.kind = ExpKind::Reloc(self.code_abc(OP_NOT, 0, e.info(), 0));
+            }
+            _ => unreachable!(),
+        }
+        std::mem::swap(&mut e.f, &mut e.t);
+        self.remove_values(e.f);
+        self.remove_values(e.t);
+        Ok(())
+    }
+
+    /* 索引 */
+
+    /// 是否为可直接用作字段名的短字符串常量
+    fn is_kstr(&self, e: &ExpDesc) -> bool {
+        match e.kind {
+            ExpKind::K(k) if !e.has_jumps() && k <= MAXARG_B as usize => {
+                matches!(&self.f.constants[k], Constant::Str(s) if s.len() <= MAX_SHORT_LEN)
+            }
+            _ => false,
+        }
+    }
+
+    /// 生成 `t[k]` 的索引表达式
+    pub fn indexed(&mut self, t: &mut ExpDesc, k: &mut ExpDesc) -> Result<(), SyntaxError> {
+        if let ExpKind::KStr(s) = &k.kind {
+            k.kind = ExpKind::K(self.string_k(s));
+        }
+        if matches!(t.kind, ExpKind::Upval(_)) && !self.is_kstr(k) {
+            // upvalue indexed by non 'Kstr': put it in a register
+            self.exp2anyreg(t)?;
+        }
+        if let ExpKind::Upval(idx) = t.kind {
+            t.kind = ExpKind::IndexUp {
+                t: idx,
+                idx: k.info(),
+            };
+        } else {
+            let treg = match t.kind {
+                ExpKind::Local { ridx, .. } => ridx,
+                ExpKind::NonReloc(reg) => reg,
+                _ => unreachable!(),
+            };
+            if self.is_kstr(k) {
+                t.kind = ExpKind::IndexStr {
+                    t: treg,
+                    idx: k.info(),
+                };
+            } else {
+                let idx = self.exp2anyreg(k)?;
+                t.kind = ExpKind::Indexed { t: treg, idx };
+            }
+        }
+        Ok(())
+    }
+
+    /* 运算符 */
+
+    pub fn prefix(&mut self, op: UnOp, e: &mut ExpDesc, line: usize) -> Result<(), SyntaxError> {
+        self.discharge_vars(e);
+        let op = match op {
+            UnOp::Minus => OP_UNM,
+            UnOp::BNot => OP_BNOT,
+            UnOp::Len => OP_LEN,
+            UnOp::Not => return self.code_not(e),
+        };
+        let r = self.exp2anyreg(e)?;
+        self.free_exp(e);
+        e.kind = ExpKind::Reloc(self.code_abc(op, 0, r, 0));
+        self.fix_line(line);
+        Ok(())
+    }
+
+    /// 处理二元运算的第一个操作数，在读取第二个操作数之前调用
+    pub fn infix(&mut self, op: BinOp, v: &mut ExpDesc) -> Result<(), SyntaxError> {
+        self.discharge_vars(v);
+        match op {
+            BinOp::And => self.go_if_true(v),
+            BinOp::Or => self.go_if_false(v),
+            // operand must be on the stack
+            BinOp::Concat => self.exp2nextreg(v),
+            _ => self.exp2anyreg(v).map(|_| ()),
+        }
+    }
+
+    /// 在两个操作数都已读取后完成二元运算，结果保存在 `e1` 中
+    pub fn posfix(
+        &mut self,
+        op: BinOp,
+        e1: &mut ExpDesc,
+        mut e2: ExpDesc,
+        line: usize,
+    ) -> Result<(), SyntaxError> {
+        self.discharge_vars(&mut e2);
+        match op {
+            BinOp::And => {
+                self.concat(&mut e2.f, e1.f)?;
+                *e1 = e2;
+            }
+            BinOp::Or => {
+                self.concat(&mut e2.t, e1.t)?;
+                *e1 = e2;
+            }
+            BinOp::Concat => {
+                self.exp2nextreg(&mut e2)?;
+                self.code_concat(e1, &e2, line);
+            }
+            BinOp::Eq | BinOp::Ne => self.code_eq(op == BinOp::Eq, e1, &mut e2)?,
+            BinOp::Lt => self.code_order(OP_LT, e1, &mut e2)?,
+            BinOp::Le => self.code_order(OP_LE, e1, &mut e2)?,
+            BinOp::Gt | BinOp::Ge => {
+                // '(a > b)' <=> '(b < a)';  '(a >= b)' <=> '(b <= a)'
+                std::mem::swap(e1, &mut e2);
+                let op = if op == BinOp::Gt { OP_LT } else { OP_LE };
+                self.code_order(op, e1, &mut e2)?;
+            }
+            _ => self.code_bin_expval(arith_opcode(op), e1, &mut e2, line)?,
+        }
+        Ok(())
+    }
+
+    /// 两个操作数都在寄存器中的算术与位运算，后跟用于元方法的 `MMBIN`
+    fn code_bin_expval(
+        &mut self,
+        op: u8,
+        e1: &mut ExpDesc,
+        e2: &mut ExpDesc,
+        line: usize,
+    ) -> Result<(), SyntaxError> {
+        let v2 = self.exp2anyreg(e2)?;
+        let v1 = self.exp2anyreg(e1)?;
+        let pc = self.code_abc(op, 0, v1, v2);
+        self.free_exps(e1, e2);
+        e1.kind = ExpKind::Reloc(pc);
+        self.fix_line(line);
+        self.code_abc(OP_MMBIN, v1, v2, (op - OP_ADD + TM_ADD) as usize);
+        self.fix_line(line);
+        Ok(())
+    }
+
+    fn code_order(
+        &mut self,
+        op: u8,
+        e1: &mut ExpDesc,
+        e2: &mut ExpDesc,
+    ) -> Result<(), SyntaxError> {
+        let r1 = self.exp2anyreg(e1)?;
+        let r2 = self.exp2anyreg(e2)?;
+        self.free_exps(e1, e2);
+        e1.kind = ExpKind::Jmp(self.cond_jump(op, r1, r2, 0, true));
+        Ok(())
+    }
+
+    fn code_eq(&mut self, eq: bool, e1: &mut ExpDesc, e2: &mut ExpDesc) -> Result<(), SyntaxError> {
+        let r1 = self.exp2anyreg(e1)?;
+        let r2 = self.exp2anyreg(e2)?;
+        self.free_exps(e1, e2);
+        e1.kind = ExpKind::Jmp(self.cond_jump(OP_EQ, r1, r2, 0, eq));
+        Ok(())
+    }
+
+    /// 连续的连接运算合并为一条 `CONCAT` 指令
+    fn code_concat(&mut self, e1: &mut ExpDesc, e2: &ExpDesc, line: usize) {
+        if let Some(pc) = self.previous_instruction() {
+            let ie2 = self.f.code[pc];
+            if ie2.opcode() == OP_CONCAT {
+                let n = ie2.get_arg_b();
+                self.free_exp(e2);
+                self.f.code[pc].set_arg_a(e1.info() as isize);
+                self.f.code[pc].set_arg_b(n + 1);
+                return;
+            }
+        }
+        self.code_abc(OP_CONCAT, e1.info(), 2, 0);
+        self.free_exp(e2);
+        self.fix_line(line);
+    }
+
+    /* 表构造 */
+
+    /// 回填 `NEWTABLE` 及其 `EXTRAARG` 中的数组与哈希大小
+    pub fn set_table_size(&mut self, pc: usize, ra: usize, asize: usize, hsize: usize) {
+        let rb = if hsize != 0 { ceil_log2(hsize) + 1 } else { 0 };
+        let extra = asize / (MAXARG_C as usize + 1);
+        let rc = asize % (MAXARG_C as usize + 1);
+        let k = extra > 0;
+        self.f.code[pc] = create_abck(
+            OP_NEWTABLE,
+            ra as isize,
+            rb as isize,
+            rc as isize,
+            k as isize,
+        );
+        self.f.code[pc + 1] = create_ax(OP_EXTRAARG, extra as isize);
+    }
+
+    /// 把 `base` 之后的 `tostore` 个值写入表，`nelems` 为之前已写入的数量
+    pub fn set_list(&mut self, base: usize, nelems: usize, tostore: isize) {
+        let tostore = if tostore == LUA_MULTRET {
+            0
+        } else {
+            tostore as usize
+        };
+        if nelems <= MAXARG_C as usize {
+            self.code_abc(OP_SETLIST, base, tostore, nelems);
+        } else {
+            let extra = nelems / (MAXARG_C as usize + 1);
+            let nelems = nelems % (MAXARG_C as usize + 1);
+            self.code_abck(OP_SETLIST, base, tostore, nelems, true);
+            self.code_extra_arg(extra);
+        }
+        self.free_reg = base + 1;
+    }
+
+    /* 收尾 */
+
+    /// 函数生成结束后的最终调整：需要关闭上值或有可变参数时，返回指令要带上额外信息
+    pub fn finish(&mut self) {
+        let vararg = self.f.is_vararg != 0;
+        for pc in 0..self.pc() {
+            let op = self.f.code[pc].opcode();
+            match op {
+                OP_RETURN0 | OP_RETURN1 if self.need_close || vararg => {
+                    self.f.code[pc].set_opcode(OP_RETURN);
+                    self.fix_return(pc, vararg);
+                }
+                OP_RETURN | OP_TAILCALL => self.fix_return(pc, vararg),
+                _ => {}
+            }
+        }
+    }
+
+    fn fix_return(&mut self, pc: usize, vararg: bool) {
+        if self.need_close {
+            self.f.code[pc].set_arg_k(1);
+        }
+        if vararg {
+            self.f.code[pc].set_arg_c(self.f.num_params as isize + 1);
+        }
+    }
+}
+
+fn fits_bx(i: i64) -> bool {
+    -OFFSET_SBX as i64 <= i && i <= (MAXARG_BX - OFFSET_SBX) as i64
+}
+
+fn ceil_log2(x: usize) -> usize {
+    (x - 1).checked_ilog2().map_or(0, |l| l as usize + 1)
+}
+
+fn arith_opcode(op: BinOp) -> u8 {
+    match op {
+        BinOp::Add => OP_ADD,
+        BinOp::Sub => OP_SUB,
+        BinOp::Mul => OP_MUL,
+        BinOp::Mod => OP_MOD,
+        BinOp::Pow => OP_POW,
+        BinOp::Div => OP_DIV,
+        BinOp::IDiv => OP_IDIV,
+        BinOp::BAnd => OP_BAND,
+        BinOp::BOr => OP_BOR,
+        BinOp::BXor => OP_BXOR,
+        BinOp::Shl => OP_SHL,
+        BinOp::Shr => OP_SHR,
+        _ => unreachable!("{:?} is not an arithmetic operator", op),
+    }
+}
@@ -0,0 +1,982 @@
+use std::fmt;
+
+use crate::state::math::{str_to_float, str_to_integer};
+
+/// 源码中的位置。行号与列号均从 1 开始，`offset` 为字节偏移。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+    pub offset: usize,
+}
+
+/// 源码中的一段区间，`end` 指向区间之后的第一个字节。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+impl Span {
+    pub fn new(start: Position, end: Position) -> Self {
+        Span { start, end }
+    }
+
+    /// 返回覆盖 `self` 与 `other` 的区间。
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start,
+            end: other.end,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    /* 保留字 */
+    And,
+    Break,
+    Do,
+    Else,
+    ElseIf,
+    End,
+    False,
+    For,
+    Function,
+    Goto,
+    If,
+    In,
+    Local,
+    Nil,
+    Not,
+    Or,
+    Repeat,
+    Return,
+    Then,
+    True,
+    Until,
+    While,
+    /* 运算符与分隔符 */
+    Plus,        // +
+    Minus,       // -
+    Star,        // *
+    Slash,       // /
+    DoubleSlash, // //
+    Percent,     // %
+    Caret,       // ^
+    Hash,        // #
+    Ampersand,   // &
+    Tilde,       // ~
+    Pipe,        // |
+    ShiftLeft,   // <<
+    ShiftRight,  // >>
+    Concat,      // ..
+    Dots,        // ...
+    Eq,          // ==
+    Ne,          // ~=
+    Lt,          // <
+    Le,          // <=
+    Gt,          // >
+    Ge,          // >=
+    Assign,      // =
+    LParen,      // (
+    RParen,      // )
+    LBrace,      // {
+    RBrace,      // }
+    LBracket,    // [
+    RBracket,    // ]
+    DoubleColon, // ::
+    Semicolon,   // ;
+    Colon,       // :
+    Comma,       // ,
+    Dot,         // .
+    /* 字面量 */
+    Float(f64),
+    Integer(i64),
+    Name(String),
+    String(Vec<u8>),
+    Eof,
+}
+
+impl Token {
+    /// 返回保留字与符号在源码中的写法，其他记号返回 `None`。
+    pub fn fixed_text(&self) -> Option<&'static str> {
+        let s = match self {
+            Token::And => "and",
+            Token::Break => "break",
+            Token::Do => "do",
+            Token::Else => "else",
+            Token::ElseIf => "elseif",
+            Token::End => "end",
+            Token::False => "false",
+            Token::For => "for",
+            Token::Function => "function",
+            Token::Goto => "goto",
+            Token::If => "if",
+            Token::In => "in",
+            Token::Local => "local",
+            Token::Nil => "nil",
+            Token::Not => "not",
+            Token::Or => "or",
+            Token::Repeat => "repeat",
+            Token::Return => "return",
+            Token::Then => "then",
+            Token::True => "true",
+            Token::Until => "until",
+            Token::While => "while",
+            Token::Plus => "+",
+            Token::Minus => "-",
+            Token::Star => "*",
+            Token::Slash => "/",
+            Token::DoubleSlash => "//",
+            Token::Percent => "%",
+            Token::Caret => "^",
+            Token::Hash => "#",
+            Token::Ampersand => "&",
+            Token::Tilde => "~",
+            Token::Pipe => "|",
+            Token::ShiftLeft => "<<",
+            Token::ShiftRight => ">>",
+            Token::Concat => "..",
+            Token::Dots => "...",
+            Token::Eq => "==",
+            Token::Ne => "~=",
+            Token::Lt => "<",
+            Token::Le => "<=",
+            Token::Gt => ">",
+            Token::Ge => ">=",
+            Token::Assign => "=",
+            Token::LParen => "(",
+            Token::RParen => ")",
+            Token::LBrace => "{",
+            Token::RBrace => "}",
+            Token::LBracket => "[",
+            Token::RBracket => "]",
+            Token::DoubleColon => "::",
+            Token::Semicolon => ";",
+            Token::Colon => ":",
+            Token::Comma => ",",
+            Token::Dot => ".",
+            _ => return None,
+        };
+        Some(s)
+    }
+
+    fn reserved(name: &str) -> Option<Token> {
+        let t = match name {
+            "and" => Token::And,
+            "break" => Token::Break,
+            "do" => Token::Do,
+            "else" => Token::Else,
+            "elseif" => Token::ElseIf,
+            "end" => Token::End,
+            "false" => Token::False,
+            "for" => Token::For,
+            "function" => Token::Function,
+            "goto" => Token::Goto,
+            "if" => Token::If,
+            "in" => Token::In,
+            "local" => Token::Local,
+            "nil" => Token::Nil,
+            "not" => Token::Not,
+            "or" => Token::Or,
+            "repeat" => Token::Repeat,
+            "return" => Token::Return,
+            "then" => Token::Then,
+            "true" => Token::True,
+            "until" => Token::Until,
+            "while" => Token::While,
+            _ => return None,
+        };
+        Some(t)
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(s) = self.fixed_text() {
+            return write!(f, "{s}");
+        }
+        match self {
+            Token::Float(n) => write!(f, "{n:?}"),
+            Token::Integer(i) => write!(f, "{i}"),
+            Token::Name(s) => write!(f, "{s}"),
+            Token::String(s) => write!(f, "{:?}", String::from_utf8_lossy(s)),
+            _ => write!(f, "<eof>"),
+        }
+    }
+}
+
+/// 带有源码区间的记号。
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenInfo {
+    pub token: Token,
+    pub span: Span,
+}
+
+/// 词法或语法错误，`near` 为出错处的记号文本（已按 Lua 的格式加上引号）。
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxError {
+    pub chunk_name: String,
+    pub position: Position,
+    pub message: String,
+    pub near: Option<String>,
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let id = super::chunk_id(&self.chunk_name);
+        write!(f, "{}:{}: {}", id, self.position.line, self.message)?;
+        if let Some(near) = &self.near {
+            write!(f, " near {near}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Lua 5.4 词法分析器。
+pub struct Lexer<'a> {
+    chunk_name: String,
+    src: &'a [u8],
+    pos: usize,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(src: &'a [u8], chunk_name: &str) -> Self {
+        Lexer {
+            chunk_name: chunk_name.to_string(),
+            src,
+            pos: 0,
+            line: 1,
+            column: 1,
+        }
+    }
+
+    pub fn chunk_name(&self) -> &str {
+        &self.chunk_name
+    }
+
+    pub fn source(&self) -> &'a [u8] {
+        self.src
+    }
+
+    /// 返回当前读取位置。
+    pub fn position(&self) -> Position {
+        Position {
+            line: self.line,
+            column: self.column,
+            offset: self.pos,
+        }
+    }
+
+    /// 读取整个代码块，返回以 `Token::Eof` 结尾的记号序列。
+    pub fn tokenize(mut self) -> Result<Vec<TokenInfo>, SyntaxError> {
+        let mut tokens = Vec::new();
+        loop {
+            let t = self.next_token()?;
+            let eof = t.token == Token::Eof;
+            tokens.push(t);
+            if eof {
+                return Ok(tokens);
+            }
+        }
+    }
+
+    /// 读取下一个记号。
+    pub fn next_token(&mut self) -> Result<TokenInfo, SyntaxError> {
+        loop {
+            let start = self.position();
+            let c = match self.current() {
+                Some(c) => c,
+                None => return Ok(self.finish(Token::Eof, start)),
+            };
+            let token = match c {
+                b'\n' | b'\r' => {
+                    self.inc_line();
+                    continue;
+                }
+                b' ' | b'\t' | 0x0b | 0x0c => {
+                    self.advance();
+                    continue;
+                }
+                b'-' => {
+                    self.advance();
+                    if self.current() != Some(b'-') {
+                        Token::Minus
+                    } else {
+                        self.advance();
+                        self.skip_comment(start)?;
+                        continue;
+                    }
+                }
+                b'[' => {
+                    let sep = self.skip_sep();
+                    if sep >= 2 {
+                        Token::String(self.read_long_string(start, sep, false)?)
+                    } else if sep == 0 {
+                        return Err(self.error_before(start, "invalid long string delimiter"));
+                    } else {
+                        Token::LBracket
+                    }
+                }
+                b'=' => self.one_or_two(b'=', Token::Eq, Token::Assign),
+                b'<' => {
+                    self.advance();
+                    if self.check_next(b'=') {
+                        Token::Le
+                    } else if self.check_next(b'<') {
+                        Token::ShiftLeft
+                    } else {
+                        Token::Lt
+                    }
+                }
+                b'>' => {
+                    self.advance();
+                    if self.check_next(b'=') {
+                        Token::Ge
+                    } else if self.check_next(b'>') {
+                        Token::ShiftRight
+                    } else {
+                        Token::Gt
+                    }
+                }
+                b'/' => self.one_or_two(b'/', Token::DoubleSlash, Token::Slash),
+                b'~' => self.one_or_two(b'=', Token::Ne, Token::Tilde),
+                b':' => self.one_or_two(b':', Token::DoubleColon, Token::Colon),
+                b'"' | b'\'' => Token::String(self.read_string(start, c)?),
+                b'.' => {
+                    if self.peek(1) == Some(b'.') {
+                        self.advance();
+                        self.advance();
+                        if self.check_next(b'.') {
+                            Token::Dots
+                        } else {
+                            Token::Concat
+                        }
+                    } else if self.peek(1).is_some_and(|c| c.is_ascii_digit()) {
+                        self.read_numeral(start)?
+                    } else {
+                        self.advance();
+                        Token::Dot
+                    }
+                }
+                b'0'..=b'9' => self.read_numeral(start)?,
+                c if c.is_ascii_alphabetic() || c == b'_' => {
+                    while self
+                        .current()
+                        .is_some_and(|c| c.is_ascii_alphanumeric() || c == b'_')
+                    {
+                        self.advance();
+                    }
+                    let name = String::from_utf8_lossy(&self.src[start.offset..self.pos]);
+                    Token::reserved(&name).unwrap_or_else(|| Token::Name(name.into_owned()))
+                }
+                _ => {
+                    self.advance();
+                    match c {
+                        b'+' => Token::Plus,
+                        b'*' => Token::Star,
+                        b'%' => Token::Percent,
+                        b'^' => Token::Caret,
+                        b'#' => Token::Hash,
+                        b'&' => Token::Ampersand,
+                        b'|' => Token::Pipe,
+                        b'(' => Token::LParen,
+                        b')' => Token::RParen,
+                        b'{' => Token::LBrace,
+                        b'}' => Token::RBrace,
+                        b']' => Token::RBracket,
+                        b';' => Token::Semicolon,
+                        b',' => Token::Comma,
+                        _ => {
+                            let near = if c.is_ascii_graphic() || c == b' ' {
+                                format!("'{}'", c as char)
+                            } else {
+                                format!("'<\\{c}>'")
+                            };
+                            return Err(SyntaxError {
+                                chunk_name: self.chunk_name.clone(),
+                                position: start,
+                                message: "unexpected symbol".to_string(),
+                                near: Some(near),
+                            });
+                        }
+                    }
+                }
+            };
+            return Ok(self.finish(token, start));
+        }
+    }
+
+    fn finish(&self, token: Token, start: Position) -> TokenInfo {
+        TokenInfo {
+            token,
+            span: Span::new(start, self.position()),
+        }
+    }
+
+    fn current(&self) -> Option<u8> {
+        self.src.get(self.pos).copied()
+    }
+
+    fn peek(&self, n: usize) -> Option<u8> {
+        self.src.get(self.pos + n).copied()
+    }
+
+    fn advance(&mut self) {
+        self.pos += 1;
+        self.column += 1;
+    }
+
+    fn check_next(&mut self, c: u8) -> bool {
+        if self.current() == Some(c) {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn one_or_two(&mut self, second: u8, two: Token, one: Token) -> Token {
+        self.advance();
+        if self.check_next(second) {
+            two
+        } else {
+            one
+        }
+    }
+
+    fn is_newline(c: Option<u8>) -> bool {
+        c == Some(b'\n') || c == Some(b'\r')
+    }
+
+    /// 跳过一个换行符（`\n`、`\r`、`\n\r` 或 `\r\n`）并增加行号。
+    fn inc_line(&mut self) {
+        let old = self.current();
+        self.advance();
+        if Self::is_newline(self.current()) && self.current() != old {
+            self.advance();
+        }
+        self.line += 1;
+        self.column = 1;
+    }
+
+    fn error(&self, start: Position, msg: &str, with_text: bool) -> SyntaxError {
+        let near = if self.pos >= self.src.len() && !with_text {
+            Some("<eof>".to_string())
+        } else {
+            let end = (self.pos + 1).min(self.src.len());
+            Some(format!(
+                "'{}'",
+                String::from_utf8_lossy(&self.src[start.offset..end])
+            ))
+        };
+        SyntaxError {
+            chunk_name: self.chunk_name.clone(),
+            position: self.position(),
+            message: msg.to_string(),
+            near,
+        }
+    }
+
+    fn skip_comment(&mut self, start: Position) -> Result<(), SyntaxError> {
+        if self.current() == Some(b'[') {
+            let sep = self.skip_sep();
+            if sep >= 2 {
+                self.read_long_string(start, sep, true)?;
+                return Ok(());
+            }
+        }
+        while self.current().is_some() && !Self::is_newline(self.current()) {
+            self.advance();
+        }
+        Ok(())
+    }
+
+    /// 读取长括号 `[==[` 或 `]==]` 的分隔符。若格式正确返回等号个数加 2，
+    /// 单个括号返回 1，其他情况返回 0。
+    fn skip_sep(&mut self) -> usize {
+        let s = self.current();
+        let mut count = 0;
+        self.advance();
+        while self.current() == Some(b'=') {
+            self.advance();
+            count += 1;
+        }
+        if self.current() == s {
+            count + 2
+        } else if count == 0 {
+            1
+        } else {
+            0
+        }
+    }
+
+    fn read_long_string(
+        &mut self,
+        start: Position,
+        sep: usize,
+        is_comment: bool,
+    ) -> Result<Vec<u8>, SyntaxError> {
+        let mut buf = Vec::new();
+        self.advance(); // skip 2nd '['
+        if Self::is_newline(self.current()) {
+            self.inc_line(); // skip first newline
+        }
+        loop {
+            match self.current() {
+                None => {
+                    let msg = if is_comment {
+                        "unfinished long comment"
+                    } else {
+                        "unfinished long string"
+                    };
+                    return Err(self.error(start, msg, false));
+                }
+                Some(b']') => {
+                    let content_end = self.pos;
+                    if self.skip_sep() == sep {
+                        self.advance(); // skip 2nd ']'
+                        return Ok(buf);
+                    }
+                    buf.extend_from_slice(&self.src[content_end..self.pos]);
+                }
+                Some(b'\n') | Some(b'\r') => {
+                    buf.push(b'\n');
+                    self.inc_line();
+                }
+                Some(c) => {
+                    buf.push(c);
+                    self.advance();
+                }
+            }
+        }
+    }
+
+    fn read_string(&mut self, start: Position, del: u8) -> Result<Vec<u8>, SyntaxError> {
+        let mut buf = Vec::new();
+        self.advance(); // skip delimiter
+        loop {
+            match self.current() {
+                None => return Err(self.error(start, "unfinished string", false)),
+                Some(b'\n') | Some(b'\r') => {
+                    return Err(self.error_before(start, "unfinished string"))
+                }
+                Some(c) if c == del => {
+                    self.advance();
+                    return Ok(buf);
+                }
+                Some(b'\\') => {
+                    self.advance();
+                    self.read_escape(start, &mut buf)?;
+                }
+                Some(c) => {
+                    buf.push(c);
+                    self.advance();
+                }
+            }
+        }
+    }
+
+    /// 与 `error` 相同，但出错文本不包含当前字符。
+    fn error_before(&self, start: Position, msg: &str) -> SyntaxError {
+        SyntaxError {
+            chunk_name: self.chunk_name.clone(),
+            position: self.position(),
+            message: msg.to_string(),
+            near: Some(format!(
+                "'{}'",
+                String::from_utf8_lossy(&self.src[start.offset..self.pos])
+            )),
+        }
+    }
+
+    fn read_escape(&mut self, start: Position, buf: &mut Vec<u8>) -> Result<(), SyntaxError> {
+        let c = match self.current() {
+            None => return Ok(()), // will raise 'unfinished string' next
+            Some(c) => c,
+        };
+        let byte = match c {
+            b'a' => 0x07,
+            b'b' => 0x08,
+            b'f' => 0x0c,
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b't' => b'\t',
+            b'v' => 0x0b,
+            b'\\' | b'"' | b'\'' => c,
+            b'\n' | b'\r' => {
+                self.inc_line();
+                buf.push(b'\n');
+                return Ok(());
+            }
+            b'x' => {
+                self.advance();
+                let hi = self.read_hex_digit(start)?;
+                self.advance();
+                let lo = self.read_hex_digit(start)?;
+                (hi * 16 + lo) as u8
+            }
+            b'z' => {
+                self.advance();
+                while let Some(c) = self.current() {
+                    if Self::is_newline(Some(c)) {
+                        self.inc_line();
+                    } else if c.is_ascii_whitespace() || c == 0x0b {
+                        self.advance();
+                    } else {
+                        break;
+                    }
+                }
+                return Ok(());
+            }
+            b'u' => {
+                self.read_utf8_escape(start, buf)?;
+                return Ok(());
+            }
+            c if c.is_ascii_digit() => {
+                let mut r: u32 = 0;
+                let mut i = 0;
+                while i < 3 && self.current().is_some_and(|c| c.is_ascii_digit()) {
+                    r = r * 10 + (self.current().unwrap() - b'0') as u32;
+                    self.advance();
+                    i += 1;
+                }
+                if r > 0xff {
+                    return Err(self.escape_error(start, "decimal escape too large"));
+                }
+                buf.push(r as u8);
+                return Ok(());
+            }
+            _ => return Err(self.escape_error(start, "invalid escape sequence")),
+        };
+        buf.push(byte);
+        self.advance();
+        Ok(())
+    }
+
+    fn escape_error(&self, start: Position, msg: &str) -> SyntaxError {
+        if self.current().is_some() {
+            self.error(start, msg, true)
+        } else {
+            self.error_before(start, msg)
+        }
+    }
+
+    fn read_hex_digit(&mut self, start: Position) -> Result<u32, SyntaxError> {
+        match self.current().and_then(|c| (c as char).to_digit(16)) {
+            Some(d) => Ok(d),
+            None => Err(self.escape_error(start, "hexadecimal digit expected")),
+        }
+    }
+
+    fn read_utf8_escape(&mut self, start: Position, buf: &mut Vec<u8>) -> Result<(), SyntaxError> {
+        self.advance(); // skip 'u'
+        if self.current() != Some(b'{') {
+            return Err(self.escape_error(start, "missing '{' in \\u{xxxx}"));
+        }
+        self.advance();
+        let mut r = self.read_hex_digit(start)?;
+        self.advance();
+        while let Some(d) = self.current().and_then(|c| (c as char).to_digit(16)) {
+            if r > (0x7FFFFFFF >> 4) {
+                return Err(self.escape_error(start, "UTF-8 value too large"));
+            }
+            r = r * 16 + d;
+            self.advance();
+        }
+        if self.current() != Some(b'}') {
+            return Err(self.escape_error(start, "missing '}' in \\u{xxxx}"));
+        }
+        self.advance();
+        utf8_encode(r, buf);
+        Ok(())
+    }
+
+    fn read_numeral(&mut self, start: Position) -> Result<Token, SyntaxError> {
+        let mut expo = [b'E', b'e'];
+        if self.current() == Some(b'0') && matches!(self.peek(1), Some(b'x') | Some(b'X')) {
+            self.advance();
+            self.advance();
+            expo = [b'P', b'p'];
+        }
+        loop {
+            match self.current() {
+                Some(c) if expo.contains(&c) => {
+                    self.advance();
+                    if matches!(self.current(), Some(b'+') | Some(b'-')) {
+                        self.advance();
+                    }
+                }
+                Some(c) if c.is_ascii_hexdigit() || c == b'.' => self.advance(),
+                _ => break,
+            }
+        }
+        if self
+            .current()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == b'_')
+        {
+            self.advance(); // force an error
+        }
+        let text = String::from_utf8_lossy(&self.src[start.offset..self.pos]);
+        if let Some(i) = str_to_integer(&text) {
+            Ok(Token::Integer(i))
+        } else if let Some(n) = str_to_float(&text) {
+            Ok(Token::Float(n))
+        } else {
+            Err(self.error_before(start, "malformed number"))
+        }
+    }
+}
+
+/// 将码点按 Lua 的扩展 UTF-8 规则（最多 6 字节，支持到 0x7FFFFFFF）编码。
+pub fn utf8_encode(mut x: u32, buf: &mut Vec<u8>) {
+    if x < 0x80 {
+        buf.push(x as u8);
+        return;
+    }
+    let mut bytes = Vec::with_capacity(6);
+    let mut mfb = 0x3f; // maximum that fits in first byte
+    loop {
+        bytes.push(0x80 | (x & 0x3f) as u8);
+        x >>= 6;
+        mfb >>= 1;
+        if x <= mfb {
+            break;
+        }
+    }
+    bytes.push(((!mfb << 1) | x) as u8);
+    bytes.reverse();
+    buf.extend_from_slice(&bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(src: &str) -> Vec<Token> {
+        Lexer::new(src.as_bytes(), "=test")
+            .tokenize()
+            .unwrap()
+            .into_iter()
+            .map(|t| t.token)
+            .collect()
+    }
+
+    fn error(src: &str) -> String {
+        Lexer::new(src.as_bytes(), "=test")
+            .tokenize()
+            .unwrap_err()
+            .to_string()
+    }
+
+    fn string(s: &str) -> Token {
+        Token::String(s.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_symbols() {
+        assert_eq!(
+            tokens("+ - * / // % ^ # & ~ | << >> .. ... == ~= < <= > >= = ( ) { } [ ] :: ; : , ."),
+            vec![
+                Token::Plus,
+                Token::Minus,
+                Token::Star,
+                Token::Slash,
+                Token::DoubleSlash,
+                Token::Percent,
+                Token::Caret,
+                Token::Hash,
+                Token::Ampersand,
+                Token::Tilde,
+                Token::Pipe,
+                Token::ShiftLeft,
+                Token::ShiftRight,
+                Token::Concat,
+                Token::Dots,
+                Token::Eq,
+                Token::Ne,
+                Token::Lt,
+                Token::Le,
+                Token::Gt,
+                Token::Ge,
+                Token::Assign,
+                Token::LParen,
+                Token::RParen,
+                Token::LBrace,
+                Token::RBrace,
+                Token::LBracket,
+                Token::RBracket,
+                Token::DoubleColon,
+                Token::Semicolon,
+                Token::Colon,
+                Token::Comma,
+                Token::Dot,
+                Token::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_names_and_keywords() {
+        assert_eq!(
+            tokens("local function _x1 elseif goto"),
+            vec![
+                Token::Local,
+                Token::Function,
+                Token::Name("_x1".to_string()),
+                Token::ElseIf,
+                Token::Goto,
+                Token::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_numbers() {
+        assert_eq!(
+            tokens("3 345 0xff 0xBEBADA 3.0 3.125 314.16e-2 0.31416E1 34e1 .5"),
+            vec![
+                Token::Integer(3),
+                Token::Integer(345),
+                Token::Integer(0xff),
+                Token::Integer(0xBEBADA),
+                Token::Float(3.0),
+                Token::Float(3.125),
+                Token::Float(314.16e-2),
+                Token::Float(0.31416E1),
+                Token::Float(34e1),
+                Token::Float(0.5),
+                Token::Eof,
+            ]
+        );
+        assert_eq!(
+            tokens("0x0.1E 0xA23p-4 0X1.921FB54442D18P+1"),
+            vec![
+                Token::Float(0.1171875),
+                Token::Float(162.1875),
+                Token::Float(std::f64::consts::PI),
+                Token::Eof,
+            ]
+        );
+        // 十进制整数溢出时转换为浮点数，十六进制整数回绕
+        assert_eq!(
+            tokens("9223372036854775807 9223372036854775808 0xffffffffffffffff"),
+            vec![
+                Token::Integer(i64::MAX),
+                Token::Float(9223372036854775808.0),
+                Token::Integer(-1),
+                Token::Eof,
+            ]
+        );
+        assert_eq!(error("x = 3x"), "test:1: malformed number near '3x'");
+        assert_eq!(error("x = 1e"), "test:1: malformed number near '1e'");
+    }
+
+    #[test]
+    fn test_strings() {
+        assert_eq!(
+            tokens(r#"'a' "b" 'it''s' "\"q\"""#),
+            vec![
+                string("a"),
+                string("b"),
+                string("it"),
+                string("s"),
+                string("\"q\""),
+                Token::Eof
+            ]
+        );
+        assert_eq!(
+            tokens(r#""\a\b\f\n\r\t\v\\\'""#),
+            vec![string("\x07\x08\x0c\n\r\t\x0b\\'"), Token::Eof]
+        );
+        assert_eq!(
+            tokens(r#""\x41\65\066\0""#),
+            vec![string("AAB\0"), Token::Eof]
+        );
+        assert_eq!(
+            tokens("\"a\\z  \n   b\" \"c\\\nd\""),
+            vec![string("ab"), string("c\nd"), Token::Eof]
+        );
+        assert_eq!(
+            tokens(r#""\u{48}\u{4E2D}\u{10FFFF}\u{7FFFFFFF}""#),
+            vec![
+                Token::String(b"H\xE4\xB8\xAD\xF4\x8F\xBF\xBF\xFD\xBF\xBF\xBF\xBF\xBF".to_vec()),
+                Token::Eof
+            ]
+        );
+        assert_eq!(
+            tokens(r#""\xff\xfe""#),
+            vec![Token::String(vec![0xff, 0xfe]), Token::Eof]
+        );
+        assert_eq!(error("'abc"), "test:1: unfinished string near <eof>");
+        assert_eq!(error("'abc\nx'"), "test:1: unfinished string near ''abc'");
+        assert_eq!(
+            error(r#"'\q'"#),
+            r#"test:1: invalid escape sequence near ''\q'"#
+        );
+        assert_eq!(
+            error(r#"'\256'"#),
+            r#"test:1: decimal escape too large near ''\256''"#
+        );
+        assert_eq!(
+            error(r#"'\xg'"#),
+            r#"test:1: hexadecimal digit expected near ''\xg'"#
+        );
+        assert_eq!(
+            error(r#"'\u{80000000}'"#),
+            r#"test:1: UTF-8 value too large near ''\u{80000000'"#
+        );
+    }
+
+    #[test]
+    fn test_long_strings_and_comments() {
+        assert_eq!(
+            tokens("[[\nalo\n123\"]] [==[\n]]x]=]]==] --[[ long\ncomment ]] -- short\n1"),
+            vec![
+                string("alo\n123\""),
+                string("]]x]=]"),
+                Token::Integer(1),
+                Token::Eof
+            ]
+        );
+        assert_eq!(tokens("a\r\nb"), tokens("a\nb"));
+        assert_eq!(
+            error("x = [==[ abc"),
+            "test:1: unfinished long string near <eof>"
+        );
+        assert_eq!(
+            error("--[[ abc"),
+            "test:1: unfinished long comment near <eof>"
+        );
+        assert_eq!(
+            error("x = [= abc"),
+            "test:1: invalid long string delimiter near '[='"
+        );
+    }
+
+    #[test]
+    fn test_positions() {
+        let toks = Lexer::new(b"local x\n  = 'a\\\nb' + 1", "=test")
+            .tokenize()
+            .unwrap();
+        let starts: Vec<(usize, usize)> = toks
+            .iter()
+            .map(|t| (t.span.start.line, t.span.start.column))
+            .collect();
+        assert_eq!(
+            starts,
+            vec![(1, 1), (1, 7), (2, 3), (2, 5), (3, 4), (3, 6), (3, 7)]
+        );
+        assert_eq!(toks[1].span.end.column, 8);
+        assert_eq!(toks[3].span.end.line, 3);
+    }
+
+    #[test]
+    fn test_unexpected_symbol() {
+        assert_eq!(error("x = @"), "test:1: unexpected symbol near '@'");
+        assert_eq!(error("\n\n$"), "test:3: unexpected symbol near '$'");
+    }
+}
@@ -0,0 +1,226 @@
+use std::{fmt, rc::Rc};
+
+use crate::binary::{
+    self,
+    chunk::{Prototype, LUA_SIGNATURE},
+    UndumpError,
+};
+
+use self::lexer::{Position, SyntaxError};
+
+pub mod ast;
+pub mod codegen;
+pub mod func_state;
+pub mod lexer;
+pub mod optimizer;
+pub mod parser;
+
+const LUA_IDSIZE: usize = 60;
+
+/// 加载代码块时产生的错误。
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadError {
+    /// 代码块的类型不被 `mode` 允许
+    Mode { chunk_name: String, message: String },
+    /// 源代码中的词法、语法或语义错误
+    Syntax(SyntaxError),
+    /// 二进制代码块格式错误
+    Binary(UndumpError),
+}
+
+impl LoadError {
+    pub fn chunk_name(&self) -> &str {
+        match self {
+            LoadError::Mode { chunk_name, .. } => chunk_name,
+            LoadError::Syntax(e) => &e.chunk_name,
+            LoadError::Binary(e) => &e.chunk_name,
+        }
+    }
+
+    /// 出错的源码位置，只有文本代码块的错误才有。
+    pub fn position(&self) -> Option<Position> {
+        match self {
+            LoadError::Syntax(e) => Some(e.position),
+            _ => None,
+        }
+    }
+
+    /// 出错处的记号文本，格式与错误信息中 `near` 之后的部分相同。
+    pub fn near(&self) -> Option<&str> {
+        match self {
+            LoadError::Syntax(e) => e.near.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Mode { message, .. } => write!(f, "{message}"),
+            LoadError::Syntax(e) => write!(f, "{e}"),
+            LoadError::Binary(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<SyntaxError> for LoadError {
+    fn from(e: SyntaxError) -> Self {
+        LoadError::Syntax(e)
+    }
+}
+
+impl From<UndumpError> for LoadError {
+    fn from(e: UndumpError) -> Self {
+        LoadError::Binary(e)
+    }
+}
+
+/// 把 Lua 源代码编译为函数原型。
+pub fn compile(src: &[u8], chunk_name: &str) -> Result<Rc<Prototype>, SyntaxError> {
+    let block = parser::parse(src, chunk_name)?;
+    codegen::gen_proto(&block, chunk_name)
+}
+
+/// 以恢复模式检查 Lua 源代码：语法错误之后会跳到下一条语句继续分析，
+/// 返回找到的全部错误，没有错误时返回空列表。
+pub fn check(src: &[u8], chunk_name: &str) -> Vec<SyntaxError> {
+    match parser::parse_recover(src, chunk_name) {
+        Ok(block) => codegen::gen_proto(&block, chunk_name)
+            .err()
+            .into_iter()
+            .collect(),
+        Err(errors) => errors,
+    }
+}
+
+/// 加载文本或二进制代码块，对应 `lua_load`。
+///
+/// `mode` 可以是 "b"、"t" 或 "bt"，决定允许哪种代码块。
+pub fn load(chunk: Vec<u8>, chunk_name: &str, mode: &str) -> Result<Rc<Prototype>, LoadError> {
+    let is_binary = chunk.first() == Some(&LUA_SIGNATURE[0]);
+    let (kind, flag) = if is_binary {
+        ("binary", 'b')
+    } else {
+        ("text", 't')
+    };
+    if !mode.contains(flag) {
+        return Err(LoadError::Mode {
+            chunk_name: chunk_name.to_string(),
+            message: format!("attempt to load a {kind} chunk (mode is '{mode}')"),
+        });
+    }
+    if is_binary {
+        Ok(binary::undump(chunk, chunk_name)?)
+    } else {
+        Ok(compile(&chunk, chunk_name)?)
+    }
+}
+
+/// 将代码块名称转换为错误信息中使用的形式，规则与 Lua 的 `luaO_chunkid` 相同。
+pub fn chunk_id(source: &str) -> String {
+    const RETS: &str = "...";
+    if let Some(name) = source.strip_prefix('=') {
+        if source.len() <= LUA_IDSIZE {
+            name.to_string()
+        } else {
+            truncate(name, LUA_IDSIZE - 1).to_string()
+        }
+    } else if let Some(name) = source.strip_prefix('@') {
+        if source.len() <= LUA_IDSIZE {
+            name.to_string()
+        } else {
+            let keep = LUA_IDSIZE - RETS.len() - 1;
+            let mut start = name.len() - keep;
+            while !name.is_char_boundary(start) {
+                start += 1;
+            }
+            format!("{RETS}{}", &name[start..])
+        }
+    } else {
+        let max = LUA_IDSIZE - "[string \"...\"]".len() - 1;
+        let first_line = source.split('\n').next().unwrap_or("");
+        if source.len() < max && first_line.len() == source.len() {
+            format!("[string \"{source}\"]")
+        } else {
+            format!("[string \"{}{RETS}\"]", truncate(first_line, max))
+        }
+    }
+}
+
+fn truncate(s: &str, max: usize) -> &str {
+    let mut end = s.len().min(max);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_errors() {
+        let err = load(b"x = 1\ny = = 2".to_vec(), "@a.lua", "bt").unwrap_err();
+        assert!(matches!(err, LoadError::Syntax(_)));
+        assert_eq!(err.chunk_name(), "@a.lua");
+        let pos = err.position().unwrap();
+        assert_eq!((pos.line, pos.column), (2, 5));
+        assert_eq!(err.near(), Some("'='"));
+        assert_eq!(err.to_string(), "a.lua:2: unexpected symbol near '='");
+
+        let err = load(b"x = 1".to_vec(), "=t", "b").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "attempt to load a text chunk (mode is 'b')"
+        );
+        assert_eq!(err.chunk_name(), "=t");
+        assert_eq!(err.position(), None);
+
+        let err = load(b"\x1bLuaS".to_vec(), "=t", "bt").unwrap_err();
+        assert!(matches!(err, LoadError::Binary(_)));
+        assert_eq!(err.to_string(), "t: bad binary format (version mismatch)");
+        assert_eq!(err.near(), None);
+
+        assert!(load(b"return 1".to_vec(), "=t", "t").is_ok());
+    }
+
+    #[test]
+    fn test_check() {
+        assert!(check(b"local x = 1", "=t").is_empty());
+        let errors: Vec<_> = check(b"x = = 1\ny = }", "=t")
+            .iter()
+            .map(|e| e.to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "t:1: unexpected symbol near '='",
+                "t:2: unexpected symbol near '}'"
+            ]
+        );
+        // semantic errors are reported once the chunk parses
+        let errors = check(b"goto x", "=t");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].to_string(),
+            "t:1: no visible label 'x' for <goto> at line 1"
+        );
+    }
+
+    #[test]
+    fn test_chunk_id() {
+        assert_eq!(chunk_id("=stdin"), "stdin");
+        assert_eq!(chunk_id("@lua/sum.lua"), "lua/sum.lua");
+        assert_eq!(chunk_id("print(1)"), "[string \"print(1)\"]");
+        assert_eq!(chunk_id("x = 1\nprint(x)"), "[string \"x = 1...\"]");
+        let long = format!("@{}", "a".repeat(100));
+        assert_eq!(chunk_id(&long), format!("...{}", "a".repeat(56)));
+        let long = "b".repeat(100);
+        assert_eq!(
+            chunk_id(&long),
+            format!("[string \"{}...\"]", "b".repeat(45))
+        );
+    }
+}
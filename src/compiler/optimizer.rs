@@ -0,0 +1,1026 @@
+use std::rc::Rc;
+
+use crate::{
+    api::op::ArithOp,
+    binary::chunk::{AbsLineInfo, Constant, Prototype},
+    state::{arith_ops, lua_value::LuaValue},
+    vm::{instruction::*, opcodes::*},
+};
+
+use super::func_state::{
+    ABS_LINE_INFO, LIM_LINE_DIFF, MAX_INDEX_RK, MAX_IWTHABS, MAX_SHORT_LEN, TM_ADD,
+};
+
+/// 跳转链的最大长度，超过后不再继续追踪（可能是死循环）
+const MAX_JUMP_CHAIN: usize = 100;
+
+/// 优化函数原型及其所有子函数，返回优化后的原型。
+///
+/// 优化只改写字节码，不改变程序的行为：常量折叠、使用立即数与常量形式的指令、
+/// 合并跳转链、删除无用的赋值和执行不到的代码，同时维护 `line_info` 与 `loc_vars`。
+pub fn optimize(proto: Rc<Prototype>) -> Rc<Prototype> {
+    let mut f = Rc::unwrap_or_clone(proto);
+    f.protos = f.protos.drain(..).map(optimize).collect();
+    Optimizer::new(&mut f).run();
+    Rc::new(f)
+}
+
+/// 寄存器集合
+#[derive(Clone, Copy, Default, PartialEq)]
+struct RegSet([u64; 4]);
+
+impl RegSet {
+    fn insert(&mut self, r: usize) {
+        if r < 256 {
+            self.0[r >> 6] |= 1 << (r & 63);
+        }
+    }
+
+    /// 加入 `[from, to)` 中的所有寄存器
+    fn insert_range(&mut self, from: usize, to: usize) {
+        for r in from..to.min(256) {
+            self.insert(r);
+        }
+    }
+
+    fn contains(&self, r: usize) -> bool {
+        r < 256 && self.0[r >> 6] & (1 << (r & 63)) != 0
+    }
+
+    fn union(&self, other: &RegSet) -> RegSet {
+        let mut s = *self;
+        for (x, y) in s.0.iter_mut().zip(other.0) {
+            *x |= y;
+        }
+        s
+    }
+
+    fn difference(&self, other: &RegSet) -> RegSet {
+        let mut s = *self;
+        for (x, y) in s.0.iter_mut().zip(other.0) {
+            *x &= !y;
+        }
+        s
+    }
+
+    fn intersects(&self, other: &RegSet) -> bool {
+        self.0.iter().zip(other.0).any(|(x, y)| x & y != 0)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..256).filter(|&r| self.contains(r))
+    }
+}
+
+/// 一条指令对寄存器的影响
+#[derive(Default)]
+struct Effects {
+    /// 可能读取的寄存器
+    uses: RegSet,
+    /// 一定会被覆盖的寄存器
+    kills: RegSet,
+    /// 可能被改写的寄存器
+    clobbers: RegSet,
+}
+
+impl Effects {
+    fn def(&mut self, from: usize, to: usize) {
+        self.kills.insert_range(from, to);
+        self.clobbers.insert_range(from, to);
+    }
+}
+
+/// 分析指令读写的寄存器，无法确定时按最坏情况估计。
+fn effects(i: u32) -> Effects {
+    let (a, b, c) = (
+        i.get_arg_a() as usize,
+        i.get_arg_b() as usize,
+        i.get_arg_c() as usize,
+    );
+    let rk = i.get_arg_k() == 0;
+    let mut e = Effects::default();
+    match i.opcode() {
+        OP_LOADI | OP_LOADF | OP_LOADK | OP_LOADKX | OP_LOADFALSE | OP_LFALSESKIP | OP_LOADTRUE
+        | OP_GETUPVAL | OP_GETTABUP | OP_NEWTABLE | OP_CLOSURE => e.def(a, a + 1),
+        OP_LOADNIL => e.def(a, a + b + 1),
+        OP_MOVE
+        | OP_GETI
+        | OP_GETFIELD
+        | OP_ADDI..=OP_SHLI
+        | OP_UNM
+        | OP_BNOT
+        | OP_NOT
+        | OP_LEN => {
+            e.uses.insert(b);
+            e.def(a, a + 1);
+        }
+        OP_TESTSET => {
+            // R[A] is only written when the test fails
+            e.uses.insert(b);
+            e.clobbers.insert(a);
+        }
+        OP_GETTABLE | OP_ADD..=OP_SHR => {
+            e.uses.insert(b);
+            e.uses.insert(c);
+            e.def(a, a + 1);
+        }
+        OP_SELF => {
+            e.uses.insert(b);
+            if rk {
+                e.uses.insert(c);
+            }
+            e.def(a, a + 2);
+        }
+        OP_SETTABUP => {
+            if rk {
+                e.uses.insert(c);
+            }
+        }
+        OP_SETTABLE => {
+            e.uses.insert(a);
+            e.uses.insert(b);
+            if rk {
+                e.uses.insert(c);
+            }
+        }
+        OP_SETI | OP_SETFIELD => {
+            e.uses.insert(a);
+            if rk {
+                e.uses.insert(c);
+            }
+        }
+        OP_MMBIN | OP_EQ | OP_LT | OP_LE => {
+            e.uses.insert(a);
+            e.uses.insert(b);
+        }
+        OP_SETUPVAL | OP_MMBINI | OP_MMBINK | OP_TBC | OP_EQK..=OP_TEST | OP_RETURN1 => {
+            e.uses.insert(a)
+        }
+        OP_CONCAT => {
+            e.uses.insert_range(a, a + b);
+            e.def(a, a + 1);
+            e.clobbers.insert_range(a, a + b);
+        }
+        OP_CALL | OP_TAILCALL => {
+            e.uses.insert_range(a, if b != 0 { a + b } else { 256 });
+            if c != 0 {
+                e.kills.insert_range(a, a + c - 1);
+            }
+            e.clobbers.insert_range(a, 256);
+        }
+        OP_RETURN => e.uses.insert_range(a, if b != 0 { a + b - 1 } else { 256 }),
+        OP_SETLIST => e.uses.insert_range(a, if b != 0 { a + b + 1 } else { 256 }),
+        OP_FORPREP | OP_FORLOOP => {
+            e.uses.insert_range(a, a + 3);
+            e.clobbers.insert_range(a, a + 4);
+        }
+        OP_VARARG => {
+            if c != 0 {
+                e.kills.insert_range(a, a + c - 1);
+            }
+            e.clobbers.insert_range(a, 256);
+        }
+        OP_JMP | OP_CLOSE | OP_RETURN0 | OP_VARARGPREP | OP_EXTRAARG => {}
+        _ => {
+            // TFORPREP, TFORCALL, TFORLOOP and anything unknown
+            e.uses.insert_range(a, 256);
+            e.clobbers.insert_range(a, 256);
+        }
+    }
+    e
+}
+
+/// 执行后会跳过下一条指令的指令，它们后面的指令不能删除
+fn skips_next(i: u32) -> bool {
+    matches!(i.opcode(), OP_EQ..=OP_TESTSET | OP_LFALSESKIP)
+}
+
+/// 指令执行后可能到达的位置
+fn successors(code: &[u32], pc: usize) -> Vec<usize> {
+    let i = code[pc];
+    let next = pc as isize + 1;
+    let bx = i.get_arg_bx();
+    let targets = match i.opcode() {
+        OP_JMP => vec![next + i.get_arg_sj()],
+        OP_EQ..=OP_TESTSET => vec![next, next + 1],
+        OP_LFALSESKIP => vec![next + 1],
+        OP_FORPREP => vec![next, next + bx + 1],
+        OP_FORLOOP | OP_TFORLOOP => vec![next, next - bx],
+        OP_TFORPREP => vec![next + bx],
+        OP_RETURN | OP_RETURN0 | OP_RETURN1 => vec![],
+        _ => vec![next],
+    };
+    targets
+        .into_iter()
+        .filter(|&t| t >= 0 && (t as usize) < code.len())
+        .map(|t| t as usize)
+        .collect()
+}
+
+/// 取出数值常量，用于常量折叠
+fn numeral(v: Option<&Constant>) -> Option<LuaValue> {
+    match v? {
+        Constant::Integer(i) => Some(LuaValue::Integer(*i)),
+        Constant::Number(n) => Some(LuaValue::Number(*n)),
+        _ => None,
+    }
+}
+
+/// 能放进 sB/sC 字段的整数
+fn sc_int(v: Option<&Constant>) -> Option<isize> {
+    match v? {
+        Constant::Integer(i) if fits_sc(*i) => Some(*i as isize),
+        _ => None,
+    }
+}
+
+/// 值为整数且能放进 sB/sC 字段的数值，返回该整数以及它是否为浮点数
+fn sc_number(v: Option<&Constant>) -> Option<(isize, isize)> {
+    match v? {
+        Constant::Integer(i) if fits_sc(*i) => Some((*i as isize, 0)),
+        Constant::Number(n) => {
+            let i = *n as i64;
+            (i as f64 == *n && fits_sc(i)).then_some((i as isize, 1))
+        }
+        _ => None,
+    }
+}
+
+fn fits_sc(i: i64) -> bool {
+    (-OFFSET_SC as i64..=(MAXARG_C - OFFSET_SC) as i64).contains(&i)
+}
+
+fn fits_sbx(i: i64) -> bool {
+    (-OFFSET_SBX as i64..=(MAXARG_BX - OFFSET_SBX) as i64).contains(&i)
+}
+
+fn same_constant(x: &Constant, y: &Constant) -> bool {
+    match (x, y) {
+        (Constant::Nil, Constant::Nil) => true,
+        (Constant::Boolean(x), Constant::Boolean(y)) => x == y,
+        (Constant::Integer(x), Constant::Integer(y)) => x == y,
+        (Constant::Number(x), Constant::Number(y)) => x.to_bits() == y.to_bits(),
+        (Constant::Str(x), Constant::Str(y)) => x == y,
+        _ => false,
+    }
+}
+
+/// 计算常量表达式的值，与 `lcode.c` 中的 `constfolding` 一样，
+/// 会出错的运算（除以零、无法转换为整数的位运算）以及结果为 NaN 或 0.0 的运算都不折叠。
+fn const_fold(op: u8, v1: &LuaValue, v2: &LuaValue) -> Option<Constant> {
+    let valid = match ArithOp::from_u8(op) {
+        Some(
+            ArithOp::BAND
+            | ArithOp::BOR
+            | ArithOp::BXOR
+            | ArithOp::SHL
+            | ArithOp::SHR
+            | ArithOp::BNOT,
+        ) => v1.to_integer().is_some() && v2.to_integer().is_some(),
+        Some(ArithOp::DIV | ArithOp::IDIV | ArithOp::MOD) => v2.to_number() != Some(0.0),
+        _ => true,
+    };
+    if !valid {
+        return None;
+    }
+    match arith_ops::arith(v1, v2, op)? {
+        LuaValue::Integer(i) => Some(Constant::Integer(i)),
+        LuaValue::Number(n) if !n.is_nan() && n != 0.0 => Some(Constant::Number(n)),
+        _ => None,
+    }
+}
+
+struct Optimizer<'a> {
+    f: &'a mut Prototype,
+    deleted: Vec<bool>,
+    /// 被子函数捕获或标记为待关闭的寄存器，它们的值可能在别处被读写
+    captured: RegSet,
+}
+
+impl<'a> Optimizer<'a> {
+    fn new(f: &'a mut Prototype) -> Self {
+        let mut captured = RegSet::default();
+        for &i in &f.code {
+            match i.opcode() {
+                OP_CLOSURE => {
+                    if let Some(p) = f.protos.get(i.get_arg_bx() as usize) {
+                        for uv in p.upvalues.iter().filter(|uv| uv.instack != 0) {
+                            captured.insert(uv.idx as usize);
+                        }
+                    }
+                }
+                OP_TBC => captured.insert(i.get_arg_a() as usize),
+                _ => {}
+            }
+        }
+        Optimizer {
+            deleted: vec![false; f.code.len()],
+            f,
+            captured,
+        }
+    }
+
+    fn run(&mut self) {
+        if self.f.code.is_empty() {
+            return;
+        }
+        self.fold_constants();
+        self.thread_jumps();
+        self.remove_unreachable();
+        while self.remove_dead_stores() {}
+        self.remove_useless_jumps();
+        self.compact();
+    }
+
+    /* 常量折叠与指令改写 */
+
+    /// 基本块的入口：跳转目标以及不能从上一条指令顺序到达的位置
+    fn leaders(&self) -> Vec<bool> {
+        let code = &self.f.code;
+        let mut leaders = vec![false; code.len()];
+        leaders[0] = true;
+        for pc in 0..code.len() {
+            let succ = successors(code, pc);
+            for &s in &succ {
+                if s != pc + 1 {
+                    leaders[s] = true;
+                }
+            }
+            if pc + 1 < code.len() && !succ.contains(&(pc + 1)) {
+                leaders[pc + 1] = true;
+            }
+        }
+        leaders
+    }
+
+    /// 在每个基本块内追踪寄存器中的常量，据此改写指令。
+    fn fold_constants(&mut self) {
+        let leaders = self.leaders();
+        let mut known: Vec<Option<Constant>> = vec![None; 256];
+        for (pc, &leader) in leaders.iter().enumerate() {
+            if leader {
+                known.fill(None);
+            }
+            if self.deleted[pc] {
+                continue;
+            }
+            self.rewrite(pc, &known);
+
+            let i = self.f.code[pc];
+            let (a, b) = (i.get_arg_a() as usize, i.get_arg_b() as usize);
+            let value = match i.opcode() {
+                OP_LOADI => Some(Constant::Integer(i.get_arg_sbx() as i64)),
+                OP_LOADF => Some(Constant::Number(i.get_arg_sbx() as f64)),
+                OP_LOADK => self.f.constants.get(i.get_arg_bx() as usize).cloned(),
+                OP_LOADKX => self
+                    .f
+                    .code
+                    .get(pc + 1)
+                    .and_then(|x| self.f.constants.get(x.get_arg_ax() as usize))
+                    .cloned(),
+                OP_LOADFALSE => Some(Constant::Boolean(false)),
+                OP_LOADTRUE => Some(Constant::Boolean(true)),
+                OP_MOVE => known[b].clone(),
+                _ => None,
+            };
+            for r in effects(i).clobbers.iter() {
+                known[r] = None;
+            }
+            if i.opcode() == OP_LOADNIL {
+                known[a..=(a + b).min(255)].fill(Some(Constant::Nil));
+            } else if value.is_some() {
+                known[a] = value;
+            }
+            for r in self.captured.iter() {
+                known[r] = None;
+            }
+        }
+    }
+
+    fn rewrite(&mut self, pc: usize, known: &[Option<Constant>]) {
+        let i = self.f.code[pc];
+        let (a, b, c, k) = (i.get_arg_a(), i.get_arg_b(), i.get_arg_c(), i.get_arg_k());
+        let kb = known[b as usize].as_ref();
+        let kc = known[c as usize].as_ref();
+        match i.opcode() {
+            OP_LOADK => {
+                if let Some(v) = self.f.constants.get(i.get_arg_bx() as usize).cloned() {
+                    self.load_constant(pc, a, &v);
+                }
+            }
+            OP_ADD..=OP_SHR => self.rewrite_arith(pc, kb, kc),
+            OP_UNM | OP_BNOT => {
+                let op = if i.opcode() == OP_UNM {
+                    ArithOp::UNM
+                } else {
+                    ArithOp::BNOT
+                };
+                if let Some(v) = numeral(kb) {
+                    if let Some(res) = const_fold(op as u8, &v, &v) {
+                        self.load_constant(pc, a, &res);
+                    }
+                }
+            }
+            OP_NOT => {
+                if let Some(v) = kb {
+                    let truthy = !matches!(v, Constant::Nil | Constant::Boolean(false));
+                    let op = if truthy { OP_LOADFALSE } else { OP_LOADTRUE };
+                    self.f.code[pc] = create_abck(op, a, 0, 0, 0);
+                }
+            }
+            OP_EQ => {
+                let ka = known[a as usize].as_ref();
+                let (r, v) = match (ka, kb) {
+                    (_, Some(v)) => (a, v),
+                    (Some(v), None) => (b, v),
+                    _ => return,
+                };
+                if let Some((imm, isfloat)) = sc_number(Some(v)) {
+                    self.f.code[pc] = create_abck(OP_EQI, r, imm + OFFSET_SB, isfloat, k);
+                } else if let Some(idx) = self.rk(v) {
+                    self.f.code[pc] = create_abck(OP_EQK, r, idx, 0, k);
+                }
+            }
+            OP_LT | OP_LE => {
+                let ka = known[a as usize].as_ref();
+                let lt = i.opcode() == OP_LT;
+                if let Some((imm, isfloat)) = sc_number(kb) {
+                    let op = if lt { OP_LTI } else { OP_LEI };
+                    self.f.code[pc] = create_abck(op, a, imm + OFFSET_SB, isfloat, k);
+                } else if let Some((imm, isfloat)) = sc_number(ka) {
+                    let op = if lt { OP_GTI } else { OP_GEI };
+                    self.f.code[pc] = create_abck(op, b, imm + OFFSET_SB, isfloat, k);
+                }
+            }
+            OP_GETTABLE => match kc {
+                Some(Constant::Str(s)) if s.len() <= MAX_SHORT_LEN => {
+                    if let Some(idx) = self.rk(kc.unwrap()) {
+                        self.f.code[pc] = create_abck(OP_GETFIELD, a, b, idx, 0);
+                    }
+                }
+                Some(Constant::Integer(n)) if (0..=MAXARG_C as i64).contains(n) => {
+                    self.f.code[pc] = create_abck(OP_GETI, a, b, *n as isize, 0);
+                }
+                _ => {}
+            },
+            OP_SETTABLE => {
+                match kb {
+                    Some(Constant::Str(s)) if s.len() <= MAX_SHORT_LEN => {
+                        if let Some(idx) = self.rk(kb.unwrap()) {
+                            self.f.code[pc] = create_abck(OP_SETFIELD, a, idx, c, k);
+                        }
+                    }
+                    Some(Constant::Integer(n)) if (0..=MAXARG_B as i64).contains(n) => {
+                        self.f.code[pc] = create_abck(OP_SETI, a, *n as isize, c, k);
+                    }
+                    _ => {}
+                }
+                self.rewrite_rk(pc, kc);
+            }
+            OP_SETTABUP | OP_SETI | OP_SETFIELD => self.rewrite_rk(pc, kc),
+            OP_SELF => {
+                if let Some(Constant::Str(_)) = kc {
+                    self.rewrite_rk(pc, kc);
+                }
+            }
+            OP_RETURN if k == 0 && c == 0 && (b == 1 || b == 2) => {
+                let op = if b == 1 { OP_RETURN0 } else { OP_RETURN1 };
+                self.f.code[pc].set_opcode(op);
+            }
+            _ => {}
+        }
+    }
+
+    /// 改写 `R[A] := R[B] op R[C]` 及其后的 `MMBIN`。
+    fn rewrite_arith(&mut self, pc: usize, kb: Option<&Constant>, kc: Option<&Constant>) {
+        let i = self.f.code[pc];
+        let (op, a, b, c) = (i.opcode(), i.get_arg_a(), i.get_arg_b(), i.get_arg_c());
+        let mm = self
+            .f
+            .code
+            .get(pc + 1)
+            .filter(|m| m.opcode() == OP_MMBIN && m.get_arg_a() == b && m.get_arg_b() == c)
+            .map(|_| pc + 1);
+        let arith_op = op - OP_ADD;
+
+        if let (Some(v1), Some(v2)) = (numeral(kb), numeral(kc)) {
+            if let Some(res) = const_fold(arith_op, &v1, &v2) {
+                if self.load_constant(pc, a, &res) {
+                    if let Some(mm) = mm {
+                        self.deleted[mm] = true;
+                    }
+                    return;
+                }
+            }
+        }
+
+        let event = match mm {
+            Some(mm) => self.f.code[mm].get_arg_c(),
+            None => (arith_op + TM_ADD) as isize,
+        };
+        // (opcode, R[B], operand, flip, MMBIN opcode, MMBIN operand)
+        let mut new = None;
+        match op {
+            OP_ADD => {
+                if let Some(v) = sc_int(kc) {
+                    new = Some((OP_ADDI, b, v + OFFSET_SC, 0, OP_MMBINI, v + OFFSET_SC));
+                } else if let Some(v) = sc_int(kb) {
+                    new = Some((OP_ADDI, c, v + OFFSET_SC, 1, OP_MMBINI, v + OFFSET_SC));
+                }
+            }
+            OP_SUB => {
+                if let Some(v) = sc_int(kc).filter(|&v| fits_sc(-v as i64)) {
+                    new = Some((OP_ADDI, b, -v + OFFSET_SC, 0, OP_MMBINI, v + OFFSET_SC));
+                }
+            }
+            OP_SHR => {
+                if let Some(v) = sc_int(kc) {
+                    new = Some((OP_SHRI, b, v + OFFSET_SC, 0, OP_MMBINI, v + OFFSET_SC));
+                }
+            }
+            OP_SHL => {
+                if let Some(v) = sc_int(kb) {
+                    new = Some((OP_SHLI, c, v + OFFSET_SC, 1, OP_MMBINI, v + OFFSET_SC));
+                } else if let Some(v) = sc_int(kc).filter(|&v| fits_sc(-v as i64)) {
+                    new = Some((OP_SHRI, b, -v + OFFSET_SC, 0, OP_MMBINI, v + OFFSET_SC));
+                }
+            }
+            _ => {}
+        }
+        if new.is_none() && op <= OP_BXOR {
+            let bitwise = op >= OP_BAND;
+            let usable = |v: Option<&Constant>| match v {
+                Some(Constant::Integer(_)) => true,
+                Some(Constant::Number(_)) => !bitwise,
+                _ => false,
+            };
+            let commutative = matches!(op, OP_ADD | OP_MUL) || bitwise;
+            let kop = op - OP_ADD + OP_ADDK;
+            if usable(kc) {
+                if let Some(idx) = self.rk(kc.unwrap()) {
+                    new = Some((kop, b, idx, 0, OP_MMBINK, idx));
+                }
+            } else if commutative && usable(kb) {
+                if let Some(idx) = self.rk(kb.unwrap()) {
+                    new = Some((kop, c, idx, 1, OP_MMBINK, idx));
+                }
+            }
+        }
+        if let Some((op, r, v, flip, mm_op, mm_v)) = new {
+            self.f.code[pc] = create_abck(op, a, r, v, 0);
+            if let Some(mm) = mm {
+                self.f.code[mm] = create_abck(mm_op, r, mm_v, event, flip);
+            }
+        }
+    }
+
+    /// 把 RK(C) 操作数中的常量寄存器换成常量索引。
+    fn rewrite_rk(&mut self, pc: usize, kc: Option<&Constant>) {
+        if self.f.code[pc].get_arg_k() != 0 {
+            return;
+        }
+        if let Some(idx) = kc.and_then(|v| self.rk(v)) {
+            self.f.code[pc].set_arg_c(idx);
+            self.f.code[pc].set_arg_k(1);
+        }
+    }
+
+    /// 用加载常量的指令替换 `pc` 处的指令，返回是否成功。
+    fn load_constant(&mut self, pc: usize, a: isize, v: &Constant) -> bool {
+        let i = match v {
+            Constant::Integer(n) if fits_sbx(*n) => {
+                create_abx(OP_LOADI, a, *n as isize + OFFSET_SBX)
+            }
+            // -0.0 must stay a constant, LOADF would turn it into 0.0
+            Constant::Number(n)
+                if (*n as i64 as f64).to_bits() == n.to_bits() && fits_sbx(*n as i64) =>
+            {
+                create_abx(OP_LOADF, a, *n as isize + OFFSET_SBX)
+            }
+            _ => match self.find_k(v, MAXARG_BX as usize) {
+                Some(idx) => create_abx(OP_LOADK, a, idx as isize),
+                None => return false,
+            },
+        };
+        self.f.code[pc] = i;
+        true
+    }
+
+    /// 可用作 RK 操作数的常量索引
+    fn rk(&mut self, v: &Constant) -> Option<isize> {
+        self.find_k(v, MAX_INDEX_RK).map(|idx| idx as isize)
+    }
+
+    /// 查找或添加常量，索引超过 `limit` 时返回 `None`。
+    fn find_k(&mut self, v: &Constant, limit: usize) -> Option<usize> {
+        let constants = &mut self.f.constants;
+        let idx = match constants.iter().position(|x| same_constant(x, v)) {
+            Some(idx) => idx,
+            None => {
+                if constants.len() > limit {
+                    return None;
+                }
+                constants.push(v.clone());
+                constants.len() - 1
+            }
+        };
+        (idx <= limit).then_some(idx)
+    }
+
+    /* 跳转优化与删除无用代码 */
+
+    /// 让跳转到 `JMP` 的跳转直接跳到最终目标。
+    fn thread_jumps(&mut self) {
+        let code = &mut self.f.code;
+        for pc in 0..code.len() {
+            if code[pc].opcode() != OP_JMP {
+                continue;
+            }
+            let mut target = (pc as isize + 1 + code[pc].get_arg_sj()) as usize;
+            for _ in 0..MAX_JUMP_CHAIN {
+                if target >= code.len() || target == pc || code[target].opcode() != OP_JMP {
+                    break;
+                }
+                target = (target as isize + 1 + code[target].get_arg_sj()) as usize;
+            }
+            code[pc].set_arg_sj(target as isize - pc as isize - 1);
+        }
+    }
+
+    /// 删除执行不到的指令（最后一条指令除外）。
+    fn remove_unreachable(&mut self) {
+        let code = &self.f.code;
+        let mut reachable = vec![false; code.len()];
+        let mut stack = vec![0];
+        while let Some(pc) = stack.pop() {
+            if !reachable[pc] {
+                reachable[pc] = true;
+                stack.extend(successors(code, pc));
+            }
+        }
+        let last = code.len() - 1;
+        for (pc, r) in reachable.into_iter().enumerate() {
+            if !r && pc != last {
+                self.deleted[pc] = true;
+            }
+        }
+    }
+
+    /// 删除写入的值不会再被读取的加载指令，返回是否删除了指令。
+    fn remove_dead_stores(&mut self) -> bool {
+        let code = &self.f.code;
+        let n = code.len();
+        let effects: Vec<Effects> = (0..n)
+            .map(|pc| {
+                if self.deleted[pc] {
+                    Effects::default()
+                } else {
+                    effects(code[pc])
+                }
+            })
+            .collect();
+        let succ: Vec<Vec<usize>> = (0..n).map(|pc| successors(code, pc)).collect();
+        let mut live_in = vec![RegSet::default(); n];
+        let live_out = |live_in: &[RegSet], pc: usize| {
+            succ[pc]
+                .iter()
+                .fold(RegSet::default(), |s, &t| s.union(&live_in[t]))
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for pc in (0..n).rev() {
+                let e = &effects[pc];
+                let live = e.uses.union(&live_out(&live_in, pc).difference(&e.kills));
+                if live != live_in[pc] {
+                    live_in[pc] = live;
+                    changed = true;
+                }
+            }
+        }
+
+        let mut removed = false;
+        for pc in 0..n - 1 {
+            let e = &effects[pc];
+            let removable = matches!(
+                code[pc].opcode(),
+                OP_LOADI
+                    | OP_LOADF
+                    | OP_LOADK
+                    | OP_LOADFALSE
+                    | OP_LOADTRUE
+                    | OP_LOADNIL
+                    | OP_MOVE
+                    | OP_GETUPVAL
+            );
+            if self.deleted[pc] || !removable || (pc > 0 && skips_next(code[pc - 1])) {
+                continue;
+            }
+            if !e.kills.intersects(&live_out(&live_in, pc)) && !e.kills.intersects(&self.captured) {
+                self.deleted[pc] = true;
+                removed = true;
+            }
+        }
+        removed
+    }
+
+    /// 删除跳到下一条（未删除）指令的无条件跳转。
+    fn remove_useless_jumps(&mut self) {
+        let code = &self.f.code;
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for pc in 0..code.len() - 1 {
+                if self.deleted[pc]
+                    || code[pc].opcode() != OP_JMP
+                    || (pc > 0 && skips_next(code[pc - 1]))
+                {
+                    continue;
+                }
+                let target = pc as isize + 1 + code[pc].get_arg_sj();
+                if target > pc as isize && (pc + 1..target as usize).all(|p| self.deleted[p]) {
+                    self.deleted[pc] = true;
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    /// 真正删除标记的指令，并修正跳转偏移、行号信息和局部变量的作用范围。
+    fn compact(&mut self) {
+        if !self.deleted.iter().any(|&d| d) {
+            return;
+        }
+        let code = &self.f.code;
+        let n = code.len();
+        // map[pc] 为 pc 处（或其后第一条保留的）指令的新位置
+        let mut map = Vec::with_capacity(n + 1);
+        let mut kept = 0;
+        for pc in 0..n {
+            map.push(kept as isize);
+            if !self.deleted[pc] {
+                kept += 1;
+            }
+        }
+        map.push(kept as isize);
+
+        let lines = decode_lines(self.f);
+        let mut new_code = Vec::with_capacity(kept);
+        let mut new_lines = Vec::with_capacity(kept);
+        for pc in 0..n {
+            if self.deleted[pc] {
+                continue;
+            }
+            let mut i = code[pc];
+            let (npc, next) = (map[pc], pc as isize + 1);
+            let bx = i.get_arg_bx();
+            match i.opcode() {
+                OP_JMP => {
+                    let target = map[(next + i.get_arg_sj()) as usize];
+                    i.set_arg_sj(target - npc - 1);
+                }
+                OP_FORPREP => i.set_arg_bx(map[(next + bx + 1) as usize] - npc - 2),
+                OP_FORLOOP | OP_TFORLOOP => i.set_arg_bx(npc + 1 - map[(next - bx) as usize]),
+                OP_TFORPREP => i.set_arg_bx(map[(next + bx) as usize] - npc - 1),
+                _ => {}
+            }
+            new_code.push(i);
+            if let Some(lines) = &lines {
+                new_lines.push(lines[pc]);
+            }
+        }
+        self.f.code = new_code;
+        if lines.is_some() {
+            encode_lines(self.f, &new_lines);
+        }
+        for var in &mut self.f.loc_vars {
+            var.start_pc = map[var.start_pc.min(n)] as usize;
+            var.end_pc = map[var.end_pc.min(n)] as usize;
+        }
+        self.deleted = vec![false; kept];
+    }
+}
+
+/// 还原每条指令的绝对行号；没有调试信息时返回 `None`。
+fn decode_lines(f: &Prototype) -> Option<Vec<usize>> {
+    if f.line_info.len() != f.code.len() {
+        return None;
+    }
+    let mut abs = f.abs_line_info.iter();
+    let mut line = f.line_defined as isize;
+    let mut lines = Vec::with_capacity(f.code.len());
+    for (pc, &diff) in f.line_info.iter().enumerate() {
+        if diff == ABS_LINE_INFO {
+            line = abs.find(|x| x.pc == pc)?.line as isize;
+        } else {
+            line += diff as isize;
+        }
+        lines.push(line as usize);
+    }
+    Some(lines)
+}
+
+/// 按 `lcode.c` 中 `savelineinfo` 的规则重新编码行号。
+fn encode_lines(f: &mut Prototype, lines: &[usize]) {
+    f.line_info.clear();
+    f.abs_line_info.clear();
+    let mut previous = f.line_defined;
+    let mut iwthabs = 0;
+    for (pc, &line) in lines.iter().enumerate() {
+        let mut diff = line as isize - previous as isize;
+        if diff.abs() >= LIM_LINE_DIFF || iwthabs >= MAX_IWTHABS {
+            f.abs_line_info.push(AbsLineInfo { pc, line });
+            diff = ABS_LINE_INFO as isize;
+            iwthabs = 1;
+        } else {
+            iwthabs += 1;
+        }
+        f.line_info.push(diff as i8);
+        previous = line;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::opcodes::OpMode;
+
+    use super::super::compile;
+    use super::*;
+
+    fn listing(f: &Prototype) -> Vec<String> {
+        f.code
+            .iter()
+            .map(|&i| {
+                let name = &i.opname()["OP_".len()..];
+                match OPCODES[i.opcode() as usize].opmode {
+                    OpMode::IABC => {
+                        let k = if i.get_arg_k() != 0 { "k" } else { "" };
+                        format!(
+                            "{name} {} {} {}{k}",
+                            i.get_arg_a(),
+                            i.get_arg_b(),
+                            i.get_arg_c()
+                        )
+                    }
+                    OpMode::IABx => format!("{name} {} {}", i.get_arg_a(), i.get_arg_bx()),
+                    OpMode::IAsBx => format!("{name} {} {}", i.get_arg_a(), i.get_arg_sbx()),
+                    OpMode::IAx => format!("{name} {}", i.get_arg_ax()),
+                    OpMode::IsJ => format!("{name} {}", i.get_arg_sj()),
+                }
+            })
+            .collect()
+    }
+
+    fn optimized(src: &str) -> Rc<Prototype> {
+        optimize(compile(src.as_bytes(), "=t").unwrap())
+    }
+
+    fn loc_vars(f: &Prototype) -> Vec<(&str, usize, usize)> {
+        f.loc_vars
+            .iter()
+            .map(|v| (v.var_name.as_str(), v.start_pc, v.end_pc))
+            .collect()
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        let f = optimized(
+            "local a = 1 + 2 * 3
+            local b = 10 // 0
+            local c = 2 ^ -1
+            local d = -(-5)
+            local e = 1 << 64
+            local f = -0.0
+            return a, b, c, d, e, f",
+        );
+        assert_eq!(
+            listing(&f)[..10],
+            [
+                "VARARGPREP 0 0 0",
+                "LOADI 0 7",
+                // division by zero is left for run time
+                "LOADI 1 10",
+                "IDIVK 1 1 0",
+                "MMBINK 1 0 12",
+                "LOADK 2 1",
+                "LOADI 3 5",
+                "LOADI 4 0",
+                // -0.0 is not folded
+                "LOADF 5 0",
+                "UNM 5 5 0",
+            ]
+        );
+        assert!(matches!(
+            f.constants[..2],
+            [Constant::Integer(0), Constant::Number(x)] if x == 0.5
+        ));
+    }
+
+    #[test]
+    fn test_immediate_forms() {
+        let f = optimized(
+            "local a, t = ...
+            a = a + 1 a = a - 1 a = 1 + a a = a * 2 a = a >> 1 a = 1 << a
+            t[1] = t.x t.y = 1.5
+            if a == 3 then a = nil elseif a < 2 then a = 2 <= a end
+            return a",
+        );
+        assert_eq!(
+            listing(&f),
+            [
+                "VARARGPREP 0 0 0",
+                "VARARG 0 0 3",
+                "ADDI 0 0 128",
+                "MMBINI 0 128 6",
+                "ADDI 0 0 126",
+                "MMBINI 0 128 7",
+                "ADDI 0 0 128",
+                "MMBINI 0 128 6k",
+                "MULK 0 0 3",
+                "MMBINK 0 3 8",
+                "SHRI 0 0 128",
+                "MMBINI 0 128 17",
+                "SHLI 0 0 128",
+                "MMBINI 0 128 16k",
+                "GETFIELD 3 1 0",
+                "SETI 1 1 3",
+                "SETFIELD 1 1 2k",
+                "EQI 0 130 0",
+                "JMP 2",
+                "LOADNIL 0 0 0",
+                "JMP 6",
+                "LTI 0 129 0",
+                "JMP 4",
+                "GEI 0 129 0k",
+                "JMP 1",
+                "LFALSESKIP 0 0 0",
+                "LOADTRUE 0 0 0",
+                "RETURN 0 2 1",
+                "RETURN 2 1 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_jumps_and_dead_code() {
+        let f = optimized(
+            "local a, b = ...
+if a then
+    if b then
+        print(1)
+    end
+else
+    print(2)
+end
+do return end
+print(3)
+local c = 4
+",
+        );
+        assert_eq!(
+            listing(&f),
+            [
+                "VARARGPREP 0 0 0",
+                "VARARG 0 0 3",
+                "TEST 0 0 0",
+                "JMP 6",
+                "TEST 1 0 0",
+                // jumps straight to the return instead of the outer `else` jump
+                "JMP 7",
+                "GETTABUP 2 0 0",
+                "LOADI 3 1",
+                "CALL 2 2 1",
+                "JMP 3",
+                "GETTABUP 2 0 0",
+                "LOADI 3 2",
+                "CALL 2 2 1",
+                "RETURN 2 1 1",
+                "RETURN 3 1 1",
+            ]
+        );
+        assert_eq!(
+            decode_lines(&f).unwrap(),
+            [1, 1, 2, 2, 3, 3, 4, 4, 4, 5, 7, 7, 7, 9, 11]
+        );
+        assert_eq!(loc_vars(&f), [("a", 2, 15), ("b", 2, 15), ("c", 14, 15)]);
+
+        let f = optimized("local t = {} for i = 1, 3 do local x = i t[i] = x end return t");
+        assert_eq!(
+            listing(&f),
+            [
+                "VARARGPREP 0 0 0",
+                "NEWTABLE 0 0 0",
+                "EXTRAARG 0",
+                "LOADI 1 1",
+                "LOADI 2 3",
+                "LOADI 3 1",
+                "FORPREP 1 2",
+                "MOVE 5 4 0",
+                "SETTABLE 0 4 5",
+                "FORLOOP 1 3",
+                "RETURN 0 2 1",
+                "RETURN 1 1 1",
+            ]
+        );
+    }
+}
@@ -0,0 +1,1161 @@
+use super::ast::*;
+use super::lexer::{Lexer, Position, Span, SyntaxError, Token, TokenInfo};
+
+/// 语法嵌套的最大深度，对应 Lua 的 `LUAI_MAXCCALLS`。
+const MAX_LEVELS: usize = 200;
+
+/// 将整个代码块解析为语法树。
+pub fn parse(src: &[u8], chunk_name: &str) -> Result<Block, SyntaxError> {
+    let mut p = Parser::new(src, chunk_name)?;
+    p.main_func()
+}
+
+/// 以恢复模式解析代码块：语句出错后跳到下一条语句继续分析，
+/// 有错误时按出现顺序返回全部错误。词法错误无法恢复，总是最后一个错误。
+pub fn parse_recover(src: &[u8], chunk_name: &str) -> Result<Block, Vec<SyntaxError>> {
+    let mut p = Parser::new(src, chunk_name).map_err(|e| vec![e])?;
+    p.recover = true;
+    let result = p.main_func();
+    let mut errors = std::mem::take(&mut p.errors);
+    match result {
+        Ok(block) if errors.is_empty() => Ok(block),
+        Ok(_) => Err(errors),
+        Err(e) => {
+            errors.push(e);
+            Err(errors)
+        }
+    }
+}
+
+/// Lua 5.4 递归下降语法分析器，结构与 `lparser.c` 保持一致。
+pub struct Parser<'a> {
+    lexer: Lexer<'a>,
+    current: TokenInfo,
+    ahead: Option<TokenInfo>,
+    prev_end: Position,
+    /// 每层函数是否为可变参数函数
+    vararg: Vec<bool>,
+    level: usize,
+    /// 是否处于错误恢复模式
+    recover: bool,
+    /// 恢复模式下已经收集的错误
+    errors: Vec<SyntaxError>,
+    /// 词法分析器出过错，之后的记号不再可靠
+    lex_failed: bool,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(src: &'a [u8], chunk_name: &str) -> Result<Self, SyntaxError> {
+        let mut lexer = Lexer::new(src, chunk_name);
+        let current = lexer.next_token()?;
+        Ok(Parser {
+            lexer,
+            current,
+            ahead: None,
+            prev_end: Position::default(),
+            vararg: Vec::new(),
+            level: 0,
+            recover: false,
+            errors: Vec::new(),
+            lex_failed: false,
+        })
+    }
+
+    /// 解析主函数：主函数总是可变参数函数，且必须读到文件结尾。
+    pub fn main_func(&mut self) -> Result<Block, SyntaxError> {
+        self.vararg.push(true);
+        let block = self.block()?;
+        while self.recover && self.token() != &Token::Eof {
+            // an unmatched 'end' or similar; after an earlier error it is most
+            // likely left over from a statement we skipped, so don't report it
+            if self.errors.is_empty() {
+                self.errors.push(self.error_expected(&Token::Eof));
+            }
+            self.next()?;
+            self.block()?;
+        }
+        self.check(&Token::Eof)?;
+        self.vararg.pop();
+        Ok(block)
+    }
+
+    /* 记号操作 */
+
+    fn token(&self) -> &Token {
+        &self.current.token
+    }
+
+    fn next(&mut self) -> Result<(), SyntaxError> {
+        self.prev_end = self.current.span.end;
+        self.current = match self.ahead.take() {
+            Some(t) => t,
+            None => self.lex()?,
+        };
+        Ok(())
+    }
+
+    fn lookahead(&mut self) -> Result<&Token, SyntaxError> {
+        if self.ahead.is_none() {
+            self.ahead = Some(self.lex()?);
+        }
+        Ok(&self.ahead.as_ref().unwrap().token)
+    }
+
+    fn lex(&mut self) -> Result<TokenInfo, SyntaxError> {
+        let t = self.lexer.next_token();
+        self.lex_failed |= t.is_err();
+        t
+    }
+
+    fn test_next(&mut self, t: &Token) -> Result<bool, SyntaxError> {
+        if self.token() == t {
+            self.next()?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn check(&self, t: &Token) -> Result<(), SyntaxError> {
+        if self.token() != t {
+            return Err(self.error_expected(t));
+        }
+        Ok(())
+    }
+
+    fn check_next(&mut self, t: &Token) -> Result<(), SyntaxError> {
+        self.check(t)?;
+        self.next()
+    }
+
+    /// 检查 `what` 是否与 `who`（位于第 `line` 行）配对。
+    fn check_match(&mut self, what: &Token, who: &Token, line: usize) -> Result<(), SyntaxError> {
+        if self.test_next(what)? {
+            return Ok(());
+        }
+        if line == self.current.span.start.line {
+            Err(self.error_expected(what))
+        } else {
+            Err(self.error(&format!(
+                "{} expected (to close {} at line {})",
+                token_to_str(what),
+                token_to_str(who),
+                line
+            )))
+        }
+    }
+
+    fn check_name(&mut self) -> Result<Name, SyntaxError> {
+        if let Token::Name(name) = self.token() {
+            let name = Name {
+                name: name.clone(),
+                span: self.current.span,
+            };
+            self.next()?;
+            Ok(name)
+        } else {
+            Err(self.error_expected(&Token::Name(String::new())))
+        }
+    }
+
+    fn span_from(&self, start: Position) -> Span {
+        if self.prev_end.offset < start.offset {
+            Span::new(start, start) // empty node
+        } else {
+            Span::new(start, self.prev_end)
+        }
+    }
+
+    /* 错误处理 */
+
+    /// 生成带有 `near <token>` 的语法错误。
+    fn error(&self, msg: &str) -> SyntaxError {
+        SyntaxError {
+            chunk_name: self.lexer.chunk_name().to_string(),
+            position: self.current.span.start,
+            message: msg.to_string(),
+            near: Some(self.near_text()),
+        }
+    }
+
+    /// 生成不带出错记号的语义错误，对应 `luaK_semerror`。
+    fn sem_error(&self, msg: &str) -> SyntaxError {
+        SyntaxError {
+            near: None,
+            ..self.error(msg)
+        }
+    }
+
+    fn error_expected(&self, t: &Token) -> SyntaxError {
+        self.error(&format!("{} expected", token_to_str(t)))
+    }
+
+    fn near_text(&self) -> String {
+        match &self.current.token {
+            Token::Name(_) | Token::String(_) | Token::Integer(_) | Token::Float(_) => {
+                let span = self.current.span;
+                let text = &self.lexer.source()[span.start.offset..span.end.offset];
+                format!("'{}'", String::from_utf8_lossy(text))
+            }
+            t => token_to_str(t),
+        }
+    }
+
+    /// 恢复模式下记录错误并跳到可能开始下一条语句的位置；
+    /// 其他情况下原样返回错误。`start` 为出错语句开始处的偏移量。
+    fn recover_from(&mut self, err: SyntaxError, start: usize) -> Result<(), SyntaxError> {
+        if !self.recover || self.lex_failed {
+            return Err(err);
+        }
+        self.errors.push(err);
+        if self.current.span.start.offset == start && self.token() != &Token::Eof {
+            self.next()?; // make sure we always move forward
+        }
+        loop {
+            match self.token() {
+                Token::Eof
+                | Token::End
+                | Token::Else
+                | Token::ElseIf
+                | Token::Until
+                | Token::Semicolon
+                | Token::DoubleColon
+                | Token::Local
+                | Token::Function
+                | Token::If
+                | Token::While
+                | Token::For
+                | Token::Repeat
+                | Token::Do
+                | Token::Return
+                | Token::Break
+                | Token::Goto => return Ok(()),
+                // a name at the start of a line most likely begins a new statement
+                Token::Name(_) if self.current.span.start.line > self.prev_end.line => {
+                    return Ok(())
+                }
+                _ => self.next()?,
+            }
+        }
+    }
+
+    fn enter_level(&mut self) -> Result<(), SyntaxError> {
+        self.level += 1;
+        if self.level > MAX_LEVELS {
+            return Err(self.sem_error("C stack overflow"));
+        }
+        Ok(())
+    }
+
+    fn leave_level(&mut self) {
+        self.level -= 1;
+    }
+
+    /* 语句 */
+
+    fn block_follow(&self, with_until: bool) -> bool {
+        match self.token() {
+            Token::Else | Token::ElseIf | Token::End | Token::Eof => true,
+            Token::Until => with_until,
+            _ => false,
+        }
+    }
+
+    /// block -> { stat [';'] } [retstat]
+    fn block(&mut self) -> Result<Block, SyntaxError> {
+        let start = self.current.span.start;
+        let mut stats = Vec::new();
+        let mut ret = None;
+        while !self.block_follow(true) {
+            let offset = self.current.span.start.offset;
+            let (level, nvararg) = (self.level, self.vararg.len());
+            let result = if self.token() == &Token::Return {
+                self.ret_stat().map(|r| ret = Some(r))
+            } else {
+                self.statement().map(|stat| stats.extend(stat))
+            };
+            match result {
+                Ok(()) if ret.is_some() => break,
+                Ok(()) => {}
+                Err(err) => {
+                    // unwind whatever the failed statement left behind
+                    self.level = level;
+                    self.vararg.truncate(nvararg);
+                    self.recover_from(err, offset)?;
+                }
+            }
+        }
+        Ok(Block {
+            stats,
+            ret,
+            span: self.span_from(start),
+        })
+    }
+
+    /// retstat -> RETURN [explist] [';']
+    fn ret_stat(&mut self) -> Result<Return, SyntaxError> {
+        let start = self.current.span.start;
+        self.next()?; // skip RETURN
+        let exprs = if self.block_follow(true) || self.token() == &Token::Semicolon {
+            Vec::new()
+        } else {
+            self.expr_list()?
+        };
+        self.test_next(&Token::Semicolon)?;
+        Ok(Return {
+            exprs,
+            span: self.span_from(start),
+        })
+    }
+
+    /// 解析一条语句，空语句 `;` 返回 `None`。
+    fn statement(&mut self) -> Result<Option<Stat>, SyntaxError> {
+        let start = self.current.span.start;
+        let line = start.line;
+        self.enter_level()?;
+        let kind = match self.token() {
+            Token::Semicolon => {
+                self.next()?;
+                self.leave_level();
+                return Ok(None);
+            }
+            Token::If => self.if_stat(line)?,
+            Token::While => {
+                self.next()?;
+                let cond = self.expr()?;
+                self.check_next(&Token::Do)?;
+                let block = self.block()?;
+                self.check_match(&Token::End, &Token::While, line)?;
+                StatKind::While { cond, block }
+            }
+            Token::Do => {
+                self.next()?;
+                let block = self.block()?;
+                self.check_match(&Token::End, &Token::Do, line)?;
+                StatKind::Do(block)
+            }
+            Token::For => self.for_stat(line)?,
+            Token::Repeat => {
+                self.next()?;
+                let block = self.block()?;
+                self.check_match(&Token::Until, &Token::Repeat, line)?;
+                let cond = self.expr()?;
+                StatKind::Repeat { block, cond }
+            }
+            Token::Function => {
+                self.next()?;
+                let name = self.func_name()?;
+                let body = Box::new(self.body()?);
+                StatKind::Function { name, body }
+            }
+            Token::Local => {
+                self.next()?;
+                if self.test_next(&Token::Function)? {
+                    let name = self.check_name()?;
+                    let body = Box::new(self.body()?);
+                    StatKind::LocalFunction { name, body }
+                } else {
+                    self.local_stat()?
+                }
+            }
+            Token::DoubleColon => {
+                self.next()?;
+                let name = self.check_name()?;
+                self.check_next(&Token::DoubleColon)?;
+                StatKind::Label(name)
+            }
+            Token::Break => {
+                self.next()?;
+                StatKind::Break
+            }
+            Token::Goto => {
+                self.next()?;
+                StatKind::Goto(self.check_name()?)
+            }
+            _ => self.expr_stat()?,
+        };
+        self.leave_level();
+        Ok(Some(Stat {
+            kind,
+            span: self.span_from(start),
+        }))
+    }
+
+    /// ifstat -> IF cond THEN block {ELSEIF cond THEN block} [ELSE block] END
+    fn if_stat(&mut self, line: usize) -> Result<StatKind, SyntaxError> {
+        let mut conds = Vec::new();
+        loop {
+            self.next()?; // skip IF or ELSEIF
+            let cond = self.expr()?;
+            self.check_next(&Token::Then)?;
+            let block = self.block()?;
+            conds.push((cond, block));
+            if self.token() != &Token::ElseIf {
+                break;
+            }
+        }
+        let else_block = if self.test_next(&Token::Else)? {
+            Some(self.block()?)
+        } else {
+            None
+        };
+        self.check_match(&Token::End, &Token::If, line)?;
+        Ok(StatKind::If { conds, else_block })
+    }
+
+    /// forstat -> FOR (fornum | forlist) END
+    fn for_stat(&mut self, line: usize) -> Result<StatKind, SyntaxError> {
+        self.next()?; // skip FOR
+        let var = self.check_name()?;
+        let kind = match self.token() {
+            Token::Assign => {
+                self.next()?;
+                let start = Box::new(self.expr()?);
+                self.check_next(&Token::Comma)?;
+                let limit = Box::new(self.expr()?);
+                let step = if self.test_next(&Token::Comma)? {
+                    Some(Box::new(self.expr()?))
+                } else {
+                    None
+                };
+                self.check_next(&Token::Do)?;
+                let block = self.block()?;
+                StatKind::NumericFor {
+                    var,
+                    start,
+                    limit,
+                    step,
+                    block,
+                }
+            }
+            Token::Comma | Token::In => {
+                let mut names = vec![var];
+                while self.test_next(&Token::Comma)? {
+                    names.push(self.check_name()?);
+                }
+                self.check_next(&Token::In)?;
+                let exprs = self.expr_list()?;
+                self.check_next(&Token::Do)?;
+                let block = self.block()?;
+                StatKind::GenericFor {
+                    names,
+                    exprs,
+                    block,
+                }
+            }
+            _ => return Err(self.error("'=' or 'in' expected")),
+        };
+        self.check_match(&Token::End, &Token::For, line)?;
+        Ok(kind)
+    }
+
+    /// funcname -> NAME {'.' NAME} [':' NAME]
+    fn func_name(&mut self) -> Result<FuncName, SyntaxError> {
+        let mut path = vec![self.check_name()?];
+        while self.test_next(&Token::Dot)? {
+            path.push(self.check_name()?);
+        }
+        let method = if self.test_next(&Token::Colon)? {
+            Some(self.check_name()?)
+        } else {
+            None
+        };
+        Ok(FuncName { path, method })
+    }
+
+    /// stat -> LOCAL NAME attrib { ',' NAME attrib } ['=' explist]
+    fn local_stat(&mut self) -> Result<StatKind, SyntaxError> {
+        let mut names = Vec::new();
+        let mut has_close = false;
+        loop {
+            let name = self.check_name()?;
+            let attrib = self.attrib()?;
+            if attrib == Some(Attrib::Close) {
+                if has_close {
+                    return Err(self.sem_error("multiple to-be-closed variables in local list"));
+                }
+                has_close = true;
+            }
+            names.push(LocalName { name, attrib });
+            if !self.test_next(&Token::Comma)? {
+                break;
+            }
+        }
+        let exprs = if self.test_next(&Token::Assign)? {
+            self.expr_list()?
+        } else {
+            Vec::new()
+        };
+        Ok(StatKind::Local { names, exprs })
+    }
+
+    /// attrib -> ['<' NAME '>']
+    fn attrib(&mut self) -> Result<Option<Attrib>, SyntaxError> {
+        if !self.test_next(&Token::Lt)? {
+            return Ok(None);
+        }
+        let attr = self.check_name()?;
+        self.check_next(&Token::Gt)?;
+        match attr.name.as_str() {
+            "const" => Ok(Some(Attrib::Const)),
+            "close" => Ok(Some(Attrib::Close)),
+            name => Err(self.sem_error(&format!("unknown attribute '{name}'"))),
+        }
+    }
+
+    /// stat -> func | assignment
+    fn expr_stat(&mut self) -> Result<StatKind, SyntaxError> {
+        let first = self.suffixed_expr()?;
+        if matches!(self.token(), Token::Assign | Token::Comma) {
+            let mut targets = vec![first];
+            loop {
+                if !is_assignable(targets.last().unwrap()) {
+                    return Err(self.error("syntax error"));
+                }
+                if !self.test_next(&Token::Comma)? {
+                    break;
+                }
+                targets.push(self.suffixed_expr()?);
+            }
+            self.check_next(&Token::Assign)?;
+            let exprs = self.expr_list()?;
+            Ok(StatKind::Assign { targets, exprs })
+        } else if matches!(
+            first.kind,
+            ExprKind::Call { .. } | ExprKind::MethodCall { .. }
+        ) {
+            Ok(StatKind::Call(first))
+        } else {
+            Err(self.error("syntax error"))
+        }
+    }
+
+    /* 表达式 */
+
+    /// explist -> expr { ',' expr }
+    fn expr_list(&mut self) -> Result<Vec<Expr>, SyntaxError> {
+        let mut exprs = vec![self.expr()?];
+        while self.test_next(&Token::Comma)? {
+            exprs.push(self.expr()?);
+        }
+        Ok(exprs)
+    }
+
+    pub fn expr(&mut self) -> Result<Expr, SyntaxError> {
+        self.sub_expr(0)
+    }
+
+    /// subexpr -> (simpleexp | unop subexpr) { binop subexpr }
+    /// 其中 binop 的左优先级需大于 `limit`。
+    fn sub_expr(&mut self, limit: u8) -> Result<Expr, SyntaxError> {
+        self.enter_level()?;
+        let start = self.current.span.start;
+        let mut lhs = if let Some(op) = unary_op(self.token()) {
+            self.next()?;
+            let expr = self.sub_expr(UNARY_PRIORITY)?;
+            Expr {
+                kind: ExprKind::Unary {
+                    op,
+                    expr: Box::new(expr),
+                },
+                span: self.span_from(start),
+            }
+        } else {
+            self.simple_expr()?
+        };
+        while let Some(op) = binary_op(self.token()) {
+            let (left, right) = op.priority();
+            if left <= limit {
+                break;
+            }
+            let op_span = self.current.span;
+            self.next()?;
+            let rhs = self.sub_expr(right)?;
+            lhs = Expr {
+                kind: ExprKind::Binary {
+                    op,
+                    op_span,
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                },
+                span: self.span_from(start),
+            };
+        }
+        self.leave_level();
+        Ok(lhs)
+    }
+
+    /// simpleexp -> FLT | INT | STRING | NIL | TRUE | FALSE | ... |
+    ///              constructor | FUNCTION body | suffixedexp
+    fn simple_expr(&mut self) -> Result<Expr, SyntaxError> {
+        let start = self.current.span.start;
+        let kind = match self.token() {
+            Token::Float(n) => ExprKind::Float(*n),
+            Token::Integer(i) => ExprKind::Integer(*i),
+            Token::String(s) => ExprKind::String(s.clone()),
+            Token::Nil => ExprKind::Nil,
+            Token::True => ExprKind::True,
+            Token::False => ExprKind::False,
+            Token::Dots => {
+                if !self.vararg.last().copied().unwrap_or(false) {
+                    return Err(self.error("cannot use '...' outside a vararg function"));
+                }
+                ExprKind::Vararg
+            }
+            Token::LBrace => return self.constructor(),
+            Token::Function => {
+                self.next()?;
+                ExprKind::Function(Box::new(self.body()?))
+            }
+            _ => return self.suffixed_expr(),
+        };
+        if !matches!(kind, ExprKind::Function(_)) {
+            self.next()?;
+        }
+        Ok(Expr {
+            kind,
+            span: self.span_from(start),
+        })
+    }
+
+    /// primaryexp -> NAME | '(' expr ')'
+    fn primary_expr(&mut self) -> Result<Expr, SyntaxError> {
+        let start = self.current.span.start;
+        match self.token() {
+            Token::Name(name) => {
+                let kind = ExprKind::Name(name.clone());
+                self.next()?;
+                Ok(Expr {
+                    kind,
+                    span: self.span_from(start),
+                })
+            }
+            Token::LParen => {
+                self.next()?;
+                let expr = self.expr()?;
+                self.check_match(&Token::RParen, &Token::LParen, start.line)?;
+                Ok(Expr {
+                    kind: ExprKind::Paren(Box::new(expr)),
+                    span: self.span_from(start),
+                })
+            }
+            _ => Err(self.error("unexpected symbol")),
+        }
+    }
+
+    /// suffixedexp -> primaryexp { '.' NAME | '[' exp ']' | ':' NAME funcargs | funcargs }
+    fn suffixed_expr(&mut self) -> Result<Expr, SyntaxError> {
+        let start = self.current.span.start;
+        let mut expr = self.primary_expr()?;
+        while let Some(kind) = self.suffix(&mut expr)? {
+            expr = Expr {
+                kind,
+                span: self.span_from(start),
+            };
+        }
+        Ok(expr)
+    }
+
+    /// 解析一个后缀，并将 `expr` 移入新的节点中；没有后缀时返回 `None`。
+    fn suffix(&mut self, expr: &mut Expr) -> Result<Option<ExprKind>, SyntaxError> {
+        let kind = match self.token() {
+            Token::Dot => {
+                self.next()?;
+                let key = name_to_string(self.check_name()?);
+                ExprKind::Index {
+                    obj: take_expr(expr),
+                    key: Box::new(key),
+                }
+            }
+            Token::LBracket => {
+                let key = Box::new(self.index()?);
+                ExprKind::Index {
+                    obj: take_expr(expr),
+                    key,
+                }
+            }
+            Token::Colon => {
+                self.next()?;
+                let method = self.check_name()?;
+                let args = self.func_args()?;
+                ExprKind::MethodCall {
+                    obj: take_expr(expr),
+                    method,
+                    args,
+                }
+            }
+            Token::LParen | Token::String(_) | Token::LBrace => {
+                let args = self.func_args()?;
+                ExprKind::Call {
+                    func: take_expr(expr),
+                    args,
+                }
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(kind))
+    }
+
+    /// index -> '[' expr ']'
+    fn index(&mut self) -> Result<Expr, SyntaxError> {
+        self.next()?; // skip '['
+        let expr = self.expr()?;
+        self.check_next(&Token::RBracket)?;
+        Ok(expr)
+    }
+
+    /// funcargs -> '(' [ explist ] ')' | constructor | STRING
+    fn func_args(&mut self) -> Result<Vec<Expr>, SyntaxError> {
+        let start = self.current.span.start;
+        match self.token() {
+            Token::LParen => {
+                self.next()?;
+                let args = if self.token() == &Token::RParen {
+                    Vec::new()
+                } else {
+                    self.expr_list()?
+                };
+                self.check_match(&Token::RParen, &Token::LParen, start.line)?;
+                Ok(args)
+            }
+            Token::LBrace => Ok(vec![self.constructor()?]),
+            Token::String(s) => {
+                let kind = ExprKind::String(s.clone());
+                self.next()?;
+                Ok(vec![Expr {
+                    kind,
+                    span: self.span_from(start),
+                }])
+            }
+            _ => Err(self.error("function arguments expected")),
+        }
+    }
+
+    /// constructor -> '{' [ field { sep field } [sep] ] '}'
+    fn constructor(&mut self) -> Result<Expr, SyntaxError> {
+        let start = self.current.span.start;
+        self.check_next(&Token::LBrace)?;
+        let mut fields = Vec::new();
+        while self.token() != &Token::RBrace {
+            fields.push(self.field()?);
+            if !self.test_next(&Token::Comma)? && !self.test_next(&Token::Semicolon)? {
+                break;
+            }
+        }
+        self.check_match(&Token::RBrace, &Token::LBrace, start.line)?;
+        Ok(Expr {
+            kind: ExprKind::Table(fields),
+            span: self.span_from(start),
+        })
+    }
+
+    /// field -> NAME '=' exp | '[' exp ']' '=' exp | exp
+    fn field(&mut self) -> Result<Field, SyntaxError> {
+        let named = matches!(self.token(), Token::Name(_)) && self.lookahead()? == &Token::Assign;
+        match self.token() {
+            Token::Name(_) if named => {
+                let name = self.check_name()?;
+                self.next()?; // skip '='
+                Ok(Field::Named(name, self.expr()?))
+            }
+            Token::LBracket => {
+                let key = self.index()?;
+                self.check_next(&Token::Assign)?;
+                Ok(Field::Keyed(key, self.expr()?))
+            }
+            _ => Ok(Field::Positional(self.expr()?)),
+        }
+    }
+
+    /// body -> '(' parlist ')' block END
+    fn body(&mut self) -> Result<FuncBody, SyntaxError> {
+        let start = self.current.span.start;
+        self.check_next(&Token::LParen)?;
+        let mut params = Vec::new();
+        let mut is_vararg = false;
+        if self.token() != &Token::RParen {
+            loop {
+                match self.token() {
+                    Token::Name(_) => params.push(self.check_name()?),
+                    Token::Dots => {
+                        self.next()?;
+                        is_vararg = true;
+                    }
+                    _ => return Err(self.error("<name> or '...' expected")),
+                }
+                if is_vararg || !self.test_next(&Token::Comma)? {
+                    break;
+                }
+            }
+        }
+        self.check_next(&Token::RParen)?;
+        self.vararg.push(is_vararg);
+        let block = self.block()?;
+        self.vararg.pop();
+        self.check_match(&Token::End, &Token::Function, start.line)?;
+        Ok(FuncBody {
+            params,
+            is_vararg,
+            block,
+            span: self.span_from(start),
+        })
+    }
+}
+
+/// 返回记号在错误信息中的写法，与 `luaX_token2str` 一致。
+fn token_to_str(t: &Token) -> String {
+    match t {
+        Token::Eof => "<eof>".to_string(),
+        Token::Name(_) => "<name>".to_string(),
+        Token::String(_) => "<string>".to_string(),
+        Token::Integer(_) => "<integer>".to_string(),
+        Token::Float(_) => "<number>".to_string(),
+        t => format!("'{}'", t.fixed_text().unwrap()),
+    }
+}
+
+fn take_expr(expr: &mut Expr) -> Box<Expr> {
+    let placeholder = Expr {
+        kind: ExprKind::Nil,
+        span: expr.span,
+    };
+    Box::new(std::mem::replace(expr, placeholder))
+}
+
+fn name_to_string(name: Name) -> Expr {
+    Expr {
+        kind: ExprKind::String(name.name.into_bytes()),
+        span: name.span,
+    }
+}
+
+fn is_assignable(e: &Expr) -> bool {
+    matches!(e.kind, ExprKind::Name(_) | ExprKind::Index { .. })
+}
+
+fn unary_op(t: &Token) -> Option<UnOp> {
+    let op = match t {
+        Token::Not => UnOp::Not,
+        Token::Minus => UnOp::Minus,
+        Token::Tilde => UnOp::BNot,
+        Token::Hash => UnOp::Len,
+        _ => return None,
+    };
+    Some(op)
+}
+
+fn binary_op(t: &Token) -> Option<BinOp> {
+    let op = match t {
+        Token::Plus => BinOp::Add,
+        Token::Minus => BinOp::Sub,
+        Token::Star => BinOp::Mul,
+        Token::Percent => BinOp::Mod,
+        Token::Caret => BinOp::Pow,
+        Token::Slash => BinOp::Div,
+        Token::DoubleSlash => BinOp::IDiv,
+        Token::Ampersand => BinOp::BAnd,
+        Token::Pipe => BinOp::BOr,
+        Token::Tilde => BinOp::BXor,
+        Token::ShiftLeft => BinOp::Shl,
+        Token::ShiftRight => BinOp::Shr,
+        Token::Concat => BinOp::Concat,
+        Token::Ne => BinOp::Ne,
+        Token::Eq => BinOp::Eq,
+        Token::Lt => BinOp::Lt,
+        Token::Le => BinOp::Le,
+        Token::Gt => BinOp::Gt,
+        Token::Ge => BinOp::Ge,
+        Token::And => BinOp::And,
+        Token::Or => BinOp::Or,
+        _ => return None,
+    };
+    Some(op)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_ok(src: &str) -> Block {
+        parse(src.as_bytes(), "=test").unwrap()
+    }
+
+    fn parse_err(src: &str) -> String {
+        parse(src.as_bytes(), "=test").unwrap_err().to_string()
+    }
+
+    fn ret_expr(src: &str) -> Expr {
+        let block = parse_ok(&format!("return {src}"));
+        block.ret.unwrap().exprs.remove(0)
+    }
+
+    /// 以全括号形式输出表达式，便于检查优先级与结合性。
+    fn show(e: &Expr) -> String {
+        match &e.kind {
+            ExprKind::Name(n) => n.clone(),
+            ExprKind::Integer(i) => i.to_string(),
+            ExprKind::String(s) => format!("{:?}", String::from_utf8_lossy(s)),
+            ExprKind::Vararg => "...".to_string(),
+            ExprKind::Binary { op, lhs, rhs, .. } => {
+                format!("({} {:?} {})", show(lhs), op, show(rhs))
+            }
+            ExprKind::Unary { op, expr } => format!("({:?} {})", op, show(expr)),
+            ExprKind::Paren(e) => format!("[{}]", show(e)),
+            ExprKind::Index { obj, key } => format!("{}[{}]", show(obj), show(key)),
+            ExprKind::Call { func, args } => {
+                let args: Vec<String> = args.iter().map(show).collect();
+                format!("{}({})", show(func), args.join(", "))
+            }
+            ExprKind::MethodCall { obj, method, args } => {
+                let args: Vec<String> = args.iter().map(show).collect();
+                format!("{}:{}({})", show(obj), method.name, args.join(", "))
+            }
+            k => format!("{k:?}"),
+        }
+    }
+
+    #[test]
+    fn test_precedence() {
+        let cases = [
+            ("1 + 2 * 3", "(1 Add (2 Mul 3))"),
+            ("1 - 2 - 3", "((1 Sub 2) Sub 3)"),
+            ("2 ^ 3 ^ 2", "(2 Pow (3 Pow 2))"),
+            ("-x ^ 2", "(Minus (x Pow 2))"),
+            ("a .. b .. c", "(a Concat (b Concat c))"),
+            ("not a == b", "((Not a) Eq b)"),
+            ("a or b and c", "(a Or (b And c))"),
+            ("a < b == c", "((a Lt b) Eq c)"),
+            ("1 | 2 ~ 3 & 4 << 5", "(1 BOr (2 BXor (3 BAnd (4 Shl 5))))"),
+            ("a .. b + c", "(a Concat (b Add c))"),
+            ("#t + 1", "((Len t) Add 1)"),
+            ("~~x // 2 % 3", "(((BNot (BNot x)) IDiv 2) Mod 3)"),
+            ("(a + b) * c", "([(a Add b)] Mul c)"),
+        ];
+        for (src, expected) in cases {
+            assert_eq!(show(&ret_expr(src)), expected, "{src}");
+        }
+    }
+
+    #[test]
+    fn test_suffixed_exprs() {
+        assert_eq!(show(&ret_expr("a.b[c].d")), "a[\"b\"][c][\"d\"]");
+        assert_eq!(show(&ret_expr("obj:m(1)(2)")), "obj:m(1)(2)");
+        assert_eq!(show(&ret_expr("f'x'")), "f(\"x\")");
+        assert_eq!(show(&ret_expr("f{}")), "f(Table([]))");
+        assert_eq!(show(&ret_expr("s:format(...)")), "s:format(...)");
+    }
+
+    #[test]
+    fn test_statements() {
+        let block = parse_ok(
+            "local a <const>, b <close> = 1\n\
+             function t.a.b:m(x, ...) return ... end\n\
+             local function f() end\n\
+             for i = 1, 10, 2 do end\n\
+             for k, v in pairs(t) do break end\n\
+             while true do goto done end\n\
+             ::done::\n\
+             repeat local x until x\n\
+             if a then elseif b then else end\n\
+             a, b.c, d[1] = f()\n\
+             do ; end",
+        );
+        let kinds: Vec<&str> = block
+            .stats
+            .iter()
+            .map(|s| match &s.kind {
+                StatKind::Local { .. } => "local",
+                StatKind::Function { .. } => "function",
+                StatKind::LocalFunction { .. } => "local function",
+                StatKind::NumericFor { .. } => "fornum",
+                StatKind::GenericFor { .. } => "forin",
+                StatKind::While { .. } => "while",
+                StatKind::Label(_) => "label",
+                StatKind::Repeat { .. } => "repeat",
+                StatKind::If { .. } => "if",
+                StatKind::Assign { .. } => "assign",
+                StatKind::Do(_) => "do",
+                _ => "other",
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                "local",
+                "function",
+                "local function",
+                "fornum",
+                "forin",
+                "while",
+                "label",
+                "repeat",
+                "if",
+                "assign",
+                "do"
+            ]
+        );
+
+        match &block.stats[0].kind {
+            StatKind::Local { names, exprs } => {
+                assert_eq!(names[0].attrib, Some(Attrib::Const));
+                assert_eq!(names[1].attrib, Some(Attrib::Close));
+                assert_eq!(exprs.len(), 1);
+            }
+            _ => unreachable!(),
+        }
+        match &block.stats[1].kind {
+            StatKind::Function { name, body } => {
+                let path: Vec<&str> = name.path.iter().map(|n| n.name.as_str()).collect();
+                assert_eq!(path, vec!["t", "a", "b"]);
+                assert_eq!(name.method.as_ref().unwrap().name, "m");
+                assert_eq!(body.params.len(), 1);
+                assert!(body.is_vararg);
+            }
+            _ => unreachable!(),
+        }
+        match &block.stats[9].kind {
+            StatKind::Assign { targets, exprs } => {
+                assert_eq!(targets.len(), 3);
+                assert!(exprs[0].is_multi());
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_table_constructor() {
+        let e = ret_expr("{1, x = 2; [3] = 4, f(), }");
+        match e.kind {
+            ExprKind::Table(fields) => {
+                assert_eq!(fields.len(), 4);
+                assert!(matches!(fields[0], Field::Positional(_)));
+                assert!(matches!(fields[1], Field::Named(..)));
+                assert!(matches!(fields[2], Field::Keyed(..)));
+                assert!(matches!(fields[3], Field::Positional(_)));
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_spans() {
+        let block = parse_ok("local x = 1\nx = x +\n  f(2)\n");
+        let stat = &block.stats[1];
+        assert_eq!((stat.span.start.line, stat.span.start.column), (2, 1));
+        assert_eq!((stat.span.end.line, stat.span.end.column), (3, 7));
+        match &stat.kind {
+            StatKind::Assign { exprs, .. } => match &exprs[0].kind {
+                ExprKind::Binary { op_span, rhs, .. } => {
+                    assert_eq!((op_span.start.line, op_span.start.column), (2, 7));
+                    assert_eq!(rhs.span.start.line, 3);
+                }
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+        assert_eq!(block.span.end.line, 3);
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        let cases = [
+            ("x = 'abc' 'd' y", "test:1: unexpected symbol near ''d''"),
+            ("f(\n", "test:2: unexpected symbol near <eof>"),
+            (
+                "if x then\n\n\nelse",
+                "test:4: 'end' expected (to close 'if' at line 1) near <eof>",
+            ),
+            ("return 1 x = 2", "test:1: <eof> expected near 'x'"),
+            (
+                "function f() return ... end",
+                "test:1: cannot use '...' outside a vararg function near '...'",
+            ),
+            ("x.y:z = 1", "test:1: function arguments expected near '='"),
+            ("x()  = 1", "test:1: syntax error near '='"),
+            ("x", "test:1: syntax error near <eof>"),
+            ("for x y", "test:1: '=' or 'in' expected near 'y'"),
+            (
+                "function f(a, 1) end",
+                "test:1: <name> or '...' expected near '1'",
+            ),
+            ("local function 1", "test:1: <name> expected near '1'"),
+            ("x = }", "test:1: unexpected symbol near '}'"),
+            ("local x <foo> = 1", "test:1: unknown attribute 'foo'"),
+            (
+                "local x <close>, y <close> = 1",
+                "test:1: multiple to-be-closed variables in local list",
+            ),
+            ("x = (1", "test:1: ')' expected near <eof>"),
+            ("x = 1 + @", "test:1: unexpected symbol near '@'"),
+        ];
+        for (src, expected) in cases {
+            assert_eq!(parse_err(src), expected, "{src}");
+        }
+    }
+
+    #[test]
+    fn test_error_recovery() {
+        let errors = |src: &str| -> Vec<String> {
+            match parse_recover(src.as_bytes(), "=test") {
+                Ok(_) => Vec::new(),
+                Err(errors) => errors.iter().map(|e| e.to_string()).collect(),
+            }
+        };
+        assert!(errors("local x = 1\nprint(x)").is_empty());
+        assert_eq!(
+            errors(
+                "local x = = 1\n\
+                 if x then y = end\n\
+                 for 1 do end\n\
+                 function f(a b) return a end\n\
+                 local t = {1, 2\n\
+                 print(t)"
+            ),
+            [
+                "test:1: unexpected symbol near '='",
+                "test:2: unexpected symbol near 'end'",
+                "test:3: <name> expected near '1'",
+                "test:4: ')' expected near 'b'",
+                "test:6: '}' expected (to close '{' at line 5) near 'print'",
+            ]
+        );
+        assert_eq!(
+            errors("x = 1\nend\ny = = 2"),
+            [
+                "test:2: <eof> expected near 'end'",
+                "test:3: unexpected symbol near '='",
+            ]
+        );
+        // lexical errors stop the analysis
+        assert_eq!(
+            errors("x = = 1\ny = \"abc\nz = = 2"),
+            [
+                "test:1: unexpected symbol near '='",
+                "test:2: unfinished string near '\"abc'",
+            ]
+        );
+
+        let err = parse_recover(b"local a\n  b c", "@f.lua").unwrap_err();
+        assert_eq!(err.len(), 1);
+        assert_eq!(err[0].chunk_name, "@f.lua");
+        assert_eq!((err[0].position.line, err[0].position.column), (2, 5));
+        assert_eq!(err[0].near.as_deref(), Some("'c'"));
+        assert_eq!(err[0].to_string(), "f.lua:2: syntax error near 'c'");
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        // 未优化构建中每层递归占用的栈空间较大，在更大的栈上运行
+        std::thread::Builder::new()
+            .stack_size(16 << 20)
+            .spawn(|| {
+                let src = format!("x = {}1", "(".repeat(300));
+                assert_eq!(parse_err(&src), "test:1: C stack overflow");
+                let src = format!("x = {}1{}", "{".repeat(150), "}".repeat(150));
+                parse_ok(&src);
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+}
@@ -0,0 +1,6 @@
+pub mod api;
+pub mod binary;
+pub mod compiler;
+pub mod state;
+pub mod stdlib;
+pub mod vm;
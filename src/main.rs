@@ -4,31 +4,60 @@ use std::{
     io::{self, Read},
 };
 
-use binary::chunk::{Constant, Prototype};
-use vm::instruction::Instruction;
+use rua::{
+    binary::chunk::{Constant, Prototype},
+    compiler,
+    vm::instruction::{Instruction, MAXARG_C},
+};
 use Constant::*;
 
-use crate::vm::instruction::MAXARG_C;
-
-mod api;
-mod binary;
-mod state;
-mod vm;
-
 fn main() -> io::Result<()> {
-    if env::args().count() > 1 {
-        let filename = env::args().nth(1).unwrap();
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    // `-O` lists the bytecode after optimization
+    let optimize = args.first().is_some_and(|a| a == "-O");
+    if optimize {
+        args.remove(0);
+    }
+    if let Some(filename) = args.first() {
         let mut file = File::open(filename)?;
 
         let mut data = Vec::new();
         file.read_to_end(&mut data)?;
+        skip_comment(&mut data);
 
-        let proto = binary::undump(data);
+        let chunk_name = format!("@{filename}");
+        let proto = match compiler::load(data.clone(), &chunk_name, "bt") {
+            Ok(proto) => proto,
+            Err(err) => {
+                if let compiler::LoadError::Syntax(_) = err {
+                    // report every syntax error in the file, not just the first one
+                    for err in compiler::check(&data, &chunk_name) {
+                        eprintln!("{err}");
+                    }
+                } else {
+                    eprintln!("{err}");
+                }
+                std::process::exit(1);
+            }
+        };
+        let proto = if optimize {
+            compiler::optimizer::optimize(proto)
+        } else {
+            proto
+        };
         list(&proto);
     }
     Ok(())
 }
 
+/// 像 `luaL_loadfile` 一样跳过以 `#` 开头的第一行（例如 Unix 的 `#!` 行），保留换行符以免行号错位。
+fn skip_comment(data: &mut Vec<u8>) {
+    if data.first() == Some(&b'#') {
+        let end = data.iter().position(|&c| c == b'\n').unwrap_or(data.len());
+        data.drain(..end);
+    }
+}
+
 fn list(f: &Prototype) {
     print_header(f);
     print_code(f);
@@ -403,7 +432,7 @@ fn print_const(f: &Prototype, i: usize) {
         Boolean(b) => print!("{b}"),
         Number(x) => print!("{x}"),
         Integer(i) => print!("{i}"),
-        Str(s) => print!("{s:?}"),
+        Str(s) => print!("{:?}", String::from_utf8_lossy(s)),
     }
 }
 
@@ -477,14 +506,32 @@ fn get_void<T>(f: &T) -> *const T {
 mod tests {
     use std::{fs::File, io::Read};
 
+    use rua::binary;
+
     use super::*;
+    #[test]
+    fn test_skip_comment() {
+        let mut data = b"#!../lua\nreturn 1".to_vec();
+        skip_comment(&mut data);
+        assert_eq!(data, b"\nreturn 1");
+        let mut data = b"-- comment\n".to_vec();
+        skip_comment(&mut data);
+        assert_eq!(data, b"-- comment\n");
+
+        let mut file = File::open("lua/all.lua").expect("Failed to open file");
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).expect("Failed to read file");
+        skip_comment(&mut data);
+        assert!(compiler::load(data, "@lua/all.lua", "t").is_ok());
+    }
+
     #[test]
     fn test_undump() {
         let mut file = File::open("lua/all.luac").expect("Failed to open file");
         let mut data = Vec::new();
         file.read_to_end(&mut data).expect("Failed to read file");
 
-        let proto = binary::undump(data);
+        let proto = binary::undump(data, "@lua/all.luac").unwrap();
         list(&proto);
     }
 }
@@ -1,7 +1,7 @@
 use super::lua_value::LuaValue;
 
 fn iadd(a: i64, b: i64) -> i64 {
-    a + b
+    a.wrapping_add(b)
 }
 
 fn fadd(a: f64, b: f64) -> f64 {
@@ -9,7 +9,7 @@ fn fadd(a: f64, b: f64) -> f64 {
 }
 
 fn isub(a: i64, b: i64) -> i64 {
-    a - b
+    a.wrapping_sub(b)
 }
 
 fn fsub(a: f64, b: f64) -> f64 {
@@ -17,7 +17,7 @@ fn fsub(a: f64, b: f64) -> f64 {
 }
 
 fn imul(a: i64, b: i64) -> i64 {
-    a * b
+    a.wrapping_mul(b)
 }
 
 fn fmul(a: f64, b: f64) -> f64 {
@@ -69,7 +69,7 @@ fn shr(a: i64, b: i64) -> i64 {
 }
 
 fn iunm(a: i64, _: i64) -> i64 {
-    -a
+    a.wrapping_neg()
 }
 
 fn funm(a: f64, _: f64) -> f64 {
@@ -105,7 +105,17 @@ pub const OPS: &'static [(fn(i64, i64) -> i64, fn(f64, f64) -> f64)] = &[
     (bnot, fnone),
 ];
 
+/// 各算术运算对应的元方法名，顺序与 `OPS` 相同
+pub const METAMETHODS: &[&str] = &[
+    "__add", "__sub", "__mul", "__mod", "__pow", "__div", "__idiv", "__band", "__bor", "__bxor",
+    "__shl", "__shr", "__unm", "__bnot",
+];
+
 pub fn arith(a: &LuaValue, b: &LuaValue, op: u8) -> Option<LuaValue> {
+    if let (LuaValue::Str(_), _) | (_, LuaValue::Str(_)) = (a, b) {
+        // strings are converted following the rules of the Lua lexer
+        return arith(&a.to_numeric()?, &b.to_numeric()?, op);
+    }
     let (iop, fop) = OPS[op as usize];
     if fop == fnone {
         if let Some(x) = a.to_integer() {
@@ -133,7 +143,7 @@ pub fn arith(a: &LuaValue, b: &LuaValue, op: u8) -> Option<LuaValue> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{api::op::ArithOp, state::lua_value::LuaValue};
+    use crate::{api::op::ArithOp, state::lua_value::LuaValue, stdlib::test_util::run};
 
     #[test]
     fn test_arith() {
@@ -340,4 +350,44 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_arith_string_coercion() {
+        let s = |s: &str| LuaValue::Str(s.as_bytes().to_vec());
+        let add = ArithOp::ADD as u8;
+        let mul = ArithOp::MUL as u8;
+        let idiv = ArithOp::IDIV as u8;
+        let div = ArithOp::DIV as u8;
+        assert_eq!(
+            arith(&s("0x10"), &LuaValue::Integer(0), add),
+            Some(LuaValue::Integer(16))
+        );
+        assert_eq!(
+            arith(&s(" 3 "), &LuaValue::Integer(2), mul),
+            Some(LuaValue::Integer(6))
+        );
+        assert_eq!(
+            arith(&s("10"), &LuaValue::Integer(3), idiv),
+            Some(LuaValue::Integer(3))
+        );
+        assert_eq!(arith(&s("10"), &s("4"), div), Some(LuaValue::Number(2.5)));
+        assert_eq!(
+            arith(&s("1.5"), &LuaValue::Integer(1), add),
+            Some(LuaValue::Number(2.5))
+        );
+        assert_eq!(
+            arith(&s("0x1p4"), &LuaValue::Integer(0), add),
+            Some(LuaValue::Number(16.0))
+        );
+        assert_eq!(arith(&s("1e"), &LuaValue::Integer(1), add), None);
+        assert_eq!(arith(&s("nan"), &LuaValue::Integer(1), add), None);
+    }
+
+    #[test]
+    fn test_arith_string_coercion_in_lua() {
+        let results = run(r#"
+            return "0x10" + 0, " 3 " * 2, "10" // 3, "10" // 3.0, "3" & 1
+        "#);
+        assert_eq!(results, ["16", "6", "3", "3.0", "1"]);
+    }
 }
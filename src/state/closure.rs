@@ -1,55 +1,123 @@
-use std::{
-    hash::{Hash, Hasher},
-    rc::Rc,
-};
+use std::{cell::RefCell, fmt, rc::Rc};
 
-use crate::{api::RustFn, binary::chunk::Prototype};
+use crate::{api::RustClosure, binary::chunk::Prototype};
 
+use super::lua_value::{drop_values, LuaValue};
+
+/// 上值。打开时引用某个栈帧中的寄存器，关闭后持有寄存器最后的值。
 #[derive(Debug)]
+pub enum Upvalue {
+    /// 栈帧的寄存器以及寄存器的索引（从 0 开始）
+    Open(Rc<RefCell<Vec<LuaValue>>>, usize),
+    Closed(LuaValue),
+}
+
+impl Upvalue {
+    pub fn get(&self) -> LuaValue {
+        match self {
+            Upvalue::Open(slots, idx) => slots.borrow().get(*idx).cloned().unwrap_or(LuaValue::Nil),
+            Upvalue::Closed(val) => val.clone(),
+        }
+    }
+
+    pub fn set(&mut self, val: LuaValue) {
+        match self {
+            Upvalue::Open(slots, idx) => {
+                if let Some(slot) = slots.borrow_mut().get_mut(*idx) {
+                    *slot = val;
+                }
+            }
+            Upvalue::Closed(v) => *v = val,
+        }
+    }
+
+    /// 把寄存器的值复制到上值中，此后上值不再引用栈帧。
+    pub fn close(&mut self) {
+        if let Upvalue::Open(..) = self {
+            *self = Upvalue::Closed(self.get());
+        }
+    }
+}
+
 pub struct Closure {
     pub proto: Rc<Prototype>,
-    pub rust_fn: Option<RustFn>,
-    rdm: usize,
+    pub rust_fn: Option<RustClosure>,
+    pub upvals: Vec<Rc<RefCell<Upvalue>>>,
 }
 
-impl Hash for Closure {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.rdm.hash(state);
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Closure")
+            .field("proto", &self.proto)
+            .field("rust_fn", &self.rust_fn.as_ref().map(Rc::as_ptr))
+            .field("upvals", &self.upvals)
+            .finish()
     }
 }
 
 impl Closure {
     pub fn new(proto: Rc<Prototype>) -> Closure {
-        Closure {
-            proto: proto,
-            rust_fn: None,
-            rdm: super::math::random(),
-        }
+        Closure::new_lua_closure(proto)
     }
 
     pub fn new_fake_closure() -> Closure {
         Closure {
             proto: new_empty_prototype(), // TODO
             rust_fn: None,
-            rdm: super::math::random(),
+            upvals: vec![],
         }
     }
 
+    /// 创建 Lua 闭包，上值的数量由原型决定，初始均为已关闭的 nil。
     pub fn new_lua_closure(proto: Rc<Prototype>) -> Closure {
+        let upvals = (0..proto.upvalues.len())
+            .map(|_| Rc::new(RefCell::new(Upvalue::Closed(LuaValue::Nil))))
+            .collect();
         Closure {
             proto: proto,
             rust_fn: None,
-            rdm: super::math::random(),
+            upvals,
         }
     }
 
-    pub fn new_rust_closure(f: RustFn) -> Closure {
+    pub fn new_rust_closure(f: RustClosure) -> Closure {
         Closure {
             proto: new_empty_prototype(), // TODO
             rust_fn: Some(f),
-            rdm: super::math::random(),
+            upvals: vec![],
+        }
+    }
+
+    /// 取出只被这个闭包引用的已关闭的上值中的表和闭包，放进 `out`，其他值直接释放。
+    pub(crate) fn take_values(&mut self, out: &mut Vec<LuaValue>) {
+        for uv in self.upvals.drain(..) {
+            if let Ok(uv) = Rc::try_unwrap(uv) {
+                if let Upvalue::Closed(val) = uv.into_inner() {
+                    if val.is_container() {
+                        out.push(val);
+                    }
+                }
+            }
         }
     }
+
+    /// 创建以 `vals` 为上值的 Rust 闭包，上值均为已关闭的。
+    pub fn new_rust_closure_with_upvalues(f: RustClosure, vals: Vec<LuaValue>) -> Closure {
+        let mut closure = Closure::new_rust_closure(f);
+        closure.upvals = vals
+            .into_iter()
+            .map(|val| Rc::new(RefCell::new(Upvalue::Closed(val))))
+            .collect();
+        closure
+    }
+}
+
+impl Drop for Closure {
+    fn drop(&mut self) {
+        let mut pending = Vec::new();
+        self.take_values(&mut pending);
+        drop_values(pending);
+    }
 }
 
 fn new_empty_prototype() -> Rc<Prototype> {
@@ -38,11 +38,8 @@ fn eq(a: &LuaValue, b: &LuaValue) -> bool {
     if let Some(x) = cmp!(a == b) {
         x
     } else {
-        match (a, b) {
-            (LuaValue::Nil, LuaValue::Nil) => true,
-            (LuaValue::Boolean(x), LuaValue::Boolean(y)) => x == y,
-            _ => false,
-        }
+        // nil, booleans, and tables and functions by identity
+        a == b
     }
 }
 
@@ -73,6 +70,15 @@ mod tests {
         assert_eq!(compare(&a, &b, op), Some(true));
     }
 
+    #[test]
+    fn test_compare_eq_identity() {
+        let a = LuaValue::new_table(0, 0);
+        let b = LuaValue::new_table(0, 0);
+        let op = CmpOp::EQ as u8;
+        assert_eq!(compare(&a, &a.clone(), op), Some(true));
+        assert_eq!(compare(&a, &b, op), Some(false));
+    }
+
     #[test]
     fn test_compare_lt() {
         let a = LuaValue::Integer(5);
@@ -0,0 +1,1375 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    mem::size_of,
+    rc::{Rc, Weak},
+};
+
+use super::{
+    closure::{Closure, Upvalue},
+    lua_stack::LuaStack,
+    lua_table::LuaTable,
+    lua_thread::LuaThread,
+    lua_userdata::LuaUserData,
+    lua_value::LuaValue,
+};
+
+/// 两次自动回收之间至少分配的字节数
+const GCMINTHRESHOLD: usize = 256 * 1024;
+
+/// 默认的回收间隔：使用中的字节数达到上次回收后的 200% 时开始新的回收
+pub const LUAI_GCPAUSE: usize = 200;
+
+/// 默认的步进倍率
+pub const LUAI_GCMUL: usize = 100;
+
+/// 默认的步长：每分配 2^13 字节（8 KB）执行一步回收
+pub const LUAI_GCSTEPSIZE: usize = 13;
+
+/// 默认的次要回收间隔
+pub const LUAI_GENMINORMUL: usize = 20;
+
+/// 默认的主要回收间隔
+pub const LUAI_GENMAJORMUL: usize = 100;
+
+/// 步进倍率的单位：倍率为 1 时，每分配一个值的大小的字节完成一个字节的工作
+const WORK2MEM: usize = size_of::<LuaValue>();
+
+/// 清除阶段每一步检查的对象数
+const GCSWEEPMAX: usize = 100;
+
+/// 回收器管理的对象，以及组成闭包和线程的上值和栈。
+#[derive(Debug, Clone)]
+enum GcObject {
+    Table(Rc<RefCell<LuaTable>>),
+    Closure(Rc<Closure>),
+    Thread(Rc<RefCell<LuaThread>>),
+    UserData(Rc<LuaUserData>),
+    Upvalue(Rc<RefCell<Upvalue>>),
+    Stack(Rc<RefCell<Vec<LuaValue>>>),
+}
+
+/// 对 `GcObject` 的引用，遍历对象时不增加引用计数。
+#[derive(Clone, Copy)]
+enum GcRef<'a> {
+    Table(&'a Rc<RefCell<LuaTable>>),
+    Closure(&'a Rc<Closure>),
+    Thread(&'a Rc<RefCell<LuaThread>>),
+    UserData(&'a Rc<LuaUserData>),
+    Upvalue(&'a Rc<RefCell<Upvalue>>),
+    Stack(&'a Rc<RefCell<Vec<LuaValue>>>),
+}
+
+impl GcObject {
+    fn as_ref(&self) -> GcRef<'_> {
+        match self {
+            GcObject::Table(t) => GcRef::Table(t),
+            GcObject::Closure(c) => GcRef::Closure(c),
+            GcObject::Thread(t) => GcRef::Thread(t),
+            GcObject::UserData(u) => GcRef::UserData(u),
+            GcObject::Upvalue(uv) => GcRef::Upvalue(uv),
+            GcObject::Stack(s) => GcRef::Stack(s),
+        }
+    }
+
+    fn to_value(&self) -> Option<LuaValue> {
+        match self {
+            GcObject::Table(t) => Some(LuaValue::Table(t.clone())),
+            GcObject::Closure(c) => Some(LuaValue::Function(c.clone())),
+            GcObject::Thread(t) => Some(LuaValue::Thread(t.clone())),
+            GcObject::UserData(u) => Some(LuaValue::UserData(u.clone())),
+            GcObject::Upvalue(_) | GcObject::Stack(_) => None,
+        }
+    }
+
+    fn downgrade(&self) -> Option<WeakObject> {
+        match self {
+            GcObject::Table(t) => Some(WeakObject::Table(Rc::downgrade(t))),
+            GcObject::Closure(c) => Some(WeakObject::Closure(Rc::downgrade(c))),
+            GcObject::Thread(t) => Some(WeakObject::Thread(Rc::downgrade(t))),
+            GcObject::UserData(u) => Some(WeakObject::UserData(Rc::downgrade(u))),
+            GcObject::Upvalue(_) | GcObject::Stack(_) => None,
+        }
+    }
+
+    /// 清空不可达的对象，释放它引用的值。闭包没有可以清空的内容，它的上值会被单独清空；
+    /// 用户数据的 Rust 值不引用 Lua 值，留给最后一个引用释放。
+    fn clear(&self) {
+        match self {
+            GcObject::Table(t) => {
+                if let Ok(mut t) = t.try_borrow_mut() {
+                    *t = LuaTable::new(0, 0);
+                }
+            }
+            GcObject::Closure(_) => {}
+            GcObject::Thread(t) => {
+                if let Ok(mut t) = t.try_borrow_mut() {
+                    t.frames.clear();
+                }
+            }
+            GcObject::UserData(u) => {
+                if let Ok(mut mt) = u.metatable.try_borrow_mut() {
+                    *mt = None;
+                }
+                if let Ok(mut uvs) = u.user_values.try_borrow_mut() {
+                    uvs.iter_mut().for_each(|uv| *uv = LuaValue::Nil);
+                }
+            }
+            GcObject::Upvalue(uv) => {
+                if let Ok(mut uv) = uv.try_borrow_mut() {
+                    *uv = Upvalue::Closed(LuaValue::Nil);
+                }
+            }
+            GcObject::Stack(s) => {
+                if let Ok(mut s) = s.try_borrow_mut() {
+                    s.clear();
+                }
+            }
+        }
+    }
+}
+
+impl<'a> GcRef<'a> {
+    fn of(val: &'a LuaValue) -> Option<GcRef<'a>> {
+        match val {
+            LuaValue::Table(t) => Some(GcRef::Table(t)),
+            LuaValue::Function(c) => Some(GcRef::Closure(c)),
+            LuaValue::Thread(t) => Some(GcRef::Thread(t)),
+            LuaValue::UserData(u) => Some(GcRef::UserData(u)),
+            _ => None,
+        }
+    }
+
+    fn id(self) -> usize {
+        match self {
+            GcRef::Table(t) => Rc::as_ptr(t) as *const u8 as usize,
+            GcRef::Closure(c) => Rc::as_ptr(c) as *const u8 as usize,
+            GcRef::Thread(t) => Rc::as_ptr(t) as *const u8 as usize,
+            GcRef::UserData(u) => Rc::as_ptr(u) as *const u8 as usize,
+            GcRef::Upvalue(uv) => Rc::as_ptr(uv) as *const u8 as usize,
+            GcRef::Stack(s) => Rc::as_ptr(s) as *const u8 as usize,
+        }
+    }
+
+    fn strong_count(self) -> usize {
+        match self {
+            GcRef::Table(t) => Rc::strong_count(t),
+            GcRef::Closure(c) => Rc::strong_count(c),
+            GcRef::Thread(t) => Rc::strong_count(t),
+            GcRef::UserData(u) => Rc::strong_count(u),
+            GcRef::Upvalue(uv) => Rc::strong_count(uv),
+            GcRef::Stack(s) => Rc::strong_count(s),
+        }
+    }
+
+    fn to_owned(self) -> GcObject {
+        match self {
+            GcRef::Table(t) => GcObject::Table(t.clone()),
+            GcRef::Closure(c) => GcObject::Closure(c.clone()),
+            GcRef::Thread(t) => GcObject::Thread(t.clone()),
+            GcRef::UserData(u) => GcObject::UserData(u.clone()),
+            GcRef::Upvalue(uv) => GcObject::Upvalue(uv.clone()),
+            GcRef::Stack(s) => GcObject::Stack(s.clone()),
+        }
+    }
+
+    /// 上值和栈是闭包和线程的组成部分，不单独跟踪，在遍历闭包和线程时发现。
+    fn is_part(self) -> bool {
+        matches!(self, GcRef::Upvalue(_) | GcRef::Stack(_))
+    }
+
+    /// 估计对象自身占用的字节数，不包括它引用的其他对象。
+    fn mem_size(self) -> usize {
+        match self {
+            GcRef::Table(t) => t
+                .try_borrow()
+                .map_or(size_of::<LuaTable>(), |t| t.mem_size()),
+            GcRef::Closure(c) => {
+                size_of::<Closure>() + c.upvals.len() * size_of::<Rc<RefCell<Upvalue>>>()
+            }
+            GcRef::Thread(t) => {
+                let nframes = t.try_borrow().map_or(0, |t| t.frames.len());
+                size_of::<LuaThread>() + nframes * size_of::<LuaStack>()
+            }
+            GcRef::UserData(u) => u.mem_size(),
+            GcRef::Upvalue(_) => size_of::<Upvalue>(),
+            GcRef::Stack(s) => s
+                .try_borrow()
+                .map_or(0, |s| s.capacity() * size_of::<LuaValue>()),
+        }
+    }
+
+    /// 依次访问对象直接持有的每一个引用。对象正在被修改而无法访问时返回 `false`。
+    fn for_each_child(self, f: &mut dyn FnMut(GcRef<'_>)) -> bool {
+        match self {
+            GcRef::Table(t) => match t.try_borrow() {
+                Ok(t) => {
+                    t.for_each_value(|val| {
+                        if let Some(r) = GcRef::of(val) {
+                            f(r);
+                        }
+                    });
+                    if let Some(mt) = &t.metatable {
+                        f(GcRef::Table(mt));
+                    }
+                    true
+                }
+                Err(_) => false,
+            },
+            GcRef::Closure(c) => {
+                c.upvals.iter().for_each(|uv| f(GcRef::Upvalue(uv)));
+                true
+            }
+            GcRef::Thread(t) => match t.try_borrow() {
+                Ok(t) => {
+                    t.frames.iter().for_each(|frame| frame_children(frame, f));
+                    if let Some(r) = t.msgh.as_ref().and_then(GcRef::of) {
+                        f(r);
+                    }
+                    true
+                }
+                Err(_) => false,
+            },
+            GcRef::UserData(u) => match (u.metatable.try_borrow(), u.user_values.try_borrow()) {
+                (Ok(mt), Ok(uvs)) => {
+                    if let Some(mt) = &*mt {
+                        f(GcRef::Table(mt));
+                    }
+                    uvs.iter().filter_map(GcRef::of).for_each(f);
+                    true
+                }
+                _ => false,
+            },
+            GcRef::Upvalue(uv) => match uv.try_borrow() {
+                Ok(uv) => {
+                    match &*uv {
+                        Upvalue::Open(slots, _) => f(GcRef::Stack(slots)),
+                        Upvalue::Closed(val) => {
+                            if let Some(r) = GcRef::of(val) {
+                                f(r);
+                            }
+                        }
+                    }
+                    true
+                }
+                Err(_) => false,
+            },
+            GcRef::Stack(s) => match s.try_borrow() {
+                Ok(s) => {
+                    s.iter().filter_map(GcRef::of).for_each(f);
+                    true
+                }
+                Err(_) => false,
+            },
+        }
+    }
+}
+
+/// 依次访问栈帧持有的每一个引用：寄存器、注册表、闭包、变长参数、打开的上值和延续保存的消息处理函数。
+fn frame_children(frame: &LuaStack, f: &mut dyn FnMut(GcRef<'_>)) {
+    f(GcRef::Stack(frame.slots()));
+    if let Some(r) = GcRef::of(frame.registry()) {
+        f(r);
+    }
+    f(GcRef::Closure(&frame.closure));
+    frame.varargs.iter().filter_map(GcRef::of).for_each(&mut *f);
+    frame.openuvs.values().for_each(|uv| f(GcRef::Upvalue(uv)));
+    let msgh = frame.cont.as_ref().and_then(|c| c.old_msgh.as_ref());
+    if let Some(r) = msgh.and_then(GcRef::of) {
+        f(r);
+    }
+}
+
+#[derive(Debug)]
+enum WeakObject {
+    Table(Weak<RefCell<LuaTable>>),
+    Closure(Weak<Closure>),
+    Thread(Weak<RefCell<LuaThread>>),
+    UserData(Weak<LuaUserData>),
+}
+
+impl WeakObject {
+    fn upgrade(&self) -> Option<GcObject> {
+        match self {
+            WeakObject::Table(t) => t.upgrade().map(GcObject::Table),
+            WeakObject::Closure(c) => c.upgrade().map(GcObject::Closure),
+            WeakObject::Thread(t) => t.upgrade().map(GcObject::Thread),
+            WeakObject::UserData(u) => u.upgrade().map(GcObject::UserData),
+        }
+    }
+
+    fn id(&self) -> usize {
+        match self {
+            WeakObject::Table(t) => Weak::as_ptr(t) as *const u8 as usize,
+            WeakObject::Closure(c) => Weak::as_ptr(c) as *const u8 as usize,
+            WeakObject::Thread(t) => Weak::as_ptr(t) as *const u8 as usize,
+            WeakObject::UserData(u) => Weak::as_ptr(u) as *const u8 as usize,
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        match self {
+            WeakObject::Table(t) => t.strong_count() > 0,
+            WeakObject::Closure(c) => c.strong_count() > 0,
+            WeakObject::Thread(t) => t.strong_count() > 0,
+            WeakObject::UserData(u) => u.strong_count() > 0,
+        }
+    }
+}
+
+/// 标记的状态：已经标记的对象，以及标记了但还没有遍历的对象。
+#[derive(Default)]
+pub struct Marker {
+    marked: HashSet<usize>,
+    gray: Vec<GcObject>,
+    /// 遍历过的线程。线程的栈没有写屏障，原子阶段要重新遍历它们。
+    threads: Vec<GcObject>,
+    /// 标记的对象的字节数
+    bytes: usize,
+    /// 遍历过的弱表，标记结束后清除其中被回收的项
+    weak: HashMap<usize, Rc<RefCell<LuaTable>>>,
+}
+
+impl Marker {
+    fn mark(&mut self, r: GcRef<'_>) {
+        if self.marked.insert(r.id()) {
+            self.bytes += r.mem_size();
+            self.gray.push(r.to_owned());
+        }
+    }
+
+    /// 无论是否已经标记，都再遍历一次对象。
+    fn gray_again(&mut self, r: GcRef<'_>) {
+        if self.marked.insert(r.id()) {
+            self.bytes += r.mem_size();
+        }
+        self.gray.push(r.to_owned());
+    }
+
+    /// 值是否已经标记。不是回收器管理的对象的值总是当作已经标记的。
+    fn is_marked(&self, val: &LuaValue) -> bool {
+        GcRef::of(val).is_none_or(|r| self.marked.contains(&r.id()))
+    }
+
+    pub fn mark_value(&mut self, val: &LuaValue) {
+        if let Some(r) = GcRef::of(val) {
+            self.mark(r);
+        }
+    }
+
+    pub fn mark_frame(&mut self, frame: &LuaStack) {
+        frame_children(frame, &mut |r| self.mark_frame_child(r));
+    }
+
+    pub fn mark_thread(&mut self, thread: &Rc<RefCell<LuaThread>>) {
+        self.gray_again(GcRef::Thread(thread));
+    }
+
+    fn mark_frame_child(&mut self, r: GcRef<'_>) {
+        match r {
+            // registers are written without barriers
+            GcRef::Stack(_) => self.gray_again(r),
+            _ => self.mark(r),
+        }
+    }
+
+    /// 遍历一个灰色对象，返回遍历的字节数；没有灰色对象时返回 `None`。
+    fn propagate_one(&mut self) -> Option<usize> {
+        let obj = self.gray.pop()?;
+        let r = obj.as_ref();
+        match r {
+            GcRef::Thread(_) => {
+                r.for_each_child(&mut |r| self.mark_frame_child(r));
+                self.threads.push(obj.clone());
+            }
+            GcRef::Table(t) => self.traverse_table(t),
+            _ => {
+                r.for_each_child(&mut |r| self.mark(r));
+            }
+        }
+        Some(r.mem_size())
+    }
+
+    /// 遍历表。弱表的弱引用部分不标记：弱值表只标记键；弱键表的值只在键被标记后才标记（ephemeron）。
+    fn traverse_table(&mut self, t: &Rc<RefCell<LuaTable>>) {
+        let Ok(tbl) = t.try_borrow() else {
+            return;
+        };
+        let (weakk, weakv) = tbl.weak_mode();
+        if !weakk && !weakv {
+            GcRef::Table(t).for_each_child(&mut |r| self.mark(r));
+            return;
+        }
+        if let Some(mt) = &tbl.metatable {
+            self.mark(GcRef::Table(mt));
+        }
+        tbl.for_each_entry(|key, val| {
+            if !weakk {
+                self.mark_value(key);
+            }
+            if !weakv && self.is_marked(key) {
+                self.mark_value(val);
+            }
+        });
+        self.weak.insert(GcRef::Table(t).id(), t.clone());
+    }
+
+    /// 标记弱键表中键已经被标记的值，返回是否标记了新的值。
+    fn converge_ephemerons(&mut self) -> bool {
+        let mut changed = false;
+        let tables: Vec<_> = self.weak.values().cloned().collect();
+        for t in tables {
+            let Ok(tbl) = t.try_borrow() else {
+                continue;
+            };
+            if tbl.weak_mode() != (true, false) {
+                continue;
+            }
+            tbl.for_each_entry(|key, val| {
+                if self.is_marked(key) && !self.is_marked(val) {
+                    self.mark_value(val);
+                    changed = true;
+                }
+            });
+        }
+        changed
+    }
+
+    /// 遍历所有的灰色对象，直到弱键表中不再有新的值需要标记。
+    fn propagate(&mut self) {
+        loop {
+            while self.propagate_one().is_some() {}
+            if !self.converge_ephemerons() {
+                break;
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for Marker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Marker")
+            .field("marked", &self.marked.len())
+            .field("gray", &self.gray.len())
+            .field("weak", &self.weak.len())
+            .finish()
+    }
+}
+
+/// 回收周期的阶段
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GcPhase {
+    /// 两次回收之间
+    Pause,
+    /// 逐步遍历灰色对象
+    Propagate,
+    /// 逐步检查本周期开始前创建的对象，找出没有标记的
+    Sweep,
+    /// 逐步清空不可达的对象
+    Clear,
+}
+
+/// 回收器的模式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcMode {
+    Incremental,
+    Generational,
+}
+
+/// 增量的标记-清除垃圾回收器，也可以切换到分代模式。
+///
+/// 值仍然由 `Rc` 持有，没有引用环的对象在最后一个引用消失时立即释放。回收器跟踪状态创建的表、闭包、线程和用户数据，
+/// 从根出发标记可达的对象，然后清空不可达的对象，打破它们之间的引用环，让 `Rc` 释放它们。
+/// 增量模式下回收分成多步进行，与程序的运行交替：
+/// * 标记阶段逐步遍历灰色对象。表和用户数据的写屏障把被修改的已遍历的对象重新变成灰色，原子阶段再遍历它们；
+///   栈没有写屏障，原子阶段重新遍历所有的根和线程。回收期间创建的对象直接当作已经标记的。
+/// * 清除阶段逐步检查周期开始前创建的对象，收集没有标记的对象，然后一次数清它们之间的引用。
+///   引用计数大于这个数的对象被堆以外（比如正在运行的 Rust 代码）或者已标记的对象持有，
+///   它们和它们引用的对象不会被回收。这一步保证了没有被屏障覆盖的写入（比如上值）也不会导致错误的回收。
+/// * 最后逐步清空剩下的不可达对象。
+///
+/// 元表中有 `__mode` 字段的表是弱表。标记时不经过弱表的弱引用，数引用时也不把它们当作外部的引用；
+/// 不可达的对象确定后，删除弱表中引用它们的项。
+///
+/// 设置元表时元表中有 `__gc` 字段的对象被标记为需要终结，回收器持有它们，`Rc` 不会释放它们。
+/// 它们不可达时不被清空，而是和它们引用的对象一起复活，等待状态调用终结器；之后它们成为普通的对象。
+///
+/// 分代模式下对象分为新生、存活和老年三代，每次回收都一次完成：
+/// * 次要回收把老年对象当作已经标记的，只遍历根、新生和存活对象，以及记忆集中的表：
+///   表的写屏障把被修改的老年表加入记忆集。存活下来的新生对象成为存活对象，存活对象成为老年对象。
+/// * 使用中的字节数超过上次主要回收后的 (100 + `genmajormul`)% 时执行主要回收：
+///   一次完整的回收，之后所有存活的对象都成为老年对象。
+#[derive(Debug)]
+pub struct Gc {
+    mode: GcMode,
+    /// 跟踪的对象，其中可能有已经被 `Rc` 释放的。增量模式的回收期间只包括已经确定存活的对象，
+    /// 分代模式下只包括老年对象。
+    objects: Vec<WeakObject>,
+    /// 上次清理 `objects` 后它的长度
+    last_len: usize,
+    phase: GcPhase,
+    /// 本周期开始前跟踪的对象
+    old: Vec<WeakObject>,
+    /// 清除阶段在 `old` 中的位置
+    cursor: usize,
+    /// 增量模式下是本周期的标记状态；分代模式下它标记的对象就是老年对象
+    marker: Marker,
+    /// 标记阶段被修改的已标记的表，分代模式下是记忆集
+    grayagain: Vec<GcObject>,
+    touched: HashSet<usize>,
+    /// 清除阶段找到的没有标记的对象
+    white: HashMap<usize, GcObject>,
+    /// 等待清空的不可达对象
+    condemned: Vec<GcObject>,
+    /// 本周期开始后新分配的字节数
+    new_bytes: usize,
+    /// 估计的使用中的字节数
+    total_bytes: usize,
+    /// 距离下一步回收还可以分配的字节数的相反数，大于 0 时执行一步回收
+    debt: isize,
+    /// 是否停止了自动回收
+    stopped: bool,
+    /// 回收间隔：使用中的字节数达到上次回收后的 `pause`% 时开始新的周期
+    pause: usize,
+    /// 步进倍率：每一步完成的工作量（遍历的字节数）与两步之间分配的字节数的比例，单位见 `WORK2MEM`
+    stepmul: usize,
+    /// 步长：每分配 2^`stepsize` 字节执行一步回收
+    stepsize: usize,
+    /// 上次回收后创建的对象
+    young: Vec<WeakObject>,
+    /// 经历过一次次要回收的对象
+    survival: Vec<WeakObject>,
+    /// 估计的老年对象的字节数
+    old_bytes: usize,
+    /// 上次主要回收后使用中的字节数
+    major_base: usize,
+    /// 标记为需要终结的对象，按标记的顺序排列
+    finobj: Vec<GcObject>,
+    finobj_ids: HashSet<usize>,
+    /// 已经不可达、等待调用终结器的对象，按调用的顺序排列
+    tobefnz: Vec<GcObject>,
+    /// 上次回收遍历过的弱表，次要回收总是重新遍历它们
+    weak_tables: Vec<Weak<RefCell<LuaTable>>>,
+    /// 次要回收的间隔：每分配使用中的字节数的 `genminormul`% 执行一次次要回收
+    genminormul: usize,
+    /// 主要回收的间隔
+    genmajormul: usize,
+}
+
+impl Gc {
+    pub fn new() -> Gc {
+        Gc {
+            mode: GcMode::Incremental,
+            objects: Vec::new(),
+            last_len: 0,
+            phase: GcPhase::Pause,
+            old: Vec::new(),
+            cursor: 0,
+            marker: Marker::default(),
+            grayagain: Vec::new(),
+            touched: HashSet::new(),
+            white: HashMap::new(),
+            condemned: Vec::new(),
+            new_bytes: 0,
+            total_bytes: 0,
+            debt: -(GCMINTHRESHOLD as isize),
+            stopped: false,
+            pause: LUAI_GCPAUSE,
+            stepmul: LUAI_GCMUL,
+            stepsize: LUAI_GCSTEPSIZE,
+            young: Vec::new(),
+            survival: Vec::new(),
+            old_bytes: 0,
+            major_base: 0,
+            finobj: Vec::new(),
+            finobj_ids: HashSet::new(),
+            tobefnz: Vec::new(),
+            weak_tables: Vec::new(),
+            genminormul: LUAI_GENMINORMUL,
+            genmajormul: LUAI_GENMAJORMUL,
+        }
+    }
+
+    /// 跟踪新创建的表、闭包、线程或用户数据，其他值被忽略。
+    pub fn track(&mut self, val: &LuaValue) {
+        let obj = match val {
+            LuaValue::Table(t) => WeakObject::Table(Rc::downgrade(t)),
+            LuaValue::Function(c) => WeakObject::Closure(Rc::downgrade(c)),
+            LuaValue::Thread(t) => WeakObject::Thread(Rc::downgrade(t)),
+            LuaValue::UserData(u) => WeakObject::UserData(Rc::downgrade(u)),
+            _ => return,
+        };
+        let size = GcRef::of(val).map_or(0, GcRef::mem_size);
+        self.total_bytes += size;
+        self.new_bytes += size;
+        self.debt += size as isize;
+        if self.mode == GcMode::Generational {
+            // the address may have belonged to an old object freed by reference counting
+            self.marker.marked.remove(&obj.id());
+            self.young.push(obj);
+            return;
+        }
+        if self.phase != GcPhase::Pause {
+            // objects created during a cycle survive it
+            self.marker.marked.insert(obj.id());
+        }
+        self.objects.push(obj);
+        if self.objects.len() >= (self.last_len * 2).max(1024) {
+            // forget objects already freed by reference counting
+            self.objects.retain(WeakObject::is_alive);
+            self.last_len = self.objects.len();
+        }
+    }
+
+    /// 表的写屏障：标记阶段修改已经遍历过的表时，让原子阶段再遍历它一次；
+    /// 分代模式下修改老年表时，把它加入记忆集。
+    pub fn barrier_back(&mut self, t: &Rc<RefCell<LuaTable>>) {
+        self.barrier(GcRef::Table(t));
+    }
+
+    /// 用户数据的写屏障，修改元表或用户值后调用，与表的写屏障相同。
+    pub fn barrier_userdata(&mut self, u: &Rc<LuaUserData>) {
+        self.barrier(GcRef::UserData(u));
+    }
+
+    fn barrier(&mut self, r: GcRef<'_>) {
+        if (self.phase == GcPhase::Propagate || self.mode == GcMode::Generational)
+            && self.marker.marked.contains(&r.id())
+            && self.touched.insert(r.id())
+        {
+            self.grayagain.push(r.to_owned());
+        }
+    }
+
+    /// 设置元表后调用：元表中有 `__gc` 字段时把对象标记为需要终结。
+    pub fn check_finalizer(&mut self, val: &LuaValue) {
+        if let Some(r) = GcRef::of(val) {
+            if self.finobj_ids.insert(r.id()) {
+                self.finobj.push(r.to_owned());
+            }
+        }
+    }
+
+    /// 取出等待调用终结器的对象。
+    pub fn take_finalizable(&mut self) -> Vec<LuaValue> {
+        let objs = std::mem::take(&mut self.tobefnz);
+        objs.iter().filter_map(GcObject::to_value).collect()
+    }
+
+    /// 取出所有等待调用终结器和标记为需要终结的对象，无论它们是否可达。用于关闭状态。
+    pub fn take_all_finalizable(&mut self) -> Vec<LuaValue> {
+        let mut objs = std::mem::take(&mut self.tobefnz);
+        objs.extend(self.finobj.drain(..).rev());
+        self.finobj_ids.clear();
+        objs.iter().filter_map(GcObject::to_value).collect()
+    }
+
+    /// 估计的使用中的字节数
+    pub fn total_bytes(&self) -> usize {
+        self.total_bytes
+    }
+
+    /// 是否应该执行一步自动回收
+    pub fn should_step(&self) -> bool {
+        !self.stopped && self.debt > 0
+    }
+
+    pub fn is_running(&self) -> bool {
+        !self.stopped
+    }
+
+    pub fn set_stopped(&mut self, stopped: bool) {
+        self.stopped = stopped;
+    }
+
+    pub fn mode(&self) -> GcMode {
+        self.mode
+    }
+
+    /// 切换到增量模式并设置它的参数，为 0 的参数保持不变。
+    pub fn set_incremental(&mut self, pause: usize, stepmul: usize, stepsize: usize) {
+        if pause != 0 {
+            self.pause = pause;
+        }
+        if stepmul != 0 {
+            self.stepmul = stepmul;
+        }
+        if stepsize != 0 {
+            self.stepsize = stepsize.min(usize::BITS as usize - 2);
+        }
+        if self.mode == GcMode::Generational {
+            // every object takes part in the next cycle
+            self.mode = GcMode::Incremental;
+            self.objects.append(&mut self.young);
+            self.objects.append(&mut self.survival);
+            self.last_len = self.objects.len();
+            self.marker = Marker::default();
+            self.grayagain.clear();
+            self.touched.clear();
+            self.weak_tables.clear();
+            self.set_pause_debt();
+        }
+    }
+
+    /// 切换到分代模式并设置它的参数，为 0 的参数保持不变。从增量模式切换时执行一次完整的回收。
+    pub fn set_generational(
+        &mut self,
+        minormul: usize,
+        majormul: usize,
+        mark_roots: &dyn Fn(&mut Marker),
+    ) {
+        if minormul != 0 {
+            self.genminormul = minormul;
+        }
+        if majormul != 0 {
+            self.genmajormul = majormul;
+        }
+        if self.mode == GcMode::Incremental {
+            // finish the current cycle before changing the rules
+            while self.phase != GcPhase::Pause {
+                self.single_step(mark_roots);
+            }
+            self.mode = GcMode::Generational;
+            self.full_gen(mark_roots);
+        }
+    }
+
+    /// 设置回收间隔，返回原来的值。
+    pub fn set_pause(&mut self, pause: usize) -> usize {
+        std::mem::replace(&mut self.pause, pause)
+    }
+
+    /// 设置步进倍率，返回原来的值。
+    pub fn set_stepmul(&mut self, stepmul: usize) -> usize {
+        std::mem::replace(&mut self.stepmul, stepmul)
+    }
+
+    /// 执行一步回收，完成的工作量由步长和步进倍率决定，`extra` 是额外的工作量。
+    /// 分代模式下执行一次次要或者主要回收。`mark_roots` 标记状态持有的根。
+    ///
+    /// 返回值：这一步是否完成了一个回收周期。
+    pub fn step(&mut self, extra: usize, mark_roots: &dyn Fn(&mut Marker)) -> bool {
+        if self.mode == GcMode::Generational {
+            let limit = (self.major_base / 100).saturating_mul(100 + self.genmajormul);
+            if self.total_bytes > limit {
+                self.full_gen(mark_roots);
+            } else {
+                self.young_collection(mark_roots);
+            }
+            return true;
+        }
+        let stepsize = 1usize << self.stepsize;
+        let mut budget = (stepsize / WORK2MEM)
+            .saturating_mul(self.stepmul.max(1))
+            .saturating_add(extra);
+        loop {
+            let work = self.single_step(mark_roots);
+            if self.phase == GcPhase::Pause {
+                return true;
+            }
+            if work >= budget {
+                break;
+            }
+            budget -= work;
+        }
+        self.debt = -(stepsize as isize);
+        false
+    }
+
+    /// 完成正在进行的回收周期，再执行一个完整的周期。
+    pub fn full_collect(&mut self, mark_roots: &dyn Fn(&mut Marker)) {
+        if self.mode == GcMode::Generational {
+            self.full_gen(mark_roots);
+            return;
+        }
+        // objects created during the current cycle are only collected by the next one
+        while self.phase != GcPhase::Pause {
+            self.single_step(mark_roots);
+        }
+        self.single_step(mark_roots);
+        while self.phase != GcPhase::Pause {
+            self.single_step(mark_roots);
+        }
+    }
+
+    /// 执行回收中的一个小步骤，返回完成的工作量。
+    fn single_step(&mut self, mark_roots: &dyn Fn(&mut Marker)) -> usize {
+        match self.phase {
+            GcPhase::Pause => {
+                self.old = std::mem::take(&mut self.objects);
+                self.last_len = 0;
+                self.cursor = 0;
+                self.new_bytes = 0;
+                mark_roots(&mut self.marker);
+                self.phase = GcPhase::Propagate;
+                0
+            }
+            GcPhase::Propagate => match self.marker.propagate_one() {
+                Some(work) => work,
+                None => {
+                    self.atomic(mark_roots);
+                    self.phase = GcPhase::Sweep;
+                    0
+                }
+            },
+            GcPhase::Sweep => {
+                let end = (self.cursor + GCSWEEPMAX).min(self.old.len());
+                for i in self.cursor..end {
+                    if let Some(obj) = self.old[i].upgrade() {
+                        let id = obj.as_ref().id();
+                        if self.marker.marked.contains(&id) {
+                            self.objects.push(std::mem::replace(
+                                &mut self.old[i],
+                                WeakObject::Table(Weak::new()),
+                            ));
+                        } else {
+                            self.white.insert(id, obj);
+                        }
+                    }
+                }
+                let work = (end - self.cursor) * size_of::<WeakObject>();
+                self.cursor = end;
+                if self.cursor == self.old.len() {
+                    self.old = Vec::new();
+                    let survivors = self.settle();
+                    self.objects.extend(survivors);
+                    self.phase = GcPhase::Clear;
+                }
+                work
+            }
+            GcPhase::Clear => match self.condemned.pop() {
+                Some(obj) => {
+                    let work = obj.as_ref().mem_size();
+                    obj.clear();
+                    work
+                }
+                None => {
+                    self.finish_cycle();
+                    0
+                }
+            },
+        }
+    }
+
+    /// 原子阶段：重新遍历根、线程和标记阶段被修改的表，完成标记。
+    fn atomic(&mut self, mark_roots: &dyn Fn(&mut Marker)) {
+        mark_roots(&mut self.marker);
+        let threads = std::mem::take(&mut self.marker.threads);
+        for t in &threads {
+            self.marker.gray_again(t.as_ref());
+        }
+        drop(threads);
+        self.marker.gray.append(&mut self.grayagain);
+        self.touched.clear();
+        self.marker.propagate();
+    }
+
+    /// 数清没有标记的对象之间的引用，标记被其他地方持有的对象和它们引用的对象，其余的等待清空。
+    ///
+    /// 返回值：被标记的跟踪的对象。
+    fn settle(&mut self) -> Vec<WeakObject> {
+        // the white objects and the white upvalues and stacks they are made of
+        let mut nodes = std::mem::take(&mut self.white);
+        let mut pending: Vec<usize> = nodes.keys().copied().collect();
+        let mut internal: HashMap<usize, usize> = HashMap::new();
+        let mut busy = Vec::new();
+        let marked = &self.marker.marked;
+        while let Some(id) = pending.pop() {
+            let obj = nodes[&id].clone();
+            let visited = obj.as_ref().for_each_child(&mut |r| {
+                if marked.contains(&r.id()) {
+                    return;
+                }
+                *internal.entry(r.id()).or_insert(0) += 1;
+                if r.is_part() && !nodes.contains_key(&r.id()) {
+                    nodes.insert(r.id(), r.to_owned());
+                    pending.push(r.id());
+                }
+            });
+            if !visited {
+                busy.push(id);
+            }
+        }
+
+        // the weak parts of weak tables do not keep anything alive
+        for t in self.marker.weak.values() {
+            let Ok(tbl) = t.try_borrow() else {
+                continue;
+            };
+            let (weakk, weakv) = tbl.weak_mode();
+            tbl.for_each_entry(|key, val| {
+                if let Some(r) = GcRef::of(key).filter(|r| weakk && !marked.contains(&r.id())) {
+                    // held by both the index and the entry of the hash part
+                    *internal.entry(r.id()).or_insert(0) += 2;
+                }
+                let weak_val =
+                    weakv || (weakk && GcRef::of(key).is_some_and(|r| !marked.contains(&r.id())));
+                if let Some(r) = GcRef::of(val).filter(|r| weak_val && !marked.contains(&r.id())) {
+                    *internal.entry(r.id()).or_insert(0) += 1;
+                }
+            });
+        }
+
+        // neither does the list of objects marked for finalization
+        for obj in &self.finobj {
+            let id = obj.as_ref().id();
+            if !marked.contains(&id) {
+                *internal.entry(id).or_insert(0) += 1;
+            }
+        }
+
+        // nodes referenced from anywhere else are alive, and so are the ones that
+        // could not be visited (the extra count is the copy held by 'nodes')
+        let external: Vec<usize> = nodes
+            .iter()
+            .filter(|(id, obj)| {
+                obj.as_ref().strong_count() > internal.get(id).copied().unwrap_or(0) + 1
+            })
+            .map(|(id, _)| *id)
+            .chain(busy)
+            .collect();
+        for id in external {
+            self.marker.mark(nodes[&id].as_ref());
+        }
+        self.marker.propagate();
+
+        // weak values are cleared before finalizers resurrect anything
+        let unreachable: HashSet<usize> = nodes
+            .keys()
+            .filter(|id| !self.marker.marked.contains(id))
+            .copied()
+            .collect();
+        self.separate_finalizable();
+
+        let mut survivors = Vec::new();
+        for (id, obj) in nodes {
+            if !self.marker.marked.contains(&id) {
+                self.condemned.push(obj);
+            } else if let Some(weak) = obj.downgrade() {
+                survivors.push(weak);
+            }
+        }
+        self.clear_weak_tables(&unreachable);
+        survivors
+    }
+
+    /// 把不可达的需要终结的对象移到等待调用终结器的列表中，后标记的先终结，然后复活它们引用的对象。
+    fn separate_finalizable(&mut self) {
+        let marked = &self.marker.marked;
+        let (dead, live): (Vec<_>, Vec<_>) = std::mem::take(&mut self.finobj)
+            .into_iter()
+            .partition(|obj| !marked.contains(&obj.as_ref().id()));
+        self.finobj = live;
+        for obj in dead.into_iter().rev() {
+            self.finobj_ids.remove(&obj.as_ref().id());
+            self.marker.mark(obj.as_ref());
+            self.tobefnz.push(obj);
+        }
+        self.marker.propagate();
+    }
+
+    /// 删除弱表中引用不可达对象的项：值在复活之前不可达的，键在复活之后仍然不可达的。
+    fn clear_weak_tables(&mut self, unreachable: &HashSet<usize>) {
+        if unreachable.is_empty() {
+            return;
+        }
+        let dead: HashSet<usize> = self.condemned.iter().map(|obj| obj.as_ref().id()).collect();
+        let dead_key = |key: &LuaValue| GcRef::of(key).is_some_and(|r| dead.contains(&r.id()));
+        let dead_val =
+            |val: &LuaValue| GcRef::of(val).is_some_and(|r| unreachable.contains(&r.id()));
+        for t in self.marker.weak.values() {
+            let mode = t.try_borrow().map(|tbl| tbl.weak_mode());
+            if let (Ok((weakk, weakv)), Ok(mut tbl)) = (mode, t.try_borrow_mut()) {
+                tbl.clear_weak(|key| weakk && dead_key(key), |val| weakv && dead_val(val));
+            }
+        }
+    }
+
+    /// 结束回收周期，根据存活的字节数决定下一个周期什么时候开始。
+    fn finish_cycle(&mut self) {
+        self.total_bytes = self.marker.bytes + self.new_bytes;
+        self.marker = Marker::default();
+        self.last_len = self.objects.len();
+        self.phase = GcPhase::Pause;
+        self.set_pause_debt();
+    }
+
+    /// 使用中的字节数达到现在的 `pause`% 时开始下一个周期。
+    fn set_pause_debt(&mut self) {
+        let threshold = (self.total_bytes / 100)
+            .saturating_mul(self.pause)
+            .max(GCMINTHRESHOLD);
+        self.debt = self.total_bytes as isize - threshold as isize;
+    }
+
+    /// 再分配使用中的字节数的 `genminormul`% 后执行下一次次要回收。
+    fn set_minor_debt(&mut self) {
+        let allowance = (self.total_bytes / 100)
+            .saturating_mul(self.genminormul)
+            .max(1 << LUAI_GCSTEPSIZE);
+        self.debt = -(allowance as isize);
+    }
+
+    /// 分代模式的主要回收：执行一次完整的回收，所有存活的对象都成为老年对象。
+    fn full_gen(&mut self, mark_roots: &dyn Fn(&mut Marker)) {
+        self.objects.append(&mut self.young);
+        self.objects.append(&mut self.survival);
+        self.grayagain.clear();
+        self.touched.clear();
+        self.marker = Marker::default();
+        self.single_step(mark_roots);
+        while self.phase != GcPhase::Clear {
+            self.single_step(mark_roots);
+        }
+        self.condemned.drain(..).for_each(|obj| obj.clear());
+        self.marker.gray.clear();
+        self.marker.threads.clear();
+        self.keep_weak_tables();
+        self.total_bytes = self.marker.bytes;
+        self.marker.bytes = 0;
+        self.old_bytes = self.total_bytes;
+        self.major_base = self.total_bytes;
+        self.last_len = self.objects.len();
+        self.phase = GcPhase::Pause;
+        self.set_minor_debt();
+    }
+
+    /// 记住遍历过的弱表，但不让它们因此存活。
+    fn keep_weak_tables(&mut self) {
+        self.weak_tables = self
+            .marker
+            .weak
+            .drain()
+            .map(|(_, t)| Rc::downgrade(&t))
+            .collect();
+    }
+
+    /// 分代模式的次要回收。
+    fn young_collection(&mut self, mark_roots: &dyn Fn(&mut Marker)) {
+        mark_roots(&mut self.marker);
+        // old tables modified since the last collection, and the weak tables whose
+        // entries may refer to young objects
+        self.marker.gray.append(&mut self.grayagain);
+        for t in std::mem::take(&mut self.weak_tables) {
+            if let Some(t) = t.upgrade() {
+                self.marker.gray_again(GcRef::Table(&t));
+            }
+        }
+        self.touched.clear();
+        self.marker.propagate();
+        self.marker.threads.clear();
+
+        let mut survival = Vec::new();
+        for weak in std::mem::take(&mut self.young) {
+            if let Some(obj) = weak.upgrade() {
+                let id = obj.as_ref().id();
+                if self.marker.marked.contains(&id) {
+                    survival.push(weak);
+                } else {
+                    self.white.insert(id, obj);
+                }
+            }
+        }
+        let mut promoted = 0;
+        for weak in std::mem::take(&mut self.survival) {
+            if let Some(obj) = weak.upgrade() {
+                let id = obj.as_ref().id();
+                if self.marker.marked.contains(&id) {
+                    promoted += obj.as_ref().mem_size();
+                    self.objects.push(weak);
+                } else {
+                    self.white.insert(id, obj);
+                }
+            }
+        }
+        // objects kept alive only by references the collector does not see survive once more
+        survival.extend(self.settle());
+        for weak in &survival {
+            self.marker.marked.remove(&weak.id());
+        }
+        self.survival = survival;
+        self.condemned.drain(..).for_each(|obj| obj.clear());
+        self.keep_weak_tables();
+
+        self.total_bytes = self.old_bytes + self.marker.bytes;
+        self.marker.bytes = 0;
+        self.old_bytes += promoted;
+        if self.objects.len() >= (self.last_len * 2).max(1024) {
+            self.objects.retain(WeakObject::is_alive);
+            self.last_len = self.objects.len();
+        }
+        self.set_minor_debt();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        api::{
+            consts::{
+                LUA_GCCOLLECT, LUA_GCCOUNT, LUA_GCGEN, LUA_GCINC, LUA_GCSTEP, LUA_GCSTOP, LUA_OK,
+            },
+            LuaAPI,
+        },
+        state::{new_lua_state, LuaState},
+    };
+
+    use super::*;
+
+    fn weak_table(val: &LuaValue) -> Weak<RefCell<LuaTable>> {
+        match val {
+            LuaValue::Table(t) => Rc::downgrade(t),
+            _ => panic!("table expected!"),
+        }
+    }
+
+    #[test]
+    fn test_collect_cycles() {
+        let src = r#"
+            local t = {}
+            t.self = t
+            local a, b = {}, {}
+            a.b, b.a = b, a
+            local obj = {}
+            function obj.get() return obj end
+            return t, a, obj
+        "#;
+        let mut ls = new_lua_state();
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        ls.call(0, 3).unwrap();
+        let weak: Vec<_> = (1..=3).map(|i| weak_table(&ls.stack().get(i))).collect();
+        ls.set_top(0);
+        assert!(weak.iter().all(|w| w.upgrade().is_some()));
+        ls.gc(LUA_GCCOLLECT, &[]);
+        assert!(weak.iter().all(|w| w.upgrade().is_none()));
+    }
+
+    #[test]
+    fn test_free_deep_chains() {
+        // freeing long chains must not recurse once per link
+        let src = r#"
+            local function build()
+                local head
+                for i = 1, 1e6 do head = { next = head } end
+            end
+            build()
+            local f = function() return 0 end
+            for i = 1, 1e5 do
+                local g = f
+                f = function() return g() end
+            end
+            f = nil
+        "#;
+        let mut ls = new_lua_state();
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        ls.call(0, 0).unwrap();
+        ls.gc(LUA_GCCOLLECT, &[]);
+        assert!(ls.gc(LUA_GCCOUNT, &[]) < 1024);
+    }
+
+    #[test]
+    fn test_keep_reachable() {
+        let src = r#"
+            local t = { n = 1 }
+            t.self = t
+            keep = { t = t }
+            local held = { list = { 1, 2, 3 } }
+            held.list.back = held
+            return held
+        "#;
+        let mut ls = new_lua_state();
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        ls.call(0, 1).unwrap();
+        // a value held only by Rust code survives a collection
+        let held = ls.stack().get(-1);
+        ls.pop(1);
+        ls.gc(LUA_GCCOLLECT, &[]);
+        ls.stack_mut().push(held);
+        ls.get_field(-1, "list").unwrap();
+        assert_eq!(ls.raw_len(-1), 3);
+        ls.get_global("keep").unwrap();
+        ls.get_field(-1, "t").unwrap();
+        ls.get_field(-1, "n").unwrap();
+        assert_eq!(ls.to_integer(-1), 1);
+    }
+
+    #[test]
+    fn test_count() {
+        let src = "for i = 1, 1000 do local t = {} t[1] = t end";
+        let mut ls = new_lua_state();
+        ls.gc(LUA_GCSTOP, &[]);
+        let before = ls.gc(LUA_GCCOUNT, &[]);
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        ls.call(0, 0).unwrap();
+        let grown = ls.gc(LUA_GCCOUNT, &[]);
+        assert!(grown > before);
+        ls.gc(LUA_GCCOLLECT, &[]);
+        assert!(ls.gc(LUA_GCCOUNT, &[]) < grown);
+    }
+
+    #[test]
+    fn test_incremental() {
+        let src = r#"
+            local t = {}
+            t.self = t
+            return t
+        "#;
+        let mut ls = new_lua_state();
+        ls.gc(LUA_GCSTOP, &[]);
+        // the smallest step size: every step does a single unit of work
+        ls.gc(LUA_GCINC, &[0, 1, 1]);
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        ls.call(0, 1).unwrap();
+        let weak = weak_table(&ls.stack().get(-1));
+        ls.set_top(0);
+        let mut steps = 1;
+        while ls.gc(LUA_GCSTEP, &[0]) == 0 {
+            steps += 1;
+        }
+        assert!(steps > 1);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_barrier() {
+        // move values from a table not traversed yet to one already traversed
+        // while the collector runs in small steps
+        let src = r#"
+            local src, dst = {}, {}
+            for i = 1, 100 do src[i] = { id = i } end
+            for i = 1, 100 do
+                dst[i], src[i] = src[i], nil
+                for _ = 1, 10 do local g = {} g.self = g end
+            end
+            local sum = 0
+            for i = 1, 100 do sum = sum + dst[i].id end
+            return sum
+        "#;
+        let mut ls = new_lua_state();
+        ls.gc(LUA_GCINC, &[0, 1, 1]);
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        ls.call(0, 1).unwrap();
+        assert_eq!(ls.to_integer(-1), 5050);
+    }
+
+    #[test]
+    fn test_generational() {
+        let mut ls = new_lua_state();
+        assert_eq!(ls.gc(LUA_GCGEN, &[]), LUA_GCINC as isize);
+        let src = "local old = { list = {} } old.self = old return old";
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        ls.call(0, 1).unwrap();
+        let old = weak_table(&ls.stack().get(1));
+        // two minor collections make it old
+        ls.gc(LUA_GCSTEP, &[0]);
+        ls.gc(LUA_GCSTEP, &[0]);
+
+        // young values stored only in an old table
+        let src = r#"
+            local old = ...
+            for i = 1, 10000 do
+                local t = { i = i }
+                t.self = t
+                if i % 100 == 0 then old.list[#old.list + 1] = t end
+            end
+            local sum = 0
+            for i = 1, #old.list do sum = sum + old.list[i].self.i end
+            return sum
+        "#;
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        ls.push_value(1);
+        ls.call(1, 1).unwrap();
+        assert_eq!(ls.to_integer(2), 505000);
+
+        // minor collections free young cycles
+        ls.set_top(0);
+        let mut grown = ls.gc(LUA_GCCOUNT, &[]);
+        let src = "for i = 1, 1000 do local t = {} t.self = t end";
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        ls.call(0, 0).unwrap();
+        assert_eq!(ls.gc(LUA_GCSTEP, &[0]), 1);
+        assert!(ls.gc(LUA_GCCOUNT, &[]) <= grown + 1);
+
+        // old cycles wait for a major collection
+        assert!(old.upgrade().is_some());
+        grown = ls.gc(LUA_GCCOUNT, &[]);
+        ls.gc(LUA_GCCOLLECT, &[]);
+        assert!(old.upgrade().is_none());
+        assert!(ls.gc(LUA_GCCOUNT, &[]) < grown);
+        assert_eq!(ls.gc(LUA_GCINC, &[]), LUA_GCGEN as isize);
+    }
+
+    /// 创建一个 `__mode` 为 `mode` 的弱表，压入栈顶。
+    fn push_weak_table(ls: &mut LuaState, mode: &str) {
+        ls.new_table();
+        ls.new_table();
+        ls.push_string(mode.to_string());
+        ls.set_field(-2, "__mode").unwrap();
+        ls.set_metatable(-2);
+    }
+
+    /// 数表中的项
+    fn count_entries(ls: &mut LuaState, idx: isize) -> usize {
+        let mut n = 0;
+        ls.push_nil();
+        while ls.next(idx).unwrap() {
+            ls.pop(1);
+            n += 1;
+        }
+        n
+    }
+
+    #[test]
+    fn test_weak_tables() {
+        let src = r#"
+            local v, k, kv = ...
+            keep = {}
+            v[1], v[2], v.s, v.n = {}, keep, "str", 1
+            k[{}], k[keep], k.s = 1, {}, {}
+            -- the value refers to its own key
+            local e = {}
+            k[e] = { ref = e }
+            kv[{}], kv[keep], kv.x = keep, {}, keep
+        "#;
+        for gen in [false, true] {
+            let mut ls = new_lua_state();
+            if gen {
+                ls.gc(LUA_GCGEN, &[]);
+            }
+            for mode in ["v", "k", "kv"] {
+                push_weak_table(&mut ls, mode);
+            }
+            if gen {
+                // the weak tables become old
+                ls.gc(LUA_GCSTEP, &[0]);
+                ls.gc(LUA_GCSTEP, &[0]);
+            }
+            assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+            for i in 1..=3 {
+                ls.push_value(i);
+            }
+            ls.call(3, 0).unwrap();
+            assert_eq!(
+                (1..=3)
+                    .map(|i| count_entries(&mut ls, i))
+                    .collect::<Vec<_>>(),
+                [4, 4, 3]
+            );
+            if gen {
+                // young values are removed by a minor collection
+                ls.gc(LUA_GCSTEP, &[0]);
+            } else {
+                ls.gc(LUA_GCCOLLECT, &[]);
+            }
+            assert_eq!(
+                (1..=3)
+                    .map(|i| count_entries(&mut ls, i))
+                    .collect::<Vec<_>>(),
+                [3, 2, 1]
+            );
+            ls.get_global("keep").unwrap();
+            ls.raw_get_i(1, 2);
+            assert!(ls.raw_equal(-1, -2));
+        }
+    }
+}
@@ -1,55 +1,109 @@
-use std::rc::Rc;
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
 
-use crate::api::consts::LUA_REGISTRYINDEX;
+use crate::{
+    api::{
+        consts::{LUA_MULTRET, LUA_REGISTRYINDEX},
+        KFunction,
+    },
+    compiler::chunk_id,
+};
 
-use super::{closure::Closure, lua_value::LuaValue};
+use super::{
+    closure::{Closure, Upvalue},
+    lua_value::LuaValue,
+};
 
 /// `LuaStack` 是一个用于操作 Lua 栈的结构体。
 #[derive(Debug)]
 pub struct LuaStack {
-    vec: Vec<LuaValue>,
+    /// 栈中的值，与引用它们的打开的上值共享
+    vec: Rc<RefCell<Vec<LuaValue>>>,
     registry: LuaValue,
     pub closure: Rc<Closure>,
     pub varargs: Vec<LuaValue>,
+    /// 引用本栈帧寄存器的打开的上值，键为寄存器索引（从 0 开始）
+    pub openuvs: HashMap<usize, Rc<RefCell<Upvalue>>>,
+    /// 待关闭的变量所在的寄存器索引（从 0 开始），按标记的顺序排列
+    pub tbclist: Vec<usize>,
     pub pc: isize,
+    /// 调用者期望的返回值数量，-1 表示全部
+    pub nresults: isize,
+    /// Rust 函数以 `pcallk` 发起的、还没有结束的保护调用
+    pub cont: Option<Continuation>,
+}
+
+/// Rust 函数以 `pcallk` 发起保护调用时保存在它的栈帧中的状态。被调用的函数让出后，这个栈帧留在调用栈上，
+/// 协程继续运行时由虚拟机完成保护调用并调用延续函数。
+#[derive(Debug)]
+pub struct Continuation {
+    pub k: KFunction,
+    pub ctx: isize,
+    /// 传给延续函数的状态码，出错时由捕获错误的地方设置
+    pub status: u8,
+    /// 被调用的函数的返回值数量
+    pub nresults: isize,
+    /// 被调用的函数在栈中的索引，出错时栈顶恢复到它的下面
+    pub func: isize,
+    /// 调用之前的消息处理函数，保护调用结束时恢复
+    pub old_msgh: Option<LuaValue>,
+    /// 调用开始时 `pending_close` 的长度，出错时关闭在它之后加入的变量
+    pub mark: usize,
 }
 
 impl LuaStack {
     /// 创建一个新的 `LuaStack`，预分配指定大小的空间。
     pub fn new(size: usize, registry: LuaValue, closure: Rc<Closure>) -> Self {
         LuaStack {
-            vec: Vec::with_capacity(size),
+            vec: Rc::new(RefCell::new(Vec::with_capacity(size))),
             registry: registry,
             closure: closure,
             varargs: Vec::new(),
+            openuvs: HashMap::new(),
+            tbclist: Vec::new(),
             pc: 0,
+            nresults: LUA_MULTRET,
+            cont: None,
         }
     }
 
     pub fn new_for_test(size: usize, closure: Rc<Closure>) -> Self {
         let registry = LuaValue::new_table(0, 0);
         LuaStack {
-            vec: Vec::with_capacity(size),
+            vec: Rc::new(RefCell::new(Vec::with_capacity(size))),
             registry: registry,
             closure: closure,
             varargs: Vec::new(),
+            openuvs: HashMap::new(),
+            tbclist: Vec::new(),
             pc: 0,
+            nresults: LUA_MULTRET,
+            cont: None,
         }
     }
 
+    /// 栈中的值，与引用它们的打开的上值共享。
+    pub(crate) fn slots(&self) -> &Rc<RefCell<Vec<LuaValue>>> {
+        &self.vec
+    }
+
+    /// 栈帧持有的注册表。
+    pub(crate) fn registry(&self) -> &LuaValue {
+        &self.registry
+    }
+
     /// 获取栈顶的索引。
     pub fn top(&self) -> isize {
-        self.vec.len() as isize
+        self.vec.borrow().len() as isize
     }
 
     /// 检查栈是否有足够的空间来存储 `n` 个元素，如果没有则分配更多的空间。
     pub fn check(&mut self, n: usize) {
-        self.vec.reserve(n);
+        self.vec.borrow_mut().reserve(n);
     }
 
     /// 将一个值推送到栈顶。
     pub fn push(&mut self, val: LuaValue) {
-        self.vec.push(val);
+        self.vec.borrow_mut().push(val);
     }
 
     /// 将 n 个值推送到栈顶。
@@ -69,7 +123,7 @@ impl LuaStack {
 
     /// 从栈顶弹出一个值。
     pub fn pop(&mut self) -> LuaValue {
-        self.vec.pop().unwrap()
+        self.vec.borrow_mut().pop().unwrap()
     }
 
     /// 从栈顶弹出 n 个值。
@@ -93,6 +147,11 @@ impl LuaStack {
 
     /// 检查一个索引是否有效。
     pub fn is_valid(&self, idx: isize) -> bool {
+        if idx < LUA_REGISTRYINDEX {
+            // upvalues
+            let uv_idx = (LUA_REGISTRYINDEX - idx - 1) as usize;
+            return uv_idx < self.closure.upvals.len();
+        }
         if idx == LUA_REGISTRYINDEX {
             return true;
         }
@@ -102,13 +161,21 @@ impl LuaStack {
 
     /// 获取指定索引的值。
     pub fn get(&self, idx: isize) -> LuaValue {
+        if idx < LUA_REGISTRYINDEX {
+            // upvalues
+            let uv_idx = (LUA_REGISTRYINDEX - idx - 1) as usize;
+            return match self.closure.upvals.get(uv_idx) {
+                Some(uv) => uv.borrow().get(),
+                None => LuaValue::Nil,
+            };
+        }
         if idx == LUA_REGISTRYINDEX {
             return self.registry.clone();
         }
         let abs_idx = self.abs_index(idx);
         if abs_idx > 0 && abs_idx <= self.top() {
             let idx = abs_idx as usize - 1;
-            self.vec[idx].clone() // TODO
+            self.vec.borrow()[idx].clone() // TODO
         } else {
             LuaValue::Nil
         }
@@ -116,6 +183,14 @@ impl LuaStack {
 
     /// 设置指定索引的值。
     pub fn set(&mut self, idx: isize, val: LuaValue) {
+        if idx < LUA_REGISTRYINDEX {
+            // upvalues
+            let uv_idx = (LUA_REGISTRYINDEX - idx - 1) as usize;
+            if let Some(uv) = self.closure.upvals.get(uv_idx) {
+                uv.borrow_mut().set(val);
+            }
+            return;
+        }
         if idx == LUA_REGISTRYINDEX {
             self.registry = val;
             return;
@@ -123,7 +198,7 @@ impl LuaStack {
         let abs_idx = self.abs_index(idx);
         if abs_idx > 0 && abs_idx <= self.top() {
             let idx = abs_idx as usize - 1;
-            self.vec[idx] = val;
+            self.vec.borrow_mut()[idx] = val;
         } else {
             panic!("invalid index!");
         }
@@ -150,12 +225,52 @@ impl LuaStack {
 
     /// 反转栈中从 `from` 到 `to` 的元素。
     pub fn reverse(&mut self, mut from: usize, mut to: usize) {
+        let mut vec = self.vec.borrow_mut();
         while from < to {
-            self.vec.swap(from, to);
+            vec.swap(from, to);
             from += 1;
             to -= 1;
         }
     }
+
+    /// 当前正在执行的指令的位置，形如 `chunkname:currentline: `，Rust 函数没有位置信息。
+    pub fn location(&self) -> Option<String> {
+        if self.closure.rust_fn.is_some() || self.pc <= 0 {
+            return None;
+        }
+        let proto = &self.closure.proto;
+        let line = proto.line_of(self.pc as usize - 1)?;
+        let source = proto.source.as_deref().map_or("?".to_string(), chunk_id);
+        Some(format!("{}:{}: ", source, line))
+    }
+
+    /// 获取引用寄存器 `idx`（从 0 开始）的打开的上值，没有时新建一个。
+    pub fn open_upvalue(&mut self, idx: usize) -> Rc<RefCell<Upvalue>> {
+        let vec = &self.vec;
+        self.openuvs
+            .entry(idx)
+            .or_insert_with(|| Rc::new(RefCell::new(Upvalue::Open(vec.clone(), idx))))
+            .clone()
+    }
+
+    /// 取出索引 `a`（从 1 开始）及其之上最后标记的待关闭的变量的值，没有时返回 `None`。
+    pub fn pop_tbc(&mut self, a: isize) -> Option<LuaValue> {
+        let idx = *self.tbclist.last().filter(|&&idx| idx as isize >= a - 1)?;
+        self.tbclist.pop();
+        Some(self.get(idx as isize + 1))
+    }
+
+    /// 关闭引用索引 `a`（从 1 开始）及其之上的寄存器的上值。
+    pub fn close_upvalues(&mut self, a: isize) {
+        self.openuvs.retain(|&idx, uv| {
+            if idx as isize >= a - 1 {
+                uv.borrow_mut().close();
+                false
+            } else {
+                true
+            }
+        });
+    }
 }
 
 #[cfg(test)]
@@ -216,9 +331,9 @@ mod tests {
     fn test_get_and_set() {
         let mut stack =
             LuaStack::new_for_test(10, Rc::new(Closure::new(Rc::new(Default::default()))));
-        stack.push(LuaValue::Str("hello".to_string()));
+        stack.push(LuaValue::Str(b"hello".to_vec()));
         stack.push(LuaValue::Number(42.0));
-        assert_eq!(stack.get(1), LuaValue::Str("hello".to_string()));
+        assert_eq!(stack.get(1), LuaValue::Str(b"hello".to_vec()));
         assert_eq!(stack.get(2), LuaValue::Number(42.0));
         stack.set(1, LuaValue::Boolean(true));
         stack.set(2, LuaValue::Nil);
@@ -238,4 +353,28 @@ mod tests {
         assert_eq!(stack.get(2), LuaValue::Number(2.0));
         assert_eq!(stack.get(3), LuaValue::Number(1.0));
     }
+
+    #[test]
+    fn test_upvalues() {
+        let mut stack =
+            LuaStack::new_for_test(10, Rc::new(Closure::new(Rc::new(Default::default()))));
+        stack.push(LuaValue::Integer(1));
+        stack.push(LuaValue::Integer(2));
+        let uv0 = stack.open_upvalue(0);
+        let uv1 = stack.open_upvalue(1);
+        assert!(Rc::ptr_eq(&uv1, &stack.open_upvalue(1)));
+
+        // open upvalues share the register with the stack
+        uv1.borrow_mut().set(LuaValue::Integer(3));
+        assert_eq!(stack.get(2), LuaValue::Integer(3));
+        stack.set(1, LuaValue::Integer(4));
+        assert_eq!(uv0.borrow().get(), LuaValue::Integer(4));
+
+        // closing keeps the last value and detaches the upvalue from the stack
+        stack.close_upvalues(2);
+        stack.set(2, LuaValue::Nil);
+        assert_eq!(uv1.borrow().get(), LuaValue::Integer(3));
+        assert_eq!(stack.openuvs.len(), 1);
+        assert!(!Rc::ptr_eq(&uv1, &stack.open_upvalue(1)));
+    }
 }
@@ -1,20 +1,83 @@
-use std::rc::Rc;
+use std::{cell::RefCell, ffi::c_void, rc::Rc};
 
 use crate::{
-    api::{consts::LUA_MINSTACK, op::ArithOp, r#type::Type, LuaAPI, LuaVM, RustFn},
+    api::{
+        consts::{
+            COS_DEAD, COS_RUN, COS_YIELD, LUAI_MAXCALLS, LUAI_MAXCCALLS, LUAI_MAXSTACK, LUA_ERRRUN,
+            LUA_ERRSYNTAX, LUA_GCCOLLECT, LUA_GCCOUNT, LUA_GCCOUNTB, LUA_GCGEN, LUA_GCINC,
+            LUA_GCISRUNNING, LUA_GCRESTART, LUA_GCSETPAUSE, LUA_GCSETSTEPMUL, LUA_GCSTEP,
+            LUA_GCSTOP, LUA_MINSTACK, LUA_MULTRET, LUA_OK, LUA_YIELD,
+        },
+        handle::{LuaRef, PendingFree},
+        op::{ArithOp, CmpOp},
+        r#type::Type,
+        KFunction, LuaAPI, LuaError, LuaVM, RustClosure, RustFn,
+    },
     binary::chunk::{Constant, Prototype},
-    state::arith_ops::arith,
-    vm::instruction::Instruction,
+    compiler::LoadError,
+    state::{
+        arith_ops::{arith, METAMETHODS},
+        math::number_to_str,
+    },
+    stdlib::auxlib,
+    vm::{
+        instr_call::{call_nresults, can_finish, finish_op},
+        instruction::Instruction,
+        opcodes::{OP_RETURN, OP_RETURN0, OP_RETURN1},
+    },
 };
 
-use super::{closure::Closure, lua_stack::LuaStack, lua_value::LuaValue};
+use super::{
+    closure::{Closure, Upvalue},
+    cmp_ops,
+    gc::{Gc, GcMode, Marker},
+    lua_stack::{Continuation, LuaStack},
+    lua_table::LuaTable,
+    lua_thread::LuaThread,
+    lua_userdata::LuaUserData,
+    lua_value::LuaValue,
+};
 
 const LUA_RIDX_GLOBALS: LuaValue = LuaValue::Integer(crate::api::consts::LUA_RIDX_GLOBALS as i64);
 
+/// `__index` 和 `__newindex` 元方法链的最大长度，用于发现循环
+const MAXTAGLOOP: usize = 2000;
+
+/// 处理栈溢出错误时允许超出 `LUAI_MAXCALLS` 的栈帧数量
+const EXTRA_FRAMES: usize = 200;
+
 #[derive(Debug)]
 pub struct LuaState {
     pub(crate) registry: LuaValue,
+    /// 正在运行的线程的调用栈
     pub(crate) frames: Vec<LuaStack>,
+    /// 加载代码块后是否运行字节码优化器
+    pub(crate) optimize: bool,
+    /// 当前保护调用的消息处理函数
+    pub(crate) msgh: Option<LuaValue>,
+    /// 正在运行的线程
+    pub(crate) thread: Rc<RefCell<LuaThread>>,
+    main_thread: Rc<RefCell<LuaThread>>,
+    /// 正在运行的线程中不可让出的调用的层数
+    pub(crate) nny: usize,
+    /// 嵌套的 Rust 调用（`call`、元方法和 `resume`）的层数
+    pub(crate) nccalls: usize,
+    /// `yield_` 弹出的、等待交给 `resume` 的值
+    transfer: Vec<LuaValue>,
+    /// 垃圾回收器
+    pub(crate) gc: Gc,
+    /// 是否正在调用终结器，调用期间不再调用新的终结器
+    finalizing: bool,
+    /// 注册表被借用时丢弃的引用的位置
+    pending_refs: PendingFree,
+    /// 出错时弹出的栈帧中还没有关闭的待关闭变量，由内向外排列，由捕获错误的地方关闭
+    pending_close: Vec<LuaValue>,
+}
+
+impl Default for LuaState {
+    fn default() -> Self {
+        LuaState::new()
+    }
 }
 
 impl LuaState {
@@ -26,22 +89,44 @@ impl LuaState {
         }
         let closure = Rc::new(Closure::new_fake_closure());
         let frame = LuaStack::new(20, registry.clone(), closure);
-        LuaState {
-            registry: registry,
-            frames: vec![frame],
-        }
+        LuaState::with_frame(registry, frame)
     }
 
     pub fn new_with_proto(proto: Rc<Prototype>) -> LuaState {
         let registry = LuaValue::new_table(0, 0);
         let closure = Rc::new(Closure::new(Rc::clone(&proto)));
         let frame = LuaStack::new(proto.max_stack_size as usize, registry.clone(), closure);
+        LuaState::with_frame(registry, frame)
+    }
+
+    fn with_frame(registry: LuaValue, frame: LuaStack) -> LuaState {
+        // the main thread runs from the start, so its frames live in the state
+        let mut main_thread = LuaThread::new(frame);
+        let frames = std::mem::take(&mut main_thread.frames);
+        let main_thread = Rc::new(RefCell::new(main_thread));
         LuaState {
             registry: registry,
-            frames: vec![frame],
+            frames,
+            optimize: false,
+            msgh: None,
+            thread: main_thread.clone(),
+            main_thread,
+            // the main thread can never yield
+            nny: 1,
+            nccalls: 0,
+            transfer: Vec::new(),
+            gc: Gc::new(),
+            finalizing: false,
+            pending_refs: PendingFree::default(),
+            pending_close: Vec::new(),
         }
     }
 
+    /// 设置加载代码块后是否运行字节码优化器，默认关闭。
+    pub fn set_optimize(&mut self, enabled: bool) {
+        self.optimize = enabled;
+    }
+
     pub(crate) fn stack_mut(&mut self) -> &mut LuaStack {
         self.frames.last_mut().unwrap() // TODO
     }
@@ -57,6 +142,107 @@ impl LuaState {
     pub(crate) fn pop_frame(&mut self) -> LuaStack {
         self.frames.pop().unwrap()
     }
+
+    /// 把新创建的表、闭包或线程交给回收器跟踪并压入栈顶，必要时执行一步回收。
+    fn push_object(&mut self, val: LuaValue) {
+        self.gc.track(&val);
+        self.stack_mut().push(val);
+        if self.gc.should_step() {
+            self.gc_step(0);
+        }
+    }
+
+    /// 把回收器和标记状态持有的根的函数交给 `f`。
+    fn with_gc<R>(&mut self, f: impl FnOnce(&mut Gc, &dyn Fn(&mut Marker)) -> R) -> R {
+        let LuaState {
+            ref registry,
+            ref frames,
+            ref msgh,
+            ref thread,
+            ref main_thread,
+            ref transfer,
+            ref pending_close,
+            ref mut gc,
+            ..
+        } = *self;
+        let mark_roots = |marker: &mut Marker| {
+            marker.mark_value(registry);
+            frames.iter().for_each(|frame| marker.mark_frame(frame));
+            marker.mark_thread(thread);
+            marker.mark_thread(main_thread);
+            transfer.iter().for_each(|val| marker.mark_value(val));
+            pending_close.iter().for_each(|val| marker.mark_value(val));
+            if let Some(msgh) = msgh {
+                marker.mark_value(msgh);
+            }
+        };
+        f(gc, &mark_roots)
+    }
+
+    /// 执行一步垃圾回收，`extra` 是额外的工作量。返回这一步是否完成了一个回收周期。
+    pub(crate) fn gc_step(&mut self, extra: usize) -> bool {
+        let done = self.with_gc(|gc, mark_roots| gc.step(extra, mark_roots));
+        self.call_finalizers();
+        done
+    }
+
+    /// 回收器当前的模式：`LUA_GCINC` 或者 `LUA_GCGEN`。
+    fn gc_mode(&self) -> isize {
+        match self.gc.mode() {
+            GcMode::Incremental => LUA_GCINC as isize,
+            GcMode::Generational => LUA_GCGEN as isize,
+        }
+    }
+
+    /// 执行一次完整的垃圾回收。
+    pub(crate) fn full_gc(&mut self) {
+        self.with_gc(|gc, mark_roots| gc.full_collect(mark_roots));
+        self.call_finalizers();
+    }
+
+    /// 调用回收器发现的不可达对象的终结器。
+    fn call_finalizers(&mut self) {
+        if self.finalizing {
+            return;
+        }
+        self.finalizing = true;
+        for obj in self.gc.take_finalizable() {
+            self.call_finalizer(obj);
+        }
+        self.finalizing = false;
+    }
+
+    /// 以 `obj` 为参数调用它的元表中的 `__gc` 字段。终结器中的错误被忽略。
+    fn call_finalizer(&mut self, obj: LuaValue) {
+        let mm = self.get_metafield(&obj, "__gc");
+        if !matches!(mm, LuaValue::Function(_)) {
+            return;
+        }
+        let top = self.get_top();
+        let msgh = self.msgh.take();
+        self.stack_mut().check(2);
+        self.stack_mut().push(mm);
+        self.stack_mut().push(obj);
+        if self.call(1, 0).is_err() {
+            self.set_top(top);
+        }
+        self.msgh = msgh;
+    }
+}
+
+impl Drop for LuaState {
+    /// 关闭状态：调用所有需要终结的对象的终结器，无论它们是否可达。
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            return;
+        }
+        self.gc.set_stopped(true);
+        self.call_finalizers();
+        self.finalizing = true;
+        for obj in self.gc.take_all_finalizable() {
+            self.call_finalizer(obj);
+        }
+    }
 }
 
 impl LuaVM for LuaState {
@@ -74,6 +260,10 @@ impl LuaVM for LuaState {
         i
     }
 
+    fn peek(&self, n: isize) -> u32 {
+        self.stack().closure.proto.code[(self.pc() + n) as usize]
+    }
+
     fn get_const(&mut self, idx: isize) {
         let c = &self.stack().closure.proto.constants[idx as usize];
         let val = match c {
@@ -96,8 +286,18 @@ impl LuaVM for LuaState {
 
     fn load_proto(&mut self, idx: usize) {
         let proto = self.stack().closure.proto.protos[idx].clone();
-        let closure = LuaValue::new_lua_closure(proto);
-        self.stack_mut().push(closure);
+        let mut closure = Closure::new_lua_closure(proto.clone());
+        for (i, uv_info) in proto.upvalues.iter().enumerate() {
+            let uv_idx = uv_info.idx as usize;
+            closure.upvals[i] = if uv_info.instack == 1 {
+                // captures a register of the enclosing function
+                self.stack_mut().open_upvalue(uv_idx)
+            } else {
+                // shares an upvalue of the enclosing function
+                self.stack().closure.upvals[uv_idx].clone()
+            };
+        }
+        self.push_object(LuaValue::Function(Rc::new(closure)));
     }
 
     fn load_vararg(&mut self, mut n: isize) {
@@ -113,6 +313,61 @@ impl LuaVM for LuaState {
     fn register_count(&self) -> usize {
         self.stack().closure.proto.max_stack_size as usize
     }
+
+    fn raw_arith(&mut self, op: u8) -> bool {
+        let a = self.stack().get(-2);
+        let b = self.stack().get(-1);
+        if zero_division(&a, &b, op).is_some() {
+            // leave the error to the MMBIN instruction that follows
+            return false;
+        }
+        match arith(&a, &b, op) {
+            Some(result) => {
+                self.pop(2);
+                self.stack_mut().push(result);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn close(&mut self, a: isize) -> Result<(), LuaError> {
+        self.stack_mut().close_upvalues(a);
+        while let Some(val) = self.stack_mut().pop_tbc(a) {
+            self.call_close_method(val, LuaValue::Nil)?;
+        }
+        Ok(())
+    }
+
+    fn runtime_error(&self, msg: &str) -> LuaError {
+        match self.stack().location() {
+            Some(loc) => LuaError::runtime(loc + msg),
+            None => LuaError::runtime(msg),
+        }
+    }
+
+    fn precall(&mut self, nargs: usize, nresults: isize) -> Result<bool, LuaError> {
+        let (c, nargs) = self.callable(nargs)?;
+        self.check_frames(nargs)?;
+        self.precall_closure(c, nargs, nresults)
+    }
+
+    fn pretailcall(&mut self, nargs: usize) -> Result<bool, LuaError> {
+        let (c, nargs) = self.callable(nargs)?;
+        if c.rust_fn.is_some() {
+            self.check_frames(nargs)?;
+            self.call_rust_closure(c, nargs, LUA_MULTRET)?;
+            return Ok(true);
+        }
+        // the callee replaces the current frame
+        let args = self.stack_mut().pop_n(nargs);
+        self.stack_mut().pop(); // pop func
+        let mut frame = self.pop_frame();
+        frame.close_upvalues(1);
+        let frame = self.new_lua_frame(c, args, frame.nresults);
+        self.push_frame(frame);
+        Ok(false)
+    }
 }
 
 impl LuaAPI for LuaState {
@@ -125,6 +380,9 @@ impl LuaAPI for LuaState {
     }
 
     fn check_stack(&mut self, n: usize) -> bool {
+        if n > LUAI_MAXSTACK.saturating_sub(self.stack().top() as usize) {
+            return false;
+        }
         self.stack_mut().check(n);
         true
     }
@@ -168,8 +426,10 @@ impl LuaAPI for LuaState {
         let t = self.stack().top() - 1; /* end of stack segment being rotated */
         let p = abs_idx - 1; /* start of segment */
         let m = if n >= 0 { t - n } else { p - n - 1 }; /* end of prefix */
-        self.stack_mut().reverse(p as usize, m as usize); /* reverse the prefix with length 'n' */
-        self.stack_mut().reverse(m as usize + 1, t as usize); /* reverse the suffix */
+        if m >= p {
+            self.stack_mut().reverse(p as usize, m as usize); /* reverse the prefix with length 'n' */
+        }
+        self.stack_mut().reverse((m + 1) as usize, t as usize); /* reverse the suffix */
         self.stack_mut().reverse(p as usize, t as usize); /* reverse the entire segment */
     }
 
@@ -271,6 +531,15 @@ impl LuaAPI for LuaState {
         }
     }
 
+    fn is_userdata(&self, idx: isize) -> bool {
+        let t = self.type_id(idx);
+        t == Type::UserData as i8 || t == Type::LightUserData as i8
+    }
+
+    fn is_light_userdata(&self, idx: isize) -> bool {
+        self.type_id(idx) == Type::LightUserData as i8
+    }
+
     fn to_boolean(&self, idx: isize) -> bool {
         self.stack().get(idx).to_boolean()
     }
@@ -304,20 +573,94 @@ impl LuaAPI for LuaState {
 
     fn to_stringx(&self, idx: isize) -> Option<String> {
         match self.stack().get(idx) {
-            LuaValue::Str(s) => Some(s),
-            LuaValue::Number(n) => Some(n.to_string()),
+            LuaValue::Str(s) => Some(String::from_utf8_lossy(&s).into_owned()),
+            LuaValue::Number(n) => Some(number_to_str(n)),
             LuaValue::Integer(n) => Some(n.to_string()),
             _ => None,
         }
     }
 
-    fn to_rust_function(&self, idx: isize) -> Option<RustFn> {
+    fn to_bytesx(&self, idx: isize) -> Option<Vec<u8>> {
         match self.stack().get(idx) {
-            LuaValue::Function(c) => c.rust_fn,
+            LuaValue::Str(s) => Some(s),
+            LuaValue::Number(n) => Some(number_to_str(n).into_bytes()),
+            LuaValue::Integer(n) => Some(n.to_string().into_bytes()),
             _ => None,
         }
     }
 
+    fn to_rust_function(&self, idx: isize) -> Option<RustClosure> {
+        match self.stack().get(idx) {
+            LuaValue::Function(c) => c.rust_fn.clone(),
+            _ => None,
+        }
+    }
+
+    fn to_userdata(&self, idx: isize) -> Option<Rc<LuaUserData>> {
+        match self.stack().get(idx) {
+            LuaValue::UserData(u) => Some(u),
+            _ => None,
+        }
+    }
+
+    fn to_light_userdata(&self, idx: isize) -> Option<*mut c_void> {
+        match self.stack().get(idx) {
+            LuaValue::LightUserData(p) => Some(p),
+            _ => None,
+        }
+    }
+
+    fn check_userdata(
+        &self,
+        arg: isize,
+        tname: &str,
+        fname: &str,
+    ) -> Result<Rc<LuaUserData>, LuaError> {
+        let val = self.stack().get(arg);
+        if let LuaValue::UserData(u) = &val {
+            let expected = self.registry_field(tname);
+            if let (Some(mt), LuaValue::Table(expected)) = (self.get_metatable_of(&val), expected) {
+                if Rc::ptr_eq(&mt, &expected) {
+                    return Ok(u.clone());
+                }
+            }
+        }
+        Err(auxlib::type_error(self, arg, fname, tname))
+    }
+
+    fn to_string_meta(&mut self, idx: isize) -> Result<String, LuaError> {
+        let s = self.to_bytes_meta(idx)?;
+        Ok(String::from_utf8(s)
+            .unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned()))
+    }
+
+    fn to_bytes_meta(&mut self, idx: isize) -> Result<Vec<u8>, LuaError> {
+        let val = self.stack().get(idx);
+        let mm = self.get_metafield(&val, "__tostring");
+        if !mm.is_nil() {
+            return match self.call_metamethod(mm, &[val])? {
+                LuaValue::Str(s) => Ok(s),
+                LuaValue::Integer(n) => Ok(n.to_string().into_bytes()),
+                LuaValue::Number(n) => Ok(number_to_str(n).into_bytes()),
+                _ => Err(LuaError::runtime("'__tostring' must return a string")),
+            };
+        }
+        if let LuaValue::Str(s) = val {
+            return Ok(s);
+        }
+        Ok(match &val {
+            LuaValue::Nil => "nil".to_string(),
+            LuaValue::Boolean(b) => b.to_string(),
+            LuaValue::Table(t) => format!("{}: {:p}", self.type_name_of(&val), Rc::as_ptr(t)),
+            LuaValue::Function(c) => format!("function: {:p}", Rc::as_ptr(c)),
+            LuaValue::Thread(t) => format!("thread: {:p}", Rc::as_ptr(t)),
+            LuaValue::UserData(u) => format!("{}: {:p}", self.type_name_of(&val), Rc::as_ptr(u)),
+            LuaValue::LightUserData(p) => format!("userdata: {:p}", p),
+            _ => self.to_stringx(idx).unwrap(),
+        }
+        .into_bytes())
+    }
+
     fn push_nil(&mut self) {
         self.stack_mut().push(LuaValue::Nil);
     }
@@ -335,11 +678,15 @@ impl LuaAPI for LuaState {
     }
 
     fn push_string(&mut self, s: String) {
+        self.stack_mut().push(LuaValue::Str(s.into_bytes()));
+    }
+
+    fn push_bytes(&mut self, s: Vec<u8>) {
         self.stack_mut().push(LuaValue::Str(s));
     }
 
     fn push_rust_function(&mut self, f: RustFn) {
-        self.stack_mut().push(LuaValue::new_rust_closure(f));
+        self.push_object(LuaValue::new_rust_closure(f));
     }
 
     fn push_global_table(&mut self) {
@@ -349,64 +696,201 @@ impl LuaAPI for LuaState {
         }
     }
 
-    fn arith(&mut self, op: u8) {
-        if op != ArithOp::UNM as u8 && op != ArithOp::BNOT as u8 {
-            let b = self.stack_mut().pop();
-            let a = self.stack_mut().pop();
-            if let Some(result) = arith(&a, &b, op) {
-                self.stack_mut().push(result);
-                return;
-            }
+    fn push_rust_closure(&mut self, f: RustFn, n: usize) {
+        self.push_closure(Rc::new(f), n);
+    }
+
+    fn push_closure(&mut self, f: RustClosure, n: usize) {
+        let vals = self.stack_mut().pop_n(n);
+        let closure = Closure::new_rust_closure_with_upvalues(f, vals);
+        self.push_object(LuaValue::Function(Rc::new(closure)));
+    }
+
+    fn push_thread(&mut self) -> bool {
+        let thread = self.thread.clone();
+        self.stack_mut().push(LuaValue::Thread(thread));
+        Rc::ptr_eq(&self.thread, &self.main_thread)
+    }
+
+    fn new_userdata(&mut self, data: Box<dyn std::any::Any>, nuvalue: usize) {
+        let u = LuaUserData::new(data, nuvalue);
+        self.push_object(LuaValue::UserData(Rc::new(u)));
+    }
+
+    fn push_light_userdata(&mut self, p: *mut c_void) {
+        self.stack_mut().push(LuaValue::LightUserData(p));
+    }
+
+    fn arith(&mut self, op: u8) -> Result<(), LuaError> {
+        let b = self.stack_mut().pop();
+        let a = if op != ArithOp::UNM as u8 && op != ArithOp::BNOT as u8 {
+            self.stack_mut().pop()
         } else {
-            let a = self.stack_mut().pop();
-            if let Some(result) = arith(&a, &a, op) {
-                self.stack_mut().push(result);
-                return;
+            b.clone()
+        };
+        if let Some(msg) = zero_division(&a, &b, op) {
+            return Err(self.runtime_error(msg));
+        }
+        if let Some(result) = arith(&a, &b, op) {
+            self.stack_mut().push(result);
+            return Ok(());
+        }
+        if let Some(result) = self.call_bin_metamethod(&a, &b, METAMETHODS[op as usize])? {
+            self.stack_mut().push(result);
+            return Ok(());
+        }
+
+        // a number operand is never the culprit
+        let bad = if a.to_number().is_some() { &b } else { &a };
+        let msg = if op >= ArithOp::BAND as u8 && op != ArithOp::UNM as u8 {
+            if a.to_number().is_some() && b.to_number().is_some() {
+                "number has no integer representation".to_string()
+            } else {
+                format!(
+                    "attempt to perform bitwise operation on a {} value",
+                    self.type_name_of(bad)
+                )
             }
+        } else {
+            format!(
+                "attempt to perform arithmetic on a {} value",
+                self.type_name_of(bad)
+            )
+        };
+        Err(self.runtime_error(&msg))
+    }
+
+    fn compare(&mut self, idx1: isize, idx2: isize, op: u8) -> Result<bool, LuaError> {
+        if !self.stack().is_valid(idx1) || !self.stack().is_valid(idx2) {
+            return Ok(false);
+        }
+        let a = self.stack().get(idx1);
+        let b = self.stack().get(idx2);
+        match CmpOp::from_u8(op) {
+            Some(CmpOp::EQ) => self.equal(&a, &b),
+            Some(CmpOp::LT) => self.order(&a, &b, CmpOp::LT as u8, "__lt"),
+            Some(CmpOp::LE) => self.order(&a, &b, CmpOp::LE as u8, "__le"),
+            // 'a > b' <=> 'b < a';  'a >= b' <=> 'b <= a'
+            Some(CmpOp::GT) => self.order(&b, &a, CmpOp::LT as u8, "__lt"),
+            Some(CmpOp::GE) => self.order(&b, &a, CmpOp::LE as u8, "__le"),
+            None => panic!("invalid comparison option!"),
         }
-        panic!("arithmetic error!");
     }
 
-    fn compare(&mut self, idx1: isize, idx2: isize, op: u8) -> bool {
+    fn raw_equal(&self, idx1: isize, idx2: isize) -> bool {
         if !self.stack().is_valid(idx1) || !self.stack().is_valid(idx2) {
             return false;
-        } else {
-            let a = self.stack().get(idx1);
-            let b = self.stack().get(idx2);
-            if let Some(result) = super::cmp_ops::compare(&a, &b, op) {
-                return result;
-            }
-            panic!("comparison error!")
         }
+        let a = self.stack().get(idx1);
+        let b = self.stack().get(idx2);
+        cmp_ops::compare(&a, &b, CmpOp::EQ as u8).unwrap()
+    }
+
+    fn len(&mut self, idx: isize) -> Result<(), LuaError> {
+        let val = self.stack().get(idx);
+        let len = match &val {
+            LuaValue::Str(s) => LuaValue::Integer(s.len() as i64),
+            _ => {
+                let mm = self.get_metafield(&val, "__len");
+                if !mm.is_nil() {
+                    self.call_metamethod(mm, &[val.clone(), val.clone()])?
+                } else if let LuaValue::Table(t) = &val {
+                    LuaValue::Integer(t.borrow().len() as i64)
+                } else {
+                    let msg = format!(
+                        "attempt to get length of a {} value",
+                        self.type_name_of(&val)
+                    );
+                    return Err(self.runtime_error(&msg));
+                }
+            }
+        };
+        self.stack_mut().push(len);
+        Ok(())
     }
 
-    fn len(&mut self, idx: isize) {
-        let _len = match self.stack().get(idx) {
+    fn raw_len(&self, idx: isize) -> usize {
+        match self.stack().get(idx) {
             LuaValue::Str(s) => s.len(),
             LuaValue::Table(t) => t.borrow().len(),
-            _ => panic!("length error!"),
-        };
-        self.stack_mut().push(LuaValue::Integer(_len as i64));
+            _ => 0,
+        }
     }
 
-    fn concat(&mut self, n: isize) {
+    fn concat(&mut self, n: isize) -> Result<(), LuaError> {
         if n == 0 {
-            self.stack_mut().push(LuaValue::Str("".to_string()));
+            self.stack_mut().push(LuaValue::Str(b"".to_vec()));
         } else if n >= 2 {
             for _ in 1..n {
                 if self.is_string(-1) && self.is_string(-2) {
-                    let s2 = self.to_string(-1);
-                    let mut s1 = self.to_string(-2);
-                    s1.push_str(&s2);
+                    let s2 = self.to_bytesx(-1).unwrap();
+                    let mut s1 = self.to_bytesx(-2).unwrap();
+                    s1.extend_from_slice(&s2);
                     self.stack_mut().pop();
                     self.stack_mut().pop();
                     self.stack_mut().push(LuaValue::Str(s1));
                 } else {
-                    panic!("concatenation error!");
+                    let b = self.stack_mut().pop();
+                    let a = self.stack_mut().pop();
+                    if let Some(result) = self.call_bin_metamethod(&a, &b, "__concat")? {
+                        self.stack_mut().push(result);
+                        continue;
+                    }
+                    let bad = match a {
+                        LuaValue::Str(_) | LuaValue::Number(_) | LuaValue::Integer(_) => &b,
+                        _ => &a,
+                    };
+                    let msg = format!("attempt to concatenate a {} value", self.type_name_of(bad));
+                    return Err(self.runtime_error(&msg));
                 }
             }
         }
         // n == 1, do nothing
+        Ok(())
+    }
+
+    fn string_to_number(&mut self, s: &str) -> bool {
+        match LuaValue::Str(s.as_bytes().to_vec()).to_numeric() {
+            Some(val) => {
+                self.stack_mut().push(val);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn next(&mut self, idx: isize) -> Result<bool, LuaError> {
+        if let LuaValue::Table(t) = self.stack().get(idx) {
+            let key = self.stack_mut().pop();
+            let next = t.borrow().next(&key);
+            match next {
+                Ok(Some((k, v))) => {
+                    self.stack_mut().push(k);
+                    self.stack_mut().push(v);
+                    Ok(true)
+                }
+                Ok(None) => Ok(false),
+                Err(msg) => Err(self.runtime_error(msg)),
+            }
+        } else {
+            panic!("table expected!");
+        }
+    }
+
+    fn to_close(&mut self, idx: isize) -> Result<(), LuaError> {
+        let val = self.stack().get(idx);
+        if !val.to_boolean() {
+            // false and nil need not be closed
+            return Ok(());
+        }
+        if self.get_metafield(&val, "__close").is_nil() {
+            let name = self.local_name(idx).unwrap_or("?".to_string());
+            let msg = format!("variable '{}' got a non-closable value", name);
+            return Err(self.runtime_error(&msg));
+        }
+        let idx = self.stack().abs_index(idx) as usize - 1;
+        self.stack_mut().tbclist.push(idx);
+        Ok(())
     }
 
     fn new_table(&mut self) {
@@ -414,117 +898,830 @@ impl LuaAPI for LuaState {
     }
 
     fn create_table(&mut self, narr: usize, nrec: usize) {
-        let t = LuaValue::new_table(narr, nrec);
-        self.stack_mut().push(t);
+        self.push_object(LuaValue::new_table(narr, nrec));
     }
 
-    fn get_table(&mut self, idx: isize) -> i8 {
+    fn get_table(&mut self, idx: isize) -> Result<i8, LuaError> {
         let t = self.stack().get(idx);
         let k = self.stack_mut().pop();
         self.get_table_impl(&t, &k)
     }
 
-    fn get_field(&mut self, idx: isize, k: &str) -> i8 {
+    fn get_field(&mut self, idx: isize, k: &str) -> Result<i8, LuaError> {
         let t = self.stack().get(idx);
-        let k = LuaValue::Str(k.to_string());
+        let k = LuaValue::Str(k.as_bytes().to_vec());
         self.get_table_impl(&t, &k)
     }
 
-    fn get_i(&mut self, idx: isize, i: i64) -> i8 {
+    fn get_i(&mut self, idx: isize, i: i64) -> Result<i8, LuaError> {
         let t = self.stack().get(idx);
         let k = LuaValue::Integer(i);
         self.get_table_impl(&t, &k)
     }
 
-    fn set_table(&mut self, idx: isize) {
+    fn raw_get(&mut self, idx: isize) -> i8 {
+        let t = self.stack().get(idx);
+        let k = self.stack_mut().pop();
+        self.raw_get_impl(&t, &k)
+    }
+
+    fn raw_get_i(&mut self, idx: isize, i: i64) -> i8 {
+        let t = self.stack().get(idx);
+        let k = LuaValue::Integer(i);
+        self.raw_get_impl(&t, &k)
+    }
+
+    fn get_metatable(&mut self, idx: isize) -> bool {
+        let val = self.stack().get(idx);
+        match self.get_metatable_of(&val) {
+            Some(mt) => {
+                self.stack_mut().push(LuaValue::Table(mt));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn get_iuservalue(&mut self, idx: isize, n: usize) -> i8 {
+        let val = match self.stack().get(idx) {
+            LuaValue::UserData(u) => n
+                .checked_sub(1)
+                .and_then(|i| u.user_values.borrow().get(i).cloned()),
+            _ => panic!("userdata expected!"),
+        };
+        match val {
+            Some(val) => {
+                let type_id = val.type_id();
+                self.stack_mut().push(val);
+                type_id
+            }
+            None => {
+                self.stack_mut().push(LuaValue::Nil);
+                Type::None as i8
+            }
+        }
+    }
+
+    fn set_table(&mut self, idx: isize) -> Result<(), LuaError> {
         let t = self.stack().get(idx);
         let v = self.stack_mut().pop();
         let k = self.stack_mut().pop();
-        Self::set_table_impl(&t, k, v);
+        self.set_table_impl(&t, k, v)
     }
 
-    fn set_field(&mut self, idx: isize, k: &str) {
+    fn set_field(&mut self, idx: isize, k: &str) -> Result<(), LuaError> {
         let t = self.stack().get(idx);
         let v = self.stack_mut().pop();
-        let k = LuaValue::Str(k.to_string());
-        Self::set_table_impl(&t, k, v);
+        let k = LuaValue::Str(k.as_bytes().to_vec());
+        self.set_table_impl(&t, k, v)
     }
 
-    fn set_i(&mut self, idx: isize, i: i64) {
+    fn set_i(&mut self, idx: isize, i: i64) -> Result<(), LuaError> {
         let t = self.stack().get(idx);
         let v = self.stack_mut().pop();
         let k = LuaValue::Integer(i);
-        Self::set_table_impl(&t, k, v);
+        self.set_table_impl(&t, k, v)
     }
 
-    fn load(&mut self, chunk: Vec<u8>, _chunk_name: &str, _mode: &str) -> u8 {
-        let proto = crate::binary::undump(chunk);
-        let closure = LuaValue::new_lua_closure(proto);
-        self.stack_mut().push(closure);
-        0
+    fn raw_set(&mut self, idx: isize) -> Result<(), LuaError> {
+        let t = self.stack().get(idx);
+        let v = self.stack_mut().pop();
+        let k = self.stack_mut().pop();
+        self.raw_set_impl(&t, k, v)
     }
 
-    fn call(&mut self, nargs: usize, nresults: isize) {
-        let val = self.stack().get(-(nargs as isize + 1));
-        if let LuaValue::Function(c) = val {
-            if c.rust_fn.is_some() {
-                self.call_rust_closure(nargs, nresults, c);
-            } else {
-                self.call_lua_closure(nargs, nresults, c);
-            }
+    fn raw_set_i(&mut self, idx: isize, i: i64) {
+        if let LuaValue::Table(tbl) = self.stack().get(idx) {
+            let v = self.stack_mut().pop();
+            tbl.borrow_mut().put(LuaValue::Integer(i), v);
+            self.gc.barrier_back(&tbl);
         } else {
-            println!("not function!");
+            panic!("table expected!");
         }
     }
 
-    fn get_global(&mut self, name: &str) -> i8 {
-        if let LuaValue::Table(r) = &self.registry {
-            let t = r.borrow().get(&LUA_RIDX_GLOBALS);
-            let k = LuaValue::Str(name.to_string()); // TODO
-            self.get_table_impl(&t, &k)
+    fn array_insert(&mut self, idx: isize, pos: i64) -> bool {
+        let tbl = match self.stack().get(idx) {
+            LuaValue::Table(tbl) if tbl.borrow().metatable.is_none() => tbl,
+            _ => return false,
+        };
+        let v = self.stack().get(-1);
+        if !tbl.borrow_mut().insert_array(pos, v) {
+            return false;
+        }
+        self.stack_mut().pop();
+        self.gc.barrier_back(&tbl);
+        true
+    }
+
+    fn array_remove(&mut self, idx: isize, pos: i64) -> bool {
+        let tbl = match self.stack().get(idx) {
+            LuaValue::Table(tbl) if tbl.borrow().metatable.is_none() => tbl,
+            _ => return false,
+        };
+        let v = tbl.borrow_mut().remove_array(pos);
+        match v {
+            Some(v) => {
+                self.stack_mut().push(v);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn array_sort(&mut self, idx: isize) -> bool {
+        match self.stack().get(idx) {
+            LuaValue::Table(tbl) if tbl.borrow().metatable.is_none() => {
+                tbl.borrow_mut().sort_array()
+            }
+            _ => false,
+        }
+    }
+
+    fn set_metatable(&mut self, idx: isize) {
+        let val = self.stack().get(idx);
+        let mt = match self.stack_mut().pop() {
+            LuaValue::Table(mt) => Some(mt),
+            LuaValue::Nil => None,
+            _ => panic!("table expected!"),
+        };
+        self.set_metatable_of(&val, mt);
+    }
+
+    fn set_iuservalue(&mut self, idx: isize, n: usize) -> bool {
+        let LuaValue::UserData(u) = self.stack().get(idx) else {
+            panic!("userdata expected!");
+        };
+        let val = self.stack_mut().pop();
+        let set = match n.checked_sub(1) {
+            Some(i) => match u.user_values.borrow_mut().get_mut(i) {
+                Some(slot) => {
+                    *slot = val;
+                    true
+                }
+                None => false,
+            },
+            None => false,
+        };
+        self.gc.barrier_userdata(&u);
+        set
+    }
+
+    fn load(&mut self, chunk: Vec<u8>, chunk_name: &str, mode: &str) -> u8 {
+        match self.load_chunk(chunk, chunk_name, mode) {
+            Ok(()) => LUA_OK,
+            Err(err) => {
+                self.push_string(err.to_string());
+                LUA_ERRSYNTAX
+            }
+        }
+    }
+
+    fn load_chunk(
+        &mut self,
+        chunk: Vec<u8>,
+        chunk_name: &str,
+        mode: &str,
+    ) -> Result<(), LoadError> {
+        let mut proto = crate::compiler::load(chunk, chunk_name, mode)?;
+        if self.optimize {
+            proto = crate::compiler::optimizer::optimize(proto);
+        }
+        let closure = Closure::new_lua_closure(proto);
+        if let Some(env) = closure.upvals.first() {
+            // the first upvalue of a main chunk is _ENV
+            self.push_global_table();
+            let globals = self.stack_mut().pop();
+            *env.borrow_mut() = Upvalue::Closed(globals);
+        }
+        self.push_object(LuaValue::Function(Rc::new(closure)));
+        Ok(())
+    }
+
+    fn call(&mut self, nargs: usize, nresults: isize) -> Result<(), LuaError> {
+        self.nny += 1;
+        let r = self.call_yieldable(nargs, nresults);
+        self.nny -= 1;
+        r
+    }
+
+    fn pcall(&mut self, nargs: usize, nresults: isize, msgh: isize) -> u8 {
+        let msgh = (msgh != 0).then(|| self.stack().get(msgh));
+        let old_msgh = std::mem::replace(&mut self.msgh, msgh);
+        let func = self.get_top() - nargs as isize;
+        let status = match self.call(nargs, nresults) {
+            Ok(()) => LUA_OK,
+            Err(err) => {
+                let err = self.handle_error(err);
+                // discard whatever the failed call left above the function
+                self.set_top(func - 1);
+                let status = err.status();
+                self.stack_mut().push(err.into_value());
+                status
+            }
+        };
+        self.msgh = old_msgh;
+        status
+    }
+
+    fn pcallk(
+        &mut self,
+        nargs: usize,
+        nresults: isize,
+        msgh: isize,
+        ctx: isize,
+        k: KFunction,
+    ) -> Result<u8, LuaError> {
+        let msgh = (msgh != 0).then(|| self.stack().get(msgh));
+        let old_msgh = std::mem::replace(&mut self.msgh, msgh);
+        let func = self.get_top() - nargs as isize;
+        let mark = self.pending_close.len();
+        // the continuation is used only if the callee yields
+        let depth = self.frames.len();
+        self.stack_mut().cont = Some(Continuation {
+            k,
+            ctx,
+            status: LUA_YIELD,
+            nresults,
+            func,
+            old_msgh,
+            mark,
+        });
+        let status = match self.call_yieldable(nargs, nresults) {
+            // the frame stays with its continuation until the coroutine is resumed
+            Err(err) if err.status() == LUA_YIELD => return Err(err),
+            Ok(()) => LUA_OK,
+            Err(err) => {
+                let err = self.handle_error(err);
+                self.set_top(func - 1);
+                let status = err.status();
+                self.stack_mut().push(err.into_value());
+                status
+            }
+        };
+        let cont = self.frames[depth - 1].cont.take().unwrap();
+        self.msgh = cont.old_msgh;
+        Ok(status)
+    }
+
+    fn error(&mut self) -> LuaError {
+        LuaError::new(self.stack_mut().pop())
+    }
+
+    fn where_(&self, level: usize) -> String {
+        match self.frames.len().checked_sub(level + 1) {
+            Some(i) => self.frames[i].location().unwrap_or_default(),
+            None => String::new(),
+        }
+    }
+
+    fn new_thread(&mut self) {
+        let f = self.stack_mut().pop();
+        let closure = Rc::new(Closure::new_fake_closure());
+        let mut base = LuaStack::new(LUA_MINSTACK, self.registry.clone(), closure);
+        base.push(f);
+        let thread = LuaThread::new(base);
+        self.push_object(LuaValue::Thread(Rc::new(RefCell::new(thread))));
+    }
+
+    fn resume(&mut self, idx: isize, nargs: usize) -> (u8, usize) {
+        let co = self.to_thread(idx);
+        let args = self.stack_mut().pop_n(nargs);
+        let msg = match self.thread_status(&co) {
+            COS_YIELD if self.nccalls >= LUAI_MAXCCALLS => Some("C stack overflow"),
+            COS_YIELD => None,
+            COS_DEAD => Some("cannot resume dead coroutine"),
+            _ => Some("cannot resume non-suspended coroutine"),
+        };
+        if let Some(msg) = msg {
+            self.push_string(msg.to_string());
+            return (LUA_ERRRUN, 1);
+        }
+
+        // switch to the frames of the coroutine
+        let frames = std::mem::take(&mut co.borrow_mut().frames);
+        let prev = std::mem::replace(&mut self.thread, co.clone());
+        prev.borrow_mut().frames = std::mem::replace(&mut self.frames, frames);
+        prev.borrow_mut().nny = std::mem::replace(&mut self.nny, 0);
+        // the coroutine has its own message handler and pending variables
+        let msgh = std::mem::replace(&mut self.msgh, co.borrow_mut().msgh.take());
+        let pending = std::mem::take(&mut self.pending_close);
+        self.nccalls += 1;
+        let r = self.resume_frames(args);
+        self.nccalls -= 1;
+        let co_msgh = std::mem::replace(&mut self.msgh, msgh);
+        let co_pending = std::mem::replace(&mut self.pending_close, pending);
+
+        let (status, results) = match r {
+            Ok(()) => {
+                // only the base frame with the results of the main function is left
+                let n = self.stack().top() as usize;
+                (LUA_OK, self.stack_mut().pop_n(n))
+            }
+            Err(err) if err.status() == LUA_YIELD => {
+                (LUA_YIELD, std::mem::take(&mut self.transfer))
+            }
+            Err(err) => {
+                // keep the pending variables and the error object on the base frame for
+                // 'close_thread', the innermost variable is closed first
+                let base = self.stack_mut();
+                base.set_top(0);
+                for (i, val) in co_pending.into_iter().rev().enumerate() {
+                    base.push(val);
+                    base.tbclist.push(i);
+                }
+                base.push(err.value().clone());
+                (err.status(), vec![err.into_value()])
+            }
+        };
+
+        // switch back to the resumer
+        let frames = std::mem::take(&mut prev.borrow_mut().frames);
+        co.borrow_mut().frames = std::mem::replace(&mut self.frames, frames);
+        co.borrow_mut().status = status;
+        if status == LUA_YIELD {
+            co.borrow_mut().msgh = co_msgh;
+        }
+        self.nny = prev.borrow().nny;
+        self.thread = prev;
+
+        let n = results.len();
+        self.stack_mut().check(n);
+        self.stack_mut().push_n(results, LUA_MULTRET);
+        (status, n)
+    }
+
+    fn yield_(&mut self, nresults: usize) -> LuaError {
+        if self.nny > 0 {
+            return LuaError::runtime(if Rc::ptr_eq(&self.thread, &self.main_thread) {
+                "attempt to yield from outside a coroutine"
+            } else {
+                "attempt to yield across a C-call boundary"
+            });
+        }
+        self.transfer = self.stack_mut().pop_n(nresults);
+        LuaError::yield_signal()
+    }
+
+    fn co_status(&self, idx: isize) -> u8 {
+        self.thread_status(&self.to_thread(idx))
+    }
+
+    fn is_yieldable(&self, idx: isize) -> bool {
+        let co = self.to_thread(idx);
+        if Rc::ptr_eq(&co, &self.thread) {
+            self.nny == 0
         } else {
-            0
+            let nny = co.borrow().nny;
+            nny == 0
         }
     }
 
-    fn set_global(&mut self, name: &str) {
-        if let LuaValue::Table(r) = &self.registry {
-            let t = r.borrow().get(&LUA_RIDX_GLOBALS);
-            let v = self.stack_mut().pop();
-            let k = LuaValue::Str(name.to_string()); // TODO
-            LuaState::set_table_impl(&t, k, v);
+    fn close_thread(&mut self, idx: isize) -> u8 {
+        let co = self.to_thread(idx);
+        if Rc::ptr_eq(&co, &self.thread) {
+            panic!("cannot close a running thread!");
+        }
+        let mut co = co.borrow_mut();
+        let mut status = co.status;
+        let mut err = (status != LUA_OK && status != LUA_YIELD).then(|| co.frames[0].get(-1));
+        let mut tbcs = Vec::new();
+        for frame in co.frames.iter_mut().rev() {
+            frame.close_upvalues(1);
+            while let Some(val) = frame.pop_tbc(1) {
+                tbcs.push(val);
+            }
+        }
+        co.frames.truncate(1);
+        co.frames[0].set_top(0);
+        co.status = LUA_OK;
+        co.msgh = None;
+        drop(co);
+        // the pending variables are closed innermost first, each one seeing the latest error
+        for val in tbcs {
+            let errval = err.clone().unwrap_or(LuaValue::Nil);
+            if let Err(e) = self.call_close_method(val, errval) {
+                status = e.status();
+                err = Some(e.into_value());
+            }
+        }
+        match err {
+            Some(err) => {
+                self.stack_mut().push(err);
+                status
+            }
+            None => LUA_OK,
         }
     }
 
-    fn register(&mut self, name: &str, f: RustFn) {
+    fn gc(&mut self, what: u8, args: &[isize]) -> isize {
+        let arg = |i: usize| args.get(i).map_or(0, |&n| n.max(0) as usize);
+        match what {
+            LUA_GCSTOP => self.gc.set_stopped(true),
+            LUA_GCRESTART => self.gc.set_stopped(false),
+            LUA_GCCOLLECT => self.full_gc(),
+            LUA_GCCOUNT => return (self.gc.total_bytes() >> 10) as isize,
+            LUA_GCCOUNTB => return (self.gc.total_bytes() & 0x3ff) as isize,
+            LUA_GCSTEP => return self.gc_step(arg(0).saturating_mul(1024)) as isize,
+            LUA_GCSETPAUSE => return self.gc.set_pause(arg(0)) as isize,
+            LUA_GCSETSTEPMUL => return self.gc.set_stepmul(arg(0)) as isize,
+            LUA_GCISRUNNING => return self.gc.is_running() as isize,
+            LUA_GCGEN => {
+                let previous = self.gc_mode();
+                self.with_gc(|gc, mark_roots| gc.set_generational(arg(0), arg(1), mark_roots));
+                self.call_finalizers();
+                return previous;
+            }
+            LUA_GCINC => {
+                let previous = self.gc_mode();
+                self.gc.set_incremental(arg(0), arg(1), arg(2));
+                return previous;
+            }
+            _ => return -1, // invalid option
+        }
+        0
+    }
+
+    fn get_global(&mut self, name: &str) -> Result<i8, LuaError> {
+        let t = self.globals();
+        let k = LuaValue::Str(name.as_bytes().to_vec());
+        self.get_table_impl(&t, &k)
+    }
+
+    fn set_global(&mut self, name: &str) -> Result<(), LuaError> {
+        let t = self.globals();
+        let v = self.stack_mut().pop();
+        let k = LuaValue::Str(name.as_bytes().to_vec());
+        self.set_table_impl(&t, k, v)
+    }
+
+    fn register(&mut self, name: &str, f: RustFn) -> Result<(), LuaError> {
         self.push_rust_function(f);
-        self.set_global(name);
+        self.set_global(name)
+    }
+
+    fn register_closure(&mut self, name: &str, f: RustClosure) -> Result<(), LuaError> {
+        self.push_closure(f, 0);
+        self.set_global(name)
+    }
+
+    fn new_ref(&mut self) -> LuaRef {
+        let val = self.stack_mut().pop();
+        let LuaValue::Table(registry) = &self.registry else {
+            unreachable!()
+        };
+        let r = LuaRef::new(registry, &self.pending_refs, val);
+        self.gc.barrier_back(registry);
+        r
+    }
+
+    fn push_ref(&mut self, r: &LuaRef) {
+        let LuaValue::Table(registry) = &self.registry else {
+            unreachable!()
+        };
+        let val = r.get(registry);
+        self.stack_mut().push(val);
     }
 }
 
 impl LuaState {
-    fn get_table_impl(&mut self, t: &LuaValue, k: &LuaValue) -> i8 {
-        if let LuaValue::Table(tbl) = t {
-            let v = tbl.borrow().get(k);
-            let type_id = v.type_id();
-            self.stack_mut().push(v);
-            type_id
+    fn get_table_impl(&mut self, t: &LuaValue, k: &LuaValue) -> Result<i8, LuaError> {
+        let v = self.index(t.clone(), k)?;
+        let type_id = v.type_id();
+        self.stack_mut().push(v);
+        Ok(type_id)
+    }
+
+    fn raw_get_impl(&mut self, t: &LuaValue, k: &LuaValue) -> i8 {
+        let v = match t {
+            LuaValue::Table(tbl) => tbl.borrow().get(k),
+            _ => panic!("table expected!"),
+        };
+        let type_id = v.type_id();
+        self.stack_mut().push(v);
+        type_id
+    }
+
+    fn set_table_impl(&mut self, t: &LuaValue, k: LuaValue, v: LuaValue) -> Result<(), LuaError> {
+        self.new_index(t.clone(), k, v)
+    }
+
+    fn raw_set_impl(&mut self, t: &LuaValue, k: LuaValue, v: LuaValue) -> Result<(), LuaError> {
+        match t {
+            LuaValue::Table(tbl) => {
+                self.check_key(&k)?;
+                tbl.borrow_mut().put(k, v);
+                self.gc.barrier_back(tbl);
+                Ok(())
+            }
+            _ => panic!("table expected!"),
+        }
+    }
+
+    /// 新键不能是 nil 或 NaN。
+    fn check_key(&self, k: &LuaValue) -> Result<(), LuaError> {
+        match k {
+            LuaValue::Nil => Err(self.runtime_error("table index is nil")),
+            LuaValue::Number(n) if n.is_nan() => Err(self.runtime_error("table index is NaN")),
+            _ => Ok(()),
+        }
+    }
+
+    /// `t[k]`，沿着 `__index` 元方法链查找。
+    fn index(&mut self, mut t: LuaValue, k: &LuaValue) -> Result<LuaValue, LuaError> {
+        for _ in 0..MAXTAGLOOP {
+            let mm = if let LuaValue::Table(tbl) = &t {
+                let v = tbl.borrow().get(k);
+                if !v.is_nil() || !tbl.borrow().has_metafield("__index") {
+                    return Ok(v);
+                }
+                self.get_metafield(&t, "__index")
+            } else {
+                let mm = self.get_metafield(&t, "__index");
+                if mm.is_nil() {
+                    let msg = format!("attempt to index a {} value", self.type_name_of(&t));
+                    return Err(self.runtime_error(&msg));
+                }
+                mm
+            };
+            if let LuaValue::Function(_) = mm {
+                return self.call_metamethod(mm, &[t, k.clone()]);
+            }
+            t = mm; // repeat the lookup on the metamethod
+        }
+        Err(self.runtime_error("'__index' chain too long; possible loop"))
+    }
+
+    /// `t[k] = v`，沿着 `__newindex` 元方法链赋值。
+    fn new_index(&mut self, mut t: LuaValue, k: LuaValue, v: LuaValue) -> Result<(), LuaError> {
+        for _ in 0..MAXTAGLOOP {
+            let mm = if let LuaValue::Table(tbl) = &t {
+                // '__newindex' is only consulted for absent keys
+                let absent = tbl.borrow().get(&k).is_nil();
+                if !absent || !tbl.borrow().has_metafield("__newindex") {
+                    if absent {
+                        self.check_key(&k)?;
+                    }
+                    tbl.borrow_mut().put(k, v);
+                    self.gc.barrier_back(tbl);
+                    return Ok(());
+                }
+                self.get_metafield(&t, "__newindex")
+            } else {
+                let mm = self.get_metafield(&t, "__newindex");
+                if mm.is_nil() {
+                    let msg = format!("attempt to index a {} value", self.type_name_of(&t));
+                    return Err(self.runtime_error(&msg));
+                }
+                mm
+            };
+            if let LuaValue::Function(_) = mm {
+                self.call_metamethod(mm, &[t, k, v])?;
+                return Ok(());
+            }
+            t = mm; // repeat the assignment on the metamethod
+        }
+        Err(self.runtime_error("'__newindex' chain too long; possible loop"))
+    }
+
+    /// `a == b`，两个不同的表或两个不同的完整用户数据相比较时调用 `__eq` 元方法。
+    fn equal(&mut self, a: &LuaValue, b: &LuaValue) -> Result<bool, LuaError> {
+        let distinct = match (a, b) {
+            (LuaValue::Table(x), LuaValue::Table(y)) => !Rc::ptr_eq(x, y),
+            (LuaValue::UserData(x), LuaValue::UserData(y)) => !Rc::ptr_eq(x, y),
+            _ => false,
+        };
+        if distinct {
+            if let Some(result) = self.call_bin_metamethod(a, b, "__eq")? {
+                return Ok(result.to_boolean());
+            }
+        }
+        Ok(cmp_ops::compare(a, b, CmpOp::EQ as u8).unwrap())
+    }
+
+    /// `a < b` 或 `a <= b`，操作数不是两个数字或两个字符串时调用 `event` 元方法。
+    fn order(&mut self, a: &LuaValue, b: &LuaValue, op: u8, event: &str) -> Result<bool, LuaError> {
+        if let Some(result) = cmp_ops::compare(a, b, op) {
+            return Ok(result);
+        }
+        if let Some(result) = self.call_bin_metamethod(a, b, event)? {
+            return Ok(result.to_boolean());
+        }
+        let (t1, t2) = (self.type_name_of(a), self.type_name_of(b));
+        let msg = if t1 == t2 {
+            format!("attempt to compare two {} values", t1)
         } else {
-            panic!("not a table!") // todo
+            format!("attempt to compare {} with {}", t1, t2)
+        };
+        Err(self.runtime_error(&msg))
+    }
+
+    /// 值的元表。表和完整用户数据有各自的元表，其他类型的值共用注册表中按类型保存的元表。
+    pub(crate) fn get_metatable_of(&self, val: &LuaValue) -> Option<Rc<RefCell<LuaTable>>> {
+        match val {
+            LuaValue::Table(t) => return t.borrow().metatable.clone(),
+            LuaValue::UserData(u) => return u.metatable.borrow().clone(),
+            _ => {}
+        }
+        if let LuaValue::Table(r) = &self.registry {
+            if let LuaValue::Table(mt) = r.borrow().get(&metatable_key(val)) {
+                return Some(mt);
+            }
+        }
+        None
+    }
+
+    fn set_metatable_of(&mut self, val: &LuaValue, mt: Option<Rc<RefCell<LuaTable>>>) {
+        let has_gc = mt
+            .as_ref()
+            .is_some_and(|mt| !mt.borrow().get(&LuaValue::Str(b"__gc".to_vec())).is_nil());
+        if let LuaValue::Table(t) = val {
+            t.borrow_mut().metatable = mt;
+            self.gc.barrier_back(t);
+        } else if let LuaValue::UserData(u) = val {
+            *u.metatable.borrow_mut() = mt;
+            self.gc.barrier_userdata(u);
+        } else {
+            if let LuaValue::Table(r) = &self.registry {
+                let mt = mt.map_or(LuaValue::Nil, LuaValue::Table);
+                r.borrow_mut().put(metatable_key(val), mt);
+                self.gc.barrier_back(r);
+            }
+            return;
+        }
+        if has_gc {
+            self.gc.check_finalizer(val);
         }
     }
 
-    fn set_table_impl(t: &LuaValue, k: LuaValue, v: LuaValue) {
-        if let LuaValue::Table(tbl) = t {
-            tbl.borrow_mut().put(k, v);
+    /// 注册表中名为 `name` 的字段，不调用元方法。
+    fn registry_field(&self, name: &str) -> LuaValue {
+        match &self.registry {
+            LuaValue::Table(r) => r.borrow().get(&LuaValue::Str(name.as_bytes().to_vec())),
+            _ => LuaValue::Nil,
+        }
+    }
+
+    /// 值的元表中名为 `event` 的字段，没有时为 nil。
+    pub(crate) fn get_metafield(&self, val: &LuaValue, event: &str) -> LuaValue {
+        match self.get_metatable_of(val) {
+            Some(mt) => mt.borrow().get(&LuaValue::Str(event.as_bytes().to_vec())),
+            None => LuaValue::Nil,
+        }
+    }
+
+    /// 以 `args` 为参数调用元方法 `mm`，返回第一个结果。
+    ///
+    /// 由 Lua 函数中可以完成的指令（见 `finish_op`）调用的元方法可以让出：让出之后指令的执行被放弃，
+    /// 协程继续运行、元方法返回时再由 `finish_op` 使用它的结果完成指令。
+    fn call_metamethod(&mut self, mm: LuaValue, args: &[LuaValue]) -> Result<LuaValue, LuaError> {
+        self.stack_mut().check(args.len() + 1);
+        self.stack_mut().push(mm);
+        for arg in args {
+            self.stack_mut().push(arg.clone());
+        }
+        let in_lua_code = self.stack().closure.rust_fn.is_none() && self.pc() > 0;
+        if self.nny == 0 && in_lua_code && can_finish(self.peek(-1)) {
+            self.call_yieldable(args.len(), 1)?;
         } else {
-            panic!("not a table!");
+            self.call(args.len(), 1)?;
         }
+        Ok(self.stack_mut().pop())
+    }
+
+    /// 先后在 `a` 和 `b` 的元表中查找 `event` 元方法并以 `a`、`b` 为参数调用，两者都没有时返回 `None`。
+    fn call_bin_metamethod(
+        &mut self,
+        a: &LuaValue,
+        b: &LuaValue,
+        event: &str,
+    ) -> Result<Option<LuaValue>, LuaError> {
+        let mut mm = self.get_metafield(a, event);
+        if mm.is_nil() {
+            mm = self.get_metafield(b, event);
+            if mm.is_nil() {
+                return Ok(None);
+            }
+        }
+        self.call_metamethod(mm, &[a.clone(), b.clone()]).map(Some)
     }
 
-    fn call_rust_closure(&mut self, nargs: usize, nresults: isize, c: Rc<Closure>) {
+    /// 用于错误信息的类型名，表的元表中有字符串 `__name` 字段时使用它。
+    fn type_name_of(&self, val: &LuaValue) -> String {
+        if let LuaValue::Table(_) | LuaValue::UserData(_) = val {
+            if let LuaValue::Str(name) = self.get_metafield(val, "__name") {
+                return String::from_utf8_lossy(&name).into_owned();
+            }
+        }
+        self.type_name(val.type_id()).to_string()
+    }
+
+    /// 当前正在执行的 Lua 函数中，位于栈索引 `idx` 处的局部变量的名字。
+    fn local_name(&self, idx: isize) -> Option<String> {
+        let proto = &self.stack().closure.proto;
+        let pc = self.stack().pc as usize - 1;
+        let mut n = self.stack().abs_index(idx);
+        for var in proto.loc_vars.iter().take_while(|var| var.start_pc <= pc) {
+            if pc < var.end_pc {
+                n -= 1;
+                if n == 0 {
+                    return Some(var.var_name.clone());
+                }
+            }
+        }
+        None
+    }
+
+    /// 找出栈顶 `nargs` 个参数之下被调用的函数。被调用的值不是函数时改为调用它的 `__call` 元方法，
+    /// 原来的值成为第一个参数。返回被调用的闭包和实际的参数数量。
+    fn callable(&mut self, nargs: usize) -> Result<(Rc<Closure>, usize), LuaError> {
+        let val = self.stack().get(-(nargs as isize + 1));
+        if let LuaValue::Function(c) = val {
+            return Ok((c, nargs));
+        }
+        // call the '__call' metamethod with the called value as its first argument
+        if let LuaValue::Function(c) = self.get_metafield(&val, "__call") {
+            self.stack_mut().push(LuaValue::Function(c.clone()));
+            self.insert(-(nargs as isize + 2));
+            return Ok((c, nargs + 1));
+        }
+        let msg = format!("attempt to call a {} value", self.type_name_of(&val));
+        let err = self.runtime_error(&msg);
+        self.pop(nargs + 1);
+        Err(err)
+    }
+
+    /// 与 `call` 相同，但是被调用的函数可以让出。让出时保留调用栈，调用者必须能在协程继续运行时完成剩下的工作。
+    fn call_yieldable(&mut self, nargs: usize, nresults: isize) -> Result<(), LuaError> {
+        // calls past the limit are only allowed while handling the overflow error
+        if self.nccalls == LUAI_MAXCCALLS || self.nccalls >= LUAI_MAXCCALLS / 10 * 11 {
+            let err = if self.nccalls == LUAI_MAXCCALLS {
+                self.runtime_error("C stack overflow")
+            } else {
+                LuaError::error_in_handler()
+            };
+            self.pop(nargs + 1);
+            return Err(err);
+        }
+        self.nccalls += 1;
+        let mark = self.pending_close.len();
+        let r = self.run_call(nargs, nresults);
+        self.nccalls -= 1;
+        match r {
+            Err(err) if err.status() != LUA_YIELD => Err(self.close_pending(mark, err)),
+            r => r,
+        }
+    }
+
+    /// 检查调用栈帧的数量，超过上限的调用只允许发生在处理栈溢出错误的时候。
+    fn check_frames(&mut self, nargs: usize) -> Result<(), LuaError> {
+        let depth = self.frames.len();
+        if depth == LUAI_MAXCALLS || depth >= LUAI_MAXCALLS + EXTRA_FRAMES {
+            let err = if depth == LUAI_MAXCALLS {
+                self.runtime_error("stack overflow")
+            } else {
+                LuaError::error_in_handler()
+            };
+            self.pop(nargs + 1);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// 调用栈顶的函数并运行到它返回。
+    fn run_call(&mut self, nargs: usize, nresults: isize) -> Result<(), LuaError> {
+        let base = self.frames.len() + 1;
+        if !self.precall(nargs, nresults)? {
+            self.execute(base)?;
+        }
+        Ok(())
+    }
+
+    fn precall_closure(
+        &mut self,
+        c: Rc<Closure>,
+        nargs: usize,
+        nresults: isize,
+    ) -> Result<bool, LuaError> {
+        if c.rust_fn.is_some() {
+            self.call_rust_closure(c, nargs, nresults)?;
+            return Ok(true);
+        }
+        let args = self.stack_mut().pop_n(nargs);
+        self.stack_mut().pop(); // pop func
+        let frame = self.new_lua_frame(c, args, nresults);
+        self.push_frame(frame);
+        Ok(false)
+    }
+
+    fn call_rust_closure(
+        &mut self,
+        c: Rc<Closure>,
+        nargs: usize,
+        nresults: isize,
+    ) -> Result<(), LuaError> {
         // create new lua stack
-        let rust_fn = c.rust_fn.unwrap();
+        let rust_fn = c.rust_fn.clone().unwrap();
         let mut new_stack = LuaStack::new(nargs + LUA_MINSTACK, self.registry.clone(), c);
 
         // pass args, pop func
@@ -535,29 +1732,60 @@ impl LuaState {
         self.stack_mut().pop(); // pop func
 
         // run closure
+        new_stack.nresults = nresults;
         self.push_frame(new_stack);
-        let r = rust_fn(self);
-        new_stack = self.pop_frame();
+        self.run_rust_frame(|ls| rust_fn(ls))
+    }
+
+    /// 运行栈顶的 Rust 函数栈帧：调用 `f`，然后弹出栈帧，把返回值按照调用者期望的数量推入调用者的栈。
+    /// 让出时，如果栈帧中有 `pcallk` 保存的延续，栈帧留在调用栈上，否则被弹出。
+    fn run_rust_frame(
+        &mut self,
+        f: impl FnOnce(&mut dyn LuaAPI) -> Result<usize, LuaError>,
+    ) -> Result<(), LuaError> {
+        let depth = self.frames.len();
+        let r = match f(self) {
+            Ok(n) => self.close(1).map(|()| n),
+            Err(err) if err.status() == LUA_YIELD => {
+                if self.frames[depth - 1].cont.is_none() {
+                    self.pop_frame();
+                }
+                return Err(err);
+            }
+            Err(err) => {
+                let err = self.handle_error(err);
+                self.defer_close();
+                Err(err)
+            }
+        };
+        let mut frame = self.pop_frame();
+        let n = r?;
 
         // return results
-        if nresults != 0 {
-            let results = new_stack.pop_n(r);
+        if frame.nresults != 0 {
+            let results = frame.pop_n(n);
             self.stack_mut().check(results.len());
-            self.stack_mut().push_n(results, nresults);
+            self.stack_mut().push_n(results, frame.nresults);
         }
+        Ok(())
     }
 
-    fn call_lua_closure(&mut self, nargs: usize, nresults: isize, c: Rc<Closure>) {
+    /// 继续运行因为被调用的函数让出而留在栈顶的 Rust 函数：完成它以 `pcallk` 发起的保护调用，然后调用延续函数。
+    fn finish_rust_frame(&mut self) -> Result<(), LuaError> {
+        let cont = self.stack_mut().cont.take().unwrap();
+        self.msgh = cont.old_msgh;
+        self.run_rust_frame(|ls| (cont.k)(ls, cont.status, cont.ctx))
+    }
+
+    /// 为 Lua 闭包创建栈帧，传入参数并分配寄存器。
+    fn new_lua_frame(&self, c: Rc<Closure>, mut args: Vec<LuaValue>, nresults: isize) -> LuaStack {
         let nregs = c.proto.max_stack_size as usize;
         let nparams = c.proto.num_params as usize;
         let is_vararg = c.proto.is_vararg == 1;
+        let nargs = args.len();
 
-        // create new lua stack
         let mut new_stack = LuaStack::new(nregs + LUA_MINSTACK, self.registry.clone(), c);
-
-        // pass args, pop func
-        let mut args = self.stack_mut().pop_n(nargs);
-        self.stack_mut().pop(); // pop func
+        new_stack.nresults = nresults;
         if nargs > nparams {
             // varargs
             for _ in nparams..nargs {
@@ -571,36 +1799,228 @@ impl LuaState {
         }
         new_stack.push_n(args, nparams as isize);
         new_stack.set_top(nregs as isize);
+        new_stack
+    }
 
-        // run closure
-        self.push_frame(new_stack);
-        self.run_lua_closure();
-        new_stack = self.pop_frame();
+    /// 解释执行栈顶的 Lua 函数。Lua 函数之间的调用不会递归地调用本函数：调用指令只压入新的栈帧，
+    /// 被调用的函数返回后再完成调用者中发起调用的指令。栈帧的数量回到 `base` 以下时返回。
+    /// 出错时弹出 `base` 及其之上的栈帧（见 `unwind`），让出时保留所有栈帧。
+    fn execute(&mut self, base: usize) -> Result<(), LuaError> {
+        loop {
+            let r = if self.stack().closure.rust_fn.is_some() {
+                // a Rust function whose protected call yielded, see 'pcallk'
+                self.finish_rust_frame().map(|()| true)
+            } else {
+                self.step()
+            };
+            match r {
+                Ok(false) => {}
+                Ok(true) => {
+                    if self.frames.len() < base {
+                        return Ok(());
+                    }
+                    // a Rust caller is finished in the next iteration
+                    if self.stack().closure.rust_fn.is_none() {
+                        if let Err(err) = finish_op(self.peek(-1), self) {
+                            self.unwind(base, err)?;
+                        }
+                    }
+                }
+                Err(err) => self.unwind(base, err)?,
+            }
+        }
+    }
 
-        // return results
-        if nresults != 0 {
-            let nrets = new_stack.top() as usize - nregs;
-            let results = new_stack.pop_n(nrets);
+    /// 执行栈顶 Lua 函数的一条指令，函数返回时返回 `true`。
+    fn step(&mut self) -> Result<bool, LuaError> {
+        let instr = self.fetch();
+        instr.execute(self)?;
+        // print_stack(instr.opname(), self);
+        if instr.opcode() == OP_RETURN
+            || instr.opcode() == OP_RETURN0
+            || instr.opcode() == OP_RETURN1
+        {
+            self.post_call();
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// 处理 `execute` 中的错误，让出时直接返回。出错时弹出 `base` 及其之上的栈帧，直到遇到以 `pcallk`
+    /// 发起保护调用、因为让出而留在调用栈上的 Rust 函数：错误在那里被捕获，就像 `pcallk` 返回了错误一样，
+    /// 之后由 `execute` 调用它的延续函数。
+    fn unwind(&mut self, base: usize, err: LuaError) -> Result<(), LuaError> {
+        if err.status() == LUA_YIELD {
+            return Err(err);
+        }
+        let mut err = self.handle_error(err);
+        while self.frames.len() >= base {
+            if let Some(cont) = &self.stack().cont {
+                let (func, mark) = (cont.func, cont.mark);
+                err = self.close_pending(mark, err);
+                self.set_top(func - 1);
+                let status = err.status();
+                self.stack_mut().push(err.into_value());
+                self.stack_mut().cont.as_mut().unwrap().status = status;
+                return Ok(());
+            }
+            self.defer_close();
+            self.pop_frame();
+        }
+        Err(err)
+    }
+
+    /// 结束栈顶的 Lua 函数：弹出栈帧并关闭上值，把返回值按照调用者期望的数量推入调用者的栈。
+    fn post_call(&mut self) {
+        let mut frame = self.pop_frame();
+        frame.close_upvalues(1);
+        if frame.nresults != 0 {
+            let nrets = frame.top() as usize - frame.closure.proto.max_stack_size as usize;
+            let results = frame.pop_n(nrets);
             self.stack_mut().check(nrets);
-            self.stack_mut().push_n(results, nresults);
+            self.stack_mut().push_n(results, frame.nresults);
         }
     }
 
-    fn run_lua_closure(&mut self) {
-        loop {
-            let instr = self.fetch();
-            instr.execute(self);
-            // print_stack(instr.opname(), self);
-            if instr.opcode() == crate::vm::opcodes::OP_RETURN
-                || instr.opcode() == crate::vm::opcodes::OP_RETURN0
-                || instr.opcode() == crate::vm::opcodes::OP_RETURN1
-            {
-                break;
+    /// 以 `val` 和错误对象 `err` 为参数调用 `val` 的 `__close` 元方法。
+    fn call_close_method(&mut self, val: LuaValue, err: LuaValue) -> Result<(), LuaError> {
+        let mm = self.get_metafield(&val, "__close");
+        self.call_metamethod(mm, &[val, err]).map(|_| ())
+    }
+
+    /// 出错时关闭栈顶栈帧的上值，把它待关闭的变量移到 `pending_close`。与 C Lua 一样，
+    /// 这些变量在捕获错误的地方才关闭：`call` 返回错误之前，或者协程出错后由 `close_thread` 关闭。
+    fn defer_close(&mut self) {
+        self.stack_mut().close_upvalues(1);
+        while let Some(val) = self.stack_mut().pop_tbc(1) {
+            self.pending_close.push(val);
+        }
+    }
+
+    /// 关闭 `pending_close` 中从 `mark` 开始的变量。`__close` 元方法出错时，新的错误取代原来的错误，
+    /// 剩下的变量仍然会被关闭。返回最后的错误。
+    fn close_pending(&mut self, mark: usize, mut err: LuaError) -> LuaError {
+        for val in self.pending_close.split_off(mark) {
+            let top = self.get_top();
+            if let Err(e) = self.call_close_method(val, err.value().clone()) {
+                self.set_top(top);
+                err = e;
             }
         }
+        err
+    }
+
+    /// 在出错的栈帧弹出之前调用消息处理函数，每个错误只处理一次。
+    fn handle_error(&mut self, mut err: LuaError) -> LuaError {
+        if err.handled {
+            return err;
+        }
+        err.handled = true;
+        if let Some(msgh) = self.msgh.take() {
+            // errors raised inside the handler are not handled again
+            err = match msgh.clone() {
+                LuaValue::Function(c) => {
+                    // the handler itself may run at the call depth limits, and never yields
+                    self.nccalls += 1;
+                    self.nny += 1;
+                    self.stack_mut().check(2);
+                    self.stack_mut().push(msgh.clone());
+                    self.stack_mut().push(err.value().clone());
+                    let base = self.frames.len() + 1;
+                    let r = match self.precall_closure(c, 1, 1) {
+                        Ok(false) => self.execute(base),
+                        r => r.map(|_| ()),
+                    };
+                    self.nny -= 1;
+                    self.nccalls -= 1;
+                    match r {
+                        Ok(()) => {
+                            let v = self.stack_mut().pop();
+                            err.set_value(v);
+                            err
+                        }
+                        Err(_) => LuaError::error_in_handler(),
+                    }
+                }
+                _ => LuaError::error_in_handler(),
+            };
+            self.msgh = Some(msgh);
+        }
+        err
+    }
+
+    /// 线程的状态，正在运行的线程是 `COS_RUN`。
+    fn thread_status(&self, co: &Rc<RefCell<LuaThread>>) -> u8 {
+        if Rc::ptr_eq(co, &self.thread) {
+            COS_RUN
+        } else {
+            co.borrow().status()
+        }
+    }
+
+    fn to_thread(&self, idx: isize) -> Rc<RefCell<LuaThread>> {
+        match self.stack().get(idx) {
+            LuaValue::Thread(t) => t,
+            _ => panic!("thread expected!"),
+        }
+    }
+
+    /// 在换入的线程的调用栈上开始或者继续运行线程，直到它让出、运行结束或者出错。
+    fn resume_frames(&mut self, args: Vec<LuaValue>) -> Result<(), LuaError> {
+        let nargs = args.len();
+        self.stack_mut().check(nargs);
+        if self.thread.borrow().status == LUA_OK {
+            // start the main function, which sits alone on the base frame
+            self.stack_mut().push_n(args, LUA_MULTRET);
+            return self.run_call(nargs, LUA_MULTRET);
+        }
+        // the values passed in become the results of the call that yielded
+        if self.frames.len() == 1 {
+            // the main function itself is a Rust function, which finishes here
+            self.stack_mut().push_n(args, LUA_MULTRET);
+            return Ok(());
+        }
+        if let Some(cont) = &self.stack().cont {
+            // the callee of 'pcallk' yielded, 'execute' calls the continuation
+            let nresults = cont.nresults;
+            self.stack_mut().push_n(args, nresults);
+        } else {
+            let i = self.peek(-1);
+            self.stack_mut().push_n(args, call_nresults(i));
+            if let Err(err) = finish_op(i, self) {
+                self.unwind(2, err)?;
+            }
+        }
+        self.execute(2)
+    }
+
+    fn globals(&self) -> LuaValue {
+        if let LuaValue::Table(t) = &self.registry {
+            t.borrow().get(&LUA_RIDX_GLOBALS)
+        } else {
+            unreachable!()
+        }
     }
 }
 
+/// 整数除以零时 `//` 和 `%` 的错误信息
+fn zero_division(a: &LuaValue, b: &LuaValue, op: u8) -> Option<&'static str> {
+    match (a, b) {
+        (LuaValue::Integer(_), LuaValue::Integer(0)) if op == ArithOp::IDIV as u8 => {
+            Some("attempt to divide by zero")
+        }
+        (LuaValue::Integer(_), LuaValue::Integer(0)) if op == ArithOp::MOD as u8 => {
+            Some("attempt to perform 'n%0'")
+        }
+        _ => None,
+    }
+}
+
+/// 注册表中保存表以外的类型共用的元表的键
+fn metatable_key(val: &LuaValue) -> LuaValue {
+    LuaValue::Str(format!("_MT{}", val.type_id()).into_bytes())
+}
+
 // debug
 fn print_stack(opname: &str, ls: &LuaState) {
     print!("  {}\t", opname);
@@ -619,7 +2039,11 @@ fn print_stack(opname: &str, ls: &LuaState) {
 
 #[cfg(test)]
 mod tests {
-    use crate::api::op::CmpOp;
+    use crate::api::{consts::lua_upvalueindex, op::CmpOp};
+    use crate::stdlib::{
+        auxlib,
+        test_util::{new_state, run_with},
+    };
 
     use super::*;
 
@@ -756,7 +2180,7 @@ mod tests {
     #[test]
     fn test_push_number() {
         let mut lua_state = LuaState::new();
-        lua_state.push_number(3.14);
+        lua_state.push_number(2.5);
         assert_eq!(lua_state.type_id(lua_state.get_top()), Type::Number as i8);
     }
 
@@ -772,8 +2196,11 @@ mod tests {
         let mut lua_state = LuaState::new();
         lua_state.push_integer(1);
         lua_state.push_integer(2);
-        lua_state.arith(ArithOp::ADD as u8);
+        lua_state.arith(ArithOp::ADD as u8).unwrap();
         assert_eq!(lua_state.to_integer(lua_state.get_top()), 3);
+        lua_state.push_integer(0);
+        let err = lua_state.arith(ArithOp::MOD as u8).unwrap_err();
+        assert_eq!(err.to_string(), "attempt to perform 'n%0'");
     }
 
     #[test]
@@ -781,14 +2208,14 @@ mod tests {
         let mut lua_state = LuaState::new();
         lua_state.push_integer(1);
         lua_state.push_integer(2);
-        assert_eq!(lua_state.compare(-1, -2, 0), false);
+        assert!(!lua_state.compare(-1, -2, 0).unwrap());
     }
 
     #[test]
     fn test_len() {
         let mut lua_state = LuaState::new();
         lua_state.push_string("hello".to_string());
-        lua_state.len(lua_state.get_top());
+        lua_state.len(lua_state.get_top()).unwrap();
         assert_eq!(lua_state.to_integer(lua_state.get_top()), 5);
     }
 
@@ -802,15 +2229,15 @@ mod tests {
         ls.push_number(4.0);
         print_stack(&ls);
 
-        ls.arith(ArithOp::ADD as u8);
+        ls.arith(ArithOp::ADD as u8).unwrap();
         print_stack(&ls);
-        ls.arith(ArithOp::BNOT as u8);
+        ls.arith(ArithOp::BNOT as u8).unwrap();
         print_stack(&ls);
-        ls.len(2);
+        ls.len(2).unwrap();
         print_stack(&ls);
-        ls.concat(3);
+        ls.concat(3).unwrap();
         print_stack(&ls);
-        let x = ls.compare(1, 2, CmpOp::EQ as u8);
+        let x = ls.compare(1, 2, CmpOp::EQ as u8).unwrap();
         ls.push_boolean(x);
         print_stack(&ls);
     }
@@ -849,15 +2276,462 @@ mod tests {
         assert_eq!(lua_state.pc(), 5);
     }
 
+    #[test]
+    fn test_next() {
+        let mut ls = LuaState::new();
+        ls.new_table();
+        ls.push_integer(10);
+        ls.set_i(1, 1).unwrap();
+        ls.push_string("v".to_string());
+        ls.set_field(1, "k").unwrap();
+
+        ls.push_nil();
+        assert!(ls.next(1).unwrap());
+        assert_eq!(ls.to_integer(-2), 1);
+        assert_eq!(ls.to_integer(-1), 10);
+        ls.pop(1);
+        assert!(ls.next(1).unwrap());
+        assert_eq!(ls.to_string(-2), "k");
+        assert_eq!(ls.to_string(-1), "v");
+        ls.pop(1);
+        assert!(!ls.next(1).unwrap());
+        assert_eq!(ls.get_top(), 1);
+
+        ls.push_string("x".to_string());
+        let err = ls.next(1).unwrap_err();
+        assert_eq!(err.to_string(), "invalid key to 'next'");
+    }
+
     #[test]
     fn test_lua_state_table() {
         let mut ls = LuaState::new();
         ls.new_table();
         ls.push_integer(1);
         ls.push_integer(2);
-        ls.set_table(1);
+        ls.set_table(1).unwrap();
         ls.push_integer(1);
-        ls.get_table(1);
+        ls.get_table(1).unwrap();
         assert_eq!(ls.to_integer(-1), 2);
     }
+
+    #[test]
+    fn test_metatable() {
+        let mut ls = LuaState::new();
+        ls.new_table();
+        assert!(!ls.get_metatable(1));
+
+        // mt = { __name = "Point", __index = { kind = "point" } }
+        ls.new_table();
+        ls.push_string("Point".to_string());
+        ls.set_field(2, "__name").unwrap();
+        ls.new_table();
+        ls.push_string("point".to_string());
+        ls.set_field(3, "kind").unwrap();
+        ls.set_field(2, "__index").unwrap();
+        ls.set_metatable(1);
+        assert_eq!(ls.get_top(), 1);
+
+        assert!(ls.get_metatable(1));
+        assert!(ls.raw_equal(-1, -1));
+        ls.pop(1);
+        assert_eq!(ls.get_field(1, "kind").unwrap(), Type::String as i8);
+        assert_eq!(ls.to_string(-1), "point");
+        ls.pop(1);
+        ls.push_string("kind".to_string());
+        assert_eq!(ls.raw_get(1), Type::Nil as i8);
+        ls.pop(1);
+        assert!(ls.to_string_meta(1).unwrap().starts_with("Point: 0x"));
+
+        // strings share one metatable
+        ls.push_string("a".to_string());
+        ls.new_table();
+        ls.new_table();
+        ls.push_integer(42);
+        ls.set_field(-2, "answer").unwrap();
+        ls.set_field(-2, "__index").unwrap();
+        ls.set_metatable(-2);
+        ls.push_string("b".to_string());
+        ls.get_field(-1, "answer").unwrap();
+        assert_eq!(ls.to_integer(-1), 42);
+
+        ls.push_nil();
+        ls.set_metatable(1);
+        assert!(!ls.get_metatable(1));
+        assert!(ls.to_string_meta(1).unwrap().starts_with("table: 0x"));
+    }
+
+    #[test]
+    fn test_to_be_closed() {
+        let mut ls = new_state();
+        let prelude = r#"
+            log = ""
+            function closable(name)
+                return setmetatable({}, { __close = function(_, e)
+                    log = log .. name .. "(" .. (e == nil and "nil" or e) .. ")"
+                end })
+            end
+        "#;
+        run_with(&mut ls, prelude);
+
+        // variables are closed in reverse order on block exit, return and break
+        let src = r#"
+            do
+                local a <close> = closable("a")
+                local b <close> = closable("b")
+                local c <close> = false
+            end
+            local function f()
+                local x <close> = closable("x")
+                return "r"
+            end
+            local r = f()
+            for i = 1, 3 do
+                local l <close> = closable("l" .. i)
+                if i == 2 then break end
+            end
+            return log, r
+        "#;
+        assert_eq!(
+            run_with(&mut ls, src),
+            ["b(nil)a(nil)x(nil)l1(nil)l2(nil)", "r"]
+        );
+
+        // errors close variables with the error object
+        let src = r#"
+            log = ""
+            local ok, e = pcall(function()
+                local a <close> = closable("a")
+                error("boom", 0)
+            end)
+            return log, ok, e
+        "#;
+        assert_eq!(run_with(&mut ls, src), ["a(boom)", "false", "boom"]);
+
+        let src = r#"
+            local ok, e = pcall(function() local v <close> = {} end)
+            return e
+        "#;
+        assert_eq!(
+            run_with(&mut ls, src),
+            ["test:2: variable 'v' got a non-closable value"]
+        );
+
+        // the fourth value of a generic for is closed when the loop ends
+        let src = r#"
+            log = ""
+            local function iter(_, i) if i < 2 then return i + 1 end end
+            for i in iter, nil, 0, closable("for") do
+                log = log .. i
+            end
+            return log
+        "#;
+        assert_eq!(run_with(&mut ls, src), ["12for(nil)"]);
+
+        // closing a suspended coroutine closes its variables
+        let src = r#"
+            log = ""
+            local co = coroutine.create(function()
+                local a <close> = closable("a")
+                coroutine.yield()
+            end)
+            coroutine.resume(co)
+            return coroutine.close(co), log
+        "#;
+        assert_eq!(run_with(&mut ls, src), ["true", "a(nil)"]);
+
+        // a coroutine that dies with an error keeps its variables pending until it is closed,
+        // then they see the error that killed it
+        let src = r#"
+            log = ""
+            local co = coroutine.create(function()
+                local a <close> = closable("a")
+                local function f()
+                    local b <close> = closable("b")
+                    error("dead", 0)
+                end
+                f()
+            end)
+            local ok, e = coroutine.resume(co)
+            local before = log
+            local ok2, e2 = coroutine.close(co)
+            return ok, e, before, coroutine.status(co), ok2, e2, log
+        "#;
+        assert_eq!(
+            run_with(&mut ls, src),
+            [
+                "false",
+                "dead",
+                "",
+                "dead",
+                "false",
+                "dead",
+                "b(dead)a(dead)"
+            ]
+        );
+
+        // coroutine.wrap closes them before propagating the error
+        let src = r#"
+            log = ""
+            local f = coroutine.wrap(function()
+                local a <close> = closable("a")
+                error("wrapped", 0)
+            end)
+            local ok, e = pcall(f)
+            return ok, e, log
+        "#;
+        assert_eq!(run_with(&mut ls, src), ["false", "wrapped", "a(wrapped)"]);
+
+        // an error in __close replaces the original error
+        let src = r#"
+            local ok, e = pcall(function()
+                local a <close> = setmetatable({}, { __close = function() error("in close", 0) end })
+                error("original", 0)
+            end)
+            return e
+        "#;
+        assert_eq!(run_with(&mut ls, src), ["in close"]);
+    }
+
+    #[test]
+    fn test_finalizers() {
+        let mut ls = new_state();
+        let src = r#"
+            log = ""
+            local function obj(name)
+                return setmetatable({ name = name }, { __gc = function(o)
+                    log = log .. o.name .. ";"
+                end })
+            end
+            local kept = obj("kept")
+            obj("a")
+            do local b = obj("b"); b.self = b end
+            collectgarbage()
+            local first = log
+            kept = nil
+            collectgarbage()
+            return first, log
+        "#;
+        assert_eq!(run_with(&mut ls, src), ["b;a;", "b;a;kept;"]);
+
+        // a finalizer may resurrect its object, which runs only once
+        let src = r#"
+            saved, count = nil, 0
+            local function make()
+                setmetatable({ v = 42 }, { __gc = function(o) saved = o; count = count + 1 end })
+            end
+            make()
+            collectgarbage()
+            local v = saved and saved.v
+            saved = nil
+            collectgarbage()
+            return v, count
+        "#;
+        assert_eq!(run_with(&mut ls, src), ["42", "1"]);
+
+        // weak values are cleared before resurrection, weak keys after
+        let src = r#"
+            local wk = setmetatable({}, { __mode = "k" })
+            local wv = setmetatable({}, { __mode = "v" })
+            local function make()
+                local o = setmetatable({}, { __gc = function(o) saved = o end })
+                wk[o], wv[1] = true, o
+            end
+            make()
+            collectgarbage()
+            local k = wk[saved]
+            return wv[1] == nil, k
+        "#;
+        assert_eq!(run_with(&mut ls, src), ["true", "true"]);
+
+        // finalizers of live objects run when the state is dropped
+        thread_local! {
+            static NOTES: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+        }
+        fn note(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+            let s = ls.to_string(1);
+            NOTES.with(|notes| notes.borrow_mut().push(s));
+            Ok(0)
+        }
+        ls.register("note", note).unwrap();
+        run_with(
+            &mut ls,
+            r#"held = setmetatable({}, { __gc = function() note("held") end })"#,
+        );
+        drop(ls);
+        assert_eq!(NOTES.with(|notes| notes.take()), ["held"]);
+    }
+
+    struct Counter {
+        n: i64,
+    }
+
+    fn counter_new(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+        let n = auxlib::check_integer(ls, 1, "Counter")?;
+        ls.new_userdata(Box::new(Counter { n }), 0);
+        auxlib::set_metatable_named(ls, "Counter")?;
+        Ok(1)
+    }
+
+    fn counter_inc(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+        let u = ls.check_userdata(1, "Counter", "inc")?;
+        u.borrow_mut::<Counter>().unwrap().n += 1;
+        Ok(0)
+    }
+
+    fn counter_get(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+        let u = ls.check_userdata(1, "Counter", "get")?;
+        let n = u.borrow::<Counter>().unwrap().n;
+        ls.push_integer(n);
+        Ok(1)
+    }
+
+    fn counter_eq(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+        let a = ls.check_userdata(1, "Counter", "__eq")?;
+        let b = ls.check_userdata(2, "Counter", "__eq")?;
+        let eq = a.borrow::<Counter>().unwrap().n == b.borrow::<Counter>().unwrap().n;
+        ls.push_boolean(eq);
+        Ok(1)
+    }
+
+    #[test]
+    fn test_userdata() {
+        let mut ls = new_state();
+        assert!(auxlib::new_metatable(&mut ls, "Counter").unwrap());
+        ls.new_table();
+        ls.push_rust_function(counter_inc);
+        ls.set_field(-2, "inc").unwrap();
+        ls.push_rust_function(counter_get);
+        ls.set_field(-2, "get").unwrap();
+        ls.set_field(-2, "__index").unwrap();
+        ls.push_rust_function(counter_eq);
+        ls.set_field(-2, "__eq").unwrap();
+        assert!(!auxlib::new_metatable(&mut ls, "Counter").unwrap());
+        ls.set_top(0);
+        ls.register("Counter", counter_new).unwrap();
+
+        let src = r#"
+            local c, d = Counter(1), Counter(3)
+            c:inc()
+            c:inc()
+            local ok, e = pcall(c.inc, {})
+            return c:get(), c == d, c ~= Counter(1), rawequal(c, c), e, c
+        "#;
+        ls.register("rawequal", |ls| {
+            ls.push_boolean(ls.raw_equal(1, 2));
+            Ok(1)
+        })
+        .unwrap();
+        let results = run_with(&mut ls, src);
+        assert_eq!(
+            results[..5],
+            [
+                "3",
+                "true",
+                "true",
+                "true",
+                "bad argument #1 to 'inc' (Counter expected, got table)"
+            ]
+        );
+        assert!(results[5].starts_with("Counter: 0x"));
+
+        // user values
+        ls.new_userdata(Box::new(5u8), 2);
+        assert!(ls.is_userdata(-1));
+        assert!(!ls.is_light_userdata(-1));
+        assert_eq!(ls.type_name(ls.type_id(-1)), "userdata");
+        ls.push_string("x".to_string());
+        assert!(ls.set_iuservalue(1, 1));
+        ls.push_nil();
+        assert!(!ls.set_iuservalue(1, 3));
+        assert_eq!(ls.get_iuservalue(1, 1), Type::String as i8);
+        assert_eq!(ls.to_string(-1), "x");
+        assert_eq!(ls.get_iuservalue(1, 2), Type::Nil as i8);
+        assert_eq!(ls.get_iuservalue(1, 3), Type::None as i8);
+        let u = ls.to_userdata(1).unwrap();
+        assert!(u.is::<u8>());
+        assert_eq!(*u.borrow::<u8>().unwrap(), 5);
+        assert!(u.borrow::<i32>().is_none());
+        assert!(!ls.get_metatable(1));
+        ls.set_top(0);
+
+        // light userdata
+        let mut x = 7;
+        let p = &mut x as *mut i32 as *mut c_void;
+        ls.push_light_userdata(p);
+        ls.push_light_userdata(p);
+        assert!(ls.is_light_userdata(1));
+        assert!(ls.raw_equal(1, 2));
+        assert_eq!(ls.to_light_userdata(1), Some(p));
+        assert!(ls.to_userdata(1).is_none());
+        ls.set_top(0);
+    }
+
+    #[test]
+    fn test_userdata_collect() {
+        thread_local! {
+            static DROPPED: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+        }
+        struct Resource;
+        impl Drop for Resource {
+            fn drop(&mut self) {
+                DROPPED.with(|dropped| dropped.set(true));
+            }
+        }
+
+        // a cycle through a user value is collected and the Rust value is dropped
+        let mut ls = new_state();
+        ls.new_userdata(Box::new(Resource), 1);
+        ls.new_table();
+        ls.push_value(1);
+        ls.set_field(-2, "owner").unwrap();
+        ls.set_iuservalue(1, 1);
+        let weak = Rc::downgrade(&ls.to_userdata(1).unwrap());
+        ls.set_top(0);
+        assert!(weak.upgrade().is_some());
+        ls.full_gc();
+        assert!(weak.upgrade().is_none());
+        assert!(DROPPED.with(|dropped| dropped.get()));
+    }
+
+    #[test]
+    fn test_rust_closures() {
+        let mut ls = new_state();
+        let calls = Rc::new(std::cell::Cell::new(0));
+        let prefix = "hello".to_string();
+        let counted = calls.clone();
+        let greet: RustClosure = Rc::new(move |ls| {
+            counted.set(counted.get() + 1);
+            let name = ls.to_string(1);
+            ls.push_string(format!("{}, {}", prefix, name));
+            Ok(1)
+        });
+        ls.register_closure("greet", greet).unwrap();
+
+        // the running total lives in an upvalue, the step is captured
+        let step = 10;
+        ls.push_integer(0);
+        ls.push_closure(
+            Rc::new(move |ls| {
+                let n = ls.to_integer(lua_upvalueindex(1)) + step;
+                ls.push_integer(n);
+                ls.copy(-1, lua_upvalueindex(1));
+                Ok(1)
+            }),
+            1,
+        );
+        ls.set_global("counter").unwrap();
+
+        let src = r#"return greet("lua"), counter(), counter()"#;
+        assert_eq!(run_with(&mut ls, src), ["hello, lua", "10", "20"]);
+        assert_eq!(calls.get(), 1);
+
+        ls.get_global("greet").unwrap();
+        assert!(ls.is_rust_function(-1));
+        let f = ls.to_rust_function(-1).unwrap();
+        ls.set_top(0);
+        ls.push_string("rust".to_string());
+        assert_eq!(f(&mut ls).unwrap(), 1);
+        assert_eq!(ls.to_string(-1), "hello, rust");
+        assert_eq!(calls.get(), 2);
+    }
 }
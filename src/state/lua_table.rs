@@ -1,18 +1,24 @@
-use std::{collections::HashMap, hash::Hash};
+use std::{cell::RefCell, cmp::Ordering, collections::HashMap, rc::Rc};
 
-use crate::state::lua_value::LuaValue;
+use crate::{
+    api::op::CmpOp,
+    state::{
+        cmp_ops,
+        lua_value::{drop_values, LuaValue},
+    },
+};
 
 #[derive(Debug, Clone)]
 pub struct LuaTable {
     arr: Vec<LuaValue>,
-    map: HashMap<LuaValue, LuaValue>,
-    rdm: usize,
-}
-
-impl Hash for LuaTable {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.rdm.hash(state);
-    }
+    /// 散列部分的键在 `entries` 中的位置
+    map: HashMap<LuaValue, usize>,
+    /// 散列部分的键值对，按插入顺序排列。删除的键保留在原位（值为 nil），
+    /// 直到插入新键时才清理，所以遍历中给已有的键赋 nil 不会打断 `next`。
+    /// 数组部分末尾的值被赋 nil 时数组会收缩，`next` 把超出数组部分的整数键当作数组部分的结尾。
+    entries: Vec<(LuaValue, LuaValue)>,
+    /// 表的元表
+    pub metatable: Option<Rc<RefCell<LuaTable>>>,
 }
 
 impl LuaTable {
@@ -20,24 +26,37 @@ impl LuaTable {
         LuaTable {
             arr: Vec::with_capacity(narr),
             map: HashMap::with_capacity(nrec),
-            rdm: super::math::random(),
+            entries: Vec::with_capacity(nrec),
+            metatable: None,
         }
     }
 
+    /// 清空表，把它引用的表和闭包（包括元表）放进 `out`，其他值直接释放。
+    pub(crate) fn take_values(&mut self, out: &mut Vec<LuaValue>) {
+        let keys = self.map.drain().map(|(key, _)| key);
+        let entries = self.entries.drain(..).flat_map(|(key, val)| [key, val]);
+        let vals = self.arr.drain(..).chain(keys).chain(entries);
+        out.extend(vals.filter(LuaValue::is_container));
+        out.extend(self.metatable.take().map(LuaValue::Table));
+    }
+
     pub fn len(&self) -> usize {
         self.arr.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.arr.is_empty() && self.entries.iter().all(|(_, val)| val.is_nil())
+    }
+
     pub fn get(&self, key: &LuaValue) -> LuaValue {
         if let Some(idx) = to_index(key) {
             if idx <= self.arr.len() {
                 return self.arr[idx - 1].clone();
             }
         }
-        if let Some(val) = self.map.get(key) {
-            val.clone()
-        } else {
-            LuaValue::Nil
+        match self.map.get(key) {
+            Some(&i) => self.entries[i].1.clone(),
+            None => LuaValue::Nil,
         }
     }
 
@@ -62,7 +81,7 @@ impl LuaTable {
                 return;
             }
             if idx == arr_len + 1 {
-                self.map.remove(&key);
+                self.remove_entry(&key);
                 if !val.is_nil() {
                     self.arr.push(val);
                     self.expand_array();
@@ -71,10 +90,186 @@ impl LuaTable {
             }
         }
 
-        if !val.is_nil() {
-            self.map.insert(key, val);
+        match self.map.get(&key) {
+            Some(&i) => self.entries[i].1 = val,
+            None if !val.is_nil() => {
+                if self.entries.len() == self.entries.capacity() {
+                    self.purge_dead_entries();
+                }
+                self.map.insert(key.clone(), self.entries.len());
+                self.entries.push((key, val));
+            }
+            None => {}
+        }
+    }
+
+    /// 返回 `key` 之后的键值对，`key` 为 nil 时返回第一个键值对，遍历完时返回 `None`。
+    /// 先按顺序遍历数组部分，再按插入顺序遍历散列部分。`key` 不在表中时返回错误。
+    pub fn next(&self, key: &LuaValue) -> Result<Option<(LuaValue, LuaValue)>, &'static str> {
+        let (arr_start, entry_start) = if key.is_nil() {
+            (0, 0)
+        } else if let Some(idx) = to_index(key).filter(|&idx| idx <= self.arr.len()) {
+            (idx, 0)
+        } else if let Some(&i) = self.map.get(key) {
+            (self.arr.len(), i + 1)
+        } else if to_index(key).is_some() {
+            // the array part shrank after 'key' was cleared during the traversal
+            (self.arr.len(), 0)
         } else {
-            self.map.remove(&key);
+            return Err("invalid key to 'next'");
+        };
+
+        for (i, val) in self.arr.iter().enumerate().skip(arr_start) {
+            if !val.is_nil() {
+                return Ok(Some((LuaValue::Integer(i as i64 + 1), val.clone())));
+            }
+        }
+        Ok(self.entries[entry_start..]
+            .iter()
+            .find(|(_, val)| !val.is_nil())
+            .cloned())
+    }
+
+    /// 依次访问表持有的每一个值：数组部分的值、散列部分的键（索引和键值对中各一份）和值，供垃圾回收器遍历。
+    pub(crate) fn for_each_value(&self, mut f: impl FnMut(&LuaValue)) {
+        self.arr.iter().for_each(&mut f);
+        self.map.keys().for_each(&mut f);
+        for (key, val) in &self.entries {
+            f(key);
+            f(val);
+        }
+    }
+
+    /// 依次访问每一个键值对，包括散列部分中已删除的键（值为 nil），供垃圾回收器遍历弱表。
+    pub(crate) fn for_each_entry(&self, mut f: impl FnMut(&LuaValue, &LuaValue)) {
+        for (i, val) in self.arr.iter().enumerate() {
+            f(&LuaValue::Integer(i as i64 + 1), val);
+        }
+        for (key, val) in &self.entries {
+            f(key, val);
+        }
+    }
+
+    /// 元表中 `__mode` 字段表示的弱引用模式：键是否是弱引用，值是否是弱引用。
+    pub(crate) fn weak_mode(&self) -> (bool, bool) {
+        let mode = self.metatable.as_ref().and_then(|mt| {
+            mt.try_borrow()
+                .ok()
+                .map(|mt| mt.get(&LuaValue::Str(b"__mode".to_vec())))
+        });
+        match mode {
+            Some(LuaValue::Str(mode)) => (mode.contains(&b'k'), mode.contains(&b'v')),
+            _ => (false, false),
+        }
+    }
+
+    /// 删除弱表中键或值已经被回收的项。只是值被回收的项保留它的键，所以遍历中的 `next` 不会被打断。
+    pub(crate) fn clear_weak(
+        &mut self,
+        dead_key: impl Fn(&LuaValue) -> bool,
+        dead_val: impl Fn(&LuaValue) -> bool,
+    ) {
+        for val in self.arr.iter_mut().filter(|val| dead_val(val)) {
+            *val = LuaValue::Nil;
+        }
+        self.shrink_array();
+        let len = self.entries.len();
+        self.entries.retain(|(key, _)| !dead_key(key));
+        for (_, val) in self.entries.iter_mut().filter(|(_, val)| dead_val(val)) {
+            *val = LuaValue::Nil;
+        }
+        if self.entries.len() != len {
+            self.reindex();
+        }
+    }
+
+    /// 估计表占用的字节数
+    pub(crate) fn mem_size(&self) -> usize {
+        let value = std::mem::size_of::<LuaValue>();
+        std::mem::size_of::<LuaTable>()
+            + self.arr.capacity() * value
+            + self.map.capacity() * (value + std::mem::size_of::<usize>())
+            + self.entries.capacity() * value * 2
+    }
+
+    /// 元表中是否有名为 `name` 的字段
+    pub fn has_metafield(&self, name: &str) -> bool {
+        match &self.metatable {
+            Some(mt) => !mt
+                .borrow()
+                .get(&LuaValue::Str(name.as_bytes().to_vec()))
+                .is_nil(),
+            None => false,
+        }
+    }
+
+    /// 在数组部分的 `pos` 处插入 `val`，原来的 `t[pos..]` 依次后移。`pos` 超出 `1..=len() + 1` 时返回 `false`。
+    pub(crate) fn insert_array(&mut self, pos: i64, val: LuaValue) -> bool {
+        if pos < 1 || pos as usize > self.arr.len() + 1 {
+            return false;
+        }
+        self.arr.insert(pos as usize - 1, val);
+        if self.arr.last().unwrap().is_nil() {
+            self.shrink_array();
+        } else {
+            self.expand_array();
+        }
+        true
+    }
+
+    /// 删除数组部分 `pos` 处的值并返回，原来的 `t[pos + 1..]` 依次前移。`pos` 超出 `1..=len()` 时返回 `None`。
+    pub(crate) fn remove_array(&mut self, pos: i64) -> Option<LuaValue> {
+        if pos < 1 || pos as usize > self.arr.len() {
+            return None;
+        }
+        let val = self.arr.remove(pos as usize - 1);
+        self.shrink_array();
+        Some(val)
+    }
+
+    /// 数组部分全是数字（不含 NaN）或全是字符串时，按 `<` 原地排序并返回 `true`；否则不做任何操作并返回 `false`。
+    pub(crate) fn sort_array(&mut self) -> bool {
+        let sortable = self.arr.iter().all(|v| {
+            matches!(v, LuaValue::Integer(_)) || matches!(v, LuaValue::Number(n) if !n.is_nan())
+        }) || self.arr.iter().all(|v| matches!(v, LuaValue::Str(_)));
+        if !sortable {
+            return false;
+        }
+        let lt = |a: &LuaValue, b: &LuaValue| cmp_ops::compare(a, b, CmpOp::LT as u8).unwrap();
+        self.arr.sort_by(|a, b| {
+            if lt(a, b) {
+                Ordering::Less
+            } else if lt(b, a) {
+                Ordering::Greater
+            } else {
+                Ordering::Equal
+            }
+        });
+        true
+    }
+
+    /// 删除散列部分中的键，返回它的值。
+    fn remove_entry(&mut self, key: &LuaValue) -> LuaValue {
+        match self.map.get(key) {
+            Some(&i) => std::mem::replace(&mut self.entries[i].1, LuaValue::Nil),
+            None => LuaValue::Nil,
+        }
+    }
+
+    /// 清理已删除的键值对
+    fn purge_dead_entries(&mut self) {
+        let len = self.entries.len();
+        self.entries.retain(|(_, val)| !val.is_nil());
+        if self.entries.len() != len {
+            self.reindex();
+        }
+    }
+
+    /// 删除键值对后重建散列部分的索引
+    fn reindex(&mut self) {
+        self.map.clear();
+        for (i, (key, _)) in self.entries.iter().enumerate() {
+            self.map.insert(key.clone(), i);
         }
     }
 
@@ -92,8 +287,8 @@ impl LuaTable {
         let mut idx = self.arr.len() + 1;
         loop {
             let key = LuaValue::Integer(idx as i64);
-            if self.map.contains_key(&key) {
-                let val = self.map.remove(&key).unwrap();
+            let val = self.remove_entry(&key);
+            if !val.is_nil() {
                 self.arr.push(val);
                 idx += 1;
             } else {
@@ -103,6 +298,14 @@ impl LuaTable {
     }
 }
 
+impl Drop for LuaTable {
+    fn drop(&mut self) {
+        let mut pending = Vec::new();
+        self.take_values(&mut pending);
+        drop_values(pending);
+    }
+}
+
 fn to_index(key: &LuaValue) -> Option<usize> {
     if let LuaValue::Integer(i) = key {
         if *i >= 1 {
@@ -125,23 +328,152 @@ mod tests {
     #[test]
     fn test_table() {
         let mut tbl = LuaTable::new(10, 10);
-        tbl.put(LuaValue::Integer(1), LuaValue::Str("2".to_string()));
-        tbl.put(LuaValue::Integer(2), LuaValue::Str("3".to_string()));
+        tbl.put(LuaValue::Integer(1), LuaValue::Str(b"2".to_vec()));
+        tbl.put(LuaValue::Integer(2), LuaValue::Str(b"3".to_vec()));
         tbl.put(
-            LuaValue::Str("hello".to_string()),
-            LuaValue::Str("world".to_string()),
+            LuaValue::Str(b"hello".to_vec()),
+            LuaValue::Str(b"world".to_vec()),
         );
         tbl.put(
-            LuaValue::Str("foo".to_string()),
-            LuaValue::Str("bar".to_string()),
+            LuaValue::Str(b"foo".to_vec()),
+            LuaValue::Str(b"bar".to_vec()),
+        );
+        tbl.put(LuaValue::Number(2.5), LuaValue::Str(b"2.5".to_vec()));
+        tbl.put(LuaValue::Number(1.414), LuaValue::Str(b"1.414".to_vec()));
+        assert!(tbl.get(&LuaValue::Integer(1)) == LuaValue::Str(b"2".to_vec()));
+        assert!(tbl.get(&LuaValue::Integer(2)) == LuaValue::Str(b"3".to_vec()));
+        assert!(tbl.get(&LuaValue::Str(b"hello".to_vec())) == LuaValue::Str(b"world".to_vec()));
+        assert!(tbl.get(&LuaValue::Str(b"foo".to_vec())) == LuaValue::Str(b"bar".to_vec()));
+        assert!(tbl.get(&LuaValue::Number(2.5)) == LuaValue::Str(b"2.5".to_vec()));
+        assert!(tbl.get(&LuaValue::Number(1.414)) == LuaValue::Str(b"1.414".to_vec()));
+    }
+
+    #[test]
+    fn test_has_metafield() {
+        let mut tbl = LuaTable::new(0, 0);
+        assert!(!tbl.has_metafield("__index"));
+        let mut mt = LuaTable::new(0, 1);
+        mt.put(
+            LuaValue::Str(b"__index".to_vec()),
+            LuaValue::new_table(0, 0),
+        );
+        tbl.metatable = Some(Rc::new(RefCell::new(mt)));
+        assert!(tbl.has_metafield("__index"));
+        assert!(!tbl.has_metafield("__newindex"));
+    }
+
+    #[test]
+    fn test_next() {
+        let mut tbl = LuaTable::new(0, 0);
+        for i in 1..=3 {
+            tbl.put(LuaValue::Integer(i), LuaValue::Integer(i * 10));
+        }
+        tbl.put(LuaValue::Str(b"a".to_vec()), LuaValue::Integer(1));
+        tbl.put(LuaValue::Str(b"b".to_vec()), LuaValue::Integer(2));
+        tbl.put(LuaValue::Number(0.5), LuaValue::Integer(3));
+
+        let mut keys = vec![];
+        let mut key = LuaValue::Nil;
+        while let Some((k, _)) = tbl.next(&key).unwrap() {
+            keys.push(k.clone());
+            key = k;
+        }
+        assert_eq!(
+            keys,
+            [
+                LuaValue::Integer(1),
+                LuaValue::Integer(2),
+                LuaValue::Integer(3),
+                LuaValue::Str(b"a".to_vec()),
+                LuaValue::Str(b"b".to_vec()),
+                LuaValue::Number(0.5),
+            ]
+        );
+
+        // resume from an arbitrary key, even one that was just removed
+        tbl.put(LuaValue::Str(b"a".to_vec()), LuaValue::Nil);
+        assert_eq!(
+            tbl.next(&LuaValue::Str(b"a".to_vec())).unwrap(),
+            Some((LuaValue::Str(b"b".to_vec()), LuaValue::Integer(2)))
+        );
+        assert_eq!(
+            tbl.next(&LuaValue::Number(2.0)).unwrap(),
+            Some((LuaValue::Integer(3), LuaValue::Integer(30)))
+        );
+        assert_eq!(
+            tbl.next(&LuaValue::Integer(3)).unwrap(),
+            Some((LuaValue::Str(b"b".to_vec()), LuaValue::Integer(2)))
+        );
+        assert_eq!(tbl.next(&LuaValue::Number(0.5)).unwrap(), None);
+        assert_eq!(LuaTable::new(0, 0).next(&LuaValue::Nil).unwrap(), None);
+
+        // removed keys are purged when new keys need room
+        for i in 0..100 {
+            tbl.put(LuaValue::Integer(-i), LuaValue::Boolean(true));
+            tbl.put(LuaValue::Integer(-i), LuaValue::Nil);
+        }
+        assert!(tbl.entries.len() < 100);
+        assert_eq!(tbl.get(&LuaValue::Str(b"b".to_vec())), LuaValue::Integer(2));
+    }
+
+    #[test]
+    fn test_clear_during_next() {
+        let mut tbl = LuaTable::new(0, 0);
+        for i in 1..=3 {
+            tbl.put(LuaValue::Integer(i), LuaValue::Integer(i * 10));
+        }
+        tbl.put(LuaValue::Str(b"a".to_vec()), LuaValue::Integer(1));
+        tbl.put(LuaValue::Str(b"b".to_vec()), LuaValue::Integer(2));
+
+        // clear every field as it is visited, like 'for k in pairs(t) do t[k] = nil end'
+        let mut visited = 0;
+        let mut key = LuaValue::Nil;
+        while let Some((k, _)) = tbl.next(&key).unwrap() {
+            tbl.put(k.clone(), LuaValue::Nil);
+            visited += 1;
+            key = k;
+        }
+        assert_eq!(visited, 5);
+        assert_eq!(tbl.len(), 0);
+        assert_eq!(tbl.next(&LuaValue::Nil).unwrap(), None);
+    }
+
+    #[test]
+    fn test_next_invalid_key() {
+        assert_eq!(
+            LuaTable::new(0, 0).next(&LuaValue::Str(b"x".to_vec())),
+            Err("invalid key to 'next'")
+        );
+    }
+
+    #[test]
+    fn test_array_ops() {
+        let mut tbl = LuaTable::new(0, 0);
+        for i in 1..=3 {
+            tbl.put(LuaValue::Integer(i), LuaValue::Integer(i * 10));
+        }
+        tbl.put(LuaValue::Integer(5), LuaValue::Integer(50));
+        assert!(tbl.insert_array(1, LuaValue::Integer(0)));
+        assert!(!tbl.insert_array(7, LuaValue::Integer(0)));
+        // t[5] moved from the hash part into the array part
+        assert_eq!(tbl.len(), 5);
+        assert!(tbl.get(&LuaValue::Integer(4)) == LuaValue::Integer(30));
+        assert!(tbl.remove_array(1) == Some(LuaValue::Integer(0)));
+        assert!(tbl.remove_array(5).is_none());
+        assert_eq!(tbl.len(), 4);
+
+        tbl.put(LuaValue::Integer(2), LuaValue::Number(-1.5));
+        assert!(tbl.sort_array());
+        let vals: Vec<_> = (1..=4).map(|i| tbl.get(&LuaValue::Integer(i))).collect();
+        assert!(
+            vals == [
+                LuaValue::Number(-1.5),
+                LuaValue::Integer(10),
+                LuaValue::Integer(30),
+                LuaValue::Integer(50)
+            ]
         );
-        tbl.put(LuaValue::Number(3.14), LuaValue::Str("3.14".to_string()));
-        tbl.put(LuaValue::Number(1.414), LuaValue::Str("1.414".to_string()));
-        assert!(tbl.get(&LuaValue::Integer(1)) == LuaValue::Str("2".to_string()));
-        assert!(tbl.get(&LuaValue::Integer(2)) == LuaValue::Str("3".to_string()));
-        assert!(tbl.get(&LuaValue::Str("hello".to_string())) == LuaValue::Str("world".to_string()));
-        assert!(tbl.get(&LuaValue::Str("foo".to_string())) == LuaValue::Str("bar".to_string()));
-        assert!(tbl.get(&LuaValue::Number(3.14)) == LuaValue::Str("3.14".to_string()));
-        assert!(tbl.get(&LuaValue::Number(1.414)) == LuaValue::Str("1.414".to_string()));
+        tbl.put(LuaValue::Integer(1), LuaValue::Str(b"x".to_vec()));
+        assert!(!tbl.sort_array());
     }
 }
@@ -0,0 +1,39 @@
+use crate::api::consts::{COS_DEAD, COS_NORM, COS_YIELD, LUA_OK, LUA_YIELD};
+
+use super::{lua_stack::LuaStack, lua_value::LuaValue};
+
+/// 线程（协程）。线程运行时，它的调用栈被交换到 `LuaState` 中，这里只保存没有运行的线程的调用栈。
+#[derive(Debug)]
+pub struct LuaThread {
+    /// 没有运行时的调用栈。第一个栈帧是基础栈帧，存放主函数和它的参数、返回值或者错误对象。
+    pub(crate) frames: Vec<LuaStack>,
+    /// `LUA_OK`、挂起时的 `LUA_YIELD`，或者使线程终止的错误的状态码
+    pub(crate) status: u8,
+    /// 没有运行时不可让出的调用的层数
+    pub(crate) nny: usize,
+    /// 让出时正在使用的消息处理函数，在 `xpcall` 中让出时不为空
+    pub(crate) msgh: Option<LuaValue>,
+}
+
+impl LuaThread {
+    pub fn new(base: LuaStack) -> LuaThread {
+        LuaThread {
+            frames: vec![base],
+            status: LUA_OK,
+            nny: 0,
+            msgh: None,
+        }
+    }
+
+    /// 没有运行的线程的状态，`COS_YIELD`、`COS_NORM` 或 `COS_DEAD`。
+    pub fn status(&self) -> u8 {
+        match self.status {
+            LUA_YIELD => COS_YIELD,
+            // it resumed another coroutine and waits for it
+            LUA_OK if self.frames.len() > 1 => COS_NORM,
+            // the main function has not started yet
+            LUA_OK if self.frames.first().is_some_and(|f| f.top() > 0) => COS_YIELD,
+            _ => COS_DEAD,
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use std::{
+    any::Any,
+    cell::{Ref, RefCell, RefMut},
+    fmt,
+    rc::Rc,
+};
+
+use super::{lua_table::LuaTable, lua_value::LuaValue};
+
+/// 完整用户数据：一个 Rust 值，以及它自己的元表和用户值。
+///
+/// 三者分别放在各自的 `RefCell` 中，借用 Rust 值的同时仍然可以读取元表和用户值。
+pub struct LuaUserData {
+    data: RefCell<Box<dyn Any>>,
+    /// 用户数据的元表
+    pub(crate) metatable: RefCell<Option<Rc<RefCell<LuaTable>>>>,
+    /// 用户值，数量在创建时确定
+    pub(crate) user_values: RefCell<Vec<LuaValue>>,
+}
+
+impl LuaUserData {
+    pub fn new(data: Box<dyn Any>, nuvalue: usize) -> Self {
+        LuaUserData {
+            data: RefCell::new(data),
+            metatable: RefCell::new(None),
+            user_values: RefCell::new(vec![LuaValue::Nil; nuvalue]),
+        }
+    }
+
+    /// Rust 值的类型是否是 `T`。
+    pub fn is<T: Any>(&self) -> bool {
+        self.data.try_borrow().is_ok_and(|data| data.is::<T>())
+    }
+
+    /// 借用类型为 `T` 的 Rust 值。类型不是 `T` 或者值正在被可变借用时返回 `None`。
+    pub fn borrow<T: Any>(&self) -> Option<Ref<'_, T>> {
+        let data = self.data.try_borrow().ok()?;
+        Ref::filter_map(data, |data| data.downcast_ref::<T>()).ok()
+    }
+
+    /// 可变借用类型为 `T` 的 Rust 值。类型不是 `T` 或者值正在被借用时返回 `None`。
+    pub fn borrow_mut<T: Any>(&self) -> Option<RefMut<'_, T>> {
+        let data = self.data.try_borrow_mut().ok()?;
+        RefMut::filter_map(data, |data| data.downcast_mut::<T>()).ok()
+    }
+
+    /// 用户值的数量
+    pub fn user_value_count(&self) -> usize {
+        self.user_values.borrow().len()
+    }
+
+    /// 估计用户数据占用的字节数，不包括 Rust 值本身。
+    pub(crate) fn mem_size(&self) -> usize {
+        std::mem::size_of::<LuaUserData>()
+            + self.user_value_count() * std::mem::size_of::<LuaValue>()
+    }
+}
+
+impl fmt::Debug for LuaUserData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "userdata: {:p}", self)
+    }
+}
@@ -1,6 +1,7 @@
 use core::fmt;
 use core::hash::Hash;
 use std::cell::RefCell;
+use std::ffi::c_void;
 use std::hash::Hasher;
 use std::rc::Rc;
 
@@ -10,6 +11,9 @@ use crate::binary::chunk::Prototype;
 
 use super::closure::Closure;
 use super::lua_table::LuaTable;
+use super::lua_thread::LuaThread;
+use super::lua_userdata::LuaUserData;
+use super::math::{str_to_float, str_to_integer};
 
 #[derive(Clone)]
 pub enum LuaValue {
@@ -17,9 +21,13 @@ pub enum LuaValue {
     Boolean(bool),
     Number(f64),
     Integer(i64),
-    Str(String),
+    Str(Vec<u8>),
     Table(Rc<RefCell<LuaTable>>),
     Function(Rc<Closure>),
+    Thread(Rc<RefCell<LuaThread>>),
+    UserData(Rc<LuaUserData>),
+    /// 轻量用户数据：一个原始指针，不归 Lua 管理
+    LightUserData(*mut c_void),
 }
 
 impl fmt::Debug for LuaValue {
@@ -29,9 +37,12 @@ impl fmt::Debug for LuaValue {
             LuaValue::Boolean(b) => write!(f, "({})", b),
             LuaValue::Number(n) => write!(f, "({})", n),
             LuaValue::Integer(i) => write!(f, "({})", i),
-            LuaValue::Str(s) => write!(f, "({})", s),
+            LuaValue::Str(s) => write!(f, "({})", String::from_utf8_lossy(s)),
             LuaValue::Table(_) => write!(f, "(table)"),
             LuaValue::Function(_) => write!(f, "(function)"),
+            LuaValue::Thread(_) => write!(f, "(thread)"),
+            LuaValue::UserData(_) => write!(f, "(userdata)"),
+            LuaValue::LightUserData(p) => write!(f, "({:p})", p),
         }
     }
 }
@@ -46,6 +57,9 @@ impl PartialEq for LuaValue {
             (LuaValue::Str(s1), LuaValue::Str(s2)) => s1 == s2,
             (LuaValue::Table(t1), LuaValue::Table(t2)) => Rc::ptr_eq(t1, t2),
             (LuaValue::Function(t1), LuaValue::Function(t2)) => Rc::ptr_eq(t1, t2),
+            (LuaValue::Thread(t1), LuaValue::Thread(t2)) => Rc::ptr_eq(t1, t2),
+            (LuaValue::UserData(u1), LuaValue::UserData(u2)) => Rc::ptr_eq(u1, u2),
+            (LuaValue::LightUserData(p1), LuaValue::LightUserData(p2)) => p1 == p2,
             _ => false,
         }
     }
@@ -61,8 +75,12 @@ impl Hash for LuaValue {
             LuaValue::Number(n) => n.to_bits().hash(state),
             LuaValue::Integer(i) => i.hash(state),
             LuaValue::Str(s) => s.hash(state),
-            LuaValue::Table(t) => t.borrow().hash(state),
-            LuaValue::Function(c) => c.hash(state),
+            // tables, functions, threads and userdata are compared by identity
+            LuaValue::Table(t) => Rc::as_ptr(t).hash(state),
+            LuaValue::Function(c) => Rc::as_ptr(c).hash(state),
+            LuaValue::Thread(t) => Rc::as_ptr(t).hash(state),
+            LuaValue::UserData(u) => Rc::as_ptr(u).hash(state),
+            LuaValue::LightUserData(p) => p.hash(state),
         }
     }
 }
@@ -84,6 +102,23 @@ impl LuaValue {
             LuaValue::Str(_) => Type::String as i8,
             LuaValue::Table(_) => Type::Table as i8,
             LuaValue::Function(_) => Type::Function as i8,
+            LuaValue::Thread(_) => Type::Thread as i8,
+            LuaValue::UserData(_) => Type::UserData as i8,
+            LuaValue::LightUserData(_) => Type::LightUserData as i8,
+        }
+    }
+
+    /// 值的基本类型名
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            LuaValue::Nil => "nil",
+            LuaValue::Boolean(_) => "boolean",
+            LuaValue::Number(_) | LuaValue::Integer(_) => "number",
+            LuaValue::Str(_) => "string",
+            LuaValue::Table(_) => "table",
+            LuaValue::Function(_) => "function",
+            LuaValue::Thread(_) => "thread",
+            LuaValue::UserData(_) | LuaValue::LightUserData(_) => "userdata",
         }
     }
 
@@ -96,23 +131,41 @@ impl LuaValue {
     }
 
     pub fn to_number(&self) -> Option<f64> {
-        match self {
-            LuaValue::Integer(i) => Some(*i as f64),
-            LuaValue::Number(n) => Some(*n),
-            LuaValue::Str(s) => s.parse::<f64>().ok(), // TODO
+        match self.to_numeric()? {
+            LuaValue::Integer(i) => Some(i as f64),
+            LuaValue::Number(n) => Some(n),
             _ => None,
         }
     }
 
     pub fn to_integer(&self) -> Option<i64> {
+        match self.to_numeric()? {
+            LuaValue::Integer(i) => Some(i),
+            LuaValue::Number(n) => float_to_integer(n),
+            _ => None,
+        }
+    }
+
+    /// 数值本身，或者由字符串转换得到的数值；与 Lua 一样，看起来像整数的字符串转换为整数。
+    pub fn to_numeric(&self) -> Option<LuaValue> {
         match self {
-            LuaValue::Integer(i) => Some(*i),
-            LuaValue::Number(n) => float_to_integer(*n),
-            LuaValue::Str(s) => string_to_integer(s),
+            LuaValue::Integer(_) | LuaValue::Number(_) => Some(self.clone()),
+            LuaValue::Str(s) => {
+                let s = std::str::from_utf8(s).ok()?;
+                match str_to_integer(s) {
+                    Some(i) => Some(LuaValue::Integer(i)),
+                    None => str_to_float(s).map(LuaValue::Number),
+                }
+            }
             _ => None,
         }
     }
 
+    /// 是否是可能引用其他表和闭包、需要交给 `drop_values` 释放的值
+    pub(crate) fn is_container(&self) -> bool {
+        matches!(self, LuaValue::Table(_) | LuaValue::Function(_))
+    }
+
     pub fn new_table(narr: usize, nrec: usize) -> LuaValue {
         LuaValue::Table(Rc::new(RefCell::new(LuaTable::new(narr, nrec))))
     }
@@ -122,24 +175,34 @@ impl LuaValue {
     }
 
     pub fn new_rust_closure(f: RustFn) -> LuaValue {
-        LuaValue::Function(Rc::new(Closure::new_rust_closure(f)))
+        LuaValue::Function(Rc::new(Closure::new_rust_closure(Rc::new(f))))
     }
 }
 
-fn float_to_integer(n: f64) -> Option<i64> {
-    let i = n as i64;
-    if i as f64 == n {
-        Some(i)
-    } else {
-        None
+/// 依次释放 `pending` 中的值。只在这里被引用的表和闭包被拆开，它们引用的表和闭包加入 `pending`，
+/// 而不是由 `Rc` 的析构逐层递归地释放，所以再长的引用链也不会耗尽栈。
+pub(crate) fn drop_values(mut pending: Vec<LuaValue>) {
+    while let Some(val) = pending.pop() {
+        match val {
+            LuaValue::Table(t) => {
+                if let Ok(t) = Rc::try_unwrap(t) {
+                    t.into_inner().take_values(&mut pending);
+                }
+            }
+            LuaValue::Function(c) => {
+                if let Ok(mut c) = Rc::try_unwrap(c) {
+                    c.take_values(&mut pending);
+                }
+            }
+            _ => {}
+        }
     }
 }
 
-fn string_to_integer(s: &String) -> Option<i64> {
-    if let Ok(i) = s.parse::<i64>() {
+fn float_to_integer(n: f64) -> Option<i64> {
+    let i = n as i64;
+    if i as f64 == n {
         Some(i)
-    } else if let Ok(n) = s.parse::<f64>() {
-        float_to_integer(n)
     } else {
         None
     }
@@ -1,8 +1,3 @@
-pub fn random() -> usize {
-    let ptr = Box::into_raw(Box::new(0));
-    ptr as usize
-}
-
 pub fn number_to_integer(n: f64) -> Option<i64> {
     let i = n as i64;
     if i as f64 == n {
@@ -13,7 +8,10 @@ pub fn number_to_integer(n: f64) -> Option<i64> {
 }
 
 pub fn i_floor_div(a: i64, b: i64) -> i64 {
-    if a > 0 && b > 0 || a < 0 && b < 0 || a % b == 0 {
+    if b == -1 {
+        // avoid overflow with `i64::MIN // -1`
+        a.wrapping_neg()
+    } else if a > 0 && b > 0 || a < 0 && b < 0 || a % b == 0 {
         a / b
     } else {
         a / b - 1
@@ -25,7 +23,11 @@ pub fn f_floor_div(a: f64, b: f64) -> f64 {
 }
 
 pub fn i_mod(a: i64, b: i64) -> i64 {
-    a - b * i_floor_div(a, b)
+    if b == -1 {
+        0
+    } else {
+        a - b * i_floor_div(a, b)
+    }
 }
 
 pub fn f_mod(a: f64, b: f64) -> f64 {
@@ -38,26 +40,270 @@ pub fn f_mod(a: f64, b: f64) -> f64 {
     }
 }
 
+/// 左移，`n` 为负时右移；与 Lua 相同，移位是逻辑移位，移出 64 位后结果为 0。
 pub fn shift_left(a: i64, n: i64) -> i64 {
-    if n >= 64 {
+    if n <= -64 || n >= 64 {
         0
     } else if n >= 0 {
-        a << n
+        ((a as u64) << n) as i64
     } else {
-        shift_right(a, -n)
+        ((a as u64) >> -n) as i64
     }
 }
 
 pub fn shift_right(a: i64, n: i64) -> i64 {
-    if n >= 64 {
-        0
-    } else if n >= 0 {
-        a >> n
+    shift_left(a, n.saturating_neg())
+}
+
+/// 将字符串转换为整数，规则与 Lua 的 `l_str2int` 相同（十六进制整数回绕，十进制溢出则失败）。
+pub fn str_to_integer(s: &str) -> Option<i64> {
+    let s = s.trim_matches(is_lua_space);
+    let (neg, digits) = split_sign(s);
+    let mut a: u64 = 0;
+    let mut empty = true;
+    if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        for c in hex.chars() {
+            a = a.wrapping_mul(16).wrapping_add(c.to_digit(16)? as u64);
+            empty = false;
+        }
+    } else {
+        let max_by_10 = i64::MAX as u64 / 10;
+        let max_last_d = i64::MAX as u64 % 10;
+        for c in digits.chars() {
+            let d = c.to_digit(10)? as u64;
+            if a >= max_by_10 && (a > max_by_10 || d > max_last_d + neg as u64) {
+                return None; // overflow
+            }
+            a = a * 10 + d;
+            empty = false;
+        }
+    }
+    if empty {
+        None
+    } else if neg {
+        Some(0u64.wrapping_sub(a) as i64)
+    } else {
+        Some(a as i64)
+    }
+}
+
+/// 将字符串转换为浮点数，支持十进制与十六进制浮点数，拒绝 `inf` 与 `nan`。
+pub fn str_to_float(s: &str) -> Option<f64> {
+    let s = s.trim_matches(is_lua_space);
+    if s.contains(['n', 'N']) {
+        return None;
+    }
+    if s.contains(['x', 'X']) {
+        hex_str_to_float(s)
+    } else {
+        s.parse::<f64>().ok()
+    }
+}
+
+fn hex_str_to_float(s: &str) -> Option<f64> {
+    const MAX_SIG_DIG: i32 = 30;
+    let (neg, s) = split_sign(s);
+    let s = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X"))?;
+    let bytes = s.as_bytes();
+    let mut r = 0.0;
+    let mut sig_dig = 0;
+    let mut no_sig_dig = 0;
+    let mut e: i32 = 0;
+    let mut has_dot = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if c == b'.' {
+            if has_dot {
+                break;
+            }
+            has_dot = true;
+        } else if let Some(d) = (c as char).to_digit(16) {
+            if sig_dig == 0 && c == b'0' {
+                no_sig_dig += 1;
+            } else {
+                sig_dig += 1;
+                if sig_dig <= MAX_SIG_DIG {
+                    r = r * 16.0 + d as f64;
+                } else {
+                    e += 1; // too many digits; ignore but still count for exponent
+                }
+            }
+            if has_dot {
+                e -= 1;
+            }
+        } else {
+            break;
+        }
+        i += 1;
+    }
+    if no_sig_dig + sig_dig == 0 {
+        return None;
+    }
+    e *= 4;
+    if i < bytes.len() && (bytes[i] == b'p' || bytes[i] == b'P') {
+        i += 1;
+        let (neg_exp, rest) = split_sign(&s[i..]);
+        i = bytes.len() - rest.len();
+        let ndigits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        if ndigits == 0 {
+            return None;
+        }
+        let mut exp1: i32 = 0;
+        for c in rest[..ndigits].bytes() {
+            exp1 = exp1.saturating_mul(10).saturating_add((c - b'0') as i32);
+        }
+        e = e.saturating_add(if neg_exp { -exp1 } else { exp1 });
+        i += ndigits;
+    }
+    if i != bytes.len() {
+        return None;
+    }
+    Some(ldexp(if neg { -r } else { r }, e))
+}
+
+/// 计算 `x * 2^e`。
+pub fn ldexp(mut x: f64, mut e: i32) -> f64 {
+    while e > 1000 {
+        x *= 2f64.powi(1000);
+        e -= 1000;
+    }
+    while e < -1000 {
+        x *= 2f64.powi(-1000);
+        e += 1000;
+    }
+    x * 2f64.powi(e)
+}
+
+/// 浮点数转换为字符串，与 Lua 的 `LUAI_NUMFFORMAT`（`%.14g`）相同；结果看起来像整数时加上 `.0`，例如 `1.0`、`1e+15`。
+pub fn number_to_str(n: f64) -> String {
+    let mut s = fmt_g(n, 14, false);
+    if s.bytes().all(|b| b == b'-' || b.is_ascii_digit()) {
+        s.push_str(".0");
+    }
+    s
+}
+
+/// 按 C 的 `%.<prec>g` 格式化浮点数。`alt` 对应 `#` 标志：保留末尾的 0 和小数点。
+pub fn fmt_g(n: f64, prec: usize, alt: bool) -> String {
+    if !n.is_finite() {
+        return fmt_non_finite(n);
+    }
+    let p = prec.max(1);
+    let e = format!("{:.*e}", p - 1, n);
+    let (_, exp) = e.split_once('e').unwrap();
+    let x: i32 = exp.parse().unwrap();
+    if x < -4 || x >= p as i32 {
+        fmt_e(n, p - 1, alt, !alt)
     } else {
-        shift_left(a, -n)
+        let s = format!("{:.*}", (p as i32 - 1 - x) as usize, n);
+        if alt {
+            if s.contains('.') {
+                s
+            } else {
+                s + "."
+            }
+        } else {
+            trim_fraction(s)
+        }
     }
 }
 
+/// 按 C 的 `%.<prec>e` 格式化浮点数，指数至少有两位并且带有符号。`trim` 时去掉尾数末尾的 0。
+pub fn fmt_e(n: f64, prec: usize, alt: bool, trim: bool) -> String {
+    if !n.is_finite() {
+        return fmt_non_finite(n);
+    }
+    let e = format!("{:.*e}", prec, n);
+    let (mantissa, exp) = e.split_once('e').unwrap();
+    let x: i32 = exp.parse().unwrap();
+    let mut mantissa = mantissa.to_string();
+    if trim {
+        mantissa = trim_fraction(mantissa);
+    } else if alt && !mantissa.contains('.') {
+        mantissa.push('.');
+    }
+    let sign = if x < 0 { '-' } else { '+' };
+    format!("{}e{}{:02}", mantissa, sign, x.abs())
+}
+
+/// 按 C 的 `%a` 格式化浮点数。`prec` 是小数点后十六进制数字的个数，`None` 时使用精确表示需要的位数。
+pub fn fmt_a(n: f64, prec: Option<usize>, alt: bool) -> String {
+    if !n.is_finite() {
+        return fmt_non_finite(n);
+    }
+    let sign = if n.is_sign_negative() { "-" } else { "" };
+    let bits = n.abs().to_bits();
+    let biased = (bits >> 52) as i64;
+    let frac = bits & ((1 << 52) - 1);
+    // subnormals keep a leading 0, like glibc
+    let (mut lead, exp) = match (biased, frac) {
+        (0, 0) => (0u64, 0),
+        (0, _) => (0, -1022),
+        _ => (1, biased - 1023),
+    };
+    let digits = match prec {
+        None => format!("{:013x}", frac).trim_end_matches('0').to_string(),
+        Some(p) if p >= 13 => format!("{:013x}{}", frac, "0".repeat(p - 13)),
+        Some(p) => {
+            // round to 'p' hex digits, ties to even
+            let shift = 52 - 4 * p as u32;
+            let half = 1u64 << (shift - 1);
+            let rem = frac & ((1 << shift) - 1);
+            let mut q = frac >> shift;
+            let odd = if p == 0 { lead & 1 } else { q & 1 };
+            if rem > half || (rem == half && odd == 1) {
+                q += 1;
+            }
+            if q >> (4 * p) != 0 {
+                lead += 1; // carry into the leading digit
+                q = 0;
+            }
+            if p == 0 {
+                String::new()
+            } else {
+                format!("{:0w$x}", q, w = p)
+            }
+        }
+    };
+    let point = if !digits.is_empty() || alt { "." } else { "" };
+    format!("{}0x{}{}{}p{:+}", sign, lead, point, digits, exp)
+}
+
+/// C 格式化无穷和 NaN 的结果。
+fn fmt_non_finite(n: f64) -> String {
+    let s = if n.is_nan() { "nan" } else { "inf" };
+    if n.is_sign_negative() {
+        format!("-{}", s)
+    } else {
+        s.to_string()
+    }
+}
+
+/// 去掉小数部分末尾的 0，小数部分为空时一起去掉小数点。
+fn trim_fraction(s: String) -> String {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.').to_string()
+    } else {
+        s
+    }
+}
+
+fn split_sign(s: &str) -> (bool, &str) {
+    if let Some(rest) = s.strip_prefix('-') {
+        (true, rest)
+    } else {
+        (false, s.strip_prefix('+').unwrap_or(s))
+    }
+}
+
+fn is_lua_space(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\n' | '\r' | '\x0b' | '\x0c')
+}
+
 fn is_positive_infinite(f: f64) -> bool {
     f.is_infinite() && f.is_sign_positive()
 }
@@ -70,13 +316,6 @@ fn is_negative_infinite(f: f64) -> bool {
 mod tests {
     use super::*;
 
-    #[test]
-    fn test_random() {
-        let a = random();
-        let b = random();
-        assert_ne!(a, b);
-    }
-
     #[test]
     fn test_number_to_integer() {
         assert_eq!(number_to_integer(3.0), Some(3));
@@ -85,12 +324,58 @@ mod tests {
         assert_eq!(number_to_integer(-3.5), None);
     }
 
+    #[test]
+    fn test_number_to_str() {
+        let cases = [
+            (1.0, "1.0"),
+            (-0.0, "-0.0"),
+            (0.1, "0.1"),
+            (1e15, "1e+15"),
+            (1e14, "1e+14"),
+            (123456789012345.0, "1.2345678901234e+14"),
+            (12345678901234.0, "12345678901234.0"),
+            (1.0 / 3.0, "0.33333333333333"),
+            (1e-5, "1e-05"),
+            (0.0001, "0.0001"),
+            (2f64.powi(63), "9.2233720368548e+18"),
+            (f64::INFINITY, "inf"),
+            (f64::NEG_INFINITY, "-inf"),
+        ];
+        for (n, s) in cases {
+            assert_eq!(number_to_str(n), s, "{}", n);
+        }
+        assert_eq!(fmt_g(100.0, 3, true), "100.");
+        assert_eq!(fmt_g(0.5, 0, false), "0.5");
+        assert_eq!(fmt_e(12345.678, 2, false, false), "1.23e+04");
+        assert_eq!(fmt_e(1.0, 0, true, false), "1.e+00");
+    }
+
+    #[test]
+    fn test_fmt_a() {
+        assert_eq!(fmt_a(1.0, None, false), "0x1p+0");
+        assert_eq!(fmt_a(0.0, None, false), "0x0p+0");
+        assert_eq!(fmt_a(-0.5, None, false), "-0x1p-1");
+        assert_eq!(fmt_a(3.0, None, true), "0x1.8p+1");
+        assert_eq!(
+            fmt_a(std::f64::consts::PI, None, false),
+            "0x1.921fb54442d18p+1"
+        );
+        assert_eq!(fmt_a(5e-324, None, false), "0x0.0000000000001p-1022");
+        assert_eq!(fmt_a(1.0, None, true), "0x1.p+0");
+        assert_eq!(fmt_a(1.0, Some(3), false), "0x1.000p+0");
+        assert_eq!(fmt_a(1.5, Some(0), false), "0x2p+0");
+        assert_eq!(fmt_a(2.5, Some(0), false), "0x1p+1");
+        assert_eq!(fmt_a(1.96875, Some(1), false), "0x2.0p+0");
+        assert_eq!(fmt_a(f64::NEG_INFINITY, None, false), "-inf");
+    }
+
     #[test]
     fn test_i_floor_div() {
         assert_eq!(i_floor_div(5, 3), 1);
         assert_eq!(i_floor_div(-5, 3), -2);
         assert_eq!(i_floor_div(5, -3), -2);
         assert_eq!(i_floor_div(-5, -3), 1);
+        assert_eq!(i_floor_div(i64::MIN, -1), i64::MIN);
     }
 
     #[test]
@@ -107,6 +392,7 @@ mod tests {
         assert_eq!(i_mod(-5, 3), 1);
         assert_eq!(i_mod(5, -3), -1);
         assert_eq!(i_mod(-5, -3), -2);
+        assert_eq!(i_mod(i64::MIN, -1), 0);
     }
 
     #[test]
@@ -139,5 +425,36 @@ mod tests {
         assert_eq!(shift_right(0xFF, -4), 0xFF0);
         assert_eq!(shift_right(0xFF, -8), 0xFF00);
         assert_eq!(shift_right(0xFF, 100), 0x0);
+        assert_eq!(shift_right(-1, 60), 0xF);
+        assert_eq!(shift_right(1, i64::MIN), 0);
+    }
+
+    #[test]
+    fn test_str_to_integer() {
+        assert_eq!(str_to_integer("123"), Some(123));
+        assert_eq!(str_to_integer(" -0x10 "), Some(-16));
+        assert_eq!(str_to_integer("0xffffffffffffffff"), Some(-1));
+        assert_eq!(str_to_integer("9223372036854775807"), Some(i64::MAX));
+        assert_eq!(str_to_integer("-9223372036854775808"), Some(i64::MIN));
+        assert_eq!(str_to_integer("9223372036854775808"), None);
+        assert_eq!(str_to_integer("1.0"), None);
+        assert_eq!(str_to_integer(""), None);
+    }
+
+    #[test]
+    fn test_str_to_float() {
+        assert_eq!(str_to_float("1.5"), Some(1.5));
+        assert_eq!(str_to_float(".5e1"), Some(5.0));
+        assert_eq!(str_to_float("0x.8"), Some(0.5));
+        assert_eq!(str_to_float("0xA.8p1"), Some(21.0));
+        assert_eq!(str_to_float("0x1p-2"), Some(0.25));
+        assert_eq!(
+            str_to_float("9223372036854775808"),
+            Some(9223372036854775808.0)
+        );
+        assert_eq!(str_to_float("inf"), None);
+        assert_eq!(str_to_float("nan"), None);
+        assert_eq!(str_to_float("0x"), None);
+        assert_eq!(str_to_float("1e"), None);
     }
 }
@@ -1,11 +1,14 @@
-mod arith_ops;
+pub mod arith_ops;
 mod closure;
 mod cmp_ops;
+mod gc;
 mod lua_stack;
 mod lua_state;
 pub mod lua_table;
+mod lua_thread;
+pub mod lua_userdata;
 pub mod lua_value;
-mod math;
+pub mod math;
 
 use std::rc::Rc;
 
@@ -23,7 +26,12 @@ pub fn new_lua_state_with_proto(proto: Rc<Prototype>) -> LuaState {
 
 #[cfg(test)]
 mod tests {
-    use crate::api::{r#type::Type, LuaAPI};
+    use crate::api::{
+        consts::{LUA_ERRRUN, LUA_ERRSYNTAX, LUA_OK},
+        r#type::Type,
+        LuaAPI,
+    };
+    use crate::compiler::LoadError;
 
     use super::*;
     #[test]
@@ -49,6 +57,321 @@ mod tests {
         print_stack(&ls);
     }
 
+    #[test]
+    fn test_load_text_chunk() {
+        let src = r#"
+            local sum = 0
+            for i = 1, 100 do if i % 2 == 0 then sum = sum + i end end
+            local t = {}
+            for i = 1, 10 do t[#t + 1] = i * i end
+            local f = 0.0
+            for x = 0.5, 2, 0.5 do f = f + x end
+            local n = 0
+            while n < 5 do n = n + 1 end
+            repeat n = n - 1 until n == 2
+            local function max(...)
+                local args = { ... }
+                local m
+                for i = 1, #args do
+                    if m == nil or args[i] > m then m = args[i] end
+                end
+                return m
+            end
+            return sum, #t, t[10], f, "a" .. 1 .. "b", n, max(3, 9, 7), 3 < 4 and "yes" or "no"
+        "#;
+        let mut ls = new_lua_state();
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        ls.call(0, -1).unwrap();
+        assert_eq!(ls.get_top(), 8);
+        assert_eq!(ls.to_integer(1), 2550);
+        assert_eq!(ls.to_integer(2), 10);
+        assert_eq!(ls.to_integer(3), 100);
+        assert_eq!(ls.to_number(4), 5.0);
+        assert_eq!(ls.to_string(5), "a1b");
+        assert_eq!(ls.to_integer(6), 2);
+        assert_eq!(ls.to_integer(7), 9);
+        assert_eq!(ls.to_string(8), "yes");
+    }
+
+    #[test]
+    fn test_load_optimized() {
+        let src = r#"
+            local t = { 10, 20, 30, k = "v" }
+            local a, b = 7, 2.5
+            local x = a + 1
+            local y = 1 + a - 3
+            local z = a * b + 2 ^ 3
+            local s = (a << 2) | (1 << a) | (a >> 1)
+            local c = 0
+            for i = 1, 10 do
+                if i == 3 or i > 8 then c = c + 1 end
+                if 5 <= i and i ~= 7 then c = c + 10 end
+            end
+            t[4] = t[1] + #t.k
+            t.w = -(-a)
+            do return x, y, z, s, c, t[4], t.w, 10 // 3, 1 == 1.0 end
+            return 0
+        "#;
+        let mut results = Vec::new();
+        for optimize in [false, true] {
+            let mut ls = new_lua_state();
+            ls.set_optimize(optimize);
+            assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+            ls.call(0, -1).unwrap();
+            assert_eq!(ls.get_top(), 9);
+            assert_eq!(ls.to_integer(1), 8);
+            assert_eq!(ls.to_integer(2), 5);
+            assert_eq!(ls.to_number(3), 25.5);
+            assert_eq!(ls.to_integer(4), 28 | 128 | 3);
+            assert_eq!(ls.to_integer(5), 53);
+            assert_eq!(ls.to_integer(6), 11);
+            assert_eq!(ls.to_integer(7), 7);
+            assert_eq!(ls.to_integer(8), 3);
+            assert!(ls.to_boolean(9));
+            results.push((1..=8).map(|i| ls.to_number(i)).collect::<Vec<_>>());
+        }
+        assert_eq!(results[0], results[1]);
+    }
+
+    #[test]
+    fn test_upvalues() {
+        let src = r#"
+            local function counter()
+                local n = 0
+                return function() n = n + 1 return n end, function() return n end
+            end
+            local inc, get = counter()
+            inc() inc()
+            local inc2 = counter()
+            inc2()
+
+            local fs = {}
+            for i = 1, 3 do fs[i] = function() return i end end
+
+            local x = 1
+            local function outer()
+                return function() x = x * 10 return x end
+            end
+            local f = outer()
+            f()
+
+            total = get() + fs[1]() + fs[2]() + fs[3]()
+            local M = {}
+            function M.twice(v) return v * 2 end
+            _ENV.module = M
+            return get(), inc2(), fs[3](), x, f(), total, module.twice(21)
+        "#;
+        let mut ls = new_lua_state();
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        ls.call(0, -1).unwrap();
+        assert_eq!(ls.get_top(), 7);
+        assert_eq!(ls.to_integer(1), 2);
+        assert_eq!(ls.to_integer(2), 2);
+        assert_eq!(ls.to_integer(3), 3);
+        assert_eq!(ls.to_integer(4), 10);
+        assert_eq!(ls.to_integer(5), 100);
+        assert_eq!(ls.to_integer(6), 8);
+        assert_eq!(ls.to_integer(7), 42);
+        ls.get_global("total").unwrap();
+        assert_eq!(ls.to_integer(-1), 8);
+    }
+
+    #[test]
+    fn test_generic_for() {
+        let src = r#"
+            local function iter(t, i)
+                i = i + 1
+                local v = t[i]
+                if v ~= nil then return i, v end
+            end
+            local function items(t) return iter, t, 0, nil end
+            local function range(n)
+                local i = 0
+                return function() if i < n then i = i + 1 return i end end
+            end
+
+            local t = { 10, 20, 30, 40 }
+            local sum, count, fs = 0, 0, {}
+            for i, v in items(t) do
+                sum = sum + i * v
+                fs[#fs + 1] = function() return v end
+                if v == 30 then break end
+            end
+            for i in range(3) do
+                for j in range(i) do count = count + 1 end
+            end
+            for _ in iter, t, 4, false do count = count + 100 end
+            return sum, count, fs[1]() + fs[3]()
+        "#;
+        let mut ls = new_lua_state();
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        ls.call(0, -1).unwrap();
+        assert_eq!(ls.get_top(), 3);
+        assert_eq!(ls.to_integer(1), 10 + 40 + 90);
+        assert_eq!(ls.to_integer(2), 6);
+        assert_eq!(ls.to_integer(3), 40);
+    }
+
+    #[test]
+    fn test_generic_for_closing_value() {
+        let src = "for _ in next, {}, nil, {} do end";
+        let mut ls = new_lua_state();
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        let err = ls.call(0, 0).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "test:1: variable '(for state)' got a non-closable value"
+        );
+    }
+
+    #[test]
+    fn test_metamethods() {
+        let src = r#"
+            Vec = { __name = "Vec" }
+            Vec.__index = Vec
+            function Vec:sum() return self.x + self.y end
+            function Vec.__add(a, b)
+                if a == 1 then return 100 + b.x end
+                return a.x + b
+            end
+            function Vec.__mul(a, b) return a * b.y end
+            function Vec.__sub(a, b) return a.x - b.x end
+            function Vec.__eq(a, b) return a.x == b.x end
+            function Vec.__lt(a, b) return a:sum() < b:sum() end
+            function Vec.__le(a, b) return a:sum() <= b:sum() end
+            function Vec.__len(v) return v.x + v.y end
+            function Vec.__unm(v) return -v.y end
+            function Vec.__concat(a, b)
+                if a == "v" then return a .. b.x end
+                return a.x .. b
+            end
+            function Vec.__call(self, n) return self.y * n end
+            p, q = { x = 1, y = 2 }, { x = 1, y = 5 }
+
+            log = {}
+            proxy = {}
+            Proxy = {
+                __index = function(t, k) return k .. "?" end,
+                __newindex = function(t, k, v) log[#log + 1] = k .. "=" .. v end,
+            }
+            defaults = { z = 0 }
+            chain = {}
+            Chain = { __index = defaults, __newindex = defaults }
+
+            function run()
+                chain.z = chain.z + 3
+                proxy.a = 1
+                proxy.b = 2
+                return p + 10, 1 + p, 2.5 * p, p - q, p == q, p ~= q, p < q, q <= p,
+                    #p, -p, "v" .. p, p .. "!", p(4), p:sum(), proxy.name, log[2], defaults.z
+            end
+        "#;
+        for optimize in [false, true] {
+            let mut ls = new_lua_state();
+            ls.set_optimize(optimize);
+            assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+            ls.call(0, 0).unwrap();
+            for (obj, mt) in [
+                ("p", "Vec"),
+                ("q", "Vec"),
+                ("proxy", "Proxy"),
+                ("chain", "Chain"),
+            ] {
+                ls.get_global(obj).unwrap();
+                ls.get_global(mt).unwrap();
+                ls.set_metatable(-2);
+                ls.pop(1);
+            }
+
+            ls.get_global("run").unwrap();
+            ls.call(0, -1).unwrap();
+            assert_eq!(ls.get_top(), 17);
+            assert_eq!(ls.to_integer(1), 11);
+            assert_eq!(ls.to_integer(2), 101);
+            assert_eq!(ls.to_number(3), 5.0);
+            assert_eq!(ls.to_integer(4), 0);
+            assert!(ls.to_boolean(5));
+            assert!(!ls.to_boolean(6));
+            assert!(ls.to_boolean(7));
+            assert!(!ls.to_boolean(8));
+            assert_eq!(ls.to_integer(9), 3);
+            assert_eq!(ls.to_integer(10), -2);
+            assert_eq!(ls.to_string(11), "v1");
+            assert_eq!(ls.to_string(12), "1!");
+            assert_eq!(ls.to_integer(13), 8);
+            assert_eq!(ls.to_integer(14), 3);
+            assert_eq!(ls.to_string(15), "name?");
+            assert_eq!(ls.to_string(16), "b=2");
+            assert_eq!(ls.to_integer(17), 3);
+
+            // the chained assignment landed in 'defaults', not in 'chain'
+            ls.get_global("chain").unwrap();
+            ls.push_string("z".to_string());
+            assert_eq!(ls.raw_get(-2), Type::Nil as i8);
+        }
+    }
+
+    #[test]
+    fn test_metamethod_missing() {
+        let src = "local t = {} return t + 1";
+        let mut ls = new_lua_state();
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        assert_eq!(ls.pcall(0, 0, 0), LUA_ERRRUN);
+        assert_eq!(
+            ls.to_string(-1),
+            "test:1: attempt to perform arithmetic on a table value"
+        );
+    }
+
+    #[test]
+    fn test_load_mode() {
+        let mut ls = new_lua_state();
+        let status = ls.load(b"return 1".to_vec(), "=test", "b");
+        assert_eq!(status, LUA_ERRSYNTAX);
+        assert_eq!(
+            ls.to_string(-1),
+            "attempt to load a text chunk (mode is 'b')"
+        );
+
+        let binary = std::fs::read("lua/hello_world.luac").unwrap();
+        let status = ls.load(binary, "=test", "t");
+        assert_eq!(status, LUA_ERRSYNTAX);
+        assert_eq!(
+            ls.to_string(-1),
+            "attempt to load a binary chunk (mode is 't')"
+        );
+
+        let status = ls.load(b"x = = 1".to_vec(), "=test", "bt");
+        assert_eq!(status, LUA_ERRSYNTAX);
+        assert_eq!(ls.to_string(-1), "test:1: unexpected symbol near '='");
+    }
+
+    #[test]
+    fn test_load_chunk_error() {
+        let mut ls = new_lua_state();
+        let top = ls.get_top();
+        let err = ls
+            .load_chunk(b"return 1".to_vec(), "=test", "b")
+            .unwrap_err();
+        assert!(matches!(err, LoadError::Mode { .. }));
+        assert_eq!(ls.get_top(), top);
+
+        let err = ls
+            .load_chunk(b"\x1bLua\x54".to_vec(), "=test", "b")
+            .unwrap_err();
+        assert!(matches!(err, LoadError::Binary(_)));
+
+        let err = ls
+            .load_chunk(b"x = = 1".to_vec(), "=test", "t")
+            .unwrap_err();
+        assert!(matches!(err, LoadError::Syntax(_)));
+        assert_eq!(err.to_string(), "test:1: unexpected symbol near '='");
+        assert_eq!(ls.get_top(), top);
+
+        assert!(ls.load_chunk(b"return 1".to_vec(), "=test", "t").is_ok());
+        assert_eq!(ls.get_top(), top + 1);
+    }
+
     fn print_stack(ls: &LuaState) {
         let top = ls.get_top();
         for i in 1..top + 1 {
@@ -0,0 +1,156 @@
+use crate::{
+    api::{consts::LUA_REGISTRYINDEX, r#type::Type, LuaAPI, LuaError},
+    state::math::{number_to_integer, str_to_float, str_to_integer},
+};
+
+/// 参数错误，形如 `bad argument #1 to 'f' (extramsg)`。
+pub fn arg_error(arg: isize, fname: &str, extramsg: &str) -> LuaError {
+    LuaError::runtime(format!(
+        "bad argument #{} to '{}' ({})",
+        arg, fname, extramsg
+    ))
+}
+
+/// 参数类型错误，形如 `bad argument #1 to 'f' (number expected, got nil)`。
+pub fn type_error(ls: &dyn LuaAPI, arg: isize, fname: &str, tname: &str) -> LuaError {
+    let got = ls.type_name(ls.type_id(arg));
+    arg_error(arg, fname, &format!("{} expected, got {}", tname, got))
+}
+
+/// 检查第 `arg` 个参数存在，可以是包括 nil 在内的任意值。
+pub fn check_any(ls: &dyn LuaAPI, arg: isize, fname: &str) -> Result<(), LuaError> {
+    if ls.is_none(arg) {
+        return Err(arg_error(arg, fname, "value expected"));
+    }
+    Ok(())
+}
+
+/// 检查第 `arg` 个参数的类型是 `t`。
+pub fn check_type(ls: &dyn LuaAPI, arg: isize, t: Type, fname: &str) -> Result<(), LuaError> {
+    if ls.type_id(arg) != t as i8 {
+        return Err(type_error(ls, arg, fname, ls.type_name(t as i8)));
+    }
+    Ok(())
+}
+
+/// 第 `arg` 个参数是字符串时返回它，用于把数字字符串转换为数字。
+fn string_arg(ls: &dyn LuaAPI, arg: isize) -> Option<String> {
+    (ls.type_id(arg) == Type::String as i8).then(|| ls.to_string(arg))
+}
+
+/// 检查第 `arg` 个参数是整数或者可以转换为整数，返回转换后的值。
+///
+/// 与 `luaL_checkinteger` 相同，接受值为整数的浮点数和数字字符串。
+pub fn check_integer(ls: &dyn LuaAPI, arg: isize, fname: &str) -> Result<i64, LuaError> {
+    if let Some(n) = ls.to_integerx(arg) {
+        return Ok(n);
+    }
+    let s = string_arg(ls, arg);
+    if let Some(n) = s.as_deref().and_then(str_to_integer) {
+        return Ok(n);
+    }
+    match ls
+        .to_numberx(arg)
+        .or_else(|| s.as_deref().and_then(str_to_float))
+    {
+        Some(n) => number_to_integer(n)
+            .ok_or_else(|| arg_error(arg, fname, "number has no integer representation")),
+        None => Err(type_error(ls, arg, fname, "number")),
+    }
+}
+
+/// 检查第 `arg` 个参数是数字或者数字字符串，返回它的浮点数值。
+pub fn check_number(ls: &dyn LuaAPI, arg: isize, fname: &str) -> Result<f64, LuaError> {
+    let n = ls.to_numberx(arg).or_else(|| {
+        let s = string_arg(ls, arg)?;
+        str_to_integer(&s)
+            .map(|i| i as f64)
+            .or_else(|| str_to_float(&s))
+    });
+    n.ok_or_else(|| type_error(ls, arg, fname, "number"))
+}
+
+/// 与 `check_integer` 相同，但是参数不存在或为 nil 时返回默认值 `def`。
+pub fn opt_integer(ls: &dyn LuaAPI, arg: isize, def: i64, fname: &str) -> Result<i64, LuaError> {
+    if ls.is_none_or_nil(arg) {
+        Ok(def)
+    } else {
+        check_integer(ls, arg, fname)
+    }
+}
+
+/// 检查第 `arg` 个参数是字符串或者数字，返回它的字符串形式。
+pub fn check_string(ls: &dyn LuaAPI, arg: isize, fname: &str) -> Result<String, LuaError> {
+    ls.to_stringx(arg)
+        .ok_or_else(|| type_error(ls, arg, fname, "string"))
+}
+
+/// 与 `check_string` 相同，但是返回字符串的原始字节，字符串可以不是合法的 UTF-8。
+pub fn check_lstring(ls: &dyn LuaAPI, arg: isize, fname: &str) -> Result<Vec<u8>, LuaError> {
+    ls.to_bytesx(arg)
+        .ok_or_else(|| type_error(ls, arg, fname, "string"))
+}
+
+/// 与 `check_string` 相同，但是参数不存在或为 nil 时返回默认值 `def`。
+pub fn opt_string(ls: &dyn LuaAPI, arg: isize, def: &str, fname: &str) -> Result<String, LuaError> {
+    if ls.is_none_or_nil(arg) {
+        Ok(def.to_string())
+    } else {
+        check_string(ls, arg, fname)
+    }
+}
+
+/// 检查第 `arg` 个参数是 `lst` 中的一个字符串，返回它在 `lst` 中的位置。参数不存在或为 nil 时使用 `def`。
+pub fn check_option(
+    ls: &dyn LuaAPI,
+    arg: isize,
+    def: Option<&str>,
+    lst: &[&str],
+    fname: &str,
+) -> Result<usize, LuaError> {
+    let name = match def {
+        Some(def) => opt_string(ls, arg, def, fname)?,
+        None => check_string(ls, arg, fname)?,
+    };
+    lst.iter()
+        .position(|&opt| opt == name)
+        .ok_or_else(|| arg_error(arg, fname, &format!("invalid option '{}'", name)))
+}
+
+/// 在注册表中创建名为 `tname` 的元表并推入栈顶，元表的 `__name` 字段为 `tname`。
+/// 注册表中已经有这个名字时推入已有的值并返回 `false`。
+pub fn new_metatable(ls: &mut dyn LuaAPI, tname: &str) -> Result<bool, LuaError> {
+    if ls.get_field(LUA_REGISTRYINDEX, tname)? != Type::Nil as i8 {
+        return Ok(false);
+    }
+    ls.pop(1);
+    ls.create_table(0, 2);
+    ls.push_string(tname.to_string());
+    ls.set_field(-2, "__name")?;
+    ls.push_value(-1);
+    ls.set_field(LUA_REGISTRYINDEX, tname)?;
+    Ok(true)
+}
+
+/// 把注册表中名为 `tname` 的元表设置为栈顶的值的元表。
+pub fn set_metatable_named(ls: &mut dyn LuaAPI, tname: &str) -> Result<(), LuaError> {
+    ls.get_field(LUA_REGISTRYINDEX, tname)?;
+    ls.set_metatable(-2);
+    Ok(())
+}
+
+/// 把指定索引处的值的元表中的 `event` 字段推入栈顶，不调用元方法，返回字段的类型。
+/// 值没有元表或者字段为 nil 时不推入任何值，返回 `Type::Nil`。
+pub fn get_metafield(ls: &mut dyn LuaAPI, obj: isize, event: &str) -> i8 {
+    if !ls.get_metatable(obj) {
+        return Type::Nil as i8;
+    }
+    ls.push_string(event.to_string());
+    let tt = ls.raw_get(-2);
+    if tt == Type::Nil as i8 {
+        ls.pop(2);
+    } else {
+        ls.remove(-2);
+    }
+    tt
+}
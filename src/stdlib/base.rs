@@ -0,0 +1,924 @@
+use std::io::{self, Write};
+
+use crate::api::{
+    consts::{
+        LUA_GCCOLLECT, LUA_GCCOUNT, LUA_GCCOUNTB, LUA_GCGEN, LUA_GCINC, LUA_GCISRUNNING,
+        LUA_GCRESTART, LUA_GCSETPAUSE, LUA_GCSETSTEPMUL, LUA_GCSTEP, LUA_GCSTOP, LUA_MULTRET,
+        LUA_OK, LUA_VERSION, LUA_YIELD,
+    },
+    r#type::Type,
+    LuaAPI, LuaError, RustFn,
+};
+
+use super::auxlib::{
+    arg_error, check_any, check_integer, check_option, check_type, get_metafield, opt_integer,
+    type_error,
+};
+
+/// 把基础库函数注册到全局表中，并设置 `_G` 和 `_VERSION`。
+pub fn open_base(ls: &mut dyn LuaAPI) -> Result<(), LuaError> {
+    let funcs: [(&str, RustFn); 19] = [
+        ("assert", base_assert),
+        ("collectgarbage", base_collectgarbage),
+        ("error", base_error),
+        ("getmetatable", base_getmetatable),
+        ("ipairs", base_ipairs),
+        ("next", base_next),
+        ("pairs", base_pairs),
+        ("pcall", base_pcall),
+        ("print", base_print),
+        ("rawequal", base_rawequal),
+        ("rawlen", base_rawlen),
+        ("rawget", base_rawget),
+        ("rawset", base_rawset),
+        ("select", base_select),
+        ("setmetatable", base_setmetatable),
+        ("tonumber", base_tonumber),
+        ("tostring", base_tostring),
+        ("type", base_type),
+        ("xpcall", base_xpcall),
+    ];
+    for (name, f) in funcs {
+        ls.register(name, f)?;
+    }
+    ls.push_global_table();
+    ls.set_global("_G")?;
+    ls.push_string(LUA_VERSION.to_string());
+    ls.set_global("_VERSION")
+}
+
+// print (···)
+fn base_print(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let n = ls.get_top();
+    let mut line = Vec::new();
+    for i in 1..=n {
+        if i > 1 {
+            line.push(b'\t');
+        }
+        line.extend_from_slice(&ls.to_bytes_meta(i)?);
+    }
+    line.push(b'\n');
+    let mut stdout = io::stdout().lock();
+    // like C Lua, output errors are ignored
+    let _ = stdout.write_all(&line).and_then(|()| stdout.flush());
+    Ok(0)
+}
+
+// type (v)
+fn base_type(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    check_any(ls, 1, "type")?;
+    let name = ls.type_name(ls.type_id(1)).to_string();
+    ls.push_string(name);
+    Ok(1)
+}
+
+// tostring (v)
+fn base_tostring(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    check_any(ls, 1, "tostring")?;
+    let s = ls.to_bytes_meta(1)?;
+    ls.push_bytes(s);
+    Ok(1)
+}
+
+// tonumber (e [, base])
+fn base_tonumber(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    if ls.is_none_or_nil(2) {
+        // standard conversion
+        if ls.type_id(1) == Type::Number as i8 {
+            ls.set_top(1);
+            return Ok(1);
+        }
+        if let Some(s) = ls.to_stringx(1) {
+            if ls.string_to_number(&s) {
+                return Ok(1);
+            }
+        }
+        check_any(ls, 1, "tonumber")?;
+    } else {
+        let base = check_integer(ls, 2, "tonumber")?;
+        check_type(ls, 1, Type::String, "tonumber")?; // no numbers as strings
+        let s = ls.to_string(1);
+        if !(2..=36).contains(&base) {
+            return Err(arg_error(2, "tonumber", "base out of range"));
+        }
+        if let Some(n) = str_to_int_base(&s, base as u32) {
+            ls.push_integer(n);
+            return Ok(1);
+        }
+    }
+    ls.push_nil(); // not a number
+    Ok(1)
+}
+
+/// 把字符串按 `base` 进制转换为整数，与 Lua 的 `l_str2int` 相同：允许前后的空白和负号，溢出时回绕。
+fn str_to_int_base(s: &str, base: u32) -> Option<i64> {
+    let s = s.trim_matches(|c: char| c.is_ascii_whitespace() || c == '\x0b');
+    let (neg, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    let mut n: u64 = 0;
+    for c in digits.chars() {
+        if !c.is_ascii_alphanumeric() {
+            return None;
+        }
+        let digit = c.to_digit(36)?;
+        if digit >= base {
+            return None;
+        }
+        n = n.wrapping_mul(base as u64).wrapping_add(digit as u64);
+    }
+    Some(if neg { 0u64.wrapping_sub(n) } else { n } as i64)
+}
+
+// select (index, ···)
+fn base_select(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let n = ls.get_top() as i64;
+    if ls.type_id(1) == Type::String as i8 && ls.to_string(1).starts_with('#') {
+        ls.push_integer(n - 1);
+        return Ok(1);
+    }
+    let mut i = check_integer(ls, 1, "select")?;
+    if i < 0 {
+        i += n;
+    } else if i > n {
+        i = n;
+    }
+    if i < 1 {
+        return Err(arg_error(1, "select", "index out of range"));
+    }
+    Ok((n - i) as usize)
+}
+
+// assert (v [, message])
+fn base_assert(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    if ls.to_boolean(1) {
+        return Ok(ls.get_top() as usize); // return all arguments
+    }
+    check_any(ls, 1, "assert")?; // there must be a condition
+    ls.remove(1); // remove it
+    ls.push_string("assertion failed!".to_string()); // default message
+    ls.set_top(1); // leave only message (default if no other one)
+    Err(ls.error())
+}
+
+// rawequal (v1, v2)
+fn base_rawequal(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    check_any(ls, 1, "rawequal")?;
+    check_any(ls, 2, "rawequal")?;
+    let eq = ls.raw_equal(1, 2);
+    ls.push_boolean(eq);
+    Ok(1)
+}
+
+// rawlen (v)
+fn base_rawlen(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let t = ls.type_id(1);
+    if t != Type::Table as i8 && t != Type::String as i8 {
+        return Err(type_error(ls, 1, "rawlen", "table or string"));
+    }
+    let len = ls.raw_len(1);
+    ls.push_integer(len as i64);
+    Ok(1)
+}
+
+// rawget (table, index)
+fn base_rawget(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    check_type(ls, 1, Type::Table, "rawget")?;
+    check_any(ls, 2, "rawget")?;
+    ls.set_top(2);
+    ls.raw_get(1);
+    Ok(1)
+}
+
+// rawset (table, index, value)
+fn base_rawset(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    check_type(ls, 1, Type::Table, "rawset")?;
+    check_any(ls, 2, "rawset")?;
+    check_any(ls, 3, "rawset")?;
+    ls.set_top(3);
+    ls.raw_set(1)?;
+    Ok(1)
+}
+
+// next (table [, index])
+fn base_next(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    check_type(ls, 1, Type::Table, "next")?;
+    ls.set_top(2); // create a 2nd argument if there isn't one
+    if ls.next(1)? {
+        Ok(2)
+    } else {
+        ls.push_nil();
+        Ok(1)
+    }
+}
+
+// pairs (t)
+fn base_pairs(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    check_any(ls, 1, "pairs")?;
+    if get_metafield(ls, 1, "__pairs") == Type::Nil as i8 {
+        // no metamethod
+        ls.push_rust_function(base_next); // will return generator,
+        ls.push_value(1); // state,
+        ls.push_nil(); // and initial value
+    } else {
+        ls.push_value(1); // argument 'self' to metamethod
+        ls.call(1, 3)?; // get 3 values from metamethod
+    }
+    Ok(3)
+}
+
+// ipairs (t)
+fn base_ipairs(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    check_any(ls, 1, "ipairs")?;
+    ls.push_rust_function(ipairs_aux); // iteration function
+    ls.push_value(1); // state
+    ls.push_integer(0); // initial value
+    Ok(3)
+}
+
+/// `ipairs` 返回的迭代函数：读取 `t[i + 1]`，值为 nil 时结束遍历。
+fn ipairs_aux(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let i = check_integer(ls, 2, "ipairs")?.wrapping_add(1);
+    ls.push_integer(i);
+    if ls.get_i(1, i)? == Type::Nil as i8 {
+        Ok(1)
+    } else {
+        Ok(2)
+    }
+}
+
+// getmetatable (object)
+fn base_getmetatable(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    check_any(ls, 1, "getmetatable")?;
+    if !ls.get_metatable(1) {
+        ls.push_nil();
+        return Ok(1); // no metatable
+    }
+    get_metafield(ls, 1, "__metatable");
+    Ok(1) // returns either __metatable field (if present) or metatable
+}
+
+// setmetatable (table, metatable)
+fn base_setmetatable(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let t = ls.type_id(2);
+    check_type(ls, 1, Type::Table, "setmetatable")?;
+    if t != Type::Nil as i8 && t != Type::Table as i8 {
+        return Err(type_error(ls, 2, "setmetatable", "nil or table"));
+    }
+    if get_metafield(ls, 1, "__metatable") != Type::Nil as i8 {
+        return Err(LuaError::runtime("cannot change a protected metatable"));
+    }
+    ls.set_top(2);
+    ls.set_metatable(1);
+    Ok(1)
+}
+
+// error (message [, level])
+fn base_error(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let level = opt_integer(ls, 2, 1, "error")?;
+    ls.set_top(1);
+    if ls.type_id(1) == Type::String as i8 && level > 0 {
+        // add position information
+        let pos = ls.where_(level as usize);
+        ls.push_string(pos);
+        ls.insert(1);
+        ls.concat(2)?;
+    }
+    Err(ls.error())
+}
+
+// pcall (f [, arg1, ···])
+fn base_pcall(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    check_any(ls, 1, "pcall")?;
+    ls.push_boolean(true); // first result if no errors
+    ls.insert(1);
+    let nargs = ls.get_top() as usize - 2;
+    let status = ls.pcallk(nargs, LUA_MULTRET, 0, 0, finish_pcall)?;
+    finish_pcall(ls, status, 0)
+}
+
+// xpcall (f, msgh [, arg1, ···])
+fn base_xpcall(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let n = ls.get_top();
+    check_type(ls, 2, Type::Function, "xpcall")?;
+    ls.push_boolean(true); // first result
+    ls.push_value(1); // function
+    ls.rotate(3, 2); // move them below function's arguments
+    let status = ls.pcallk(n as usize - 2, LUA_MULTRET, 2, 2, finish_pcall)?;
+    finish_pcall(ls, status, 2)
+}
+
+// collectgarbage ([opt [, arg]])
+fn base_collectgarbage(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    const OPTS: [&str; 10] = [
+        "stop",
+        "restart",
+        "collect",
+        "count",
+        "step",
+        "setpause",
+        "setstepmul",
+        "isrunning",
+        "generational",
+        "incremental",
+    ];
+    const OPTNUM: [u8; 10] = [
+        LUA_GCSTOP,
+        LUA_GCRESTART,
+        LUA_GCCOLLECT,
+        LUA_GCCOUNT,
+        LUA_GCSTEP,
+        LUA_GCSETPAUSE,
+        LUA_GCSETSTEPMUL,
+        LUA_GCISRUNNING,
+        LUA_GCGEN,
+        LUA_GCINC,
+    ];
+    let o = OPTNUM[check_option(ls, 1, Some("collect"), &OPTS, "collectgarbage")?];
+    match o {
+        LUA_GCCOUNT => {
+            let k = ls.gc(LUA_GCCOUNT, &[]);
+            let b = ls.gc(LUA_GCCOUNTB, &[]);
+            ls.push_number(k as f64 + b as f64 / 1024.0);
+        }
+        LUA_GCSTEP => {
+            let step = opt_integer(ls, 2, 0, "collectgarbage")?;
+            let res = ls.gc(o, &[step as isize]);
+            ls.push_boolean(res != 0);
+        }
+        LUA_GCSETPAUSE | LUA_GCSETSTEPMUL => {
+            let p = opt_integer(ls, 2, 0, "collectgarbage")?;
+            let previous = ls.gc(o, &[p as isize]);
+            ls.push_integer(previous as i64);
+        }
+        LUA_GCISRUNNING => {
+            let res = ls.gc(o, &[]);
+            ls.push_boolean(res != 0);
+        }
+        LUA_GCGEN => {
+            let minormul = opt_integer(ls, 2, 0, "collectgarbage")?;
+            let majormul = opt_integer(ls, 3, 0, "collectgarbage")?;
+            let previous = ls.gc(o, &[minormul as isize, majormul as isize]);
+            push_mode(ls, previous);
+        }
+        LUA_GCINC => {
+            let pause = opt_integer(ls, 2, 0, "collectgarbage")?;
+            let stepmul = opt_integer(ls, 3, 0, "collectgarbage")?;
+            let stepsize = opt_integer(ls, 4, 0, "collectgarbage")?;
+            let args = [pause as isize, stepmul as isize, stepsize as isize];
+            let previous = ls.gc(o, &args);
+            push_mode(ls, previous);
+        }
+        _ => {
+            let res = ls.gc(o, &[]);
+            ls.push_integer(res as i64);
+        }
+    }
+    Ok(1)
+}
+
+/// 把回收器的模式的名字推入栈顶。
+fn push_mode(ls: &mut dyn LuaAPI, mode: isize) {
+    let name = if mode == LUA_GCINC as isize {
+        "incremental"
+    } else {
+        "generational"
+    };
+    ls.push_string(name.to_string());
+}
+
+/// 整理保护调用的结果：成功时返回 `true` 和函数的所有返回值，失败时返回 `false` 和错误对象。
+/// `extra` 是栈底不属于返回值的值的数量。
+fn finish_pcall(ls: &mut dyn LuaAPI, status: u8, extra: isize) -> Result<usize, LuaError> {
+    if status != LUA_OK && status != LUA_YIELD {
+        ls.push_boolean(false);
+        ls.push_value(-2);
+        return Ok(2); // return false, msg
+    }
+    Ok((ls.get_top() - extra) as usize) // return all results
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        api::consts::{LUA_ERRERR, LUA_ERRRUN},
+        state::new_lua_state,
+        stdlib::test_util::run,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_error() {
+        assert_eq!(run(r#"return pcall(error, "boom")"#), ["false", "boom"]);
+        assert_eq!(
+            run(r#"return pcall(function() error("boom") end)"#),
+            ["false", "test:1: boom"]
+        );
+        let src = r#"
+            local function f() error("level 2", 2) end
+            return pcall(function()
+                f()
+            end)
+        "#;
+        assert_eq!(run(src), ["false", "test:4: level 2"]);
+        assert_eq!(
+            run(r#"return pcall(function() error("boom", 0) end)"#),
+            ["false", "boom"]
+        );
+        assert_eq!(
+            run("local _, e = pcall(error, { code = 1 }) return e.code"),
+            ["1"]
+        );
+        assert_eq!(run("return pcall(error)"), ["false", "nil"]);
+        assert_eq!(
+            run("return pcall(function() error(42) end)"),
+            ["false", "42"]
+        );
+        assert_eq!(
+            run(r#"return pcall(error, "a", "b")"#),
+            [
+                "false",
+                "bad argument #2 to 'error' (number expected, got string)"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pcall() {
+        assert_eq!(
+            run("return pcall(function(a, b) return a + b, a * b end, 3, 4)"),
+            ["true", "7", "12"]
+        );
+        assert_eq!(
+            run("return pcall(pcall)"),
+            ["false", "bad argument #1 to 'pcall' (value expected)"]
+        );
+        let cases = [
+            (
+                "local t; return t.x",
+                "test:1: attempt to index a nil value",
+            ),
+            ("return 1 // 0", "test:1: attempt to divide by zero"),
+            ("return 1 % 0", "test:1: attempt to perform 'n%0'"),
+            ("local t = {} t[nil] = 1", "test:1: table index is nil"),
+            (
+                "return {} < {}",
+                "test:1: attempt to compare two table values",
+            ),
+            (
+                "return 1 < 'x'",
+                "test:1: attempt to compare number with string",
+            ),
+            (
+                "return 'x' .. {}",
+                "test:1: attempt to concatenate a table value",
+            ),
+            (
+                "return #nil",
+                "test:1: attempt to get length of a nil value",
+            ),
+            ("undefined()", "test:1: attempt to call a nil value"),
+            ("for i = 1, 10, 0 do end", "test:1: 'for' step is zero"),
+            (
+                "for i = 1, nil do end",
+                "test:1: bad 'for' limit (number expected, got nil)",
+            ),
+            (
+                "for i = {}, 1 do end",
+                "test:1: bad 'for' initial value (number expected, got table)",
+            ),
+        ];
+        for (body, msg) in cases {
+            let src = format!("return pcall(function() {} end)", body);
+            assert_eq!(run(&src), ["false", msg], "{}", body);
+        }
+    }
+
+    #[test]
+    fn test_xpcall() {
+        let src = r#"
+            return xpcall(function() error("x") end, function(m) return "handled: " .. m end)
+        "#;
+        assert_eq!(run(src), ["false", "handled: test:2: x"]);
+        let src = r#"return xpcall(function() error("x") end, function(m) error("y") end)"#;
+        assert_eq!(run(src), ["false", "error in error handling"]);
+        let src = r#"return xpcall(nil, function(m) return "H " .. m end)"#;
+        assert_eq!(run(src), ["false", "H attempt to call a nil value"]);
+        assert_eq!(
+            run("return xpcall(function(...) return ... end, error, 1, 2)"),
+            ["true", "1", "2"]
+        );
+        assert_eq!(
+            run("return pcall(xpcall, error)"),
+            [
+                "false",
+                "bad argument #2 to 'xpcall' (function expected, got no value)"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        let src = r#"
+            local function f() return f() + 1 end
+            local ok, e = pcall(f)
+            return ok, e, xpcall(f, function(m) return "H " .. m end)
+        "#;
+        assert_eq!(
+            run(src),
+            [
+                "false",
+                "test:2: stack overflow",
+                "false",
+                "H test:2: stack overflow"
+            ]
+        );
+        // Lua calls do not consume the Rust stack
+        let src = r#"
+            local function depth(n) if n == 0 then return 0 end return 1 + depth(n - 1) end
+            return depth(100000)
+        "#;
+        assert_eq!(run(src), ["100000"]);
+    }
+
+    #[test]
+    fn test_collectgarbage() {
+        // cycles are collected automatically while reachable values survive
+        let src = r#"
+            local keep = {}
+            for i = 1, 200000 do
+                local t = { i = i }
+                t.self = t
+                if i % 1000 == 0 then keep[#keep + 1] = t end
+            end
+            local sum = 0
+            for i = 1, #keep do sum = sum + keep[i].self.i end
+            local k = collectgarbage("count")
+            return sum, k > 0 and k < 1024, collectgarbage(), collectgarbage("count") <= k
+        "#;
+        assert_eq!(run(src), ["20100000", "true", "0", "true"]);
+        let src = r#"
+            local running = collectgarbage("isrunning")
+            collectgarbage("stop")
+            local stopped = collectgarbage("isrunning")
+            collectgarbage("restart")
+            return running, stopped, collectgarbage("isrunning"), collectgarbage("step", 10)
+        "#;
+        assert_eq!(run(src), ["true", "false", "true", "true"]);
+        let src = r#"
+            local mode = collectgarbage("incremental", 150, 200, 10)
+            local pause = collectgarbage("setpause", 100)
+            local stepmul = collectgarbage("setstepmul", 400)
+            for i = 1, 100000 do local t = {} t.self = t end
+            return mode, pause, stepmul, collectgarbage("setpause", 200)
+        "#;
+        assert_eq!(run(src), ["incremental", "150", "200", "100"]);
+        let src = r#"
+            local before = collectgarbage("generational")
+            local keep = {}
+            for i = 1, 100000 do
+                local t = { i = i }
+                t.self = t
+                if i % 1000 == 0 then keep[#keep + 1] = t end
+            end
+            local sum = 0
+            for i = 1, #keep do sum = sum + keep[i].self.i end
+            return before, sum, collectgarbage("incremental", 0, 0, 0), collectgarbage("count") < 1024
+        "#;
+        assert_eq!(run(src), ["incremental", "5050000", "generational", "true"]);
+        assert_eq!(
+            run("return pcall(collectgarbage, 'x')"),
+            [
+                "false",
+                "bad argument #1 to 'collectgarbage' (invalid option 'x')"
+            ]
+        );
+        assert_eq!(
+            run("return pcall(collectgarbage, {})"),
+            [
+                "false",
+                "bad argument #1 to 'collectgarbage' (string expected, got table)"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_api_pcall() {
+        let mut ls = new_lua_state();
+        open_base(&mut ls).unwrap();
+        let src = "local t = ... return t.x.y";
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        ls.push_value(-1);
+        ls.new_table();
+        assert_eq!(ls.pcall(1, 1, 0), LUA_ERRRUN);
+        assert_eq!(ls.to_string(-1), "test:1: attempt to index a nil value");
+        assert_eq!(ls.get_top(), 2);
+        ls.pop(1);
+
+        // errors from 'call' are returned to the host
+        ls.push_nil();
+        let err = ls.call(1, 0).unwrap_err();
+        assert_eq!(err.status(), LUA_ERRRUN);
+        assert_eq!(err.to_string(), "test:1: attempt to index a nil value");
+        assert_eq!(ls.get_top(), 0);
+
+        // a message handler that fails
+        ls.push_rust_function(base_error);
+        ls.push_rust_function(base_error);
+        ls.push_string("boom".to_string());
+        assert_eq!(ls.pcall(1, 0, 1), LUA_ERRERR);
+        assert_eq!(ls.to_string(-1), "error in error handling");
+    }
+
+    #[test]
+    fn test_tostring() {
+        let src = r#"
+            return tostring(1.0), tostring(-0.0), tostring(1e15), tostring(2^63), tostring(10 // 3),
+                tostring(1 / 0), tostring(0.1), tostring(nil), tostring(true), 3 / 2, 7 // 2.0
+        "#;
+        assert_eq!(
+            run(src),
+            [
+                "1.0",
+                "-0.0",
+                "1e+15",
+                "9.2233720368548e+18",
+                "3",
+                "inf",
+                "0.1",
+                "nil",
+                "true",
+                "1.5",
+                "3.0"
+            ]
+        );
+        let src = r#"
+            local t = setmetatable({}, { __tostring = function() return "T" end })
+            local named = setmetatable({}, { __name = "Point" })
+            return tostring(t), tostring({}), tostring(print), tostring(named)
+        "#;
+        let results = run(src);
+        assert_eq!(results[0], "T");
+        assert!(results[1].starts_with("table: 0x"), "{}", results[1]);
+        assert!(results[2].starts_with("function: 0x"), "{}", results[2]);
+        assert!(results[3].starts_with("Point: 0x"), "{}", results[3]);
+        assert_eq!(
+            run("return pcall(tostring)"),
+            ["false", "bad argument #1 to 'tostring' (value expected)"]
+        );
+        assert_eq!(
+            run("return pcall(tostring, setmetatable({}, { __tostring = function() return {} end }))"),
+            ["false", "'__tostring' must return a string"]
+        );
+        assert_eq!(
+            run("return type(nil), type(1), type('x'), type({}), type(print), type(type), pcall(type)"),
+            [
+                "nil",
+                "number",
+                "string",
+                "table",
+                "function",
+                "function",
+                "false",
+                "bad argument #1 to 'type' (value expected)"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tonumber() {
+        let src = r#"
+            return tonumber("10"), tonumber("  0x10  "), tonumber("1e2"), tonumber(" 5. "),
+                tonumber("z", 36), tonumber("ff", 16), tonumber(" -101 ", 2), tonumber("7fffffffffffffff", 16),
+                tonumber("ffffffffffffffff", 16), tonumber(3.5), tonumber("10", 8)
+        "#;
+        assert_eq!(
+            run(src),
+            [
+                "10",
+                "16",
+                "100.0",
+                "5.0",
+                "35",
+                "255",
+                "-5",
+                "9223372036854775807",
+                "-1",
+                "3.5",
+                "8"
+            ]
+        );
+        let src = r#"
+            return tonumber("x"), tonumber(""), tonumber("1e"), tonumber("8", 8), tonumber("1 2"),
+                tonumber({}), tonumber(nil), tonumber("0x"), tonumber("inf"), tonumber("nan")
+        "#;
+        assert_eq!(run(src), ["nil"; 10]);
+        let cases = [
+            (
+                "tonumber()",
+                "bad argument #1 to 'tonumber' (value expected)",
+            ),
+            (
+                "tonumber(10, 16)",
+                "bad argument #1 to 'tonumber' (string expected, got number)",
+            ),
+            (
+                "tonumber('10', 99)",
+                "bad argument #2 to 'tonumber' (base out of range)",
+            ),
+            (
+                "tonumber('10', 1)",
+                "bad argument #2 to 'tonumber' (base out of range)",
+            ),
+            (
+                "tonumber('10', 'x')",
+                "bad argument #2 to 'tonumber' (number expected, got string)",
+            ),
+        ];
+        for (call, msg) in cases {
+            let src = format!("return pcall(function() return {} end)", call);
+            assert_eq!(run(&src), ["false", msg], "{}", call);
+        }
+    }
+
+    #[test]
+    fn test_select_assert() {
+        let src = r#"
+            return select('#'), select('#', nil, nil), select(2, 'a', 'b', 'c'), select(-1, 'a', 'b', 'c')
+        "#;
+        assert_eq!(run(src), ["0", "2", "b", "c"]);
+        assert_eq!(run("return select(5, 'a', 'b')"), Vec::<String>::new());
+        assert_eq!(
+            run("return pcall(select, 0, 'a')"),
+            ["false", "bad argument #1 to 'select' (index out of range)"]
+        );
+        assert_eq!(
+            run("return pcall(select, -3, 'a')"),
+            ["false", "bad argument #1 to 'select' (index out of range)"]
+        );
+        assert_eq!(
+            run("return pcall(select, 'x')"),
+            [
+                "false",
+                "bad argument #1 to 'select' (number expected, got string)"
+            ]
+        );
+        assert_eq!(run("return assert(1, 2, 3)"), ["1", "2", "3"]);
+        assert_eq!(
+            run("return pcall(assert, false)"),
+            ["false", "assertion failed!"]
+        );
+        assert_eq!(
+            run("return pcall(assert, nil, 'custom')"),
+            ["false", "custom"]
+        );
+        assert_eq!(
+            run("local _, e = pcall(assert, false, { code = 7 }) return e.code"),
+            ["7"]
+        );
+        assert_eq!(
+            run("return pcall(assert)"),
+            ["false", "bad argument #1 to 'assert' (value expected)"]
+        );
+    }
+
+    #[test]
+    fn test_raw_and_iteration() {
+        let src = r#"
+            local log = {}
+            local t = setmetatable({}, {
+                __index = function(_, k) return "idx" end,
+                __newindex = function(t, k, v) log[#log + 1] = k end,
+                __eq = function() return true end,
+                __len = function() return 99 end,
+            })
+            rawset(t, "a", 1)
+            t.b = 2
+            local u = setmetatable({}, getmetatable(t))
+            return rawget(t, "a"), rawget(t, "b"), t.b, #log, rawequal(t, u), t == u, rawlen(t), #t,
+                rawlen("abc"), rawequal("a", "a")
+        "#;
+        assert_eq!(
+            run(src),
+            ["1", "nil", "idx", "1", "false", "true", "0", "99", "3", "true"]
+        );
+        let src = r#"
+            local t = { 10, 20, nil, 40 }
+            local s = 0
+            for i, v in ipairs(t) do s = s + i * v end
+            local proxy = setmetatable({}, { __index = function(_, i) if i <= 3 then return i * 2 end end })
+            local p = 0
+            for _, v in ipairs(proxy) do p = p + v end
+            local keys = 0
+            for k, v in pairs({ 1, 2, x = 3, y = 4 }) do keys = keys + 1 end
+            local cleared = { 1, 2, 3, x = 4, y = 5 }
+            for k in pairs(cleared) do cleared[k] = nil end
+            local custom = setmetatable({}, { __pairs = function(t) return function(_, k)
+                if not k then return 1, "one" end
+            end, t, nil end })
+            local c = {}
+            for k, v in pairs(custom) do c[#c + 1] = k .. "=" .. v end
+            return s, p, keys, c[1], next({}), next({ 5 }), type(next), next(cleared)
+        "#;
+        assert_eq!(
+            run(src),
+            ["50", "12", "4", "1=one", "nil", "1", "function", "nil"]
+        );
+        let cases = [
+            (
+                "rawget(1, 1)",
+                "bad argument #1 to 'rawget' (table expected, got number)",
+            ),
+            ("rawget({})", "bad argument #2 to 'rawget' (value expected)"),
+            (
+                "rawset({}, 1)",
+                "bad argument #3 to 'rawset' (value expected)",
+            ),
+            ("rawset({}, nil, 1)", "table index is nil"),
+            (
+                "rawlen(1)",
+                "bad argument #1 to 'rawlen' (table or string expected, got number)",
+            ),
+            (
+                "rawequal(1)",
+                "bad argument #2 to 'rawequal' (value expected)",
+            ),
+            ("ipairs()", "bad argument #1 to 'ipairs' (value expected)"),
+            ("pairs()", "bad argument #1 to 'pairs' (value expected)"),
+            ("next({}, 'x')", "invalid key to 'next'"),
+            (
+                "next(1)",
+                "bad argument #1 to 'next' (table expected, got number)",
+            ),
+        ];
+        for (call, msg) in cases {
+            let src = format!("return pcall(function() return {} end)", call);
+            assert_eq!(run(&src), ["false", msg], "{}", call);
+        }
+    }
+
+    #[test]
+    fn test_metatables() {
+        let src = r#"
+            local mt = {}
+            local t = setmetatable({}, mt)
+            local protected = setmetatable({}, { __metatable = "locked" })
+            local ok, e = pcall(setmetatable, protected, {})
+            return getmetatable(t) == mt, getmetatable(protected), ok, e, getmetatable(1),
+                setmetatable(t, nil) == t, getmetatable(t)
+        "#;
+        assert_eq!(
+            run(src),
+            [
+                "true",
+                "locked",
+                "false",
+                "cannot change a protected metatable",
+                "nil",
+                "true",
+                "nil"
+            ]
+        );
+        assert_eq!(
+            run("return pcall(setmetatable, {}, 1)"),
+            [
+                "false",
+                "bad argument #2 to 'setmetatable' (nil or table expected, got number)"
+            ]
+        );
+        assert_eq!(
+            run("return pcall(setmetatable, 1, {})"),
+            [
+                "false",
+                "bad argument #1 to 'setmetatable' (table expected, got number)"
+            ]
+        );
+        assert_eq!(
+            run("return _G == _G._G, _G.print == print, _VERSION"),
+            ["true", "true", "Lua 5.4"]
+        );
+    }
+
+    #[test]
+    fn test_open_libs() {
+        let mut ls = new_lua_state();
+        crate::stdlib::open_libs(&mut ls).unwrap();
+        let src = include_str!("../../lua/hello_world.lua");
+        assert_eq!(
+            ls.load(src.as_bytes().to_vec(), "@hello_world.lua", "t"),
+            LUA_OK
+        );
+        ls.call(0, 0).unwrap();
+        let src = "return type(print), type(coroutine.wrap), print(1, nil, 2.5, {}) == nil";
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        ls.call(0, LUA_MULTRET).unwrap();
+        assert_eq!(ls.to_string(1), "function");
+        assert_eq!(ls.to_string(2), "function");
+        assert!(ls.to_boolean(3));
+    }
+}
@@ -0,0 +1,428 @@
+use crate::api::{
+    consts::{lua_upvalueindex, COS_DEAD, COS_YIELD, LUA_OK, LUA_YIELD},
+    r#type::Type,
+    LuaAPI, LuaError,
+};
+
+use super::auxlib::check_type;
+
+/// 与 `COS_*` 状态对应的名字
+const STATUS_NAMES: [&str; 4] = ["running", "dead", "suspended", "normal"];
+
+/// 创建 `coroutine` 表，把协程库函数注册到其中。
+pub fn open_coroutine(ls: &mut dyn LuaAPI) -> Result<(), LuaError> {
+    let funcs: [(&str, crate::api::RustFn); 8] = [
+        ("create", co_create),
+        ("resume", co_resume),
+        ("yield", co_yield),
+        ("status", co_status),
+        ("wrap", co_wrap),
+        ("isyieldable", co_isyieldable),
+        ("running", co_running),
+        ("close", co_close),
+    ];
+    ls.create_table(0, funcs.len());
+    for (name, f) in funcs {
+        ls.push_rust_function(f);
+        ls.set_field(-2, name)?;
+    }
+    ls.set_global("coroutine")
+}
+
+/// 检查第一个参数是线程。
+fn get_co(ls: &dyn LuaAPI, fname: &str) -> Result<(), LuaError> {
+    check_type(ls, 1, Type::Thread, fname)
+}
+
+/// 以栈顶之下的 `nargs` 个值为参数运行第一个参数处的协程。成功时栈顶是协程的结果，失败时是错误对象。
+///
+/// 返回值：成功时是结果的数量，失败时是 `None`。
+fn aux_resume(ls: &mut dyn LuaAPI, nargs: usize) -> Option<usize> {
+    let (status, n) = ls.resume(1, nargs);
+    (status == LUA_OK || status == LUA_YIELD).then_some(n)
+}
+
+// coroutine.create (f)
+fn co_create(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    check_type(ls, 1, Type::Function, "coroutine.create")?;
+    ls.set_top(1);
+    ls.new_thread();
+    Ok(1)
+}
+
+// coroutine.resume (co [, val1, ···])
+fn co_resume(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    get_co(ls, "coroutine.resume")?;
+    let nargs = ls.get_top() as usize - 1;
+    match aux_resume(ls, nargs) {
+        Some(n) => {
+            ls.push_boolean(true);
+            ls.insert(-(n as isize + 1));
+            Ok(n + 1) // return true + 'resume' returns
+        }
+        None => {
+            ls.push_boolean(false);
+            ls.insert(-2);
+            Ok(2) // return false + error message
+        }
+    }
+}
+
+// coroutine.yield (···)
+fn co_yield(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let n = ls.get_top() as usize;
+    Err(ls.yield_(n))
+}
+
+// coroutine.status (co)
+fn co_status(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    get_co(ls, "coroutine.status")?;
+    let status = ls.co_status(1);
+    ls.push_string(STATUS_NAMES[status as usize].to_string());
+    Ok(1)
+}
+
+// coroutine.wrap (f)
+fn co_wrap(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    check_type(ls, 1, Type::Function, "coroutine.wrap")?;
+    ls.set_top(1);
+    ls.new_thread();
+    ls.push_rust_closure(aux_wrap, 1);
+    Ok(1)
+}
+
+/// `coroutine.wrap` 返回的函数，唯一的上值是协程。
+fn aux_wrap(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let nargs = ls.get_top() as usize;
+    ls.push_value(lua_upvalueindex(1));
+    ls.insert(1);
+    if let Some(n) = aux_resume(ls, nargs) {
+        return Ok(n);
+    }
+    // the coroutine is dead: close it and propagate the error
+    if ls.close_thread(1) != LUA_OK {
+        ls.remove(-2); // error object returned by 'close_thread'
+    }
+    if ls.type_id(-1) == Type::String as i8 {
+        // add extra position information
+        let pos = ls.where_(1);
+        ls.push_string(pos);
+        ls.insert(-2);
+        ls.concat(2)?;
+    }
+    Err(ls.error())
+}
+
+// coroutine.isyieldable ([co])
+fn co_isyieldable(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    if ls.is_none(1) {
+        ls.push_thread();
+    } else {
+        get_co(ls, "coroutine.isyieldable")?;
+        ls.push_value(1);
+    }
+    let yieldable = ls.is_yieldable(-1);
+    ls.push_boolean(yieldable);
+    Ok(1)
+}
+
+// coroutine.running ()
+fn co_running(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let is_main = ls.push_thread();
+    ls.push_boolean(is_main);
+    Ok(2)
+}
+
+// coroutine.close (co)
+fn co_close(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    get_co(ls, "coroutine.close")?;
+    match ls.co_status(1) {
+        COS_DEAD | COS_YIELD => {
+            if ls.close_thread(1) == LUA_OK {
+                ls.push_boolean(true);
+                Ok(1)
+            } else {
+                ls.push_boolean(false);
+                ls.insert(-2);
+                Ok(2) // return false + error object
+            }
+        }
+        status => {
+            let msg = format!("cannot close a {} coroutine", STATUS_NAMES[status as usize]);
+            Err(LuaError::runtime(msg))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stdlib::test_util::{new_state, run};
+
+    use super::*;
+
+    #[test]
+    fn test_resume_yield() {
+        let src = r#"
+            local log = {}
+            local co = coroutine.create(function(a, b)
+                log[#log + 1] = a .. b
+                local c, d = coroutine.yield(a + b, a * b)
+                log[#log + 1] = c .. tostr(d)
+                return "done"
+            end)
+            function tostr(v) return v == nil and "nil" or v end
+            local ok1, s, p = coroutine.resume(co, 3, 4)
+            local st = coroutine.status(co)
+            local ok2, r = coroutine.resume(co, "x")
+            local ok3, e = coroutine.resume(co)
+            return ok1, s, p, st, ok2, r, coroutine.status(co), ok3, e, log[1], log[2]
+        "#;
+        assert_eq!(
+            run(src),
+            [
+                "true",
+                "7",
+                "12",
+                "suspended",
+                "true",
+                "done",
+                "dead",
+                "false",
+                "cannot resume dead coroutine",
+                "34",
+                "xnil"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_yield_across_frames() {
+        // yields from nested Lua calls, generic for loops and tail calls
+        let src = r#"
+            local function walk(t)
+                for _, v in iter(t) do
+                    if v.v then coroutine.yield(v.v) else walk(v) end
+                end
+            end
+            function iter(t)
+                return function(t, i)
+                    i = i + 1
+                    if t[i] ~= nil then return i, t[i] end
+                end, t, 0
+            end
+            local gen = coroutine.wrap(function(t) walk(t) return "end" end)
+            local out = gen({{v = 1}, {{v = 2}, {{v = 3}}}, {v = 4}})
+            for i = 1, 4 do out = out .. gen() end
+            local loop = coroutine.wrap(function()
+                local function f(n) if n == 0 then return coroutine.yield("tail") end return f(n - 1) end
+                return f(100000)
+            end)
+            return out, loop(), loop("back")
+        "#;
+        assert_eq!(run(src), ["1234end", "tail", "back"]);
+    }
+
+    #[test]
+    fn test_yield_across_pcall() {
+        let src = r#"
+            local co = coroutine.create(function(a)
+                local ok, b = pcall(function(x) return coroutine.yield(x + 1) end, a)
+                local ok2, e = pcall(function() coroutine.yield("again") error("late") end)
+                local ok3, e3 = xpcall(function()
+                    coroutine.yield("x")
+                    error({})
+                end, function(m) return type(m) .. " handled" end)
+                return ok, b, ok2, e, ok3, e3
+            end)
+            local _, r1 = coroutine.resume(co, 1)
+            local _, r2 = coroutine.resume(co, 10)
+            local _, r3 = coroutine.resume(co)
+            local r = {coroutine.resume(co)}
+            return r1, r2, r3, table.unpack(r)
+        "#;
+        assert_eq!(
+            run(src),
+            [
+                "2",
+                "again",
+                "x",
+                "true",
+                "true",
+                "10",
+                "false",
+                "test:4: late",
+                "false",
+                "table handled"
+            ]
+        );
+        // pcall itself as the coroutine body
+        let src = r#"
+            local co = coroutine.wrap(pcall)
+            local y = co(function(a) return coroutine.yield(a) * 2 end, 21)
+            return y, co(y)
+        "#;
+        assert_eq!(run(src), ["21", "true", "42"]);
+    }
+
+    #[test]
+    fn test_yield_in_metamethods() {
+        let src = r#"
+            local mt = {
+                __index = function(t, k) return coroutine.yield(k) end,
+                __lt = function(a, b) return coroutine.yield("lt") end,
+                __add = function(a, b) return coroutine.yield("add") end,
+                __concat = function(a, b) return coroutine.yield("concat") end,
+                __eq = function(a, b) return coroutine.yield("eq") end,
+            }
+            local a, b = setmetatable({}, mt), setmetatable({}, mt)
+            local co = coroutine.wrap(function()
+                local v = a.key
+                local lt = a < b and "yes" or "no"
+                local s = a + 1
+                local c = "x" .. a .. "y"
+                local e = a == b
+                return v, lt, s, c, e
+            end)
+            local out = {co(), co("val"), co(false), co(5), co("cc"), co(true)}
+            return table.unpack(out)
+        "#;
+        assert_eq!(
+            run(src),
+            ["key", "lt", "add", "concat", "eq", "val", "no", "5", "xcc", "true"]
+        );
+    }
+
+    #[test]
+    fn test_status_and_running() {
+        let src = r#"
+            local main, ismain = coroutine.running()
+            local co
+            co = coroutine.create(function()
+                local self, m = coroutine.running()
+                return self == co, m, coroutine.status(co), coroutine.status(main),
+                    coroutine.isyieldable(), coroutine.isyieldable(main)
+            end)
+            local st = coroutine.status(co)
+            return ismain, coroutine.isyieldable(), st, coroutine.resume(co)
+        "#;
+        assert_eq!(
+            run(src),
+            [
+                "true",
+                "false",
+                "suspended",
+                "true",
+                "true",
+                "false",
+                "running",
+                "normal",
+                "true",
+                "false"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let src = r#"
+            local co = coroutine.create(function() local x = nil; return x.y end)
+            local ok, e = coroutine.resume(co)
+            local w = coroutine.wrap(function() error("boom") end)
+            local ok2, e2 = pcall(w)
+            local ok3, e3 = pcall(coroutine.yield, 1)
+            local ok4, e4 = coroutine.resume(coroutine.create(function()
+                return pcall(coroutine.yield, 1)
+            end))
+            return ok, e, coroutine.status(co), ok2, e2, ok3, e3, ok4, e4
+        "#;
+        assert_eq!(
+            run(src),
+            [
+                "false",
+                "test:2: attempt to index a nil value",
+                "dead",
+                "false",
+                "test:4: boom",
+                "false",
+                "attempt to yield from outside a coroutine",
+                "true",
+                "1"
+            ]
+        );
+        assert_eq!(
+            run("return pcall(coroutine.resume, nil)"),
+            [
+                "false",
+                "bad argument #1 to 'coroutine.resume' (thread expected, got nil)"
+            ]
+        );
+        let src = r#"
+            local co = coroutine.wrap(function() return coroutine.resume(coroutine.running()) end)
+            return co()
+        "#;
+        assert_eq!(run(src), ["false", "cannot resume non-suspended coroutine"]);
+    }
+
+    #[test]
+    fn test_close() {
+        let src = r#"
+            local co = coroutine.create(function() error({ code = 7 }) end)
+            coroutine.resume(co)
+            local ok, e = coroutine.close(co)
+            local fresh = coroutine.create(function() end)
+            local closed = coroutine.close(fresh)
+            local ok2, e2 = pcall(coroutine.close, coroutine.running())
+            return ok, e.code, coroutine.status(co), closed, coroutine.status(fresh), ok2, e2
+        "#;
+        assert_eq!(
+            run(src),
+            [
+                "false",
+                "7",
+                "dead",
+                "true",
+                "dead",
+                "false",
+                "cannot close a running coroutine"
+            ]
+        );
+        // closing a suspended coroutine closes its upvalues
+        let src = r#"
+            local get
+            local co = coroutine.create(function()
+                local x = 1
+                get = function() return x end
+                coroutine.yield()
+                x = 2
+            end)
+            coroutine.resume(co)
+            return coroutine.close(co), get(), coroutine.resume(co)
+        "#;
+        assert_eq!(
+            run(src),
+            ["true", "1", "false", "cannot resume dead coroutine"]
+        );
+    }
+
+    #[test]
+    fn test_api_resume() {
+        let mut ls = new_state();
+        let src = "local n = ... while true do n = n + coroutine.yield(n) end";
+        assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+        ls.new_thread();
+        ls.push_integer(1);
+        assert_eq!(ls.resume(1, 1), (LUA_YIELD, 1));
+        assert_eq!(ls.to_integer(-1), 1);
+        ls.pop(1);
+        ls.push_integer(10);
+        assert_eq!(ls.resume(1, 1), (LUA_YIELD, 1));
+        assert_eq!(ls.to_integer(-1), 11);
+        assert_eq!(ls.get_top(), 2);
+        assert_eq!(ls.co_status(1), COS_YIELD);
+        assert!(ls.is_yieldable(1));
+
+        // yielding from the main thread is an error
+        let err = ls.yield_(0);
+        assert_eq!(err.to_string(), "attempt to yield from outside a coroutine");
+    }
+}
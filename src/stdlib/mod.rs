@@ -0,0 +1,20 @@
+pub mod auxlib;
+pub mod base;
+pub mod coroutine;
+mod pack;
+mod pattern;
+pub mod string;
+pub mod table;
+#[cfg(test)]
+pub(crate) mod test_util;
+
+use crate::api::{LuaAPI, LuaError};
+
+/// 打开所有标准库。
+pub fn open_libs(ls: &mut dyn LuaAPI) -> Result<(), LuaError> {
+    base::open_base(ls)?;
+    coroutine::open_coroutine(ls)?;
+    string::open_string(ls)?;
+    table::open_table(ls)?;
+    Ok(())
+}
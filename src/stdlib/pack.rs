@@ -0,0 +1,648 @@
+use crate::api::{LuaAPI, LuaError};
+
+use super::auxlib::{arg_error, check_integer, check_lstring, check_number, opt_integer};
+
+/// 整数的最大字节数
+const MAXINTSIZE: usize = 16;
+/// 一个字节的位数
+const NB: usize = 8;
+/// 一个字节的掩码
+const MC: u8 = 0xff;
+/// `lua_Integer` 的字节数
+const SZINT: usize = std::mem::size_of::<i64>();
+/// 选项 `!` 默认的最大对齐
+const MAXALIGN: usize = 8;
+/// 格式结果的最大长度
+const MAXSIZE: usize = i32::MAX as usize;
+/// 用于填充的字节
+const PACKPADBYTE: u8 = 0x00;
+
+/// 格式选项的种类
+#[derive(Clone, Copy, PartialEq)]
+enum KOption {
+    /// 有符号整数
+    Int,
+    /// 无符号整数
+    Uint,
+    /// C 的 float
+    Float,
+    /// Lua 的浮点数
+    Number,
+    /// C 的 double
+    Double,
+    /// 定长字符串
+    Char,
+    /// 带长度前缀的字符串
+    Str,
+    /// 以 0 结尾的字符串
+    Zstr,
+    /// 填充字节
+    Padding,
+    /// 按下一个选项对齐
+    PaddAlign,
+    /// 不产生数据的选项
+    Nop,
+}
+
+/// 解析格式字符串的状态：当前的字节序和最大对齐，以及正在解析的位置。
+struct Header<'a> {
+    fmt: &'a [u8],
+    pos: usize,
+    islittle: bool,
+    maxalign: usize,
+    /// 函数名，用于错误信息
+    fname: &'static str,
+}
+
+impl<'a> Header<'a> {
+    fn new(fmt: &'a [u8], fname: &'static str) -> Header<'a> {
+        Header {
+            fmt,
+            pos: 0,
+            islittle: cfg!(target_endian = "little"),
+            maxalign: 1,
+            fname,
+        }
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.fmt.len()
+    }
+
+    fn peek_digit(&self) -> bool {
+        self.fmt.get(self.pos).is_some_and(u8::is_ascii_digit)
+    }
+
+    /// 读取一个十进制数，没有数字时返回 `None`。
+    fn get_num(&mut self) -> Option<usize> {
+        if !self.peek_digit() {
+            return None;
+        }
+        let mut a = 0;
+        loop {
+            a = a * 10 + (self.fmt[self.pos] - b'0') as usize;
+            self.pos += 1;
+            if !(self.peek_digit() && a <= (MAXSIZE - 9) / 10) {
+                return Some(a);
+            }
+        }
+    }
+
+    /// 读取整数的字节数，没有数字时返回 `df`，超出 `[1, MAXINTSIZE]` 时出错。
+    fn get_num_limit(&mut self, df: usize) -> Result<usize, LuaError> {
+        let sz = self.get_num().unwrap_or(df);
+        if sz > MAXINTSIZE || sz == 0 {
+            return Err(LuaError::runtime(format!(
+                "integral size ({}) out of limits [1,{}]",
+                sz, MAXINTSIZE
+            )));
+        }
+        Ok(sz)
+    }
+
+    /// 读取下一个选项，返回它的种类和大小。
+    fn get_option(&mut self) -> Result<(KOption, usize), LuaError> {
+        let opt = self.fmt[self.pos];
+        self.pos += 1;
+        let res = match opt {
+            b'b' => (KOption::Int, 1),
+            b'B' => (KOption::Uint, 1),
+            b'h' => (KOption::Int, 2),
+            b'H' => (KOption::Uint, 2),
+            b'l' | b'j' => (KOption::Int, 8),
+            b'L' | b'J' | b'T' => (KOption::Uint, 8),
+            b'f' => (KOption::Float, 4),
+            b'n' => (KOption::Number, 8),
+            b'd' => (KOption::Double, 8),
+            b'i' => (KOption::Int, self.get_num_limit(4)?),
+            b'I' => (KOption::Uint, self.get_num_limit(4)?),
+            b's' => (KOption::Str, self.get_num_limit(8)?),
+            b'c' => match self.get_num() {
+                Some(size) => (KOption::Char, size),
+                None => return Err(LuaError::runtime("missing size for format option 'c'")),
+            },
+            b'z' => (KOption::Zstr, 0),
+            b'x' => (KOption::Padding, 1),
+            b'X' => (KOption::PaddAlign, 0),
+            b' ' => (KOption::Nop, 0),
+            b'<' => {
+                self.islittle = true;
+                (KOption::Nop, 0)
+            }
+            b'>' => {
+                self.islittle = false;
+                (KOption::Nop, 0)
+            }
+            b'=' => {
+                self.islittle = cfg!(target_endian = "little");
+                (KOption::Nop, 0)
+            }
+            b'!' => {
+                self.maxalign = self.get_num_limit(MAXALIGN)?;
+                (KOption::Nop, 0)
+            }
+            _ => {
+                return Err(LuaError::runtime(format!(
+                    "invalid format option '{}'",
+                    opt as char
+                )))
+            }
+        };
+        Ok(res)
+    }
+
+    /// 读取下一个选项，返回它的种类、大小和在 `totalsize` 之后需要填充的字节数。
+    fn get_details(&mut self, totalsize: usize) -> Result<(KOption, usize, usize), LuaError> {
+        let (opt, size) = self.get_option()?;
+        let mut align = size; // usually, alignment follows size
+        if opt == KOption::PaddAlign {
+            // 'X' gets alignment from following option
+            if self.at_end() {
+                return Err(arg_error(
+                    1,
+                    self.fname,
+                    "invalid next option for option 'X'",
+                ));
+            }
+            let (next, next_size) = self.get_option()?;
+            align = next_size;
+            if next == KOption::Char || align == 0 {
+                return Err(arg_error(
+                    1,
+                    self.fname,
+                    "invalid next option for option 'X'",
+                ));
+            }
+        }
+        let ntoalign = if align <= 1 || opt == KOption::Char {
+            0 // need no alignment
+        } else {
+            align = align.min(self.maxalign); // enforce maximum alignment
+            if !align.is_power_of_two() {
+                return Err(arg_error(
+                    1,
+                    self.fname,
+                    "format asks for alignment not power of 2",
+                ));
+            }
+            (align - (totalsize & (align - 1))) & (align - 1)
+        };
+        Ok((opt, size, ntoalign))
+    }
+}
+
+/// 把整数 `n` 的低 `size` 个字节按字节序追加到 `b`。`neg` 时超过 8 个字节的部分用 0xff 填充。
+fn pack_int(b: &mut Vec<u8>, mut n: u64, islittle: bool, size: usize, neg: bool) {
+    let mut buff = [0u8; MAXINTSIZE];
+    for (i, byte) in buff.iter_mut().enumerate().take(size) {
+        *byte = if i >= SZINT {
+            if neg {
+                MC
+            } else {
+                0
+            }
+        } else {
+            let c = n as u8;
+            n >>= NB;
+            c
+        };
+    }
+    let buff = &mut buff[..size];
+    if !islittle {
+        buff.reverse();
+    }
+    b.extend_from_slice(buff);
+}
+
+/// 按字节序读取 `size` 个字节的整数。`issigned` 时对不足 8 个字节的整数做符号扩展。
+fn unpack_int(s: &[u8], islittle: bool, size: usize, issigned: bool) -> Result<i64, LuaError> {
+    let byte = |i: usize| if islittle { s[i] } else { s[size - 1 - i] };
+    let limit = size.min(SZINT);
+    let mut res: u64 = 0;
+    for i in (0..limit).rev() {
+        res <<= NB;
+        res |= byte(i) as u64;
+    }
+    if size < SZINT {
+        // real size smaller than lua_Integer?
+        if issigned {
+            // needs sign extension
+            let mask = 1u64 << (size * NB - 1);
+            res = (res ^ mask).wrapping_sub(mask);
+        }
+    } else if size > SZINT {
+        // must check unread bytes
+        let mask = if !issigned || (res as i64) >= 0 {
+            0
+        } else {
+            MC
+        };
+        if (limit..size).any(|i| byte(i) != mask) {
+            return Err(LuaError::runtime(format!(
+                "{}-byte integer does not fit into Lua Integer",
+                size
+            )));
+        }
+    }
+    Ok(res as i64)
+}
+
+/// 按字节序追加浮点数的字节。
+fn pack_float<const N: usize>(b: &mut Vec<u8>, le: [u8; N], islittle: bool) {
+    let mut bytes = le;
+    if !islittle {
+        bytes.reverse();
+    }
+    b.extend_from_slice(&bytes);
+}
+
+/// 按字节序读取 `N` 个字节，结果是小端序。
+fn unpack_bytes<const N: usize>(s: &[u8], islittle: bool) -> [u8; N] {
+    let mut bytes: [u8; N] = s[..N].try_into().unwrap();
+    if !islittle {
+        bytes.reverse();
+    }
+    bytes
+}
+
+// string.pack (fmt, v1, v2, ···)
+pub fn str_pack(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let fmt = check_lstring(ls, 1, "pack")?;
+    let mut h = Header::new(&fmt, "pack");
+    let mut b = Vec::new();
+    let mut arg = 1;
+    let mut totalsize = 0;
+    while !h.at_end() {
+        let (opt, size, ntoalign) = h.get_details(totalsize)?;
+        totalsize += ntoalign + size;
+        b.resize(b.len() + ntoalign, PACKPADBYTE); // fill alignment
+        arg += 1;
+        match opt {
+            KOption::Int => {
+                // signed integers
+                let n = check_integer(ls, arg, "pack")?;
+                if size < SZINT {
+                    // need overflow check?
+                    let lim = 1i64 << (size * NB - 1);
+                    if !(-lim..lim).contains(&n) {
+                        return Err(arg_error(arg, "pack", "integer overflow"));
+                    }
+                }
+                pack_int(&mut b, n as u64, h.islittle, size, n < 0);
+            }
+            KOption::Uint => {
+                // unsigned integers
+                let n = check_integer(ls, arg, "pack")?;
+                if size < SZINT && (n as u64) >= 1u64 << (size * NB) {
+                    return Err(arg_error(arg, "pack", "unsigned overflow"));
+                }
+                pack_int(&mut b, n as u64, h.islittle, size, false);
+            }
+            KOption::Float => {
+                let f = check_number(ls, arg, "pack")? as f32;
+                pack_float(&mut b, f.to_le_bytes(), h.islittle);
+            }
+            KOption::Number | KOption::Double => {
+                let f = check_number(ls, arg, "pack")?;
+                pack_float(&mut b, f.to_le_bytes(), h.islittle);
+            }
+            KOption::Char => {
+                // fixed-size string
+                let s = check_lstring(ls, arg, "pack")?;
+                if s.len() > size {
+                    return Err(arg_error(arg, "pack", "string longer than given size"));
+                }
+                b.extend_from_slice(&s);
+                b.resize(b.len() + size - s.len(), PACKPADBYTE); // pad extra space
+            }
+            KOption::Str => {
+                // strings with length count
+                let s = check_lstring(ls, arg, "pack")?;
+                if size < SZINT && s.len() as u64 >= 1u64 << (size * NB) {
+                    return Err(arg_error(
+                        arg,
+                        "pack",
+                        "string length does not fit in given size",
+                    ));
+                }
+                pack_int(&mut b, s.len() as u64, h.islittle, size, false); // pack length
+                b.extend_from_slice(&s);
+                totalsize += s.len();
+            }
+            KOption::Zstr => {
+                // zero-terminated string
+                let s = check_lstring(ls, arg, "pack")?;
+                if s.contains(&0) {
+                    return Err(arg_error(arg, "pack", "string contains zeros"));
+                }
+                b.extend_from_slice(&s);
+                b.push(0); // add zero at the end
+                totalsize += s.len() + 1;
+            }
+            KOption::Padding => {
+                b.push(PACKPADBYTE);
+                arg -= 1; // undo increment
+            }
+            KOption::PaddAlign | KOption::Nop => arg -= 1, // undo increment
+        }
+    }
+    ls.push_bytes(b);
+    Ok(1)
+}
+
+// string.packsize (fmt)
+pub fn str_packsize(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let fmt = check_lstring(ls, 1, "packsize")?;
+    let mut h = Header::new(&fmt, "packsize");
+    let mut totalsize = 0;
+    while !h.at_end() {
+        let (opt, size, ntoalign) = h.get_details(totalsize)?;
+        if opt == KOption::Str || opt == KOption::Zstr {
+            return Err(arg_error(1, "packsize", "variable-length format"));
+        }
+        let size = size + ntoalign; // total space used by option
+        if totalsize > MAXSIZE - size {
+            return Err(arg_error(1, "packsize", "format result too large"));
+        }
+        totalsize += size;
+    }
+    ls.push_integer(totalsize as i64);
+    Ok(1)
+}
+
+// string.unpack (fmt, s [, pos])
+pub fn str_unpack(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let fmt = check_lstring(ls, 1, "unpack")?;
+    let data = check_lstring(ls, 2, "unpack")?;
+    let ld = data.len();
+    let init = opt_integer(ls, 3, 1, "unpack")?;
+    // like 'posrelat_i' in string.rs
+    let mut pos = if init > 0 {
+        init as usize - 1
+    } else if init == 0 || init < -(ld as i64) {
+        0
+    } else {
+        (ld as i64 + init) as usize
+    };
+    if pos > ld {
+        return Err(arg_error(3, "unpack", "initial position out of string"));
+    }
+    let mut h = Header::new(&fmt, "unpack");
+    let mut n = 0; // number of results
+    while !h.at_end() {
+        let (opt, size, ntoalign) = h.get_details(pos)?;
+        if ntoalign + size > ld - pos {
+            return Err(arg_error(2, "unpack", "data string too short"));
+        }
+        pos += ntoalign; // skip alignment
+                         // stack space for item + next position
+        if !ls.check_stack(2) {
+            return Err(LuaError::runtime("stack overflow (too many results)"));
+        }
+        n += 1;
+        let s = &data[pos..];
+        match opt {
+            KOption::Int | KOption::Uint => {
+                let res = unpack_int(s, h.islittle, size, opt == KOption::Int)?;
+                ls.push_integer(res);
+            }
+            KOption::Float => {
+                let f = f32::from_le_bytes(unpack_bytes(s, h.islittle));
+                ls.push_number(f as f64);
+            }
+            KOption::Number | KOption::Double => {
+                let f = f64::from_le_bytes(unpack_bytes(s, h.islittle));
+                ls.push_number(f);
+            }
+            KOption::Char => ls.push_bytes(s[..size].to_vec()),
+            KOption::Str => {
+                let len = unpack_int(s, h.islittle, size, false)? as u64;
+                if len > (ld - pos - size) as u64 {
+                    return Err(arg_error(2, "unpack", "data string too short"));
+                }
+                let len = len as usize;
+                ls.push_bytes(s[size..size + len].to_vec());
+                pos += len; // skip string
+            }
+            KOption::Zstr => {
+                let len = s.iter().position(|&c| c == 0).unwrap_or(s.len());
+                if pos + len >= ld {
+                    return Err(arg_error(2, "unpack", "unfinished string for format 'z'"));
+                }
+                ls.push_bytes(s[..len].to_vec());
+                pos += len + 1; // skip string plus final '\0'
+            }
+            KOption::PaddAlign | KOption::Padding | KOption::Nop => n -= 1, // undo increment
+        }
+        pos += size;
+    }
+    ls.push_integer(pos as i64 + 1); // next position
+    Ok(n + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stdlib::test_util::{run, run_err};
+
+    /// 把 `string.pack` 的结果转换为十六进制，便于比较
+    const HEX: &str = r#"
+        local function hex(s)
+            return (string.gsub(s, ".", function(c) return string.format("%02x", string.byte(c)) end))
+        end
+    "#;
+
+    #[test]
+    fn test_pack_integers() {
+        let src = format!(
+            "{}{}",
+            HEX,
+            r#"
+            return hex(string.pack("<i4", 1)), hex(string.pack(">i4", 1)), hex(string.pack("<h", -2)),
+                hex(string.pack(">I3", 0x010203)), hex(string.pack("<b B", -1, 255)),
+                hex(string.pack("<j", -1)), hex(string.pack("<i16", -2)), hex(string.pack(">I9", 1)),
+                hex(string.pack("<T", 3))
+            "#
+        );
+        assert_eq!(
+            run(&src),
+            [
+                "01000000",
+                "00000001",
+                "feff",
+                "010203",
+                "ffff",
+                "ffffffffffffffff",
+                "feffffffffffffffffffffffffffffff",
+                "000000000000000001",
+                "0300000000000000"
+            ]
+        );
+        let src = r#"
+            local a, b, c, d, e, f = string.unpack("<i2 I2 b B i16", string.pack("<i2 I2 b B i16", -300, 65535, -128, 200, -5))
+            return a, b, c, d, e, f, string.unpack(">i3", "\255\255\254"), string.unpack("<I8", "\255\255\255\255\255\255\255\255")
+        "#;
+        assert_eq!(
+            run(src),
+            ["-300", "65535", "-128", "200", "-5", "23", "-2", "-1", "9"]
+        );
+    }
+
+    #[test]
+    fn test_pack_floats_and_strings() {
+        let src = format!(
+            "{}{}",
+            HEX,
+            r#"
+            return hex(string.pack("<d", 1.5)), hex(string.pack(">f", -2)), hex(string.pack("<n", 0.1)),
+                hex(string.pack("z", "ab")), hex(string.pack("<s1", "hi")), hex(string.pack(">s2", "")),
+                hex(string.pack("c5", "abc")), hex(string.pack("c2", "\0\255"))
+            "#
+        );
+        assert_eq!(
+            run(&src),
+            [
+                "000000000000f83f",
+                "c0000000",
+                "9a9999999999b93f",
+                "616200",
+                "026869",
+                "0000",
+                "6162630000",
+                "00ff"
+            ]
+        );
+        let src = r#"
+            local packed = string.pack("<d f s4 z c3", 3.25, 0.5, "hello\0world", "zero", "xyz")
+            local d, f, s, z, c, nxt = string.unpack("<d f s4 z c3", packed)
+            return d, f, #s, s == "hello\0world", z, c, nxt == #packed + 1
+        "#;
+        assert_eq!(
+            run(src),
+            ["3.25", "0.5", "11", "true", "zero", "xyz", "true"]
+        );
+    }
+
+    #[test]
+    fn test_pack_alignment() {
+        let src = format!(
+            "{}{}",
+            HEX,
+            r#"
+            return hex(string.pack("<!4 b i4", 1, 2)), hex(string.pack("<!8 b d", 1, 0)),
+                hex(string.pack("<b x h", 1, 2)), hex(string.pack("<!4 b Xi4 b", 1, 2)),
+                string.packsize("!8 b d"), string.packsize("i4 i8 !4 b i8"), string.packsize("c10 b h"),
+                string.packsize("<i3 !2 Xi8")
+            "#
+        );
+        assert_eq!(
+            run(&src),
+            [
+                "0100000002000000",
+                "01000000000000000000000000000000",
+                "01000200",
+                "0100000002",
+                "16",
+                "24",
+                "13",
+                "4"
+            ]
+        );
+        let src = r#"
+            local s = string.pack(">!4 b i4 h", 7, -1, 3)
+            return string.unpack(">!4 b i4 h", s)
+        "#;
+        assert_eq!(run(src), ["7", "-1", "3", "11"]);
+    }
+
+    #[test]
+    fn test_unpack_positions() {
+        let src = r#"
+            local s = string.pack("b b b", 1, 2, 3)
+            local a, p1 = string.unpack("b", s, 2)
+            local b, p2 = string.unpack("b", s, -1)
+            return a, p1, b, p2, select('#', string.unpack('', s)), string.unpack("", s, 4)
+        "#;
+        assert_eq!(run(src), ["2", "3", "3", "4", "1", "4"]);
+    }
+
+    #[test]
+    fn test_pack_errors() {
+        let cases = [
+            (
+                "string.pack('i17', 1)",
+                "integral size (17) out of limits [1,16]",
+            ),
+            (
+                "string.pack('i0', 1)",
+                "integral size (0) out of limits [1,16]",
+            ),
+            ("string.pack('y', 1)", "invalid format option 'y'"),
+            (
+                "string.pack('c', 'a')",
+                "missing size for format option 'c'",
+            ),
+            (
+                "string.pack('b', 128)",
+                "bad argument #2 to 'pack' (integer overflow)",
+            ),
+            (
+                "string.pack('B', -1)",
+                "bad argument #2 to 'pack' (unsigned overflow)",
+            ),
+            (
+                "string.pack('i4', 1.5)",
+                "number has no integer representation",
+            ),
+            (
+                "string.pack('c2', 'abc')",
+                "bad argument #2 to 'pack' (string longer than given size)",
+            ),
+            (
+                "string.pack('s1', string.rep('a', 256))",
+                "string length does not fit in given size",
+            ),
+            (
+                "string.pack('z', 'a\\0b')",
+                "bad argument #2 to 'pack' (string contains zeros)",
+            ),
+            (
+                "string.pack('!3 i4', 1)",
+                "format asks for alignment not power of 2",
+            ),
+            (
+                "string.pack('!4 X', 1)",
+                "invalid next option for option 'X'",
+            ),
+            (
+                "string.pack('Xc1', 1)",
+                "invalid next option for option 'X'",
+            ),
+            (
+                "string.packsize('s')",
+                "bad argument #1 to 'packsize' (variable-length format)",
+            ),
+            ("string.packsize('z')", "variable-length format"),
+            (
+                "string.unpack('i4', 'abc')",
+                "bad argument #2 to 'unpack' (data string too short)",
+            ),
+            (
+                "string.unpack('z', 'abc')",
+                "unfinished string for format 'z'",
+            ),
+            ("string.unpack('s1', '\\5ab')", "data string too short"),
+            (
+                "string.unpack('b', 'a', 3)",
+                "bad argument #3 to 'unpack' (initial position out of string)",
+            ),
+            (
+                "string.unpack('i9', string.rep('\\255', 8) .. '\\1')",
+                "9-byte integer does not fit into Lua Integer",
+            ),
+        ];
+        for (src, msg) in cases {
+            let err = run_err(src);
+            assert!(err.contains(msg), "{}: {}", src, err);
+        }
+    }
+}
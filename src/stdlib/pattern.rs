@@ -0,0 +1,392 @@
+use crate::api::LuaError;
+
+/// 模式中捕获的最大数量
+pub const LUA_MAXCAPTURES: usize = 32;
+/// 模式匹配的最大递归深度
+const MAXCCALLS: usize = 200;
+
+const CAP_UNFINISHED: isize = -1;
+const CAP_POSITION: isize = -2;
+
+const L_ESC: u8 = b'%';
+/// 使模式不能按普通字符串查找的字符
+const SPECIALS: &[u8] = b"^$*+?.([%-";
+
+/// 捕获的值：子串的字节范围，或者位置捕获 `()` 得到的位置（从 1 开始）。
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Capture {
+    Str(usize, usize),
+    Position(usize),
+}
+
+/// 模式不包含任何特殊字符，可以按普通字符串查找。
+pub fn no_specials(p: &[u8]) -> bool {
+    !p.iter().any(|c| SPECIALS.contains(c))
+}
+
+/// 一次模式匹配的状态，对应 lstrlib.c 中的 `MatchState`。
+///
+/// 位置都是 `src` 和 `pat` 中的字节下标。
+pub struct MatchState<'a> {
+    src: &'a [u8],
+    pat: &'a [u8],
+    matchdepth: usize,
+    level: usize,
+    capture: [(usize, isize); LUA_MAXCAPTURES],
+}
+
+impl<'a> MatchState<'a> {
+    pub fn new(src: &'a [u8], pat: &'a [u8]) -> MatchState<'a> {
+        MatchState {
+            src,
+            pat,
+            matchdepth: MAXCCALLS,
+            level: 0,
+            capture: [(0, 0); LUA_MAXCAPTURES],
+        }
+    }
+
+    /// 在开始新的匹配之前重置状态。
+    pub fn reprepstate(&mut self) {
+        self.level = 0;
+        self.matchdepth = MAXCCALLS;
+    }
+
+    /// 从 `src[s]` 开始匹配 `pat[p..]`，成功时返回匹配结束的位置。
+    pub fn do_match(&mut self, s: usize, p: usize) -> Result<Option<usize>, LuaError> {
+        if self.matchdepth == 0 {
+            return Err(LuaError::runtime("pattern too complex"));
+        }
+        self.matchdepth -= 1;
+        let res = self.match_inner(s, p)?;
+        self.matchdepth += 1;
+        Ok(res)
+    }
+
+    fn match_inner(&mut self, mut s: usize, mut p: usize) -> Result<Option<usize>, LuaError> {
+        let plen = self.pat.len();
+        loop {
+            if p == plen {
+                return Ok(Some(s)); // end of pattern
+            }
+            let next = self.pat.get(p + 1).copied();
+            match self.pat[p] {
+                b'(' => {
+                    // start capture
+                    return if next == Some(b')') {
+                        self.start_capture(s, p + 2, CAP_POSITION)
+                    } else {
+                        self.start_capture(s, p + 1, CAP_UNFINISHED)
+                    };
+                }
+                b')' => return self.end_capture(s, p + 1), // end capture
+                b'$' if p + 1 == plen => {
+                    // '$' is the last char in pattern: check end of subject
+                    return Ok((s == self.src.len()).then_some(s));
+                }
+                L_ESC if next == Some(b'b') => {
+                    // balanced string
+                    match self.match_balance(s, p + 2)? {
+                        Some(e) => {
+                            s = e;
+                            p += 4;
+                            continue;
+                        }
+                        None => return Ok(None),
+                    }
+                }
+                L_ESC if next == Some(b'f') => {
+                    // frontier
+                    p += 2;
+                    if self.pat.get(p) != Some(&b'[') {
+                        return Err(LuaError::runtime("missing '[' after '%f' in pattern"));
+                    }
+                    let ep = self.class_end(p)?;
+                    let prev = if s == 0 { 0 } else { self.src[s - 1] };
+                    let cur = self.src.get(s).copied().unwrap_or(0);
+                    if !self.match_bracket_class(prev, p, ep - 1)
+                        && self.match_bracket_class(cur, p, ep - 1)
+                    {
+                        p = ep;
+                        continue;
+                    }
+                    return Ok(None);
+                }
+                L_ESC if next.is_some_and(|c| c.is_ascii_digit()) => {
+                    // capture results (%0-%9)
+                    match self.match_capture(s, next.unwrap())? {
+                        Some(e) => {
+                            s = e;
+                            p += 2;
+                            continue;
+                        }
+                        None => return Ok(None),
+                    }
+                }
+                _ => {}
+            }
+
+            // pattern class plus optional suffix
+            let ep = self.class_end(p)?;
+            let epc = self.pat.get(ep).copied();
+            if !self.single_match(s, p, ep) {
+                if matches!(epc, Some(b'*' | b'?' | b'-')) {
+                    // accept empty
+                    p = ep + 1;
+                    continue;
+                }
+                return Ok(None);
+            }
+            // matched single
+            match epc {
+                Some(b'?') => {
+                    if let Some(res) = self.do_match(s + 1, ep + 1)? {
+                        return Ok(Some(res));
+                    }
+                    p = ep + 1;
+                }
+                Some(b'+') => return self.max_expand(s + 1, p, ep), // 1 or more repetitions
+                Some(b'*') => return self.max_expand(s, p, ep),     // 0 or more repetitions
+                Some(b'-') => return self.min_expand(s, p, ep), // 0 or more repetitions (minimum)
+                _ => {
+                    // no suffix
+                    s += 1;
+                    p = ep;
+                }
+            }
+        }
+    }
+
+    /// 捕获的数量。没有捕获时整个匹配算作一个捕获。
+    pub fn num_captures(&self, whole: bool) -> usize {
+        if self.level == 0 && whole {
+            1
+        } else {
+            self.level
+        }
+    }
+
+    /// 第 `i` 个捕获的值。`i` 为 0 并且没有捕获时返回整个匹配 `src[s..e]`。
+    pub fn get_capture(&self, i: usize, s: usize, e: usize) -> Result<Capture, LuaError> {
+        if i >= self.level {
+            if i != 0 {
+                return Err(LuaError::runtime(format!(
+                    "invalid capture index %{}",
+                    i + 1
+                )));
+            }
+            return Ok(Capture::Str(s, e)); // add whole match
+        }
+        let (init, len) = self.capture[i];
+        match len {
+            CAP_UNFINISHED => Err(LuaError::runtime("unfinished capture")),
+            CAP_POSITION => Ok(Capture::Position(init + 1)),
+            _ => Ok(Capture::Str(init, init + len as usize)),
+        }
+    }
+
+    fn class_end(&self, mut p: usize) -> Result<usize, LuaError> {
+        let c = self.pat[p];
+        p += 1;
+        if c == L_ESC {
+            if p >= self.pat.len() {
+                return Err(LuaError::runtime("malformed pattern (ends with '%')"));
+            }
+            return Ok(p + 1);
+        }
+        if c == b'[' {
+            if self.pat.get(p) == Some(&b'^') {
+                p += 1;
+            }
+            // look for a ']'
+            loop {
+                if p >= self.pat.len() {
+                    return Err(LuaError::runtime("malformed pattern (missing ']')"));
+                }
+                let cc = self.pat[p];
+                p += 1;
+                if cc == L_ESC && p < self.pat.len() {
+                    p += 1; // skip escapes (e.g. '%]')
+                }
+                if self.pat.get(p) == Some(&b']') {
+                    break;
+                }
+            }
+            return Ok(p + 1);
+        }
+        Ok(p)
+    }
+
+    /// `pat[p]` 是 `[`，`pat[ec]` 是对应的 `]`。
+    fn match_bracket_class(&self, c: u8, mut p: usize, ec: usize) -> bool {
+        let mut sig = true;
+        if self.pat[p + 1] == b'^' {
+            sig = false;
+            p += 1; // skip the '^'
+        }
+        loop {
+            p += 1;
+            if p >= ec {
+                break;
+            }
+            if self.pat[p] == L_ESC {
+                p += 1;
+                if match_class(c, self.pat[p]) {
+                    return sig;
+                }
+            } else if self.pat[p + 1] == b'-' && p + 2 < ec {
+                p += 2;
+                if self.pat[p - 2] <= c && c <= self.pat[p] {
+                    return sig;
+                }
+            } else if self.pat[p] == c {
+                return sig;
+            }
+        }
+        !sig
+    }
+
+    fn single_match(&self, s: usize, p: usize, ep: usize) -> bool {
+        let Some(&c) = self.src.get(s) else {
+            return false;
+        };
+        match self.pat[p] {
+            b'.' => true, // matches any char
+            L_ESC => match_class(c, self.pat[p + 1]),
+            b'[' => self.match_bracket_class(c, p, ep - 1),
+            pc => pc == c,
+        }
+    }
+
+    fn match_balance(&self, s: usize, p: usize) -> Result<Option<usize>, LuaError> {
+        if p + 1 >= self.pat.len() {
+            return Err(LuaError::runtime(
+                "malformed pattern (missing arguments to '%b')",
+            ));
+        }
+        let (b, e) = (self.pat[p], self.pat[p + 1]);
+        if self.src.get(s) != Some(&b) {
+            return Ok(None);
+        }
+        let mut cont = 1;
+        for (i, &c) in self.src.iter().enumerate().skip(s + 1) {
+            if c == e {
+                cont -= 1;
+                if cont == 0 {
+                    return Ok(Some(i + 1));
+                }
+            } else if c == b {
+                cont += 1;
+            }
+        }
+        Ok(None) // string ends out of balance
+    }
+
+    fn max_expand(&mut self, s: usize, p: usize, ep: usize) -> Result<Option<usize>, LuaError> {
+        let mut i = 0; // counts maximum expand for item
+        while self.single_match(s + i, p, ep) {
+            i += 1;
+        }
+        // keeps trying to match with the maximum repetitions
+        loop {
+            if let Some(res) = self.do_match(s + i, ep + 1)? {
+                return Ok(Some(res));
+            }
+            if i == 0 {
+                return Ok(None);
+            }
+            i -= 1; // else didn't match; reduce 1 repetition to try again
+        }
+    }
+
+    fn min_expand(&mut self, mut s: usize, p: usize, ep: usize) -> Result<Option<usize>, LuaError> {
+        loop {
+            if let Some(res) = self.do_match(s, ep + 1)? {
+                return Ok(Some(res));
+            } else if self.single_match(s, p, ep) {
+                s += 1; // try with one more repetition
+            } else {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn start_capture(
+        &mut self,
+        s: usize,
+        p: usize,
+        what: isize,
+    ) -> Result<Option<usize>, LuaError> {
+        if self.level >= LUA_MAXCAPTURES {
+            return Err(LuaError::runtime("too many captures"));
+        }
+        self.capture[self.level] = (s, what);
+        self.level += 1;
+        let res = self.do_match(s, p)?;
+        if res.is_none() {
+            self.level -= 1; // undo capture
+        }
+        Ok(res)
+    }
+
+    fn end_capture(&mut self, s: usize, p: usize) -> Result<Option<usize>, LuaError> {
+        let l = self.capture_to_close()?;
+        self.capture[l].1 = (s - self.capture[l].0) as isize; // close capture
+        let res = self.do_match(s, p)?;
+        if res.is_none() {
+            self.capture[l].1 = CAP_UNFINISHED; // undo capture
+        }
+        Ok(res)
+    }
+
+    fn match_capture(&self, s: usize, l: u8) -> Result<Option<usize>, LuaError> {
+        let l = self.check_capture(l)?;
+        let (init, len) = self.capture[l];
+        let len = len as usize;
+        if self.src.len() - s >= len && self.src[init..init + len] == self.src[s..s + len] {
+            Ok(Some(s + len))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn check_capture(&self, l: u8) -> Result<usize, LuaError> {
+        let l = l as isize - b'1' as isize;
+        if l < 0 || l as usize >= self.level || self.capture[l as usize].1 == CAP_UNFINISHED {
+            return Err(LuaError::runtime(format!(
+                "invalid capture index %{}",
+                l + 1
+            )));
+        }
+        Ok(l as usize)
+    }
+
+    fn capture_to_close(&self) -> Result<usize, LuaError> {
+        (0..self.level)
+            .rev()
+            .find(|&l| self.capture[l].1 == CAP_UNFINISHED)
+            .ok_or_else(|| LuaError::runtime("invalid pattern capture"))
+    }
+}
+
+/// 字符 `c` 属于类 `%cl`。大写的类表示补集，不是字母的 `cl` 只匹配它本身。
+fn match_class(c: u8, cl: u8) -> bool {
+    let res = match cl.to_ascii_lowercase() {
+        b'a' => c.is_ascii_alphabetic(),
+        b'c' => c.is_ascii_control(),
+        b'd' => c.is_ascii_digit(),
+        b'g' => c.is_ascii_graphic(),
+        b'l' => c.is_ascii_lowercase(),
+        b'p' => c.is_ascii_punctuation(),
+        b's' => c == b' ' || (b'\t'..=b'\r').contains(&c),
+        b'u' => c.is_ascii_uppercase(),
+        b'w' => c.is_ascii_alphanumeric(),
+        b'x' => c.is_ascii_hexdigit(),
+        _ => return cl == c,
+    };
+    if cl.is_ascii_uppercase() {
+        !res
+    } else {
+        res
+    }
+}
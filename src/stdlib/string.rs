@@ -0,0 +1,1042 @@
+use crate::{
+    api::{consts::lua_upvalueindex, r#type::Type, LuaAPI, LuaError, RustFn},
+    state::math::{fmt_a, fmt_e, fmt_g},
+};
+
+use super::{
+    auxlib::{arg_error, check_integer, check_lstring, check_number, opt_integer, type_error},
+    pack::{str_pack, str_packsize, str_unpack},
+    pattern::{no_specials, Capture, MatchState},
+};
+
+/// 字符串库产生的字符串的最大长度，更长的结果报告 "resulting string too large"，而不是在分配内存时终止进程
+const MAX_STRING_SIZE: usize = i32::MAX as usize;
+
+/// 创建 `string` 表，把字符串库函数注册到其中，并为字符串设置共享的元表。
+pub fn open_string(ls: &mut dyn LuaAPI) -> Result<(), LuaError> {
+    let funcs: [(&str, RustFn); 16] = [
+        ("byte", str_byte),
+        ("char", str_char),
+        ("find", str_find),
+        ("format", str_format),
+        ("gmatch", str_gmatch),
+        ("gsub", str_gsub),
+        ("len", str_len),
+        ("lower", str_lower),
+        ("match", str_match),
+        ("pack", str_pack),
+        ("packsize", str_packsize),
+        ("rep", str_rep),
+        ("reverse", str_reverse),
+        ("sub", str_sub),
+        ("unpack", str_unpack),
+        ("upper", str_upper),
+    ];
+    ls.create_table(0, funcs.len());
+    for (name, f) in funcs {
+        ls.push_rust_function(f);
+        ls.set_field(-2, name)?;
+    }
+    create_metatable(ls)?;
+    ls.set_global("string")
+}
+
+/// 创建字符串共享的元表，`__index` 字段为栈顶的 `string` 表。
+fn create_metatable(ls: &mut dyn LuaAPI) -> Result<(), LuaError> {
+    ls.create_table(0, 1); // table to be metatable for strings
+    ls.push_string(String::new()); // dummy string
+    ls.push_value(-2); // copy table
+    ls.set_metatable(-2); // set table as metatable for strings
+    ls.pop(1); // pop dummy string
+    ls.push_value(-2); // get string library
+    ls.set_field(-2, "__index")?; // metatable.__index = string
+    ls.pop(1); // pop metatable
+    Ok(())
+}
+
+/// 把相对的起始位置转换为从 1 开始的绝对位置，负数从末尾算起，结果至少为 1。
+fn posrelat_i(pos: i64, len: usize) -> usize {
+    if pos > 0 {
+        pos as usize
+    } else if pos == 0 || pos < -(len as i64) {
+        1
+    } else {
+        (len as i64 + pos + 1) as usize
+    }
+}
+
+/// 读取第 `arg` 个参数作为结束位置，默认值为 `def`，结果在 `[0, len]` 之内。
+fn get_end_pos(
+    ls: &dyn LuaAPI,
+    arg: isize,
+    def: i64,
+    len: usize,
+    fname: &str,
+) -> Result<usize, LuaError> {
+    let pos = opt_integer(ls, arg, def, fname)?;
+    Ok(if pos > len as i64 {
+        len
+    } else if pos >= 0 {
+        pos as usize
+    } else if pos < -(len as i64) {
+        0
+    } else {
+        (len as i64 + pos + 1) as usize
+    })
+}
+
+// string.len (s)
+fn str_len(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let s = check_lstring(ls, 1, "len")?;
+    ls.push_integer(s.len() as i64);
+    Ok(1)
+}
+
+// string.sub (s, i [, j])
+fn str_sub(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let s = check_lstring(ls, 1, "sub")?;
+    let l = s.len();
+    let start = posrelat_i(check_integer(ls, 2, "sub")?, l);
+    let end = get_end_pos(ls, 3, -1, l, "sub")?;
+    if start <= end {
+        ls.push_bytes(s[start - 1..end].to_vec());
+    } else {
+        ls.push_string(String::new());
+    }
+    Ok(1)
+}
+
+// string.reverse (s)
+fn str_reverse(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let s = check_lstring(ls, 1, "reverse")?;
+    let mut s = s;
+    s.reverse();
+    ls.push_bytes(s);
+    Ok(1)
+}
+
+// string.lower (s)
+fn str_lower(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let s = check_lstring(ls, 1, "lower")?;
+    ls.push_bytes(s.to_ascii_lowercase());
+    Ok(1)
+}
+
+// string.upper (s)
+fn str_upper(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let s = check_lstring(ls, 1, "upper")?;
+    ls.push_bytes(s.to_ascii_uppercase());
+    Ok(1)
+}
+
+// string.rep (s, n [, sep])
+fn str_rep(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let s = check_lstring(ls, 1, "rep")?;
+    let n = check_integer(ls, 2, "rep")?;
+    let sep = if ls.is_none_or_nil(3) {
+        Vec::new()
+    } else {
+        check_lstring(ls, 3, "rep")?
+    };
+    if n <= 0 {
+        ls.push_string(String::new());
+        return Ok(1);
+    }
+    let mut res = Vec::new();
+    let total = (s.len() + sep.len())
+        .checked_mul(n as usize)
+        .filter(|&t| t <= MAX_STRING_SIZE);
+    if total.is_none_or(|t| res.try_reserve_exact(t).is_err()) {
+        return Err(LuaError::runtime("resulting string too large"));
+    }
+    for i in 0..n {
+        if i > 0 {
+            res.extend_from_slice(&sep);
+        }
+        res.extend_from_slice(&s);
+    }
+    ls.push_bytes(res);
+    Ok(1)
+}
+
+// string.byte (s [, i [, j]])
+fn str_byte(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let s = check_lstring(ls, 1, "byte")?;
+    let l = s.len();
+    let pi = opt_integer(ls, 2, 1, "byte")?;
+    let pose = get_end_pos(ls, 3, pi, l, "byte")?;
+    let posi = posrelat_i(pi, l);
+    if posi > pose {
+        return Ok(0); // empty interval; return no values
+    }
+    let n = pose - posi + 1;
+    if n >= i32::MAX as usize || !ls.check_stack(n) {
+        return Err(LuaError::runtime("string slice too long"));
+    }
+    for &b in &s[posi - 1..pose] {
+        ls.push_integer(b as i64);
+    }
+    Ok(n)
+}
+
+// string.char (···)
+fn str_char(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let n = ls.get_top();
+    let mut b = Vec::with_capacity(n as usize);
+    for i in 1..=n {
+        let c = check_integer(ls, i, "char")?;
+        if !(0..=u8::MAX as i64).contains(&c) {
+            return Err(arg_error(i, "char", "value out of range"));
+        }
+        b.push(c as u8);
+    }
+    ls.push_bytes(b);
+    Ok(1)
+}
+
+/// 把一个捕获推入栈顶。
+fn push_capture(ls: &mut dyn LuaAPI, src: &[u8], cap: Capture) {
+    match cap {
+        Capture::Str(s, e) => ls.push_bytes(src[s..e].to_vec()),
+        Capture::Position(pos) => ls.push_integer(pos as i64),
+    }
+}
+
+/// 把所有捕获推入栈顶，返回推入的值的数量。`whole` 为匹配的范围，没有捕获时推入整个匹配。
+fn push_captures(
+    ls: &mut dyn LuaAPI,
+    ms: &MatchState,
+    src: &[u8],
+    whole: Option<(usize, usize)>,
+) -> Result<usize, LuaError> {
+    let nlevels = ms.num_captures(whole.is_some());
+    if !ls.check_stack(nlevels) {
+        return Err(LuaError::runtime("too many captures"));
+    }
+    let (s, e) = whole.unwrap_or((0, 0));
+    for i in 0..nlevels {
+        let cap = ms.get_capture(i, s, e)?;
+        push_capture(ls, src, cap);
+    }
+    Ok(nlevels)
+}
+
+/// `string.find` 和 `string.match` 的实现。
+fn str_find_aux(ls: &mut dyn LuaAPI, find: bool) -> Result<usize, LuaError> {
+    let fname = if find { "find" } else { "match" };
+    let s = check_lstring(ls, 1, fname)?;
+    let p = check_lstring(ls, 2, fname)?;
+    let (s, p) = (&s[..], &p[..]);
+    let init = posrelat_i(opt_integer(ls, 3, 1, fname)?, s.len()) - 1;
+    if init > s.len() {
+        // start after string's end?
+        ls.push_nil(); // cannot find anything
+        return Ok(1);
+    }
+    // explicit request or no special characters?
+    if find && (ls.to_boolean(4) || no_specials(p)) {
+        // do a plain search
+        let found = if p.is_empty() {
+            Some(0)
+        } else {
+            s[init..].windows(p.len()).position(|w| w == p)
+        };
+        if let Some(i) = found {
+            ls.push_integer((init + i + 1) as i64);
+            ls.push_integer((init + i + p.len()) as i64);
+            return Ok(2);
+        }
+    } else {
+        let mut ms = MatchState::new(s, p);
+        let anchor = p.first() == Some(&b'^');
+        let pstart = anchor as usize; // skip anchor character
+        let mut s1 = init;
+        loop {
+            ms.reprepstate();
+            if let Some(e) = ms.do_match(s1, pstart)? {
+                if find {
+                    ls.push_integer(s1 as i64 + 1); // start
+                    ls.push_integer(e as i64); // end
+                    return Ok(push_captures(ls, &ms, s, None)? + 2);
+                }
+                return push_captures(ls, &ms, s, Some((s1, e)));
+            }
+            if s1 >= s.len() || anchor {
+                break;
+            }
+            s1 += 1;
+        }
+    }
+    ls.push_nil(); // not found
+    Ok(1)
+}
+
+// string.find (s, pattern [, init [, plain]])
+fn str_find(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    str_find_aux(ls, true)
+}
+
+// string.match (s, pattern [, init])
+fn str_match(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    str_find_aux(ls, false)
+}
+
+// string.gmatch (s, pattern [, init])
+fn str_gmatch(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let s = check_lstring(ls, 1, "gmatch")?;
+    check_lstring(ls, 2, "gmatch")?;
+    let init = posrelat_i(opt_integer(ls, 3, 1, "gmatch")?, s.len()) - 1;
+    ls.set_top(2); // keep strings on closure to avoid being collected
+                   // start after string's end?
+    ls.push_integer(init.min(s.len() + 1) as i64);
+    ls.push_integer(-1); // no last match
+    ls.push_rust_closure(gmatch_aux, 4);
+    Ok(1)
+}
+
+/// `string.gmatch` 返回的迭代函数。上值依次是字符串、模式、下次匹配的起始位置和上次匹配的结束位置。
+fn gmatch_aux(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let s = ls.to_bytesx(lua_upvalueindex(1)).unwrap();
+    let p = ls.to_bytesx(lua_upvalueindex(2)).unwrap();
+    let (s, p) = (&s[..], &p[..]);
+    let start = ls.to_integer(lua_upvalueindex(3)) as usize;
+    let lastmatch = ls.to_integer(lua_upvalueindex(4));
+    let mut ms = MatchState::new(s, p);
+    for src in start..=s.len() {
+        ms.reprepstate();
+        if let Some(e) = ms.do_match(src, 0)? {
+            if e as i64 != lastmatch {
+                ls.push_integer(e as i64);
+                ls.copy(-1, lua_upvalueindex(3));
+                ls.replace(lua_upvalueindex(4));
+                return push_captures(ls, &ms, s, Some((src, e)));
+            }
+        }
+    }
+    ls.push_integer(s.len() as i64 + 1);
+    ls.replace(lua_upvalueindex(3)); // not found
+    Ok(0)
+}
+
+/// 按替换字符串 `news` 把匹配 `src[s..e]` 的替换结果追加到 `b`。
+fn add_s(
+    ms: &MatchState,
+    b: &mut Vec<u8>,
+    src: &[u8],
+    (s, e): (usize, usize),
+    news: &[u8],
+) -> Result<(), LuaError> {
+    let mut i = 0;
+    while i < news.len() {
+        let c = news[i];
+        i += 1;
+        if c != b'%' {
+            b.push(c);
+            continue;
+        }
+        match news.get(i).copied() {
+            Some(b'%') => b.push(b'%'),
+            Some(b'0') => b.extend_from_slice(&src[s..e]),
+            Some(d) if d.is_ascii_digit() => match ms.get_capture((d - b'1') as usize, s, e)? {
+                Capture::Str(cs, ce) => b.extend_from_slice(&src[cs..ce]),
+                Capture::Position(pos) => b.extend_from_slice(pos.to_string().as_bytes()),
+            },
+            _ => {
+                return Err(LuaError::runtime(
+                    "invalid use of '%' in replacement string",
+                ))
+            }
+        }
+        i += 1;
+    }
+    Ok(())
+}
+
+/// 把匹配 `src[s..e]` 的替换结果追加到 `b`。返回是否真的做了替换。
+fn add_value(
+    ls: &mut dyn LuaAPI,
+    ms: &MatchState,
+    b: &mut Vec<u8>,
+    src: &[u8],
+    (s, e): (usize, usize),
+    tr: i8,
+) -> Result<bool, LuaError> {
+    if tr == Type::Function as i8 {
+        // call the function
+        ls.push_value(3); // push the function
+        let n = push_captures(ls, ms, src, Some((s, e)))?; // all captures as arguments
+        ls.call(n, 1)?;
+    } else if tr == Type::Table as i8 {
+        // index the table
+        let cap = ms.get_capture(0, s, e)?; // first capture is the index
+        push_capture(ls, src, cap);
+        ls.get_table(3)?;
+    } else {
+        // string or number
+        let news = ls.to_bytesx(3).unwrap();
+        add_s(ms, b, src, (s, e), &news)?;
+        return Ok(true); // something changed
+    }
+    if !ls.to_boolean(-1) {
+        // nil or false?
+        ls.pop(1); // remove value
+        b.extend_from_slice(&src[s..e]); // keep original text
+        return Ok(false); // no changes
+    }
+    if !ls.is_string(-1) {
+        let tname = ls.type_name(ls.type_id(-1)).to_string();
+        return Err(LuaError::runtime(format!(
+            "invalid replacement value (a {})",
+            tname
+        )));
+    }
+    let value = ls.to_bytesx(-1).unwrap();
+    b.extend_from_slice(&value); // add result to accumulator
+    ls.pop(1);
+    Ok(true)
+}
+
+// string.gsub (s, pattern, repl [, n])
+fn str_gsub(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let src = check_lstring(ls, 1, "gsub")?;
+    let p = check_lstring(ls, 2, "gsub")?;
+    let (src, p) = (&src[..], &p[..]);
+    let tr = ls.type_id(3); // replacement type
+    if !(tr == Type::Number as i8
+        || tr == Type::String as i8
+        || tr == Type::Function as i8
+        || tr == Type::Table as i8)
+    {
+        return Err(type_error(ls, 3, "gsub", "string/function/table"));
+    }
+    let max_s = opt_integer(ls, 4, src.len() as i64 + 1, "gsub")?; // max replacements
+    let anchor = p.first() == Some(&b'^');
+    let pstart = anchor as usize; // skip anchor character
+    let mut lastmatch = None; // end of last match
+    let mut changed = false; // change flag
+    let mut n = 0; // replacement count
+    let mut s = 0;
+    let mut b = Vec::with_capacity(src.len());
+    let mut ms = MatchState::new(src, p);
+    while n < max_s {
+        ms.reprepstate();
+        match ms.do_match(s, pstart)? {
+            Some(e) if Some(e) != lastmatch => {
+                // match?
+                n += 1;
+                changed |= add_value(ls, &ms, &mut b, src, (s, e), tr)?; // add replacement to buffer
+                s = e;
+                lastmatch = Some(e);
+            }
+            _ if s < src.len() => {
+                // otherwise, skip one character
+                b.push(src[s]);
+                s += 1;
+            }
+            _ => break, // end of subject
+        }
+        if anchor {
+            break;
+        }
+    }
+    if changed {
+        b.extend_from_slice(&src[s..]);
+        ls.push_bytes(b); // create and return new string
+    } else {
+        ls.push_value(1); // return original string
+    }
+    ls.push_integer(n);
+    Ok(2)
+}
+
+/// 一个转换说明的最大长度
+const MAX_FORMAT: usize = 32;
+
+/// `a A e E f F g G` 可用的标志
+const L_FMTFLAGSF: &[u8] = b"-+ #0";
+/// `o x X` 可用的标志
+const L_FMTFLAGSX: &[u8] = b"-#0";
+/// `d i` 可用的标志
+const L_FMTFLAGSI: &[u8] = b"-+ 0";
+/// `u` 可用的标志
+const L_FMTFLAGSU: &[u8] = b"-0";
+/// `c s` 可用的标志
+const L_FMTFLAGSC: &[u8] = b"-";
+
+/// 转换说明中的标志、宽度和精度。
+#[derive(Default)]
+struct FormatSpec {
+    minus: bool,
+    plus: bool,
+    space: bool,
+    alt: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+/// 读取 `strfrmt[i..]` 开头的转换说明（不包括 `%`），返回 `%` 加上转换说明，`i` 移动到说明之后。
+fn get_format(strfrmt: &[u8], i: &mut usize) -> Result<String, LuaError> {
+    // spans flags, width, and precision ('0' is included as a flag)
+    let mut len = strfrmt[*i..]
+        .iter()
+        .take_while(|c| b"-+ #0123456789.".contains(c))
+        .count();
+    len += 1; // adds following character (which should be the specifier)
+    if len >= MAX_FORMAT - 10 {
+        return Err(LuaError::runtime("invalid format string to 'format'"));
+    }
+    let end = (*i + len).min(strfrmt.len());
+    let form = format!("%{}", String::from_utf8_lossy(&strfrmt[*i..end]));
+    *i = end;
+    Ok(form)
+}
+
+/// 读取至多两位数字。
+fn get_2digits(spec: &[u8], j: &mut usize) -> usize {
+    let mut n = 0;
+    for _ in 0..2 {
+        match spec.get(*j) {
+            Some(c) if c.is_ascii_digit() => {
+                n = n * 10 + (c - b'0') as usize;
+                *j += 1;
+            }
+            _ => break,
+        }
+    }
+    n
+}
+
+/// 检查转换说明只使用了 `flags` 中的标志，宽度和精度至多两位，`precision` 为假时不能有精度。
+fn check_format(form: &str, flags: &[u8], precision: bool) -> Result<FormatSpec, LuaError> {
+    let spec = &form.as_bytes()[1..]; // skip '%'
+    let mut fs = FormatSpec::default();
+    let mut j = 0;
+    while let Some(&c) = spec.get(j).filter(|c| flags.contains(c)) {
+        match c {
+            b'-' => fs.minus = true,
+            b'+' => fs.plus = true,
+            b' ' => fs.space = true,
+            b'#' => fs.alt = true,
+            _ => fs.zero = true,
+        }
+        j += 1;
+    }
+    // a width cannot start with '0'
+    if spec.get(j) != Some(&b'0') {
+        fs.width = get_2digits(spec, &mut j);
+        if spec.get(j) == Some(&b'.') && precision {
+            j += 1;
+            fs.precision = Some(get_2digits(spec, &mut j));
+        }
+    }
+    // did not go to the end?
+    if !spec.get(j).is_some_and(|c| c.is_ascii_alphabetic()) {
+        return Err(LuaError::runtime(format!(
+            "invalid conversion specification: '{}'",
+            form
+        )));
+    }
+    Ok(fs)
+}
+
+/// 按宽度填充 `prefix` 和 `body` 后追加到 `b`。`zero_ok` 时 `0` 标志在前缀和内容之间填充 0。
+fn add_padded(b: &mut Vec<u8>, fs: &FormatSpec, prefix: &str, body: &[u8], zero_ok: bool) {
+    let fill = fs.width.saturating_sub(prefix.len() + body.len());
+    if fs.minus {
+        b.extend_from_slice(prefix.as_bytes());
+        b.extend_from_slice(body);
+        b.resize(b.len() + fill, b' ');
+    } else if fs.zero && zero_ok {
+        b.extend_from_slice(prefix.as_bytes());
+        b.resize(b.len() + fill, b'0');
+        b.extend_from_slice(body);
+    } else {
+        b.resize(b.len() + fill, b' ');
+        b.extend_from_slice(prefix.as_bytes());
+        b.extend_from_slice(body);
+    }
+}
+
+/// 数字的符号前缀。
+fn sign_prefix(fs: &FormatSpec, neg: bool) -> &'static str {
+    if neg {
+        "-"
+    } else if fs.plus {
+        "+"
+    } else if fs.space {
+        " "
+    } else {
+        ""
+    }
+}
+
+/// 按 `d i u o x X` 格式化整数。
+fn add_integer(b: &mut Vec<u8>, fs: &FormatSpec, n: i64, conv: u8) {
+    let (prefix, mut digits) = match conv {
+        b'd' | b'i' => (sign_prefix(fs, n < 0), n.unsigned_abs().to_string()),
+        b'u' => ("", (n as u64).to_string()),
+        b'o' => ("", format!("{:o}", n as u64)),
+        b'x' => (
+            if fs.alt && n != 0 { "0x" } else { "" },
+            format!("{:x}", n as u64),
+        ),
+        _ => (
+            if fs.alt && n != 0 { "0X" } else { "" },
+            format!("{:X}", n as u64),
+        ),
+    };
+    match fs.precision {
+        Some(0) if n == 0 => digits.clear(),
+        Some(p) if digits.len() < p => digits.insert_str(0, &"0".repeat(p - digits.len())),
+        _ => {}
+    }
+    if conv == b'o' && fs.alt && !digits.starts_with('0') {
+        digits.insert(0, '0');
+    }
+    // the '0' flag is ignored when a precision is given
+    add_padded(b, fs, prefix, digits.as_bytes(), fs.precision.is_none());
+}
+
+/// 按 `a A e E f F g G` 格式化浮点数。
+fn add_float(b: &mut Vec<u8>, fs: &FormatSpec, n: f64, conv: u8) {
+    let x = n.abs();
+    let mut body = match conv.to_ascii_lowercase() {
+        b'a' => fmt_a(x, fs.precision, fs.alt),
+        b'e' => fmt_e(x, fs.precision.unwrap_or(6), fs.alt, false),
+        b'g' => fmt_g(x, fs.precision.unwrap_or(6), fs.alt),
+        _ if !x.is_finite() => fmt_g(x, 1, false),
+        _ => {
+            let s = format!("{:.*}", fs.precision.unwrap_or(6), x);
+            if fs.alt && !s.contains('.') {
+                s + "."
+            } else {
+                s
+            }
+        }
+    };
+    let mut prefix = sign_prefix(fs, n.is_sign_negative()).to_string();
+    if x.is_finite() && conv.eq_ignore_ascii_case(&b'a') {
+        prefix.push_str(&body[..2]); // zeros go after "0x"
+        body.drain(..2);
+    }
+    if conv.is_ascii_uppercase() {
+        prefix.make_ascii_uppercase();
+        body.make_ascii_uppercase();
+    }
+    add_padded(b, fs, &prefix, body.as_bytes(), x.is_finite());
+}
+
+/// 按 `%q` 把字符串加上引号和转义，结果可以被 Lua 读回。
+fn add_quoted(b: &mut Vec<u8>, s: &[u8]) {
+    b.push(b'"');
+    for (i, &c) in s.iter().enumerate() {
+        if c == b'"' || c == b'\\' || c == b'\n' {
+            b.push(b'\\');
+            b.push(c);
+        } else if c.is_ascii_control() {
+            let esc = if s.get(i + 1).is_some_and(|d| d.is_ascii_digit()) {
+                format!("\\{:03}", c)
+            } else {
+                format!("\\{}", c)
+            };
+            b.extend_from_slice(esc.as_bytes());
+        } else {
+            b.push(c);
+        }
+    }
+    b.push(b'"');
+}
+
+/// 按 `%q` 追加第 `arg` 个参数的字面量形式。
+fn add_literal(ls: &mut dyn LuaAPI, b: &mut Vec<u8>, arg: isize) -> Result<(), LuaError> {
+    let t = ls.type_id(arg);
+    if t == Type::String as i8 {
+        add_quoted(b, &ls.to_bytesx(arg).unwrap());
+    } else if t == Type::Number as i8 {
+        let lit = match ls.to_integerx(arg) {
+            // corner case: use hex
+            Some(i64::MIN) => format!("0x{:x}", i64::MIN),
+            Some(n) => n.to_string(),
+            None => {
+                let n = ls.to_number(arg);
+                if n == f64::INFINITY {
+                    "1e9999".to_string()
+                } else if n == f64::NEG_INFINITY {
+                    "-1e9999".to_string()
+                } else if n.is_nan() {
+                    "(0/0)".to_string()
+                } else {
+                    fmt_a(n, None, false)
+                }
+            }
+        };
+        b.extend_from_slice(lit.as_bytes());
+    } else if t == Type::Nil as i8 || t == Type::Boolean as i8 {
+        b.extend_from_slice(&ls.to_bytes_meta(arg)?);
+    } else {
+        return Err(arg_error(arg, "format", "value has no literal form"));
+    }
+    Ok(())
+}
+
+// string.format (formatstring, ···)
+fn str_format(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let top = ls.get_top();
+    let strfrmt = check_lstring(ls, 1, "format")?;
+    let strfrmt = &strfrmt[..];
+    let mut arg = 1;
+    let mut b = Vec::with_capacity(strfrmt.len());
+    let mut i = 0;
+    while i < strfrmt.len() {
+        let c = strfrmt[i];
+        i += 1;
+        if c != b'%' {
+            b.push(c);
+            continue;
+        }
+        if strfrmt.get(i) == Some(&b'%') {
+            b.push(b'%'); // %%
+            i += 1;
+            continue;
+        }
+        // format item
+        arg += 1;
+        if arg > top {
+            return Err(arg_error(arg, "format", "no value"));
+        }
+        let form = get_format(strfrmt, &mut i)?;
+        let conv = *form.as_bytes().last().unwrap();
+        match conv {
+            b'c' => {
+                let fs = check_format(&form, L_FMTFLAGSC, false)?;
+                let c = check_integer(ls, arg, "format")? as u8;
+                add_padded(&mut b, &fs, "", &[c], false);
+            }
+            b'd' | b'i' | b'u' | b'o' | b'x' | b'X' => {
+                let n = check_integer(ls, arg, "format")?;
+                let flags = match conv {
+                    b'd' | b'i' => L_FMTFLAGSI,
+                    b'u' => L_FMTFLAGSU,
+                    _ => L_FMTFLAGSX,
+                };
+                let fs = check_format(&form, flags, true)?;
+                add_integer(&mut b, &fs, n, conv);
+            }
+            b'a' | b'A' | b'e' | b'E' | b'f' | b'F' | b'g' | b'G' => {
+                let n = check_number(ls, arg, "format")?;
+                let fs = check_format(&form, L_FMTFLAGSF, true)?;
+                add_float(&mut b, &fs, n, conv);
+            }
+            b'q' => {
+                if form.len() != 2 {
+                    return Err(LuaError::runtime("specifier '%q' cannot have modifiers"));
+                }
+                add_literal(ls, &mut b, arg)?;
+            }
+            b's' => {
+                let s = ls.to_bytes_meta(arg)?;
+                if form.len() == 2 {
+                    b.extend_from_slice(&s); // keep entire string
+                    continue;
+                }
+                if s.contains(&0) {
+                    return Err(arg_error(arg, "format", "string contains zeros"));
+                }
+                let fs = check_format(&form, L_FMTFLAGSC, true)?;
+                match fs.precision {
+                    // no precision and string is too long to be formatted
+                    None if s.len() >= 100 => b.extend_from_slice(&s),
+                    p => {
+                        let body = &s[..p.unwrap_or(s.len()).min(s.len())];
+                        add_padded(&mut b, &fs, "", body, false);
+                    }
+                }
+            }
+            _ => {
+                return Err(LuaError::runtime(format!(
+                    "invalid conversion '{}' to 'format'",
+                    form
+                )));
+            }
+        }
+    }
+    ls.push_bytes(b);
+    Ok(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stdlib::test_util::{run, run_err};
+
+    #[test]
+    fn test_basic_functions() {
+        let src = r#"
+            local s = "Hello"
+            return #s, s:len(), s:upper(), s:lower(), s:reverse(), s:sub(2, -2),
+                s:sub(-3), s:sub(10), ("ab"):rep(3, ","), ("x"):rep(0),
+                string.char(72, 105), s:byte(), s:byte(-1), select('#', s:byte(1, -1))
+        "#;
+        assert_eq!(
+            run(src),
+            [
+                "5", "5", "HELLO", "hello", "olleH", "ell", "llo", "", "ab,ab,ab", "", "Hi", "72",
+                "111", "5"
+            ]
+        );
+        assert!(
+            run_err("string.char(256)").contains("bad argument #1 to 'char' (value out of range)")
+        );
+        assert!(run_err("string.rep()")
+            .contains("bad argument #1 to 'rep' (string expected, got no value)"));
+        assert_eq!(
+            run("return pcall(string.rep, 'x', 1e10)"),
+            ["false", "resulting string too large"]
+        );
+        assert!(run_err("string.rep('ab', 2^30, ',')").contains("resulting string too large"));
+    }
+
+    /// 逐个运行 `return <expr>`，检查每个表达式的所有返回值
+    fn check_all(cases: &[(&str, &[&str])]) {
+        for (expr, expected) in cases {
+            assert_eq!(run(&format!("return {}", expr)), *expected, "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_binary_strings() {
+        check_all(&[
+            ("string.char(200, 0, 255):byte(1, -1)", &["200", "0", "255"]),
+            (r#"#("\xff\0" .. "\200")"#, &["3"]),
+            (
+                r#"("\255"):rep(3):upper():reverse() == "\255\255\255""#,
+                &["true"],
+            ),
+            (
+                r#"tostring("\200\1") == "\200\1", ("\200\1"):sub(2) == "\1""#,
+                &["true", "true"],
+            ),
+            (r#"string.find("a\0b\200", "[\128-\255]")"#, &["4", "4"]),
+            (r#"string.gsub("\0\1\2", "%c", "x")"#, &["xxx", "3"]),
+        ]);
+    }
+
+    #[test]
+    fn test_find_match() {
+        check_all(&[
+            (r#"string.find("hello world", "o w")"#, &["5", "7"]),
+            (r#"string.find("a.b", ".", 1, true)"#, &["2", "2"]),
+            (r#"string.find("hello", "l+")"#, &["3", "4"]),
+            (r#"string.find("hello", "")"#, &["1", "0"]),
+            (r#"string.find("abc", "b", -1)"#, &["nil"]),
+            (r#"string.find("abc", "b", 10)"#, &["nil"]),
+            (
+                r#"string.find("key = value", "(%w+)%s*=")"#,
+                &["1", "5", "key"],
+            ),
+            (
+                r#"string.match("key = value", "(%w+)%s*=%s*(%w+)")"#,
+                &["key", "value"],
+            ),
+            (r#"string.match("  trim  ", "^%s*(.-)%s*$")"#, &["trim"]),
+            (r#"string.match("hello", "()ll()")"#, &["3", "5"]),
+            (r#"string.match("hello", "l+", 4)"#, &["l"]),
+            (
+                r#"string.match("2024-01-15", "(%d+)-(%d+)-(%d+)")"#,
+                &["2024", "01", "15"],
+            ),
+        ]);
+    }
+
+    #[test]
+    fn test_pattern_items() {
+        check_all(&[
+            (r#"string.match("f(a(b)c)d", "%b()")"#, &["(a(b)c)"]),
+            (
+                r#"string.match("THE (quick) fox", "%f[%a]%a+%f[%A]", 5)"#,
+                &["quick"],
+            ),
+            (r#"string.match("[[x]]", "[]]")"#, &["]"]),
+            (
+                r#"string.match("hello there", "(h)(.-)%1")"#,
+                &["h", "ello t"],
+            ),
+            (r#"string.match("a-b", "[%a%-]+")"#, &["a-b"]),
+            (r#"string.match("x = 0x1F;", "0x(%x+)")"#, &["1F"]),
+            (r#"string.match("abc", "[^%l]")"#, &["nil"]),
+            (r#"string.find("aaa", "a-b")"#, &["nil"]),
+            (r#"string.match("aaab", "a-b")"#, &["aaab"]),
+            (r#"string.match("abc", "^(a)")"#, &["a"]),
+            (r#"string.match("xabc", "^(a)")"#, &["nil"]),
+            (r#"string.match("ab", "a?b?c?$")"#, &["ab"]),
+            (r#"string.match("a.b", "%.")"#, &["."]),
+        ]);
+        assert!(run_err("string.find('a', '%')").contains("malformed pattern (ends with '%')"));
+        assert!(run_err("string.find('a', '[a')").contains("malformed pattern (missing ']')"));
+        assert!(run_err("string.find('a', '(a')").contains("unfinished capture"));
+        assert!(run_err("string.match('a', 'a)')").contains("invalid pattern capture"));
+        assert!(run_err("string.find('a', '%1')").contains("invalid capture index %1"));
+        assert!(run_err("string.find('a', '%f')").contains("missing '[' after '%f' in pattern"));
+    }
+
+    #[test]
+    fn test_gmatch() {
+        let src = r#"
+            local words, pairs_ = {}, {}
+            for w in string.gmatch("one two  three", "%a+") do words[#words + 1] = w end
+            for k, v in ("a=1, b=2"):gmatch("(%w+)=(%w+)") do pairs_[#pairs_ + 1] = k .. v end
+            local n = 0
+            for _ in ("abc"):gmatch("") do n = n + 1 end
+            return table_concat(words), table_concat(pairs_), n
+        "#;
+        let src = src.replace(
+            "table_concat",
+            "(function(t) local s = '' for i = 1, #t do s = s .. t[i] .. '|' end return s end)",
+        );
+        assert_eq!(run(&src), ["one|two|three|", "a1|b2|", "4"]);
+    }
+
+    #[test]
+    fn test_gsub() {
+        check_all(&[
+            (
+                r#"string.gsub("hello world", "o", "0")"#,
+                &["hell0 w0rld", "2"],
+            ),
+            (
+                r#"string.gsub("hello world", "(%w+)", "<%1>")"#,
+                &["<hello> <world>", "2"],
+            ),
+            (
+                r#"string.gsub("hello world", "%w+", "%0 %0", 1)"#,
+                &["hello hello world", "1"],
+            ),
+            (
+                r#"string.gsub("$name is $version, $x", "%$(%w+)", { name = "lua", version = 5.4 })"#,
+                &["lua is 5.4, $x", "3"],
+            ),
+            (
+                r#"string.gsub("abc", "%w", function(c) return c:upper() .. "." end)"#,
+                &["A.B.C.", "3"],
+            ),
+            (r#"string.gsub("abc", "", "-")"#, &["-a-b-c-", "4"]),
+            (r#"string.gsub("abc", "b", "%%")"#, &["a%c", "1"]),
+            (r#"string.gsub("abc", "()", "%1")"#, &["1a2b3c4", "4"]),
+            (
+                r#"string.gsub("hello", "l", function() end)"#,
+                &["hello", "2"],
+            ),
+            (r#"string.gsub("hello", "^h", "j")"#, &["jello", "1"]),
+        ]);
+        assert!(run_err("string.gsub('a', 'a', '%2')").contains("invalid capture index %2"));
+        assert!(run_err("string.gsub('a', 'a', '%x')")
+            .contains("invalid use of '%' in replacement string"));
+        assert!(run_err("string.gsub('a', 'a', {a = {}})")
+            .contains("invalid replacement value (a table)"));
+    }
+
+    #[test]
+    fn test_format() {
+        check_all(&[
+            (
+                r#"string.format("%d %5d %-5d| %05d %+d %.3d", 42, 42, 42, -42, 42, 7)"#,
+                &["42    42 42   | -0042 +42 007"],
+            ),
+            (r#"string.format("%i %u %d", -3, 3, 3.0)"#, &["-3 3 3"]),
+            (
+                r#"string.format("%x %X %#x %o %#o %x", 255, 255, 255, 8, 8, -1)"#,
+                &["ff FF 0xff 10 010 ffffffffffffffff"],
+            ),
+            (
+                r#"string.format("%c%c%3c%-3c|", 76, 117, 97, 98)"#,
+                &["Lu  ab  |"],
+            ),
+            (
+                r#"string.format("%f %.2f %10.3f %-10.1f| %+.0f %#.0f", 1.5, 3.14159, -2.5, 2.25, 2.5, 3.0)"#,
+                &["1.500000 3.14     -2.500 2.2       | +2 3."],
+            ),
+            (
+                r#"string.format("%e %.3E %g %g %g %G %#g", 12345.678, 0.00012, 0.0001, 1e-5, 100000000, 1e20, 1.0)"#,
+                &["1.234568e+04 1.200E-04 0.0001 1e-05 1e+08 1E+20 1.00000"],
+            ),
+            (
+                r#"string.format("%g %.3g %10.4g|", 1/3, 2/3, 3.14159265358979)"#,
+                &["0.333333 0.667      3.142|"],
+            ),
+            (
+                r#"string.format("%a %A %.2a %08.1a", 1.0, 255.5, 1/3, -1.5)"#,
+                &["0x1p+0 0X1.FFP+7 0x1.55p-2 -0x1.8p+0"],
+            ),
+            (
+                r#"string.format("%f %5.1f %e %g %-6f|%06f", 1/0, -1/0, -1/0, 1/0, 1/0, -1/0)"#,
+                &["inf  -inf -inf inf inf   |  -inf"],
+            ),
+            (
+                r#"string.format("%s|%10s|%-5s|%.2s|%s", "hi", "right", "left", "truncate", 1.0)"#,
+                &["hi|     right|left |tr|1.0"],
+            ),
+            (
+                r#"string.format("%s %s %s", nil, true, {} ~= nil)"#,
+                &["nil true true"],
+            ),
+            (
+                r#"string.format("%5.1s|%%|%.0s|", "abc", "gone")"#,
+                &["    a|%||"],
+            ),
+        ]);
+    }
+
+    #[test]
+    fn test_format_q() {
+        check_all(&[
+            (
+                r#"string.format("%q", 'a "quoted"\n\\ line\0' .. "1\r\t")"#,
+                &["\"a \\\"quoted\\\"\\\n\\\\ line\\0001\\13\\9\""],
+            ),
+            (
+                r#"string.format("%q %q %q", 42, (-9223372036854775807 - 1), 1.5)"#,
+                &["42 0x8000000000000000 0x1.8p+0"],
+            ),
+            (
+                r#"string.format("%q %q %q %q", 1/0, -1/0, 0/0, 1.0)"#,
+                &["1e9999 -1e9999 (0/0) 0x1p+0"],
+            ),
+            (r#"string.format("%q %q", nil, false)"#, &["nil false"]),
+        ]);
+        // the literals read back as the same values
+        let src = r#"return string.format("return %q, %q, %q, %q", "x\0y\n\"", (-9223372036854775807 - 1), 0.1, -1/0)"#;
+        let chunk = run(src).remove(0);
+        assert_eq!(
+            run(&chunk),
+            ["x\0y\n\"", "-9223372036854775808", "0.1", "-inf"]
+        );
+        assert!(
+            run_err("string.format('%10q', 'x')").contains("specifier '%q' cannot have modifiers")
+        );
+        assert!(run_err("string.format('%q', {})")
+            .contains("bad argument #2 to 'format' (value has no literal form)"));
+    }
+
+    #[test]
+    fn test_format_errors() {
+        assert!(run_err("string.format('%d')").contains("bad argument #2 to 'format' (no value)"));
+        assert!(
+            run_err("string.format('%d', 1.5)").contains("number has no integer representation")
+        );
+        assert!(run_err("string.format('%f', 'x')")
+            .contains("bad argument #2 to 'format' (number expected, got string)"));
+        assert!(run_err("string.format('%y', 1)").contains("invalid conversion '%y' to 'format'"));
+        assert!(run_err("string.format('%', 1)").contains("invalid conversion '%' to 'format'"));
+        assert!(run_err("string.format('%100d', 1)")
+            .contains("invalid conversion specification: '%100d'"));
+        assert!(
+            run_err("string.format('%+x', 1)").contains("invalid conversion specification: '%+x'")
+        );
+        assert!(run_err("string.format('%.3c', 65)")
+            .contains("invalid conversion specification: '%.3c'"));
+        assert!(run_err("string.format('%5s', 'a\\0b')").contains("string contains zeros"));
+        assert!(run_err("string.format('%0000000000000000000000d', 1)")
+            .contains("invalid format string to 'format'"));
+        assert_eq!(run("return string.format('%d %s', '10', 0x10)"), ["10 16"]);
+    }
+}
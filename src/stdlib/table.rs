@@ -0,0 +1,543 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::api::{
+    consts::{LUA_OPEQ, LUA_OPLT},
+    r#type::Type,
+    LuaAPI, LuaError, RustFn,
+};
+
+use super::auxlib::{
+    arg_error, check_integer, check_lstring, check_type, get_metafield, opt_integer, type_error,
+};
+
+// operations that an object must define to mimic a table
+const TAB_R: u8 = 1; // read
+const TAB_W: u8 = 2; // write
+const TAB_L: u8 = 4; // length
+const TAB_RW: u8 = TAB_R | TAB_W;
+
+/// 数组不超过这个长度时，总是取中点作为划分的枢轴
+const RANLIMIT: u64 = 100;
+
+/// 创建 `table` 表，把表库函数注册到其中。
+pub fn open_table(ls: &mut dyn LuaAPI) -> Result<(), LuaError> {
+    let funcs: [(&str, RustFn); 7] = [
+        ("concat", tab_concat),
+        ("insert", tab_insert),
+        ("move", tab_move),
+        ("pack", tab_pack),
+        ("remove", tab_remove),
+        ("sort", tab_sort),
+        ("unpack", tab_unpack),
+    ];
+    ls.create_table(0, funcs.len());
+    for (name, f) in funcs {
+        ls.push_rust_function(f);
+        ls.set_field(-2, name)?;
+    }
+    ls.set_global("table")
+}
+
+/// 检查参数 `arg` 是表，或者它的元表提供了 `what` 要求的元方法。
+fn check_tab(ls: &mut dyn LuaAPI, arg: isize, what: u8, fname: &str) -> Result<(), LuaError> {
+    if ls.type_id(arg) == Type::Table as i8 {
+        return Ok(());
+    }
+    let events = [(TAB_R, "__index"), (TAB_W, "__newindex"), (TAB_L, "__len")];
+    let mut ok = ls.get_metatable(arg);
+    if ok {
+        ls.pop(1);
+        for (bit, event) in events {
+            if what & bit != 0 {
+                if get_metafield(ls, arg, event) == Type::Nil as i8 {
+                    ok = false;
+                    break;
+                }
+                ls.pop(1);
+            }
+        }
+    }
+    if ok {
+        Ok(())
+    } else {
+        Err(type_error(ls, arg, fname, "table"))
+    }
+}
+
+/// 参数 `arg` 的长度，会调用 `__len` 元方法。
+fn len_of(ls: &mut dyn LuaAPI, arg: isize) -> Result<i64, LuaError> {
+    ls.len(arg)?;
+    let n = ls.to_integerx(-1);
+    ls.pop(1);
+    n.ok_or_else(|| LuaError::runtime("object length is not an integer"))
+}
+
+/// 检查参数 `arg` 可以按 `what` 使用，并返回它的长度。
+fn aux_getn(ls: &mut dyn LuaAPI, arg: isize, what: u8, fname: &str) -> Result<i64, LuaError> {
+    check_tab(ls, arg, what | TAB_L, fname)?;
+    len_of(ls, arg)
+}
+
+// table.insert (list, [pos,] value)
+fn tab_insert(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let e = aux_getn(ls, 1, TAB_RW, "insert")?.wrapping_add(1); // first empty element
+    let pos = match ls.get_top() {
+        2 => e, // insert new element at the end
+        3 => {
+            let pos = check_integer(ls, 2, "insert")?;
+            // check whether 'pos' is in [1, e]
+            if (pos as u64).wrapping_sub(1) >= e as u64 {
+                return Err(arg_error(2, "insert", "position out of bounds"));
+            }
+            if ls.array_insert(1, pos) {
+                return Ok(0);
+            }
+            // move up elements
+            for i in (pos + 1..=e).rev() {
+                ls.get_i(1, i - 1)?;
+                ls.set_i(1, i)?; // t[i] = t[i - 1]
+            }
+            pos
+        }
+        _ => return Err(LuaError::runtime("wrong number of arguments to 'insert'")),
+    };
+    if !ls.array_insert(1, pos) {
+        ls.set_i(1, pos)?; // t[pos] = v
+    }
+    Ok(0)
+}
+
+// table.remove (list [, pos])
+fn tab_remove(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let size = aux_getn(ls, 1, TAB_RW, "remove")?;
+    let mut pos = opt_integer(ls, 2, size, "remove")?;
+    // validate 'pos' if given; check whether 'pos' is in [1, size + 1]
+    if pos != size && (pos as u64).wrapping_sub(1) > size as u64 {
+        return Err(arg_error(2, "remove", "position out of bounds"));
+    }
+    if ls.array_remove(1, pos) {
+        return Ok(1);
+    }
+    ls.get_i(1, pos)?; // result = t[pos]
+    while pos < size {
+        ls.get_i(1, pos + 1)?;
+        ls.set_i(1, pos)?; // t[pos] = t[pos + 1]
+        pos += 1;
+    }
+    ls.push_nil();
+    ls.set_i(1, pos)?; // remove entry t[pos]
+    Ok(1)
+}
+
+// table.move (a1, f, e, t [,a2])
+fn tab_move(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let f = check_integer(ls, 2, "move")?;
+    let e = check_integer(ls, 3, "move")?;
+    let t = check_integer(ls, 4, "move")?;
+    let tt = if !ls.is_none_or_nil(5) { 5 } else { 1 }; // destination table
+    check_tab(ls, 1, TAB_R, "move")?;
+    check_tab(ls, tt, TAB_W, "move")?;
+    if e >= f {
+        // otherwise, nothing to move
+        if !(f > 0 || e < i64::MAX + f) {
+            return Err(arg_error(3, "move", "too many elements to move"));
+        }
+        let n = e - f + 1; // number of elements to move
+        if t > i64::MAX - n + 1 {
+            return Err(arg_error(4, "move", "destination wrap around"));
+        }
+        if t > e || t <= f || (tt != 1 && !ls.compare(1, tt, LUA_OPEQ)?) {
+            for i in 0..n {
+                ls.get_i(1, f + i)?;
+                ls.set_i(tt, t + i)?;
+            }
+        } else {
+            for i in (0..n).rev() {
+                ls.get_i(1, f + i)?;
+                ls.set_i(tt, t + i)?;
+            }
+        }
+    }
+    ls.push_value(tt); // return destination table
+    Ok(1)
+}
+
+/// 把 `t[i]` 追加到 `buf`，它不是字符串或数字时返回错误。
+fn add_field(ls: &mut dyn LuaAPI, buf: &mut Vec<u8>, i: i64) -> Result<(), LuaError> {
+    ls.get_i(1, i)?;
+    if !ls.is_string(-1) {
+        return Err(LuaError::runtime(format!(
+            "invalid value (at index {}) in table for 'concat'",
+            i
+        )));
+    }
+    buf.extend_from_slice(&ls.to_bytesx(-1).unwrap());
+    ls.pop(1);
+    Ok(())
+}
+
+// table.concat (list [, sep [, i [, j]]])
+fn tab_concat(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let mut last = aux_getn(ls, 1, TAB_R, "concat")?;
+    let sep = if ls.is_none_or_nil(2) {
+        Vec::new()
+    } else {
+        check_lstring(ls, 2, "concat")?
+    };
+    let mut i = opt_integer(ls, 3, 1, "concat")?;
+    last = opt_integer(ls, 4, last, "concat")?;
+    let mut buf = Vec::new();
+    while i < last {
+        add_field(ls, &mut buf, i)?;
+        buf.extend_from_slice(&sep);
+        i += 1;
+    }
+    if i == last {
+        // add last value (if interval was not empty)
+        add_field(ls, &mut buf, i)?;
+    }
+    ls.push_bytes(buf);
+    Ok(1)
+}
+
+// table.pack (···)
+fn tab_pack(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let n = ls.get_top(); // number of elements to pack
+    ls.create_table(n as usize, 1); // create result table
+    ls.insert(1); // put it at index 1
+    for i in (1..=n).rev() {
+        // assign elements
+        ls.set_i(1, i as i64)?;
+    }
+    ls.push_integer(n as i64);
+    ls.set_field(1, "n")?; // t.n = number of elements
+    Ok(1) // return table
+}
+
+// table.unpack (list [, i [, j]])
+fn tab_unpack(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let mut i = opt_integer(ls, 2, 1, "unpack")?;
+    let e = if ls.is_none_or_nil(3) {
+        len_of(ls, 1)?
+    } else {
+        check_integer(ls, 3, "unpack")?
+    };
+    if i > e {
+        return Ok(0); // empty range
+    }
+    let n = (e as u64).wrapping_sub(i as u64); // number of elements minus 1 (avoid overflows)
+    if n >= i32::MAX as u64 || !ls.check_stack(n as usize + 1) {
+        return Err(LuaError::runtime("too many results to unpack"));
+    }
+    while i < e {
+        // push t[i..e - 1] (to avoid overflows)
+        ls.get_i(1, i)?;
+        i += 1;
+    }
+    ls.get_i(1, e)?; // push last element
+    Ok(n as usize + 1)
+}
+
+/*
+ * Quicksort
+ * (based on 'Algorithms in MODULA-3', Robert Sedgewick;
+ *  Addison-Wesley, 1993.)
+ */
+
+/// 产生一个随机数，用来在划分不均衡时随机选择枢轴
+fn randomize_pivot() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_secs().wrapping_add(now.subsec_nanos() as u64)
+}
+
+/// 把栈顶的两个值依次赋给 `t[i]` 和 `t[j]`，并弹出它们。
+fn set2(ls: &mut dyn LuaAPI, i: u64, j: u64) -> Result<(), LuaError> {
+    ls.set_i(1, i as i64)?;
+    ls.set_i(1, j as i64)
+}
+
+/// 比较栈上 `a` 和 `b` 处的值：没有比较函数时用 `<`，否则调用参数 2 处的比较函数。
+fn sort_comp(ls: &mut dyn LuaAPI, a: isize, b: isize) -> Result<bool, LuaError> {
+    if ls.is_nil(2) {
+        // no function?
+        ls.compare(a, b, LUA_OPLT) // a < b
+    } else {
+        ls.push_value(2); // push function
+        ls.push_value(a - 1); // -1 to compensate function
+        ls.push_value(b - 2); // -2 to compensate function and 'a'
+        ls.call(2, 1)?; // call function
+        let res = ls.to_boolean(-1); // get result
+        ls.pop(1); // pop result
+        Ok(res)
+    }
+}
+
+fn invalid_order() -> LuaError {
+    LuaError::runtime("invalid order function for sorting")
+}
+
+/// 以栈顶的值 P（即 `a[up - 1]`）为枢轴划分 `a[lo..up]`，返回枢轴最终的位置。
+/// 划分后 `a[lo..p - 1] <= a[p] == P <= a[p + 1..up]`。
+fn partition(ls: &mut dyn LuaAPI, lo: u64, up: u64) -> Result<u64, LuaError> {
+    let mut i = lo; // will be incremented before first use
+    let mut j = up - 1; // will be decremented before first use
+                        // loop invariant: a[lo .. i] <= P <= a[j .. up], a[up - 1] == P
+    loop {
+        // next loop: repeat ++i while a[i] < P
+        loop {
+            i += 1;
+            ls.get_i(1, i as i64)?;
+            if !sort_comp(ls, -1, -2)? {
+                break;
+            }
+            if i == up - 1 {
+                // a[i] < P  but a[up - 1] == P  ??
+                return Err(invalid_order());
+            }
+            ls.pop(1); // remove a[i]
+        }
+        // after the loop, a[i] >= P and a[lo .. i - 1] < P
+        // next loop: repeat --j while P < a[j]
+        loop {
+            j -= 1;
+            ls.get_i(1, j as i64)?;
+            if !sort_comp(ls, -3, -1)? {
+                break;
+            }
+            if j < i {
+                // j < i  but  a[j] > P ??
+                return Err(invalid_order());
+            }
+            ls.pop(1); // remove a[j]
+        }
+        // after the loop, a[j] <= P and a[j + 1 .. up] >= P
+        if j < i {
+            // no elements to be exchanged?
+            ls.pop(1); // pop a[j]
+                       // swap pivot (a[up - 1]) with a[i] to satisfy pred.
+            set2(ls, up - 1, i)?;
+            return Ok(i);
+        }
+        // otherwise, swap a[i] - a[j] to restore invariant and repeat
+        set2(ls, i, j)?;
+    }
+}
+
+/// 在 `[lo, up]` 的中间一半内选择一个枢轴，`rnd` 用于随机化。
+fn choose_pivot(lo: u64, up: u64, rnd: u64) -> u64 {
+    let r4 = (up - lo) / 4; // range/4
+    rnd % (r4 * 2) + (lo + r4)
+}
+
+/// 对 `a[lo..up]` 做快速排序。
+fn aux_sort(ls: &mut dyn LuaAPI, mut lo: u64, mut up: u64, mut rnd: u64) -> Result<(), LuaError> {
+    while lo < up {
+        // loop for tail recursion
+        // sort elements 'lo', 'p', and 'up'
+        ls.get_i(1, lo as i64)?;
+        ls.get_i(1, up as i64)?;
+        if sort_comp(ls, -1, -2)? {
+            // a[up] < a[lo]?
+            set2(ls, lo, up)?; // swap a[lo] - a[up]
+        } else {
+            ls.pop(2); // remove both values
+        }
+        if up - lo == 1 {
+            // only 2 elements?
+            break; // already sorted
+        }
+        let p = if up - lo < RANLIMIT || rnd == 0 {
+            // small interval or no randomize?
+            (lo + up) / 2 // use middle point
+        } else {
+            // for larger intervals, it is better to use a random point
+            choose_pivot(lo, up, rnd)
+        };
+        ls.get_i(1, p as i64)?;
+        ls.get_i(1, lo as i64)?;
+        if sort_comp(ls, -2, -1)? {
+            // a[p] < a[lo]?
+            set2(ls, p, lo)?; // swap a[p] - a[lo]
+        } else {
+            ls.pop(1); // remove second element
+            ls.get_i(1, up as i64)?;
+            if sort_comp(ls, -1, -2)? {
+                // a[up] < a[p]?
+                set2(ls, p, up)?; // swap up - p
+            } else {
+                ls.pop(2); // clean stack
+            }
+        }
+        if up - lo == 2 {
+            // only 3 elements?
+            break; // already sorted
+        }
+        ls.get_i(1, p as i64)?; // get median (Pivot)
+        ls.push_value(-1); // push Pivot
+        ls.get_i(1, (up - 1) as i64)?; // push a[up - 1]
+        set2(ls, p, up - 1)?; // a[p] = a[up - 1]; a[up - 1] = a[p]
+        let p = partition(ls, lo, up)?;
+        // a[lo .. p - 1] <= a[p] == P <= a[p + 1 .. up]
+        let n = if p - lo < up - p {
+            // lower interval is shorter?
+            aux_sort(ls, lo, p - 1, rnd)?; // call recursively for lower interval
+            let n = p - lo; // size of smaller interval
+            lo = p + 1; // tail call for [p + 1 .. up] (upper interval)
+            n
+        } else {
+            aux_sort(ls, p + 1, up, rnd)?; // call recursively for upper interval
+            let n = up - p; // size of smaller interval
+            up = p - 1; // tail call for [lo .. p - 1]  (lower interval)
+            n
+        };
+        if (up - lo) / 128 > n {
+            // partition too imbalanced?
+            rnd = randomize_pivot(); // try a new randomization
+        }
+    }
+    Ok(())
+}
+
+// table.sort (list [, comp])
+fn tab_sort(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
+    let n = aux_getn(ls, 1, TAB_RW, "sort")?;
+    if n > 1 {
+        // non-trivial interval?
+        if n >= i32::MAX as i64 {
+            return Err(arg_error(1, "sort", "array too big"));
+        }
+        if !ls.is_none_or_nil(2) {
+            // is there a 2nd argument?
+            check_type(ls, 2, Type::Function, "sort")?; // must be a function
+        } else if ls.array_sort(1) {
+            return Ok(0);
+        }
+        ls.set_top(2); // make sure there are two arguments
+        aux_sort(ls, 1, n as u64, 0)?;
+    }
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stdlib::test_util::{run, run_err};
+
+    #[test]
+    fn test_insert_remove() {
+        let src = r#"
+            local t = {1, 2, 3}
+            table.insert(t, 4)
+            table.insert(t, 1, 0)
+            table.insert(t, 3, 1.5)
+            local r1 = table.remove(t)
+            local r2 = table.remove(t, 1)
+            local e = {}
+            local r3 = table.remove(e)
+            local r4 = table.remove(e, 0)
+            return table.concat(t, ","), #t, r1, r2, r3, r4
+        "#;
+        assert_eq!(run(src), ["1,1.5,2,3", "4", "4", "0", "nil", "nil"]);
+        assert!(run_err("table.insert({}, 3, 1)")
+            .contains("bad argument #2 to 'insert' (position out of bounds)"));
+        assert!(
+            run_err("table.insert({}, 1, 2, 3)").contains("wrong number of arguments to 'insert'")
+        );
+        assert!(run_err("table.remove({1}, 3)")
+            .contains("bad argument #2 to 'remove' (position out of bounds)"));
+        assert!(run_err("table.insert(1, 2)")
+            .contains("bad argument #1 to 'insert' (table expected, got number)"));
+    }
+
+    #[test]
+    fn test_metamethods() {
+        // a proxy that stores its elements in another table
+        let src = r#"
+            local store, log = {}, {}
+            local proxy = setmetatable({}, {
+                __index = function(_, k) return store[k] end,
+                __newindex = function(_, k, v) log[#log + 1] = k; store[k] = v end,
+                __len = function() return #store end,
+            })
+            table.insert(proxy, "a")
+            table.insert(proxy, "b")
+            table.insert(proxy, 1, "c")
+            local r = table.remove(proxy, 1)
+            table.move({"x", "y"}, 1, 2, 3, proxy)
+            return table.concat(store, ","), r, table.concat(log, ","),
+                table.concat(proxy, "-"), table.unpack(proxy, 2, 3)
+        "#;
+        assert_eq!(
+            run(src),
+            ["a,b,x,y", "c", "1,2,3,2,1,1,2,3,3,4", "a-b-x-y", "b", "x"]
+        );
+    }
+
+    #[test]
+    fn test_move() {
+        let src = r#"
+            local a = {1, 2, 3, 4, 5}
+            table.move(a, 1, 3, 2)
+            local b = {1, 2, 3, 4, 5}
+            table.move(b, 2, 5, 1)
+            local c = table.move({7, 8}, 1, 2, 1, {})
+            return table.concat(a, ","), table.concat(b, ","), table.concat(c, ","),
+                table.move(a, 2, 1, 1) == a
+        "#;
+        assert_eq!(run(src), ["1,1,2,3,5", "2,3,4,5,5", "7,8", "true"]);
+        assert!(run_err("table.move({}, -1, 9223372036854775807, 1)")
+            .contains("bad argument #3 to 'move' (too many elements to move)"));
+        assert!(run_err("table.move({}, 1, 10, 9223372036854775800)")
+            .contains("bad argument #4 to 'move' (destination wrap around)"));
+    }
+
+    #[test]
+    fn test_concat_pack_unpack() {
+        let src = r#"
+            local p = table.pack(1, nil, 3)
+            return table.concat({1, 2.5, "x"}), table.concat({1, 2, 3}, ", ", 2),
+                table.concat({}, ","), table.concat({1, 2, 3}, "", 3, 2),
+                p.n, p[3], select('#', table.unpack({1, 2, 3})),
+                select('#', table.unpack({}, 1, 3)), table.unpack({1, 2, 3}, -1, 1)
+        "#;
+        assert_eq!(
+            run(src),
+            ["12.5x", "2, 3", "", "", "3", "3", "3", "3", "nil", "nil", "1"]
+        );
+        assert!(run_err("table.concat({1, {}, 3})")
+            .contains("invalid value (at index 2) in table for 'concat'"));
+        assert!(run_err("table.unpack({}, 1, 1e8)").contains("too many results to unpack"));
+    }
+
+    #[test]
+    fn test_sort() {
+        let src = r#"
+            local a = {5, 2, 8, 1, 9, 3, 2.5}
+            table.sort(a)
+            local b = {"pear", "apple", "fig"}
+            table.sort(b, function(x, y) return x > y end)
+            local c = {}
+            for i = 1, 500 do c[i] = (i * 7919) % 1009 end
+            table.sort(c, function(x, y) return x < y end)
+            local sorted = true
+            for i = 2, #c do
+                if c[i - 1] > c[i] then sorted = false end
+            end
+            local d = setmetatable({3, 1, 2}, {})
+            table.sort(d)
+            return table.concat(a, ","), table.concat(b, ","), sorted, table.concat(d, ",")
+        "#;
+        assert_eq!(
+            run(src),
+            ["1,2,2.5,3,5,8,9", "pear,fig,apple", "true", "1,2,3"]
+        );
+        assert!(run_err(
+            "local t = {} for i = 1, 100 do t[i] = i end table.sort(t, function() return true end)"
+        )
+        .contains("invalid order function for sorting"));
+        assert!(run_err("table.sort({1, 'x', 2})").contains("attempt to compare"));
+        assert!(run_err("table.sort({1, 2}, 3)")
+            .contains("bad argument #2 to 'sort' (function expected, got number)"));
+    }
+}
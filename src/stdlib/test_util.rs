@@ -0,0 +1,39 @@
+use crate::{
+    api::{
+        consts::{LUA_MULTRET, LUA_OK},
+        LuaAPI,
+    },
+    state::{new_lua_state, LuaState},
+};
+
+use super::open_libs;
+
+/// 创建一个打开了所有标准库的状态
+pub(crate) fn new_state() -> LuaState {
+    let mut ls = new_lua_state();
+    open_libs(&mut ls).unwrap();
+    ls
+}
+
+/// 在 `ls` 中运行代码块，把它的返回值转换为字符串，然后清空栈
+pub(crate) fn run_with(ls: &mut dyn LuaAPI, src: &str) -> Vec<String> {
+    assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+    ls.call(0, LUA_MULTRET).unwrap();
+    let results = (1..=ls.get_top())
+        .map(|i| ls.to_string_meta(i).unwrap())
+        .collect();
+    ls.set_top(0);
+    results
+}
+
+/// 在新的状态中运行代码块，把它的返回值转换为字符串
+pub(crate) fn run(src: &str) -> Vec<String> {
+    run_with(&mut new_state(), src)
+}
+
+/// 在新的状态中运行代码块，返回它抛出的错误
+pub(crate) fn run_err(src: &str) -> String {
+    let mut ls = new_state();
+    assert_eq!(ls.load(src.as_bytes().to_vec(), "=test", "t"), LUA_OK);
+    ls.call(0, 0).unwrap_err().to_string()
+}
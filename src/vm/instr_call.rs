@@ -1,36 +1,46 @@
-use crate::api::LuaVM;
+use crate::api::{consts::LUA_MULTRET, LuaError, LuaVM};
 
-use super::instruction::Instruction;
+use super::{instr_table::push_rk, instruction::Instruction, opcodes::*};
 
 // OP_SELF             A B C               R[A+1] := R[B]; R[A] := R[B][RK(C):string]
-pub fn _self(i: u32, vm: &mut dyn LuaVM) {
+pub fn _self(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
     let (a, b, c) = (i.get_arg_a() + 1, i.get_arg_b() + 1, i.get_arg_c());
 
     vm.copy(b, a + 1);
-    vm.get_rk(c);
-    vm.get_table(b);
+    push_rk(vm, c, i.get_arg_k());
+    vm.get_table(b)?;
     vm.replace(a);
+    Ok(())
 }
 
 // OP_CALL             A B C               R[A], ... ,R[A+C-2] := R[A](R[A+1], ... ,R[A+B-1])
-pub fn call(i: u32, vm: &mut dyn LuaVM) {
+pub fn call(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
     let (a, b, c) = (i.get_arg_a() + 1, i.get_arg_b(), i.get_arg_c());
     let nargs = push_func_and_args(a, b, vm);
-    vm.call(nargs, c - 1);
-    pop_results(a, c, vm);
+    if vm.precall(nargs, c - 1)? {
+        pop_results(a, c, vm);
+    }
+    Ok(())
 }
 
 // OP_TAILCALL         A B C k             return R[A](R[A+1], ... ,R[A+B-1])
-pub fn tail_call(i: u32, vm: &mut dyn LuaVM) {
-    let (a, b, c) = (i.get_arg_a() + 1, i.get_arg_b(), 0);
+pub fn tail_call(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
+    let (a, b) = (i.get_arg_a() + 1, i.get_arg_b());
     let nargs = push_func_and_args(a, b, vm);
-    vm.call(nargs, c - 1);
-    pop_results(a, c, vm);
+    if vm.pretailcall(nargs)? {
+        // the following RETURN returns the results of the Rust function
+        pop_results(a, 0, vm);
+    }
+    Ok(())
 }
 
 // OP_RETURN           A B C k             return R[A], ... ,R[A+B-2]  (see note)
-pub fn _return(i: u32, vm: &mut dyn LuaVM) {
+pub fn _return(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
     let (a, b) = (i.get_arg_a() + 1, i.get_arg_b());
+    if i.get_arg_k() != 0 {
+        // close the upvalues and to-be-closed variables of the function first
+        vm.close(1)?;
+    }
 
     if b == 1 {
         // no return values
@@ -43,6 +53,7 @@ pub fn _return(i: u32, vm: &mut dyn LuaVM) {
     } else {
         fix_stack(a, vm);
     }
+    Ok(())
 }
 
 // OP_RETURN0                              return
@@ -50,6 +61,13 @@ pub fn return0(_i: u32, _vm: &mut dyn LuaVM) {
     // no results
 }
 
+// OP_RETURN1         A                   return R[A]
+pub fn return1(i: u32, vm: &mut dyn LuaVM) {
+    let a = i.get_arg_a() + 1;
+    vm.check_stack(1);
+    vm.push_value(a);
+}
+
 // OP_CLOSURE          A Bx                R[A] := closure(KPROTO[Bx])
 pub fn closure(i: u32, vm: &mut dyn LuaVM) {
     let (a, bx) = (i.get_arg_a() + 1, i.get_arg_bx());
@@ -57,6 +75,16 @@ pub fn closure(i: u32, vm: &mut dyn LuaVM) {
     vm.replace(a);
 }
 
+// OP_TFORCALL         A C                 R[A+4], ... ,R[A+3+C] := R[A](R[A+1], R[A+2]);
+pub fn tfor_call(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
+    let (a, c) = (i.get_arg_a() + 1, i.get_arg_c());
+    push_func_and_args(a, 3, vm);
+    if vm.precall(2, c)? {
+        pop_results(a + 4, c + 1, vm);
+    }
+    Ok(())
+}
+
 // OP_VARARG           A C                 R[A], R[A+1], ..., R[A+C-2] = vararg
 pub fn vararg(i: u32, vm: &mut dyn LuaVM) {
     let (a, c) = (i.get_arg_a() + 1, i.get_arg_c());
@@ -66,6 +94,89 @@ pub fn vararg(i: u32, vm: &mut dyn LuaVM) {
     }
 }
 
+/// 指令 `i` 发起的调用期望的返回值数量，-1 表示全部。
+pub fn call_nresults(i: u32) -> isize {
+    match i.opcode() {
+        OP_CALL => i.get_arg_c() - 1,
+        OP_TFORCALL => i.get_arg_c(),
+        OP_TAILCALL => LUA_MULTRET,
+        _ => 1, // metamethods, see 'finish_op'
+    }
+}
+
+/// 被调用的函数返回后（或者让出的协程继续运行时），完成发起调用的 `CALL`、`TAILCALL` 或 `TFORCALL` 指令：
+/// 按照 `call_nresults` 推入栈顶的返回值被移入寄存器。
+pub fn finish_call(i: u32, vm: &mut dyn LuaVM) {
+    let a = i.get_arg_a() + 1;
+    match i.opcode() {
+        OP_CALL => pop_results(a, i.get_arg_c(), vm),
+        OP_TFORCALL => pop_results(a + 4, i.get_arg_c() + 1, vm),
+        _ => pop_results(a, 0, vm), // OP_TAILCALL
+    }
+}
+
+/// 调用的函数让出、协程继续运行之后，在函数返回时完成指令 `i`，与 C Lua 的 `luaV_finishOp` 相同。
+/// 调用指令交给 `finish_call`，其他指令调用的是元方法，它的结果在栈顶。
+pub fn finish_op(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
+    let a = i.get_arg_a() + 1;
+    match i.opcode() {
+        OP_CALL | OP_TAILCALL | OP_TFORCALL => finish_call(i, vm),
+        OP_MMBIN | OP_MMBINI | OP_MMBINK => {
+            // the arithmetic instruction precedes the MMBIN
+            vm.replace(vm.peek(-2).get_arg_a() + 1);
+        }
+        OP_UNM | OP_BNOT | OP_LEN | OP_GETTABUP | OP_GETTABLE | OP_GETI | OP_GETFIELD | OP_SELF => {
+            vm.replace(a)
+        }
+        OP_EQ | OP_LT | OP_LE | OP_LTI | OP_LEI | OP_GTI | OP_GEI => {
+            let res = vm.to_boolean(-1);
+            vm.pop(3); // the result and both operands
+            if res != (i.get_arg_k() != 0) {
+                vm.add_pc(1);
+            }
+        }
+        OP_CONCAT => {
+            // the result replaced the last two values, concatenate the rest
+            let n = vm.get_top() - vm.register_count() as isize;
+            vm.concat(n)?;
+            vm.replace(a);
+        }
+        OP_SETTABUP | OP_SETTABLE | OP_SETI | OP_SETFIELD => vm.pop(1),
+        _ => unreachable!("cannot finish {}", i.opname()),
+    }
+    Ok(())
+}
+
+/// 指令 `i` 调用的元方法让出之后，能否由 `finish_op` 完成这条指令。
+pub fn can_finish(i: u32) -> bool {
+    matches!(
+        i.opcode(),
+        OP_MMBIN
+            | OP_MMBINI
+            | OP_MMBINK
+            | OP_UNM
+            | OP_BNOT
+            | OP_LEN
+            | OP_GETTABUP
+            | OP_GETTABLE
+            | OP_GETI
+            | OP_GETFIELD
+            | OP_SELF
+            | OP_EQ
+            | OP_LT
+            | OP_LE
+            | OP_LTI
+            | OP_LEI
+            | OP_GTI
+            | OP_GEI
+            | OP_CONCAT
+            | OP_SETTABUP
+            | OP_SETTABLE
+            | OP_SETI
+            | OP_SETFIELD
+    )
+}
+
 fn push_func_and_args(a: isize, b: isize, vm: &mut dyn LuaVM) -> usize {
     if b >= 1 {
         vm.check_stack(b as usize);
@@ -87,7 +198,9 @@ fn fix_stack(a: isize, vm: &mut dyn LuaVM) {
     for i in a..x {
         vm.push_value(i);
     }
-    vm.rotate(vm.register_count() as isize + 1, x - a);
+    if x > a {
+        vm.rotate(vm.register_count() as isize + 1, x - a);
+    }
 }
 
 fn pop_results(a: isize, c: isize, vm: &mut dyn LuaVM) {
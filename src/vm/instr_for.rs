@@ -1,4 +1,6 @@
-use crate::api::LuaVM;
+use crate::api::{LuaError, LuaVM};
+
+use crate::state::math::number_to_integer;
 
 use super::instruction::Instruction;
 
@@ -18,26 +20,63 @@ pub fn for_loop(i: u32, vm: &mut dyn LuaVM) {
             vm.replace(a);
             vm.push_integer(idx);
             vm.replace(a + 3);
-            vm.add_pc(-bx as isize);
+            vm.add_pc(-bx);
         }
     } else if is_number_for_loop(vm, a) {
-        vm.add_pc(-bx as isize);
+        let step = vm.to_number(a + 2);
+        let limit = vm.to_number(a + 1);
+        let idx = vm.to_number(a) + step;
+        let go_on = if step > 0f64 {
+            idx <= limit
+        } else {
+            limit <= idx
+        };
+        if go_on {
+            vm.push_number(idx);
+            vm.replace(a);
+            vm.push_number(idx);
+            vm.replace(a + 3);
+            vm.add_pc(-bx);
+        }
     }
 }
+// OP_TFORPREP         A Bx                create upvalue for R[A + 3]; pc+=Bx
+pub fn tfor_prep(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
+    let (a, bx) = (i.get_arg_a() + 1, i.get_arg_bx());
+    vm.to_close(a + 3)?;
+    vm.add_pc(bx);
+    Ok(())
+}
+
+// OP_TFORLOOP         A Bx                if R[A+4] ~= nil then { R[A+2]=R[A+4]; pc -= Bx }
+pub fn tfor_loop(i: u32, vm: &mut dyn LuaVM) {
+    let (a, bx) = (i.get_arg_a() + 1, i.get_arg_bx());
+    if !vm.is_nil(a + 4) {
+        vm.copy(a + 4, a + 2);
+        vm.add_pc(-bx);
+    }
+}
+
 // OP_FORPREP          A Bx                <check values and prepare counters>; if not to run then pc+=Bx+1;
-pub fn for_prep(i: u32, vm: &mut dyn LuaVM) {
+pub fn for_prep(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
     let (a, bx) = (i.get_arg_a() + 1, i.get_arg_bx());
 
-    if vm.is_integer(a) && vm.is_integer(a + 1) {
+    if vm.is_integer(a) && vm.is_integer(a + 2) {
         let init = vm.to_integer(a);
-        let limit = vm.to_integer(a + 1);
         let step = vm.to_integer(a + 2);
 
         if step == 0 {
-            panic!("for statement with step=0");
+            return Err(vm.runtime_error("'for' step is zero"));
         }
         vm.push_integer(init);
         vm.replace(a + 3);
+        let limit = match for_limit(vm, a + 1, step)? {
+            Some(limit) => limit,
+            None => {
+                vm.add_pc(bx + 1);
+                return Ok(());
+            }
+        };
         if 0 < step && limit < init || step < 0 && init < limit {
             vm.add_pc(bx + 1);
         } else {
@@ -55,14 +94,11 @@ pub fn for_prep(i: u32, vm: &mut dyn LuaVM) {
             vm.replace(a + 1);
         }
     } else {
-        if !is_number_for_loop(vm, a) {
-            panic!("for statement with non-numeric limit");
-        }
-        let init = vm.to_number(a);
-        let limit = vm.to_number(a + 1);
-        let step = vm.to_number(a + 2);
+        let limit = for_number(vm, a + 1, "limit")?;
+        let step = for_number(vm, a + 2, "step")?;
+        let init = for_number(vm, a, "initial value")?;
         if step == 0f64 {
-            panic!("for statement with step=0");
+            return Err(vm.runtime_error("'for' step is zero"));
         }
         if 0f64 < step && limit < init || step < 0f64 && init < limit {
             vm.add_pc(bx + 1);
@@ -77,6 +113,41 @@ pub fn for_prep(i: u32, vm: &mut dyn LuaVM) {
             vm.replace(a + 3);
         }
     }
+    Ok(())
+}
+
+/// 把循环上限转换为整数：浮点上限按步长方向取整，超出整数范围时截断；
+/// 返回 `None` 表示循环一次都不会执行
+fn for_limit(vm: &mut dyn LuaVM, idx: isize, step: i64) -> Result<Option<i64>, LuaError> {
+    if vm.is_integer(idx) {
+        return Ok(Some(vm.to_integer(idx)));
+    }
+    let limit = for_number(vm, idx, "limit")?;
+    let limit = if step < 0 {
+        limit.ceil()
+    } else {
+        limit.floor()
+    };
+    Ok(if let Some(limit) = number_to_integer(limit) {
+        Some(limit)
+    } else if limit > 0f64 {
+        // limit is a huge positive number (or the loop never starts)
+        (step > 0).then_some(i64::MAX)
+    } else {
+        (step < 0).then_some(i64::MIN)
+    })
+}
+
+/// 把循环的控制值转换为浮点数，`what` 用于错误信息
+fn for_number(vm: &mut dyn LuaVM, idx: isize, what: &str) -> Result<f64, LuaError> {
+    match vm.to_numberx(idx) {
+        Some(n) => Ok(n),
+        None => {
+            let tname = vm.type_name(vm.type_id(idx));
+            let msg = format!("bad 'for' {} (number expected, got {})", what, tname);
+            Err(vm.runtime_error(&msg))
+        }
+    }
 }
 
 fn is_number_for_loop(vm: &mut dyn LuaVM, a: isize) -> bool {
@@ -99,7 +170,7 @@ mod tests {
         assert!(vm.stack().get(2).to_integer().unwrap() == 10);
         assert!(vm.stack().get(3).to_integer().unwrap() == 2);
         assert!(vm.stack().get(4).to_integer().unwrap() == 0);
-        for_prep(0b00000000000000000_00000000_1001010, &mut vm);
+        for_prep(0b00000000000000000_00000000_1001010, &mut vm).unwrap();
         assert!(vm.stack().get(1).to_integer().unwrap() == 0);
         assert!(vm.stack().get(2).to_integer().unwrap() == 5);
         assert!(vm.stack().get(3).to_integer().unwrap() == 2);
@@ -118,7 +189,7 @@ mod tests {
         assert!(vm.stack().get(2).to_number().unwrap() == 10f64);
         assert!(vm.stack().get(3).to_number().unwrap() == 1f64);
         assert!(vm.stack().get(4).to_number().unwrap() == 0f64);
-        for_prep(0b00000000000000000_00000000_1001010, &mut vm);
+        for_prep(0b00000000000000000_00000000_1001010, &mut vm).unwrap();
         assert!(vm.pc() == 0);
         assert!(vm.stack().get(1).to_number().unwrap() == 1.1f64);
         assert!(vm.stack().get(2).to_number().unwrap() == 10f64);
@@ -139,7 +210,7 @@ mod tests {
         assert!(vm.stack().get(2).to_integer().unwrap() == 10);
         assert!(vm.stack().get(3).to_integer().unwrap() == 2);
         assert!(vm.stack().get(4).to_integer().unwrap() == 0);
-        for_prep(0b00000000000000000_00000000_1001010, &mut vm);
+        for_prep(0b00000000000000000_00000000_1001010, &mut vm).unwrap();
         assert!(vm.stack().get(1).to_integer().unwrap() == 0);
         assert!(vm.stack().get(2).to_integer().unwrap() == 5);
         assert!(vm.stack().get(3).to_integer().unwrap() == 2);
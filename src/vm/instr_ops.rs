@@ -1,6 +1,9 @@
-use crate::api::{
-    op::{ArithOp, CmpOp},
-    LuaVM,
+use crate::{
+    api::{
+        op::{ArithOp, CmpOp},
+        LuaError, LuaVM,
+    },
+    compiler::func_state::TM_ADD,
 };
 
 use super::instruction::Instruction;
@@ -11,18 +14,16 @@ fn arith(i: u32, vm: &mut dyn LuaVM, op: u8) {
 
     vm.get_rk(b);
     vm.get_rk(c);
-    vm.arith(op);
-    vm.replace(a);
+    try_arith(vm, a, op);
 }
 
 //                     R[A] := R[B] + sC
 fn arith_i(i: u32, vm: &mut dyn LuaVM, op: u8) {
-    let (a, b, c) = (i.get_arg_a() + 1, i.get_arg_b(), i.get_arg_c());
+    let (a, b, sc) = (i.get_arg_a() + 1, i.get_arg_b(), i.get_arg_sc());
 
     vm.get_rk(b);
-    vm.push_integer(c as i64);
-    vm.arith(op);
-    vm.replace(a);
+    vm.push_integer(sc as i64);
+    try_arith(vm, a, op);
 }
 
 //                     R[A] := R[B] + K[C]:number
@@ -31,49 +32,72 @@ fn arith_k(i: u32, vm: &mut dyn LuaVM, op: u8) {
 
     vm.get_rk(b);
     vm.get_const(c);
-    vm.arith(op);
+    try_arith(vm, a, op);
+}
+
+/// 不调用元方法对栈顶的两个操作数执行运算。成功时把结果存入 R[A] 并跳过随后的 `MMBIN` 类指令，
+/// 失败时丢弃操作数，由 `MMBIN` 类指令调用元方法。
+fn try_arith(vm: &mut dyn LuaVM, a: isize, op: u8) {
+    if vm.raw_arith(op) {
+        vm.replace(a);
+        vm.add_pc(1);
+    } else {
+        vm.pop(2);
+    }
+}
+
+/// 以栈顶的两个值为操作数调用事件 `event` 的元方法，结果存入上一条算术指令的 R[A]。
+fn mm_arith(vm: &mut dyn LuaVM, event: isize) -> Result<(), LuaError> {
+    // the arithmetic instruction precedes the MMBIN that is being executed
+    let a = vm.peek(-2).get_arg_a() + 1;
+    vm.arith((event - TM_ADD as isize) as u8)?;
     vm.replace(a);
+    Ok(())
 }
 
 //                    R(A) := op R(B)
-fn unary_arith(i: u32, vm: &mut dyn LuaVM, op: u8) {
+fn unary_arith(i: u32, vm: &mut dyn LuaVM, op: u8) -> Result<(), LuaError> {
     let (a, b) = (i.get_arg_a() + 1, i.get_arg_b() + 1);
     vm.push_value(b);
-    vm.arith(op);
+    vm.arith(op)?;
     vm.replace(a);
+    Ok(())
 }
 
 //                    if ((R[A] op R[B]) ~= k) then pc++
-fn compare(i: u32, vm: &mut dyn LuaVM, op: u8) {
+fn compare(i: u32, vm: &mut dyn LuaVM, op: u8) -> Result<(), LuaError> {
     let (a, b, k) = (i.get_arg_a(), i.get_arg_b(), i.get_arg_k());
     vm.get_rk(a);
     vm.get_rk(b);
-    if vm.compare(-2, -1, op) != (k != 0) {
+    if vm.compare(-2, -1, op)? != (k != 0) {
         vm.add_pc(1);
     }
     vm.pop(2);
+    Ok(())
 }
 
 //                    if ((R[A] op sB) ~= k) then pc++
-fn compare_i(i: u32, vm: &mut dyn LuaVM, op: u8) {
+fn compare_i(i: u32, vm: &mut dyn LuaVM, op: u8) -> Result<(), LuaError> {
     let (a, sb, k) = (i.get_arg_a(), i.get_arg_sb(), i.get_arg_k());
     vm.get_rk(a);
     vm.push_integer(sb as i64);
-    if vm.compare(-2, -1, op) != (k != 0) {
+    if vm.compare(-2, -1, op)? != (k != 0) {
         vm.add_pc(1);
     }
     vm.pop(2);
+    Ok(())
 }
 
 //                    if ((R[A] op K[B]) ~= k) then pc++
-fn compare_k(i: u32, vm: &mut dyn LuaVM, op: u8) {
-    let (a, b, k) = (i.get_arg_a() + 1, i.get_arg_b(), i.get_arg_k());
+fn compare_k(i: u32, vm: &mut dyn LuaVM, op: u8) -> Result<(), LuaError> {
+    let (a, b, k) = (i.get_arg_a(), i.get_arg_b(), i.get_arg_k());
     vm.get_rk(a);
     vm.get_const(b);
-    if vm.compare(-2, -1, op) != (k != 0) {
+    if vm.compare(-2, -1, op)? != (k != 0) {
         vm.add_pc(1);
     }
     vm.pop(2);
+    Ok(())
 }
 
 // OP_ADDI             A B sC              R[A] := R[B] + sC
@@ -131,12 +155,16 @@ pub fn bxor_k(i: u32, vm: &mut dyn LuaVM) {
     arith_k(i, vm, ArithOp::BXOR as u8);
 }
 
-// OP_SHLI             A B C               R[A] := sC << R[B]
+// OP_SHLI             A B sC              R[A] := sC << R[B]
 pub fn shl_i(i: u32, vm: &mut dyn LuaVM) {
-    arith_i(i, vm, ArithOp::SHL as u8);
+    let (a, b, sc) = (i.get_arg_a() + 1, i.get_arg_b(), i.get_arg_sc());
+
+    vm.push_integer(sc as i64);
+    vm.get_rk(b);
+    try_arith(vm, a, ArithOp::SHL as u8);
 }
 
-// OP_SHRI             A B C               R[A] := R[B] >> sC
+// OP_SHRI             A B sC              R[A] := R[B] >> sC
 pub fn shr_i(i: u32, vm: &mut dyn LuaVM) {
     arith_i(i, vm, ArithOp::SHR as u8);
 }
@@ -201,14 +229,60 @@ pub fn shr(i: u32, vm: &mut dyn LuaVM) {
     arith(i, vm, ArithOp::SHR as u8);
 }
 
+// OP_MMBIN            A B C               call C metamethod over R[A] and R[B]
+pub fn mm_bin(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
+    let (a, b, c) = (i.get_arg_a() + 1, i.get_arg_b() + 1, i.get_arg_c());
+    vm.push_value(a);
+    vm.push_value(b);
+    mm_arith(vm, c)
+}
+
+// OP_MMBINI           A sB C k            call C metamethod over R[A] and sB
+pub fn mm_bin_i(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
+    let (a, sb, c, k) = (
+        i.get_arg_a() + 1,
+        i.get_arg_sb(),
+        i.get_arg_c(),
+        i.get_arg_k(),
+    );
+    if k != 0 {
+        // the immediate was the first operand
+        vm.push_integer(sb as i64);
+        vm.push_value(a);
+    } else {
+        vm.push_value(a);
+        vm.push_integer(sb as i64);
+    }
+    mm_arith(vm, c)
+}
+
+// OP_MMBINK           A B C k             call C metamethod over R[A] and K[B]
+pub fn mm_bin_k(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
+    let (a, b, c, k) = (
+        i.get_arg_a() + 1,
+        i.get_arg_b(),
+        i.get_arg_c(),
+        i.get_arg_k(),
+    );
+    if k != 0 {
+        // the constant was the first operand
+        vm.get_const(b);
+        vm.push_value(a);
+    } else {
+        vm.push_value(a);
+        vm.get_const(b);
+    }
+    mm_arith(vm, c)
+}
+
 // OP_UNM              A B                 R[A] := -R[B]
-pub fn unm(i: u32, vm: &mut dyn LuaVM) {
-    unary_arith(i, vm, ArithOp::UNM as u8);
+pub fn unm(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
+    unary_arith(i, vm, ArithOp::UNM as u8)
 }
 
 // OP_BNOT             A B                 R[A] := ~R[B]
-pub fn bnot(i: u32, vm: &mut dyn LuaVM) {
-    unary_arith(i, vm, ArithOp::BNOT as u8);
+pub fn bnot(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
+    unary_arith(i, vm, ArithOp::BNOT as u8)
 }
 
 // OP_NOT              A B                 R[A] := not R[B]
@@ -219,66 +293,68 @@ pub fn not(i: u32, vm: &mut dyn LuaVM) {
 }
 
 // OP_LEN              A B                 R[A] := #R[B] (length operator)
-pub fn len(i: u32, vm: &mut dyn LuaVM) {
+pub fn len(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
     let (a, b) = (i.get_arg_a() + 1, i.get_arg_b() + 1);
-    vm.len(b);
+    vm.len(b)?;
     vm.replace(a);
+    Ok(())
 }
 
 // OP_CONCAT           A B                 R[A] := R[A].. ... ..R[A + B - 1]
-pub fn concat(i: u32, vm: &mut dyn LuaVM) {
+pub fn concat(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
     let (a, b) = (i.get_arg_a() + 1, i.get_arg_b());
     vm.check_stack(b as usize);
     for i in (a)..(a + b) {
         vm.push_value(i);
     }
-    vm.concat(b);
+    vm.concat(b)?;
     vm.replace(a);
+    Ok(())
 }
 
 // OP_EQ               A B k               if ((R[A] == R[B]) ~= k) then pc++
-pub fn eq(i: u32, vm: &mut dyn LuaVM) {
-    compare(i, vm, CmpOp::EQ as u8);
+pub fn eq(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
+    compare(i, vm, CmpOp::EQ as u8)
 }
 
 // OP_LT               A B k               if ((R[A] <  R[B]) ~= k) then pc++
-pub fn lt(i: u32, vm: &mut dyn LuaVM) {
-    compare(i, vm, CmpOp::LT as u8);
+pub fn lt(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
+    compare(i, vm, CmpOp::LT as u8)
 }
 
 // OP_LE               A B k               if ((R[A] <= R[B]) ~= k) then pc++
-pub fn le(i: u32, vm: &mut dyn LuaVM) {
-    compare(i, vm, CmpOp::LE as u8);
+pub fn le(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
+    compare(i, vm, CmpOp::LE as u8)
 }
 
 // OP_EQK              A B k               if ((R[A] == K[B]) ~= k) then pc++
-pub fn eq_k(i: u32, vm: &mut dyn LuaVM) {
-    compare_k(i, vm, CmpOp::EQ as u8);
+pub fn eq_k(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
+    compare_k(i, vm, CmpOp::EQ as u8)
 }
 
 // OP_EQI              A sB k              if ((R[A] == sB) ~= k) then pc++
-pub fn eq_i(i: u32, vm: &mut dyn LuaVM) {
-    compare_i(i, vm, CmpOp::EQ as u8);
+pub fn eq_i(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
+    compare_i(i, vm, CmpOp::EQ as u8)
 }
 
 // OP_LTI              A sB k              if ((R[A] < sB) ~= k) then pc++
-pub fn lt_i(i: u32, vm: &mut dyn LuaVM) {
-    compare_i(i, vm, CmpOp::LT as u8);
+pub fn lt_i(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
+    compare_i(i, vm, CmpOp::LT as u8)
 }
 
 // OP_LEI              A sB k              if ((R[A] <= sB) ~= k) then pc++
-pub fn le_i(i: u32, vm: &mut dyn LuaVM) {
-    compare_i(i, vm, CmpOp::LE as u8);
+pub fn le_i(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
+    compare_i(i, vm, CmpOp::LE as u8)
 }
 
 // OP_GTI              A sB k              if ((R[A] > sB) ~= k) then pc++
-pub fn gt_i(i: u32, vm: &mut dyn LuaVM) {
-    compare_i(i, vm, CmpOp::GT as u8);
+pub fn gt_i(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
+    compare_i(i, vm, CmpOp::GT as u8)
 }
 
 // OP_GEI              A sB k              if ((R[A] >= sB) ~= k) then pc++
-pub fn ge_i(i: u32, vm: &mut dyn LuaVM) {
-    compare_i(i, vm, CmpOp::GE as u8);
+pub fn ge_i(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
+    compare_i(i, vm, CmpOp::GE as u8)
 }
 
 // OP_TESTSET          A B k               if (not R[B] == k) then pc++ else R[A] := R[B] (*)
@@ -315,7 +391,7 @@ mod tests {
         let mut vm = LuaState::new();
         vm.push_nil();
         vm.push_integer(1);
-        add_i(0b00000010_00000001_0_00000000_0010101, &mut vm);
+        add_i(0b10000001_00000001_0_00000000_0010101, &mut vm);
         assert!(vm.is_integer(1));
         assert!(vm.to_integer(1) == 3)
     }
@@ -325,7 +401,7 @@ mod tests {
         let mut vm = LuaState::new();
         vm.push_nil();
         vm.push_number(1.1);
-        add_i(0b00000010_00000001_0_00000000_0010101, &mut vm);
+        add_i(0b10000001_00000001_0_00000000_0010101, &mut vm);
         assert!(vm.is_number(1));
         assert!(numbers_are_equal(vm.to_number(1), 3.1, 0.01))
     }
@@ -513,8 +589,8 @@ mod tests {
     fn test_shl_i() {
         let mut vm = LuaState::new();
         vm.push_nil();
-        vm.push_integer(0b00001111);
-        shl_i(0b00000001_00000001_0_00000000_0100000, &mut vm);
+        vm.push_integer(1);
+        shl_i(0b10001110_00000001_0_00000000_0100000, &mut vm);
         assert!(vm.is_integer(1));
         assert!(vm.to_integer(1) == 0b00011110)
     }
@@ -524,7 +600,7 @@ mod tests {
         let mut vm = LuaState::new();
         vm.push_nil();
         vm.push_integer(0b00001111);
-        shr_i(0b00000001_00000001_0_00000000_0100001, &mut vm);
+        shr_i(0b10000000_00000001_0_00000000_0100001, &mut vm);
         assert!(vm.is_integer(1));
         assert!(vm.to_integer(1) == 0b0000111)
     }
@@ -724,7 +800,7 @@ mod tests {
         let mut vm = LuaState::new();
         vm.push_nil();
         vm.push_integer(1);
-        unm(0b00000000_00000001_0_00000000_0110001, &mut vm);
+        unm(0b00000000_00000001_0_00000000_0110001, &mut vm).unwrap();
         assert!(vm.is_integer(1));
         assert!(vm.to_integer(1) == -1)
     }
@@ -734,7 +810,7 @@ mod tests {
         let mut vm = LuaState::new();
         vm.push_nil();
         vm.push_number(1.0);
-        unm(0b00000000_00000001_0_00000000_0110001, &mut vm);
+        unm(0b00000000_00000001_0_00000000_0110001, &mut vm).unwrap();
         assert!(vm.is_number(1));
         assert!(vm.to_number(1) == -1.0)
     }
@@ -744,7 +820,7 @@ mod tests {
         let mut vm = LuaState::new();
         vm.push_nil();
         vm.push_integer(0b11111111);
-        bnot(0b00000000_00000001_0_00000000_0110010, &mut vm);
+        bnot(0b00000000_00000001_0_00000000_0110010, &mut vm).unwrap();
         assert!(vm.is_integer(1));
         assert!(vm.to_integer(1) == -256)
     }
@@ -768,7 +844,7 @@ mod tests {
         let mut vm = LuaState::new();
         vm.push_nil();
         vm.push_string("hello".to_string());
-        len(0b00000000_00000001_0_00000000_0110100, &mut vm);
+        len(0b00000000_00000001_0_00000000_0110100, &mut vm).unwrap();
         assert!(vm.is_integer(1));
         assert!(vm.to_integer(1) == 5)
     }
@@ -779,7 +855,7 @@ mod tests {
         vm.push_nil();
         vm.push_string("hello".to_string());
         vm.push_string("world".to_string());
-        concat(0b00000000_00000010_0_00000001_0110101, &mut vm);
+        concat(0b00000000_00000010_0_00000001_0110101, &mut vm).unwrap();
         assert!(vm.is_string(2));
         assert!(vm.to_string(2) == "helloworld")
     }
@@ -790,12 +866,12 @@ mod tests {
         vm.push_integer(1);
         vm.push_integer(1);
         assert_eq!(vm.pc(), 0);
-        eq(0b00000000_00000001_0_00000000_0111001, &mut vm);
+        eq(0b00000000_00000001_0_00000000_0111001, &mut vm).unwrap();
         assert_eq!(vm.pc(), 1);
 
         vm.push_integer(0);
         assert_eq!(vm.pc(), 1);
-        eq(0b00000000_00000010_0_00000001_0111001, &mut vm);
+        eq(0b00000000_00000010_0_00000001_0111001, &mut vm).unwrap();
         assert_eq!(vm.pc(), 1);
     }
 
@@ -805,17 +881,17 @@ mod tests {
         vm.push_integer(1);
         vm.push_integer(2);
         assert_eq!(vm.pc(), 0);
-        lt(0b00000000_00000001_0_00000000_0111010, &mut vm);
+        lt(0b00000000_00000001_0_00000000_0111010, &mut vm).unwrap();
         assert_eq!(vm.pc(), 1);
 
         vm.push_integer(2);
         assert_eq!(vm.pc(), 1);
-        lt(0b00000000_00000010_0_00000001_0111010, &mut vm);
+        lt(0b00000000_00000010_0_00000001_0111010, &mut vm).unwrap();
         assert_eq!(vm.pc(), 1);
 
         vm.push_integer(0);
         assert_eq!(vm.pc(), 1);
-        lt(0b00000000_00000011_0_00000001_0111010, &mut vm);
+        lt(0b00000000_00000011_0_00000001_0111010, &mut vm).unwrap();
         assert_eq!(vm.pc(), 1);
     }
 
@@ -825,17 +901,17 @@ mod tests {
         vm.push_integer(1);
         vm.push_integer(2);
         assert_eq!(vm.pc(), 0);
-        le(0b00000000_00000001_0_00000000_0111011, &mut vm);
+        le(0b00000000_00000001_0_00000000_0111011, &mut vm).unwrap();
         assert_eq!(vm.pc(), 1);
 
         vm.push_integer(2);
         assert_eq!(vm.pc(), 1);
-        le(0b00000000_00000010_0_00000001_0111011, &mut vm);
+        le(0b00000000_00000010_0_00000001_0111011, &mut vm).unwrap();
         assert_eq!(vm.pc(), 2);
 
         vm.push_integer(0);
         assert_eq!(vm.pc(), 2);
-        le(0b00000000_00000011_0_00000001_0111011, &mut vm);
+        le(0b00000000_00000011_0_00000001_0111011, &mut vm).unwrap();
         assert_eq!(vm.pc(), 2);
     }
 
@@ -848,12 +924,12 @@ mod tests {
         vm.push_nil();
         vm.push_integer(1);
         assert_eq!(vm.pc(), 0);
-        eq_k(0b00000000_00000000_0_00000000_0111100, &mut vm);
+        eq_k(0b00000000_00000000_0_00000001_0111100, &mut vm).unwrap();
         assert_eq!(vm.pc(), 1);
 
         vm.push_integer(0);
         assert_eq!(vm.pc(), 1);
-        eq_k(0b00000000_00000001_0_00000001_0111100, &mut vm);
+        eq_k(0b00000000_00000001_0_00000010_0111100, &mut vm).unwrap();
         assert_eq!(vm.pc(), 1);
     }
 
@@ -863,11 +939,11 @@ mod tests {
         vm.push_nil();
         vm.push_integer(1);
         assert_eq!(vm.pc(), 0);
-        eq_i(0b00000000_10000000_0_00000001_0111101, &mut vm);
+        eq_i(0b00000000_10000000_0_00000001_0111101, &mut vm).unwrap();
         assert_eq!(vm.pc(), 1);
 
         assert_eq!(vm.pc(), 1);
-        eq_i(0b00000000_01111111_0_00000001_0111101, &mut vm);
+        eq_i(0b00000000_01111111_0_00000001_0111101, &mut vm).unwrap();
         assert_eq!(vm.pc(), 1);
     }
 
@@ -877,16 +953,16 @@ mod tests {
         vm.push_nil();
         vm.push_integer(1);
         assert_eq!(vm.pc(), 0);
-        lt_i(0b00000000_10000001_0_00000001_0111110, &mut vm);
+        lt_i(0b00000000_10000001_0_00000001_0111110, &mut vm).unwrap();
         assert_eq!(vm.pc(), 1);
 
         vm.push_integer(2);
         assert_eq!(vm.pc(), 1);
-        lt_i(0b00000000_10000001_0_00000010_0111110, &mut vm);
+        lt_i(0b00000000_10000001_0_00000010_0111110, &mut vm).unwrap();
         assert_eq!(vm.pc(), 1);
 
         assert_eq!(vm.pc(), 1);
-        lt_i(0b00000000_00000000_0_00000010_111110, &mut vm);
+        lt_i(0b00000000_00000000_0_00000010_111110, &mut vm).unwrap();
         assert_eq!(vm.pc(), 1);
     }
 
@@ -896,17 +972,17 @@ mod tests {
         vm.push_nil();
         vm.push_integer(1);
         assert_eq!(vm.pc(), 0);
-        le_i(0b00000000_10000001_0_00000001_0111111, &mut vm);
+        le_i(0b00000000_10000001_0_00000001_0111111, &mut vm).unwrap();
         assert_eq!(vm.pc(), 1);
 
         vm.push_integer(2);
         assert_eq!(vm.pc(), 1);
-        le_i(0b00000000_10000001_0_00000010_0111111, &mut vm);
+        le_i(0b00000000_10000001_0_00000010_0111111, &mut vm).unwrap();
         assert_eq!(vm.pc(), 2);
 
         vm.push_integer(0);
         assert_eq!(vm.pc(), 2);
-        le_i(0b00000000_01111111_0_00000010_0111111, &mut vm);
+        le_i(0b00000000_01111111_0_00000010_0111111, &mut vm).unwrap();
         assert_eq!(vm.pc(), 2);
     }
 
@@ -916,16 +992,16 @@ mod tests {
         vm.push_nil();
         vm.push_integer(1);
         assert_eq!(vm.pc(), 0);
-        gt_i(0b00000000_01111111_0_00000001_1000000, &mut vm);
+        gt_i(0b00000000_01111111_0_00000001_1000000, &mut vm).unwrap();
         assert_eq!(vm.pc(), 1);
 
         vm.push_integer(1);
         assert_eq!(vm.pc(), 1);
-        gt_i(0b00000000_10000000_0_00000010_1000000, &mut vm);
+        gt_i(0b00000000_10000000_0_00000010_1000000, &mut vm).unwrap();
         assert_eq!(vm.pc(), 1);
 
         assert_eq!(vm.pc(), 1);
-        gt_i(0b00000000_10000001_0_00000010_1000000, &mut vm);
+        gt_i(0b00000000_10000001_0_00000010_1000000, &mut vm).unwrap();
         assert_eq!(vm.pc(), 1);
     }
 
@@ -935,16 +1011,16 @@ mod tests {
         vm.push_nil();
         vm.push_integer(1);
         assert_eq!(vm.pc(), 0);
-        ge_i(0b00000000_01111111_0_00000001_1000001, &mut vm);
+        ge_i(0b00000000_01111111_0_00000001_1000001, &mut vm).unwrap();
         assert_eq!(vm.pc(), 1);
 
         vm.push_integer(1);
         assert_eq!(vm.pc(), 1);
-        ge_i(0b00000000_10000000_0_00000010_1000001, &mut vm);
+        ge_i(0b00000000_10000000_0_00000010_1000001, &mut vm).unwrap();
         assert_eq!(vm.pc(), 2);
 
         assert_eq!(vm.pc(), 2);
-        ge_i(0b00000000_10000001_0_00000010_1000001, &mut vm);
+        ge_i(0b00000000_10000001_0_00000010_1000001, &mut vm).unwrap();
         assert_eq!(vm.pc(), 2);
     }
 
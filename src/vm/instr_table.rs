@@ -1,4 +1,4 @@
-use crate::api::LuaVM;
+use crate::api::{LuaError, LuaVM};
 
 use super::instruction::{Instruction, MAXARG_C};
 
@@ -14,65 +14,75 @@ pub fn new_table(i: u32, vm: &mut dyn LuaVM) {
         b = 1 << (b - 1);
     }
     if k != 0 {
-        c += (vm.pc() as u32).get_arg_ax() * (MAXARG_C + 1)
+        c += vm.fetch().get_arg_ax() * (MAXARG_C + 1);
+    } else {
+        vm.add_pc(1);
     }
-    vm.add_pc(1);
-    vm.create_table(b as usize, c as usize);
+    vm.create_table(c as usize, b as usize);
     vm.replace(a);
 }
 
 // OP_GETTABLE         A B C               R[A] := R[B][R[C]]
-pub fn get_table(i: u32, vm: &mut dyn LuaVM) {
+pub fn get_table(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
     let (a, b, c) = (i.get_arg_a() + 1, i.get_arg_b() + 1, i.get_arg_c());
     vm.get_rk(c);
-    vm.get_table(b);
+    vm.get_table(b)?;
     vm.replace(a);
+    Ok(())
 }
 
 // OP_GETI             A B C               R[A] := R[B][C]
-pub fn get_i(i: u32, vm: &mut dyn LuaVM) {
+pub fn get_i(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
     let (a, b, c) = (i.get_arg_a() + 1, i.get_arg_b() + 1, i.get_arg_c());
-    vm.get_i(b, c as i64);
+    vm.get_i(b, c as i64)?;
     vm.replace(a);
+    Ok(())
 }
 // OP_GETFIELD         A B C               R[A] := R[B][K[C]:string]
-pub fn get_field(i: u32, vm: &mut dyn LuaVM) {
+pub fn get_field(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
     let (a, b, c) = (i.get_arg_a() + 1, i.get_arg_b() + 1, i.get_arg_c());
     vm.get_const(c);
     let k = vm.to_string(-1);
     vm.pop(1);
-    vm.get_field(b, k.as_str());
+    vm.get_field(b, k.as_str())?;
     vm.replace(a);
+    Ok(())
 }
 
 // OP_SETTABLE         A B C               R[A][R[B]] := RK(C)
-pub fn set_table(i: u32, vm: &mut dyn LuaVM) {
+pub fn set_table(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
     let (a, b, c) = (i.get_arg_a() + 1, i.get_arg_b(), i.get_arg_c());
     vm.get_rk(b);
-    vm.get_rk(c);
-    vm.set_table(a);
+    push_rk(vm, c, i.get_arg_k());
+    vm.set_table(a)?;
+    Ok(())
 }
 
 // OP_SETI             A B C               R[A][B] := RK(C)
-pub fn set_i(i: u32, vm: &mut dyn LuaVM) {
+pub fn set_i(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
     let (a, b, c) = (i.get_arg_a() + 1, i.get_arg_b(), i.get_arg_c());
-    vm.get_const(c);
-    vm.set_i(a, b as i64);
+    push_rk(vm, c, i.get_arg_k());
+    vm.set_i(a, b as i64)?;
+    Ok(())
 }
 
 // OP_SETFIELD         A B C               R[A][K[B]:string] := RK(C)
-pub fn set_field(i: u32, vm: &mut dyn LuaVM) {
+pub fn set_field(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
     let (a, b, c) = (i.get_arg_a() + 1, i.get_arg_b(), i.get_arg_c());
     vm.get_const(b);
     let k = vm.to_string(-1);
     vm.pop(1);
-    vm.get_const(c);
-    vm.set_field(a, k.as_str());
+    push_rk(vm, c, i.get_arg_k());
+    vm.set_field(a, k.as_str())?;
+    Ok(())
 }
 
 // OP_SETLIST          A B C k             R[A][C+i] := R[A+i], 1 <= i <= B
 pub fn set_list(i: u32, vm: &mut dyn LuaVM) {
-    let (a, mut b, c) = (i.get_arg_a() + 1, i.get_arg_b(), i.get_arg_c());
+    let (a, mut b, mut c) = (i.get_arg_a() + 1, i.get_arg_b(), i.get_arg_c());
+    if i.get_arg_k() != 0 {
+        c += vm.fetch().get_arg_ax() * (MAXARG_C + 1);
+    }
 
     let b_is_zero = b == 0;
     if b_is_zero {
@@ -85,7 +95,7 @@ pub fn set_list(i: u32, vm: &mut dyn LuaVM) {
     for j in 1..(b + 1) {
         idx += 1;
         vm.push_value(a + j);
-        vm.set_i(a, idx as i64);
+        vm.raw_set_i(a, idx as i64);
     }
 
     if b_is_zero {
@@ -93,7 +103,7 @@ pub fn set_list(i: u32, vm: &mut dyn LuaVM) {
         for j in (nreg + 1)..(vm.get_top() + 1) {
             idx += 1;
             vm.push_value(j);
-            vm.set_i(a, idx as i64);
+            vm.raw_set_i(a, idx as i64);
         }
 
         // clear stack
@@ -101,15 +111,35 @@ pub fn set_list(i: u32, vm: &mut dyn LuaVM) {
     }
 }
 
+/// 按 k 标志把 RK(C) 推入栈顶：k 为 1 时 C 是常量索引，否则是寄存器
+pub fn push_rk(vm: &mut dyn LuaVM, c: isize, k: isize) {
+    if k != 0 {
+        vm.get_const(c);
+    } else {
+        vm.push_value(c + 1);
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::{api::LuaAPI, state::LuaState};
+    use std::rc::Rc;
+
+    use crate::{
+        api::LuaAPI,
+        binary::chunk::Prototype,
+        state::LuaState,
+        vm::{instruction::create_ax, opcodes::OP_EXTRAARG},
+    };
 
     use super::*;
 
     #[test]
     fn test_table() {
-        let mut vm = LuaState::new();
+        let proto = Prototype {
+            code: vec![0, create_ax(OP_EXTRAARG, 1)],
+            ..Default::default()
+        };
+        let mut vm = LuaState::new_with_proto(Rc::new(proto));
         vm.push_nil();
         new_table(0b00000000_00000000_0_00000000_0010011, &mut vm);
         assert!(vm.is_table(1));
@@ -118,8 +148,8 @@ mod tests {
         assert!(vm.is_table(2));
         vm.push_integer(1);
         vm.push_string("1".to_string());
-        set_table(0b00000011_00000010_0_00000001_0010000, &mut vm);
-        get_table(0b00000010_00000001_0_00000000_0001100, &mut vm);
+        set_table(0b00000011_00000010_0_00000001_0010000, &mut vm).unwrap();
+        get_table(0b00000010_00000001_0_00000000_0001100, &mut vm).unwrap();
         assert!(vm.is_table(2));
         assert!(vm.is_string(1));
         assert!(vm.to_string(1) == "1".to_string());
@@ -1,13 +1,86 @@
-use super::instruction::Instruction;
-use crate::api::LuaVM;
+use super::{instr_table::push_rk, instruction::Instruction};
+use crate::api::{consts::lua_upvalueindex, LuaError, LuaVM};
 
-// OP_GETTABUP         A B C               R[A] := UpValue[B][K[C]:string]
-pub fn get_tab_up(i: u32, vm: &mut dyn LuaVM) {
-    let (a, c) = (i.get_arg_a() + 1, i.get_arg_c());
+// OP_GETUPVAL         A B                 R[A] := UpValue[B]
+pub fn get_upval(i: u32, vm: &mut dyn LuaVM) {
+    let (a, b) = (i.get_arg_a() + 1, i.get_arg_b() + 1);
+    vm.copy(lua_upvalueindex(b), a);
+}
 
-    vm.push_global_table();
+// OP_SETUPVAL         A B                 UpValue[B] := R[A]
+pub fn set_upval(i: u32, vm: &mut dyn LuaVM) {
+    let (a, b) = (i.get_arg_a() + 1, i.get_arg_b() + 1);
+    vm.copy(a, lua_upvalueindex(b));
+}
+
+// OP_GETTABUP         A B C               R[A] := UpValue[B][K[C]:string]
+pub fn get_tab_up(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
+    let (a, b, c) = (i.get_arg_a() + 1, i.get_arg_b() + 1, i.get_arg_c());
     vm.get_const(c);
-    vm.get_table(-2);
+    vm.get_table(lua_upvalueindex(b))?;
     vm.replace(a);
-    vm.pop(1);
+    Ok(())
+}
+
+// OP_SETTABUP         A B C k             UpValue[A][K[B]:string] := RK(C)
+pub fn set_tab_up(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
+    let (a, b, c) = (i.get_arg_a() + 1, i.get_arg_b(), i.get_arg_c());
+    vm.get_const(b);
+    push_rk(vm, c, i.get_arg_k());
+    vm.set_table(lua_upvalueindex(a))?;
+    Ok(())
+}
+
+// OP_CLOSE            A                   close all upvalues >= R[A]
+pub fn close(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
+    let a = i.get_arg_a() + 1;
+    vm.close(a)
+}
+
+// OP_TBC              A                   mark variable A "to be closed"
+pub fn tbc(i: u32, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
+    let a = i.get_arg_a() + 1;
+    vm.to_close(a)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use crate::{
+        api::LuaAPI,
+        binary::chunk::{Constant, Prototype, Upvalue},
+        state::LuaState,
+        vm::{instruction::create_abck, opcodes::*},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_upval() {
+        let proto = Prototype {
+            constants: vec![Constant::Str(b"x".to_vec())],
+            upvalues: vec![Upvalue {
+                instack: 1,
+                idx: 0,
+                kind: 0,
+            }],
+            ..Default::default()
+        };
+        let mut vm = LuaState::new_with_proto(Rc::new(proto));
+        vm.new_table();
+        vm.push_integer(1);
+        set_upval(create_abck(OP_SETUPVAL, 0, 0, 0, 0), &mut vm);
+        get_upval(create_abck(OP_GETUPVAL, 1, 0, 0, 0), &mut vm);
+        assert!(vm.is_table(2));
+
+        // UpValue[0]["x"] := R[1]
+        vm.push_integer(42);
+        vm.replace(2);
+        set_tab_up(create_abck(OP_SETTABUP, 0, 0, 1, 0), &mut vm).unwrap();
+        get_tab_up(create_abck(OP_GETTABUP, 1, 0, 0, 0), &mut vm).unwrap();
+        assert_eq!(vm.to_integer(2), 42);
+        vm.get_field(1, "x").unwrap();
+        assert_eq!(vm.to_integer(-1), 42);
+    }
 }
@@ -16,7 +16,7 @@
 */
 
 use crate::{
-    api::LuaVM,
+    api::{LuaError, LuaVM},
     vm::{
         instr_call::*, instr_for::*, instr_load::*, instr_misc::*, instr_ops::*, instr_table::*,
         instr_upval::*, opcodes::*,
@@ -48,6 +48,7 @@ pub const MAXARG_C: isize = (1 << SIZE_C) - 1;
 pub const MAXARG_K: isize = (1 << SIZE_K) - 1;
 pub const MAXARG_BX: isize = (1 << SIZE_BX) - 1;
 pub const MAXARG_AX: isize = (1 << SIZE_AX) - 1;
+pub const MAXARG_SJ: isize = (1 << SIZE_SJ) - 1;
 pub const OFFSET_SB: isize = MAXARG_B >> 1;
 pub const OFFSET_SC: isize = MAXARG_C >> 1;
 pub const OFFSET_SBX: isize = MAXARG_BX >> 1;
@@ -67,7 +68,44 @@ pub trait Instruction {
     fn get_arg_sc(self) -> isize;
     fn get_arg_sbx(self) -> isize;
     fn get_arg_sj(self) -> isize;
-    fn execute(self, vm: &mut dyn LuaVM);
+    fn set_opcode(&mut self, op: u8);
+    fn set_arg_a(&mut self, v: isize);
+    fn set_arg_b(&mut self, v: isize);
+    fn set_arg_c(&mut self, v: isize);
+    fn set_arg_k(&mut self, v: isize);
+    fn set_arg_bx(&mut self, v: isize);
+    fn set_arg_sj(&mut self, v: isize);
+    fn execute(self, vm: &mut dyn LuaVM) -> Result<(), LuaError>;
+}
+
+/// 用 `v` 替换指令中 `pos` 起 `size` 位宽的字段
+fn set_arg(i: &mut u32, v: isize, pos: isize, size: isize) {
+    let mask = (((1u64 << size) - 1) as u32) << pos;
+    *i = (*i & !mask) | (((v as u32) << pos) & mask);
+}
+
+/// 构造 iABC 格式的指令
+pub fn create_abck(op: u8, a: isize, b: isize, c: isize, k: isize) -> u32 {
+    (op as u32) << POS_OP
+        | (a as u32) << POS_A
+        | (b as u32) << POS_B
+        | (c as u32) << POS_C
+        | (k as u32) << POS_K
+}
+
+/// 构造 iABx 格式的指令
+pub fn create_abx(op: u8, a: isize, bx: isize) -> u32 {
+    (op as u32) << POS_OP | (a as u32) << POS_A | (bx as u32) << POS_BX
+}
+
+/// 构造 iAx 格式的指令
+pub fn create_ax(op: u8, ax: isize) -> u32 {
+    (op as u32) << POS_OP | (ax as u32) << POS_AX
+}
+
+/// 构造 isJ 格式的指令，`sj` 为有符号跳转偏移
+pub fn create_sj(op: u8, sj: isize) -> u32 {
+    (op as u32) << POS_OP | ((sj + OFFSET_SJ) as u32) << POS_SJ
 }
 
 impl Instruction for u32 {
@@ -123,7 +161,35 @@ impl Instruction for u32 {
         (self >> POS_SJ & MAXARG_AX as u32) as isize - OFFSET_SJ
     }
 
-    fn execute(self, vm: &mut dyn LuaVM) {
+    fn set_opcode(&mut self, op: u8) {
+        set_arg(self, op as isize, POS_OP, SIZE_OP);
+    }
+
+    fn set_arg_a(&mut self, v: isize) {
+        set_arg(self, v, POS_A, SIZE_A);
+    }
+
+    fn set_arg_b(&mut self, v: isize) {
+        set_arg(self, v, POS_B, SIZE_B);
+    }
+
+    fn set_arg_c(&mut self, v: isize) {
+        set_arg(self, v, POS_C, SIZE_C);
+    }
+
+    fn set_arg_k(&mut self, v: isize) {
+        set_arg(self, v, POS_K, SIZE_K);
+    }
+
+    fn set_arg_bx(&mut self, v: isize) {
+        set_arg(self, v, POS_BX, SIZE_BX);
+    }
+
+    fn set_arg_sj(&mut self, v: isize) {
+        set_arg(self, v + OFFSET_SJ, POS_SJ, SIZE_SJ);
+    }
+
+    fn execute(self, vm: &mut dyn LuaVM) -> Result<(), LuaError> {
         match self.opcode() {
             OP_MOVE => _move(self, vm),
             OP_LOADI => load_i(self, vm),
@@ -134,15 +200,18 @@ impl Instruction for u32 {
             OP_LFALSESKIP => load_l_false_skip(self, vm),
             OP_LOADTRUE => load_true(self, vm),
             OP_LOADNIL => load_nil(self, vm),
-            OP_GETTABUP => get_tab_up(self, vm),
-            OP_GETTABLE => get_table(self, vm),
-            OP_GETI => get_i(self, vm),
-            OP_GETFIELD => get_field(self, vm),
-            OP_SETTABLE => set_table(self, vm),
-            OP_SETI => set_i(self, vm),
-            OP_SETFIELD => set_field(self, vm),
+            OP_GETUPVAL => get_upval(self, vm),
+            OP_SETUPVAL => set_upval(self, vm),
+            OP_GETTABUP => get_tab_up(self, vm)?,
+            OP_GETTABLE => get_table(self, vm)?,
+            OP_GETI => get_i(self, vm)?,
+            OP_GETFIELD => get_field(self, vm)?,
+            OP_SETTABUP => set_tab_up(self, vm)?,
+            OP_SETTABLE => set_table(self, vm)?,
+            OP_SETI => set_i(self, vm)?,
+            OP_SETFIELD => set_field(self, vm)?,
             OP_NEWTABLE => new_table(self, vm),
-            OP_SELF => _self(self, vm),
+            OP_SELF => _self(self, vm)?,
             OP_ADDI => add_i(self, vm),
             OP_ADDK => add_k(self, vm),
             OP_SUBK => sub_k(self, vm),
@@ -168,32 +237,38 @@ impl Instruction for u32 {
             OP_BXOR => bxor(self, vm),
             OP_SHL => shl(self, vm),
             OP_SHR => shr(self, vm),
-            OP_MMBIN => {}
-            OP_MMBINI => {}
-            OP_MMBINK => {}
-            OP_UNM => unm(self, vm),
-            OP_BNOT => bnot(self, vm),
+            OP_MMBIN => mm_bin(self, vm)?,
+            OP_MMBINI => mm_bin_i(self, vm)?,
+            OP_MMBINK => mm_bin_k(self, vm)?,
+            OP_UNM => unm(self, vm)?,
+            OP_BNOT => bnot(self, vm)?,
             OP_NOT => not(self, vm),
-            OP_LEN => len(self, vm),
-            OP_CONCAT => concat(self, vm),
+            OP_LEN => len(self, vm)?,
+            OP_CONCAT => concat(self, vm)?,
+            OP_CLOSE => close(self, vm)?,
+            OP_TBC => tbc(self, vm)?,
             OP_JMP => jmp(self, vm),
-            OP_EQ => eq(self, vm),
-            OP_LT => lt(self, vm),
-            OP_LE => le(self, vm),
-            OP_EQK => eq_k(self, vm),
-            OP_EQI => eq_i(self, vm),
-            OP_LTI => lt_i(self, vm),
-            OP_LEI => le_i(self, vm),
-            OP_GTI => gt_i(self, vm),
-            OP_GEI => ge_i(self, vm),
+            OP_EQ => eq(self, vm)?,
+            OP_LT => lt(self, vm)?,
+            OP_LE => le(self, vm)?,
+            OP_EQK => eq_k(self, vm)?,
+            OP_EQI => eq_i(self, vm)?,
+            OP_LTI => lt_i(self, vm)?,
+            OP_LEI => le_i(self, vm)?,
+            OP_GTI => gt_i(self, vm)?,
+            OP_GEI => ge_i(self, vm)?,
             OP_TEST => test(self, vm),
             OP_SETLIST => set_list(self, vm),
-            OP_CALL => call(self, vm),
-            OP_TAILCALL => tail_call(self, vm),
-            OP_RETURN => _return(self, vm),
+            OP_CALL => call(self, vm)?,
+            OP_TAILCALL => tail_call(self, vm)?,
+            OP_RETURN => _return(self, vm)?,
             OP_RETURN0 => return0(self, vm),
+            OP_RETURN1 => return1(self, vm),
             OP_FORLOOP => for_loop(self, vm),
-            OP_FORPREP => for_prep(self, vm),
+            OP_FORPREP => for_prep(self, vm)?,
+            OP_TFORPREP => tfor_prep(self, vm)?,
+            OP_TFORCALL => tfor_call(self, vm)?,
+            OP_TFORLOOP => tfor_loop(self, vm),
             OP_TESTSET => test_set(self, vm),
             OP_CLOSURE => closure(self, vm),
             OP_VARARG => vararg(self, vm),
@@ -204,6 +279,7 @@ impl Instruction for u32 {
                 unimplemented!()
             }
         }
+        Ok(())
     }
 }
 
@@ -212,7 +288,7 @@ mod tests {
     use std::{fs::File, io::Read};
 
     use crate::{
-        api::LuaAPI,
+        api::{LuaAPI, LuaError},
         state::{self},
     };
 
@@ -225,12 +301,12 @@ mod tests {
         let _ = file.read_to_end(&mut data);
 
         let mut ls = state::new_lua_state();
-        ls.register("print", print);
+        ls.register("print", print).unwrap();
         ls.load(data, &filename, "b");
-        ls.call(0, 0);
+        ls.call(0, 0).unwrap();
     }
 
-    fn print(ls: &dyn LuaAPI) -> usize {
+    fn print(ls: &mut dyn LuaAPI) -> Result<usize, LuaError> {
         let nargs = ls.get_top();
         for i in 1..(nargs + 1) {
             if ls.is_boolean(i) {
@@ -245,6 +321,6 @@ mod tests {
             }
         }
         println!("");
-        return 0;
+        Ok(0)
     }
 }